@@ -9,8 +9,13 @@ use web_sys::{
     ProgressEvent, UiEvent, Url, WebGl2RenderingContext, Window,
 };
 
-use scene::{Id, Layer, Rect, Sprite};
+use scene::{
+    comms::ChatMessage, BlendMode, Colour, Grid, Id, Layer, Rect, ScenePoint, Sprite, Template,
+    Token,
+};
 
+use crate::interactor::InitiativeDetails;
+use crate::interactor::RollDetails;
 use crate::interactor::SceneDetails;
 use crate::interactor::SpriteDetails;
 use crate::programs::Renderer;
@@ -29,6 +34,41 @@ extern "C" {
     // scene canvas.
     pub fn sprite_dropdown(sprite: Id, x: f32, y: f32);
 
+    // Removes all ruler distance labels from the scene canvas.
+    pub fn clear_ruler_labels();
+
+    // Shows a ruler distance label at (x, y) on the scene canvas.
+    pub fn add_ruler_label(label: &str, x: f32, y: f32);
+
+    // Removes all token name plates and condition icons.
+    pub fn clear_token_labels();
+
+    // Shows the name and conditions of a token centred below (x, y).
+    #[wasm_bindgen(js_name = add_token_label)]
+    fn _add_token_label(token_json: String, x: f32, y: f32);
+
+    // Shows the result of a dice roll in the dice menu.
+    #[wasm_bindgen(js_name = add_roll)]
+    fn _add_roll(roll_json: String);
+
+    // Shows an error message for an invalid dice expression.
+    pub fn roll_error(message: &str);
+
+    // Adds a message to the chat log.
+    #[wasm_bindgen(js_name = add_chat_message)]
+    fn _add_chat_message(message_json: String);
+
+    // Shows an error message for a chat message which couldn't be sent.
+    pub fn chat_error(message: &str);
+
+    // Updates the initiative tracker to show this turn order.
+    #[wasm_bindgen(js_name = update_initiative)]
+    fn _update_initiative(initiative_json: String);
+
+    // Updates the list of users in the game, who may be made token owners.
+    #[wasm_bindgen(js_name = update_players)]
+    fn _update_players(players_json: String);
+
     // Shows or hides the relevant UI elements given a role integer.
     pub fn update_interface(role: i32);
 
@@ -57,6 +97,9 @@ extern "C" {
     #[wasm_bindgen(js_name = expose_closure)]
     pub fn expose_closure_f64(name: &str, closure: &Closure<dyn FnMut(f64)>);
 
+    #[wasm_bindgen(js_name = expose_closure)]
+    pub fn expose_closure_bool(name: &str, closure: &Closure<dyn FnMut(bool)>);
+
     #[wasm_bindgen(js_name = expose_closure)]
     pub fn expose_closure_string_out(name: &str, closure: &Closure<dyn FnMut() -> String>);
 
@@ -403,6 +446,8 @@ pub enum Key {
     Control,
     Delete,
     Shift,
+    LeftBracket,
+    RightBracket,
     A,
     B,
     C,
@@ -438,6 +483,8 @@ impl Key {
             "Control" => Self::Control,
             "Delete" => Self::Delete,
             "Shift" => Self::Shift,
+            "[" => Self::LeftBracket,
+            "]" => Self::RightBracket,
             "a" => Self::A,
             "b" => Self::B,
             "c" => Self::C,
@@ -623,8 +670,12 @@ impl Context {
         self.gl.clear(Gl::COLOR_BUFFER_BIT);
     }
 
-    pub fn draw_grid(&mut self, vp: Rect, dims: Rect, grid_size: f32) {
-        self.renderer.render_grid(vp, dims, grid_size);
+    pub fn draw_grid(&mut self, vp: Rect, dims: Rect, grid: Grid, grid_size: f32) {
+        self.renderer.render_grid(vp, dims, grid, grid_size);
+    }
+
+    pub fn set_layer_style(&mut self, opacity: f32, blend: BlendMode) {
+        self.renderer.set_layer_style(opacity, blend);
     }
 
     pub fn draw_sprites(&mut self, vp: Rect, sprites: &[Sprite], grid_size: f32) {
@@ -637,6 +688,65 @@ impl Context {
     pub fn draw_outline(&mut self, vp: Rect, outline: Rect) {
         self.renderer.draw_outline(vp, outline);
     }
+
+    pub fn draw_path(&mut self, vp: Rect, path: &[ScenePoint], grid_size: f32, colour: Colour) {
+        let path = path
+            .iter()
+            .map(|p| ScenePoint::new(p.x * grid_size, p.y * grid_size))
+            .collect::<Vec<ScenePoint>>();
+        self.renderer.draw_path(vp, &path, colour);
+    }
+
+    // Draw a bar along the bottom of each sprite which has HP and a maximum,
+    // filled in proportion to the HP remaining.
+    pub fn draw_health_bars(&mut self, vp: Rect, sprites: &[Sprite], grid_size: f32) {
+        const BACKGROUND: Colour = [0.2, 0.2, 0.2, 0.8];
+        const MIN_HEIGHT: f32 = 4.0;
+
+        for sprite in sprites {
+            if let Some(health) = sprite.token.health() {
+                let rect = Rect::scaled_from(sprite.rect.positive_dimensions(), grid_size);
+                let h = (rect.h * 0.1).max(MIN_HEIGHT);
+                let bar = Rect::new(rect.x, rect.y + rect.h - h, rect.w, h);
+                self.renderer
+                    .draw_polygon(vp, &rect_points(bar), BACKGROUND);
+                self.renderer.draw_polygon(
+                    vp,
+                    &rect_points(Rect {
+                        w: bar.w * health,
+                        ..bar
+                    }),
+                    [1.0 - health, health, 0.0, 0.9],
+                );
+            }
+        }
+    }
+
+    pub fn draw_templates(&mut self, vp: Rect, templates: &[Template], grid_size: f32) {
+        for template in templates {
+            let outline = template
+                .outline()
+                .iter()
+                .map(|p| ScenePoint::new(p.x * grid_size, p.y * grid_size))
+                .collect::<Vec<ScenePoint>>();
+            self.renderer.draw_polygon(vp, &outline, template.colour);
+        }
+    }
+}
+
+fn rect_points(Rect { x, y, w, h }: Rect) -> [ScenePoint; 4] {
+    [
+        ScenePoint::new(x, y),
+        ScenePoint::new(x + w, y),
+        ScenePoint::new(x + w, y + h),
+        ScenePoint::new(x, y + h),
+    ]
+}
+
+pub fn add_token_label(token: &Token, x: f32, y: f32) {
+    if let Ok(token_json) = serde_json::to_string(token) {
+        _add_token_label(token_json, x, y);
+    }
 }
 
 pub fn set_selected_sprite(sprite: SpriteDetails) {
@@ -645,6 +755,30 @@ pub fn set_selected_sprite(sprite: SpriteDetails) {
     }
 }
 
+pub fn add_roll(roll: RollDetails) {
+    if let Ok(roll_json) = serde_json::to_string(&roll) {
+        _add_roll(roll_json);
+    }
+}
+
+pub fn add_chat_message(message: ChatMessage) {
+    if let Ok(message_json) = serde_json::to_string(&message) {
+        _add_chat_message(message_json);
+    }
+}
+
+pub fn update_initiative(initiative: InitiativeDetails) {
+    if let Ok(initiative_json) = serde_json::to_string(&initiative) {
+        _update_initiative(initiative_json);
+    }
+}
+
+pub fn update_players(players: Vec<(Id, String)>) {
+    if let Ok(players_json) = serde_json::to_string(&players) {
+        _update_players(players_json);
+    }
+}
+
 pub fn set_scene_details(details: SceneDetails) {
     if let Ok(details_json) = serde_json::to_string(&details) {
         _set_scene_details(details_json);
@@ -658,6 +792,8 @@ pub struct JsLayerInfo {
     pub z: f64,
     pub visible: bool,
     pub locked: bool,
+    pub opacity: f64,
+    pub blend: BlendMode,
     pub n_sprites: f64,
 }
 
@@ -669,6 +805,8 @@ impl JsLayerInfo {
             z: layer.z as f64,
             visible: layer.visible,
             locked: layer.locked,
+            opacity: layer.opacity as f64,
+            blend: layer.blend,
             n_sprites: layer.sprites.len() as f64,
         }
     }
@@ -700,9 +838,9 @@ fn create_context(element: &HtmlCanvasElement) -> Result<Gl, JsError> {
         _ => return JsError::error("Failed to get rendering context."),
     };
 
-    // Enable transparency
+    // Enable transparency. Shaders output premultiplied alpha.
     gl.enable(Gl::BLEND);
-    gl.blend_func(Gl::SRC_ALPHA, Gl::ONE_MINUS_SRC_ALPHA);
+    gl.blend_func(Gl::ONE, Gl::ONE_MINUS_SRC_ALPHA);
 
     Ok(gl)
 }
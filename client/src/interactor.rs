@@ -1,13 +1,15 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     sync::atomic::{AtomicI64, Ordering},
 };
 
 use bincode::serialize;
 use scene::{
-    comms::{ClientEvent, ClientMessage, SceneEvent, ServerEvent},
+    comms::{Audience, ChatMessage, ClientEvent, ClientMessage, Grouping, SceneEvent, ServerEvent},
+    dice::{Expression, Roll},
     perms::Perms,
-    Dimension, Id, Layer, Rect, Scene, ScenePoint, Sprite, SpriteShape, SpriteVisual,
+    BlendMode, Dimension, DistanceRule, DistanceUnit, Grid, GridType, History, Id, Layer, Rect,
+    Scene, ScenePoint, Sprite, SpriteShape, SpriteVisual, TemplateShape, Token, ZOrder,
 };
 
 use crate::client::Client;
@@ -23,6 +25,10 @@ pub struct Changes {
     // A change to the selected sprite that will require the sprite menu to be
     // updated.
     selected: bool,
+
+    // A change to the turn order that will require the initiative tracker to
+    // be updated.
+    initiative: bool,
 }
 
 impl Changes {
@@ -31,6 +37,7 @@ impl Changes {
             layer: true,
             sprite: true,
             selected: true,
+            initiative: true,
         }
     }
 
@@ -38,6 +45,7 @@ impl Changes {
         self.layer = true;
         self.sprite = true;
         self.selected = true;
+        self.initiative = true;
     }
 
     fn all_change_if(&mut self, changed: bool) {
@@ -88,6 +96,20 @@ impl Changes {
         ret
     }
 
+    fn initiative_change(&mut self) {
+        self.initiative = true;
+    }
+
+    fn initiative_change_if(&mut self, changed: bool) {
+        self.initiative = self.initiative || changed;
+    }
+
+    pub fn handle_initiative_change(&mut self) -> bool {
+        let ret = self.initiative;
+        self.initiative = false;
+        ret
+    }
+
     fn sprite_selected_change(&mut self) {
         self.sprite = true;
         self.selected = true;
@@ -103,6 +125,18 @@ pub struct SpriteDetails {
     pub w: Option<f32>,
     pub h: Option<f32>,
     pub texture: Option<Id>,
+    pub vision: Option<f32>,
+    pub name: Option<String>,
+    pub hp: Option<i32>,
+    pub max_hp: Option<i32>,
+    pub temp_hp: Option<i32>,
+    pub conditions: Option<Vec<String>>,
+    pub fields: Option<BTreeMap<String, String>>,
+    pub owners: Option<Vec<Id>>,
+
+    // Damage to deal to the sprite, or healing if negative.
+    #[serde(skip_serializing)]
+    pub damage: Option<i32>,
 }
 
 impl SpriteDetails {
@@ -119,6 +153,15 @@ impl SpriteDetails {
             w: Some(sprite.rect.w),
             h: Some(sprite.rect.h),
             texture,
+            vision: Some(sprite.vision.unwrap_or(0.0)),
+            name: sprite.token.name.clone(),
+            hp: sprite.token.hp,
+            max_hp: sprite.token.max_hp,
+            temp_hp: Some(sprite.token.temp_hp),
+            conditions: Some(sprite.token.conditions.clone()),
+            fields: Some(sprite.token.fields.clone()),
+            owners: Some(sprite.owners.clone()),
+            damage: None,
         }
     }
 
@@ -142,6 +185,39 @@ impl SpriteDetails {
         if self.texture.is_some() && SpriteVisual::Texture(self.texture.unwrap()) != sprite.visual {
             self.texture = None;
         }
+
+        if self.vision != Some(sprite.vision.unwrap_or(0.0)) {
+            self.vision = None;
+        }
+
+        let token = &sprite.token;
+        if self.name != token.name {
+            self.name = None;
+        }
+
+        if self.hp != token.hp {
+            self.hp = None;
+        }
+
+        if self.max_hp != token.max_hp {
+            self.max_hp = None;
+        }
+
+        if self.temp_hp != Some(token.temp_hp) {
+            self.temp_hp = None;
+        }
+
+        if self.conditions.as_ref() != Some(&token.conditions) {
+            self.conditions = None;
+        }
+
+        if self.fields.as_ref() != Some(&token.fields) {
+            self.fields = None;
+        }
+
+        if self.owners.as_ref() != Some(&sprite.owners) {
+            self.owners = None;
+        }
     }
 
     fn update_sprite(&self, sprite: &mut Sprite) -> Option<SceneEvent> {
@@ -166,10 +242,93 @@ impl SpriteDetails {
             events.push(sprite.set_visual(SpriteVisual::Texture(id)));
         }
 
-        if events.is_empty() {
-            None
-        } else {
+        // A vision radius of zero indicates that this sprite is not a token.
+        if let Some(radius) = self.vision {
+            let vision = if radius > 0.0 { Some(radius) } else { None };
+            if sprite.vision != vision {
+                events.push(sprite.set_vision(vision));
+            }
+        }
+
+        // An empty name removes the name.
+        if let Some(name) = &self.name {
+            let name = name
+                .trim()
+                .chars()
+                .take(Token::MAX_NAME_LENGTH)
+                .collect::<String>();
+            let name = Some(name).filter(|n| !n.is_empty());
+            if sprite.token.name != name {
+                events.push(sprite.set_name(name));
+            }
+        }
+
+        // The maximum is set first as HP is capped at it.
+        if let Some(max_hp) = self.max_hp {
+            if sprite.token.max_hp != Some(max_hp) {
+                events.push(sprite.set_max_hp(Some(max_hp)));
+            }
+        }
+
+        // HP is changed by a delta where possible so that concurrent changes
+        // by other users aren't lost.
+        if let Some(hp) = self.hp {
+            match sprite.token.hp {
+                Some(old) if old != hp => events.extend(sprite.adjust_hp(hp - old)),
+                None => events.push(sprite.set_hp(Some(hp))),
+                _ => {}
+            }
+        }
+
+        if let Some(temp_hp) = self.temp_hp {
+            if sprite.token.temp_hp != temp_hp {
+                events.push(sprite.set_temp_hp(temp_hp.max(0)));
+            }
+        }
+
+        if let Some(damage) = self.damage {
+            events.extend(sprite.damage(damage));
+        }
+
+        if let Some(conditions) = &self.conditions {
+            for condition in sprite.token.conditions.clone() {
+                if !conditions.contains(&condition) {
+                    events.extend(sprite.remove_condition(condition));
+                }
+            }
+
+            for condition in conditions {
+                events.extend(sprite.add_condition(condition.trim().to_string()));
+            }
+        }
+
+        if let Some(fields) = &self.fields {
+            for key in sprite.token.fields.keys().cloned().collect::<Vec<String>>() {
+                if !fields.contains_key(&key) {
+                    events.extend(sprite.set_field(key, None));
+                }
+            }
+
+            for (key, value) in fields {
+                events.extend(sprite.set_field(key.clone(), Some(value.clone())));
+            }
+        }
+
+        if let Some(owners) = &self.owners {
+            let mut owners = owners.clone();
+            owners.sort_unstable();
+            owners.dedup();
+            if sprite.owners != owners {
+                events.push(sprite.set_owners(owners));
+            }
+        }
+
+        // Single events are sent alone, as players may be permitted to make
+        // a change to their own token which isn't allowed as part of a set.
+        if events.len() > 1 {
             Some(SceneEvent::EventSet(events))
+        } else {
+            events.pop()
         }
     }
 }
@@ -181,15 +340,40 @@ pub struct SceneDetails {
     pub title: Option<String>,
     pub w: Option<u32>,
     pub h: Option<u32>,
+    pub fog_of_war: Option<bool>,
+    pub grid: Option<GridType>,
+    pub grid_size: Option<f32>,
+    pub grid_x: Option<f32>,
+    pub grid_y: Option<f32>,
+    pub grid_colour: Option<[f32; 3]>,
+    pub grid_opacity: Option<f32>,
+    pub grid_thickness: Option<f32>,
+    pub grid_visible: Option<bool>,
+    pub grid_rule: Option<DistanceRule>,
+    pub grid_units_per_tile: Option<f32>,
+    pub grid_unit: Option<DistanceUnit>,
 }
 
 impl SceneDetails {
     fn from(scene: &Scene) -> Self {
+        let [r, g, b, a] = scene.grid.colour;
         SceneDetails {
             id: scene.id,
             title: scene.title.clone(),
             w: Some(scene.w),
             h: Some(scene.h),
+            fog_of_war: Some(scene.fog_of_war),
+            grid: Some(scene.grid.kind),
+            grid_size: Some(scene.grid.size),
+            grid_x: Some(scene.grid.offset.x),
+            grid_y: Some(scene.grid.offset.y),
+            grid_colour: Some([r, g, b]),
+            grid_opacity: Some(a),
+            grid_thickness: Some(scene.grid.thickness),
+            grid_visible: Some(scene.grid.visible),
+            grid_rule: Some(scene.grid.rule),
+            grid_units_per_tile: Some(scene.grid.units_per_tile),
+            grid_unit: Some(scene.grid.unit),
         }
     }
 
@@ -206,6 +390,120 @@ impl SceneDetails {
             scene.h = h;
         }
     }
+
+    fn update_grid(&self, scene: &mut Scene) -> Option<SceneEvent> {
+        let mut grid = scene.grid;
+        if let Some(kind) = self.grid {
+            grid.kind = kind;
+        }
+
+        if let Some(size) = self.grid_size {
+            grid.size = size;
+        }
+
+        if let Some(x) = self.grid_x {
+            grid.offset.x = x;
+        }
+
+        if let Some(y) = self.grid_y {
+            grid.offset.y = y;
+        }
+
+        if let Some([r, g, b]) = self.grid_colour {
+            grid.colour = [r, g, b, grid.colour[3]];
+        }
+
+        if let Some(opacity) = self.grid_opacity {
+            grid.colour[3] = opacity.clamp(0.0, 1.0);
+        }
+
+        if let Some(thickness) = self.grid_thickness {
+            grid.thickness = thickness;
+        }
+
+        if let Some(visible) = self.grid_visible {
+            grid.visible = visible;
+        }
+
+        if let Some(rule) = self.grid_rule {
+            grid.rule = rule;
+        }
+
+        if let Some(units_per_tile) = self.grid_units_per_tile {
+            grid.units_per_tile = units_per_tile;
+        }
+
+        if let Some(unit) = self.grid_unit {
+            grid.unit = unit;
+        }
+
+        scene.set_grid(grid)
+    }
+}
+
+#[derive(serde_derive::Deserialize)]
+pub struct RollRequest {
+    expression: String,
+    #[serde(default)]
+    audience: Audience,
+}
+
+#[derive(serde_derive::Deserialize)]
+pub struct ChatRequest {
+    text: String,
+    #[serde(default)]
+    audience: Audience,
+}
+
+#[derive(serde_derive::Deserialize)]
+pub struct CombatantRequest {
+    name: String,
+    #[serde(default)]
+    sprite: Option<Id>,
+    #[serde(default)]
+    initiative: Option<i32>,
+}
+
+#[derive(serde_derive::Serialize)]
+pub struct CombatantDetails {
+    pub id: Id,
+    pub name: String,
+    pub sprite: Option<Id>,
+    pub initiative: Option<i32>,
+
+    // Whether this user may set the initiative of this combatant.
+    pub editable: bool,
+}
+
+#[derive(serde_derive::Serialize)]
+pub struct InitiativeDetails {
+    pub round: u32,
+    pub turn: Option<Id>,
+    pub combatants: Vec<CombatantDetails>,
+
+    // Whether this user may add combatants and change turns.
+    pub controls: bool,
+}
+
+#[derive(serde_derive::Serialize)]
+pub struct RollDetails {
+    pub user: Id,
+    pub private: bool,
+    pub expression: String,
+    pub breakdown: String,
+    pub total: i64,
+}
+
+impl RollDetails {
+    fn from(user: Id, audience: &Audience, roll: &Roll) -> Self {
+        RollDetails {
+            user,
+            private: !matches!(audience, Audience::Public),
+            expression: roll.expression.clone(),
+            breakdown: roll.breakdown(),
+            total: roll.total,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -215,6 +513,8 @@ enum HeldObject {
     None,
     Selection(ScenePoint),
     Sprite(Id, ScenePoint),
+    Template(Id, ScenePoint),
+    TemplateAim(Id),
 }
 
 impl HeldObject {
@@ -233,6 +533,10 @@ impl HeldObject {
         )
     }
 
+    fn is_template(&self) -> bool {
+        matches!(self, HeldObject::Template(..) | HeldObject::TemplateAim(..))
+    }
+
     fn grab_sprite_anchor(sprite: &Sprite, at: ScenePoint) -> Option<Self> {
         let Rect { x, y, w, h } = sprite.rect;
 
@@ -278,15 +582,33 @@ pub struct Interactor {
     pub changes: Changes,
     client: Option<Client>,
     holding: HeldObject,
-    history: Vec<SceneEvent>,
-    redo_history: Vec<Option<SceneEvent>>,
+    history: History,
     issued_events: Vec<ClientMessage>,
     perms: Perms,
     scene: Scene,
     selected_layer: Id,
     selected_sprites: Vec<Id>,
+    selected_template: Option<Id>,
     selection_marquee: Option<Rect>,
     user: Id,
+
+    // Waypoints of the ruler being measured by this user, if any.
+    ruler: Vec<ScenePoint>,
+
+    // Whether this user's ruler is shown to other players.
+    share_ruler: bool,
+
+    // Rulers shared by other users in the game.
+    shared_rulers: HashMap<Id, Vec<ScenePoint>>,
+
+    // Dice rolls received which are yet to be displayed.
+    rolls: Vec<RollDetails>,
+
+    // Chat messages received which are yet to be displayed.
+    chat: Vec<ChatMessage>,
+
+    // Users in the game, if changed since last displayed.
+    players: Option<Vec<(Id, String)>>,
 }
 
 impl Interactor {
@@ -299,15 +621,21 @@ impl Interactor {
             changes: Changes::new(),
             client,
             holding: HeldObject::None,
-            history: vec![],
-            redo_history: vec![],
+            history: History::new(),
             issued_events: vec![],
             perms: Perms::new(),
             scene,
             selected_layer,
             selected_sprites: vec![],
+            selected_template: None,
             selection_marquee: None,
             user: scene::perms::CANONICAL_UPDATER,
+            ruler: vec![],
+            share_ruler: false,
+            shared_rulers: HashMap::new(),
+            rolls: vec![],
+            chat: vec![],
+            players: None,
         }
     }
 
@@ -326,7 +654,9 @@ impl Interactor {
 
     fn unwind_event(&mut self, id: Id) {
         if let Some(i) = self.issued_events.iter().position(|c| c.id == id) {
-            if let ClientEvent::SceneUpdate(e) = self.issued_events.remove(i).event {
+            if let ClientEvent::SceneUpdate(e) | ClientEvent::Undo(e) =
+                self.issued_events.remove(i).event
+            {
                 // If we got rejected while dragging a sprite, release that
                 // sprite to prevent visual jittering and allow the position to
                 // reset.
@@ -335,6 +665,7 @@ impl Interactor {
                 }
 
                 self.changes.layer_change_if(e.is_layer());
+                self.changes.initiative_change_if(e.is_initiative());
                 self.changes.sprite_selected_change();
                 self.scene.unwind_event(e);
             }
@@ -349,25 +680,49 @@ impl Interactor {
             ServerEvent::PermsUpdate(perms_event) => {
                 self.perms
                     .handle_event(scene::perms::CANONICAL_UPDATER, perms_event);
+
+                // Roles determine who may edit the turn order.
+                self.changes.initiative_change();
             }
             ServerEvent::SceneChange(scene) => self.replace_scene(scene),
             ServerEvent::SceneUpdate(scene_event) => {
                 self.changes.layer_change_if(scene_event.is_layer());
+                self.changes
+                    .initiative_change_if(scene_event.is_initiative());
+                if let Some(id) = scene_event.item() {
+                    self.changes.selected_change_if(self.is_selected(id));
+                }
+                self.history.handle_remote(&scene_event);
+                self.scene.apply_event(scene_event);
             }
             ServerEvent::UserId(id) => {
                 self.user = id;
+                self.changes.initiative_change();
+            }
+            ServerEvent::Roll(user, audience, roll) => {
+                self.rolls.push(RollDetails::from(user, &audience, &roll));
+            }
+            ServerEvent::Chat(message) => self.chat.push(message),
+            ServerEvent::ChatHistory(mut messages) => self.chat.append(&mut messages),
+            ServerEvent::Players(players) => self.players = Some(players),
+            ServerEvent::Ruler(user, points) => {
+                if points.is_empty() {
+                    self.shared_rulers.remove(&user);
+                } else {
+                    self.shared_rulers.insert(user, points);
+                }
             }
         }
     }
 
-    fn issue_client_event(&mut self, scene_event: SceneEvent) {
+    fn issue_client_event(&mut self, event: ClientEvent) {
         static EVENT_ID: AtomicI64 = AtomicI64::new(1);
 
         // Queue event to be sent to server
         if let Some(client) = &self.client {
             let message = ClientMessage {
                 id: EVENT_ID.fetch_add(1, Ordering::Relaxed),
-                event: ClientEvent::SceneUpdate(scene_event),
+                event,
             };
             client.send_message(&message);
             self.issued_events.push(message);
@@ -375,20 +730,24 @@ impl Interactor {
     }
 
     fn scene_event(&mut self, event: SceneEvent) {
-        if self
-            .perms
-            .permitted(self.user, &event, self.scene.event_layer(&event))
-        {
-            self.issue_client_event(event.clone());
+        if self.perms.permitted(
+            self.user,
+            &event,
+            self.scene.event_layer(&event),
+            &self.scene.event_owners(&event),
+            now(),
+            &self.scene.initiative,
+        ) {
+            self.issue_client_event(ClientEvent::SceneUpdate(event.clone()));
 
             self.changes.layer_change_if(event.is_layer());
-            self.changes.sprite_change_if(event.is_sprite());
+            self.changes.initiative_change_if(event.is_initiative());
+            self.changes
+                .sprite_change_if(event.is_sprite() || event.is_template());
             if let Some(id) = event.item() {
                 self.changes.selected_change_if(self.is_selected(id));
             }
 
-            // When adding a new entry to the history, all undone events are lost.
-            self.redo_history.clear();
             self.history.push(event);
         } else {
             self.scene.unwind_event(event);
@@ -401,102 +760,24 @@ impl Interactor {
         }
     }
 
-    fn start_move_group(&mut self) {
-        self.history.push(SceneEvent::Dummy);
-    }
-
-    fn group_moves_single(&mut self, last: SceneEvent) {
-        let (sprite, mut start, finish) = if let SceneEvent::SpriteMove(id, from, to) = last {
-            (id, from, to)
-        } else {
-            return;
-        };
-
-        while let Some(e) = self.history.pop() {
-            if let SceneEvent::SpriteMove(id, from, _) = e {
-                if id == sprite {
-                    start = from;
-                    continue;
-                }
-            }
-
-            if !matches!(e, SceneEvent::Dummy) {
-                self.history.push(e);
-            }
-            break;
-        }
-
-        self.history
-            .push(SceneEvent::SpriteMove(sprite, start, finish));
-    }
-
-    fn group_moves_set(&mut self, last: SceneEvent) {
-        self.history.push(last);
-        let mut moves = HashMap::new();
-
-        while let Some(e) = self.history.pop() {
-            if let SceneEvent::EventSet(v) = e {
-                for event in v {
-                    if let SceneEvent::SpriteMove(id, from, _) = event {
-                        if let Some(SceneEvent::SpriteMove(_, start, _)) = moves.get_mut(&id) {
-                            *start = from;
-                        } else {
-                            moves.insert(id, event);
-                        }
-                    }
-                }
-                continue;
-            }
-
-            if !matches!(e, SceneEvent::Dummy) {
-                self.history.push(e);
-            }
-            break;
-        }
-
-        self.history.push(SceneEvent::EventSet(
-            moves.into_values().collect::<Vec<SceneEvent>>(),
-        ));
-    }
-
-    fn end_move_group(&mut self) {
-        let opt = self.history.pop();
-        if let Some(event) = opt {
-            match event {
-                SceneEvent::SpriteMove(..) => self.group_moves_single(event),
-                SceneEvent::EventSet(..) => self.group_moves_set(event),
-                _ => self.history.push(event),
-            };
-        }
-    }
-
     pub fn undo(&mut self) {
-        if let Some(event) = self.history.pop() {
-            if matches!(event, SceneEvent::Dummy) {
-                self.undo();
-                return;
-            }
+        if let Some(event) = self.history.undo(&mut self.scene) {
+            self.changes.layer_change_if(event.is_layer());
+            self.changes.initiative_change_if(event.is_initiative());
+            self.changes.sprite_selected_change();
 
-            let opt = self.scene.unwind_event(event);
-            if let Some(event) = &opt {
-                let layers_changed = event.is_layer();
-                self.issue_client_event(event.clone());
-                self.changes.layer_change_if(layers_changed);
-                self.changes.sprite_selected_change();
-            }
-            self.redo_history.push(opt);
+            // Undo events are tagged so that the server can check that they
+            // only revert this user's own changes.
+            self.issue_client_event(ClientEvent::Undo(event));
         }
     }
 
     pub fn redo(&mut self) {
-        if let Some(Some(event)) = self.redo_history.pop() {
-            if let Some(event) = self.scene.unwind_event(event) {
-                let layers_changed = event.is_layer();
-                self.issue_client_event(event.clone());
-                self.history.push(event);
-                self.changes.layer_change_if(layers_changed);
-                self.changes.sprite_selected_change();
-            }
+        if let Some(event) = self.history.redo(&mut self.scene) {
+            self.changes.layer_change_if(event.is_layer());
+            self.changes.initiative_change_if(event.is_initiative());
+            self.changes.sprite_selected_change();
+            self.issue_client_event(ClientEvent::SceneUpdate(event));
         }
     }
 
@@ -529,6 +810,7 @@ impl Interactor {
 
     fn clear_selection(&mut self) {
         self.selected_sprites.clear();
+        self.selected_template = None;
         self.changes.selected_change();
     }
 
@@ -569,9 +851,10 @@ impl Interactor {
     }
 
     pub fn grab(&mut self, at: ScenePoint, ctrl: bool) {
-        self.holding = match self.scene.sprite_at(at) {
+        self.holding = match self.scene.sprite_at(at, self.user) {
             Some(s) => {
                 let id = s.id;
+                self.selected_template = None;
                 if !self.is_selected(id) {
                     if !ctrl {
                         self.clear_selection();
@@ -580,11 +863,18 @@ impl Interactor {
                 }
                 self.grab_selection(at)
             }
-            None => HeldObject::Marquee(at),
+            None => match self.scene.template_at(at).map(|t| (t.id, t.origin)) {
+                Some((id, origin)) => {
+                    self.clear_selection();
+                    self.selected_template = Some(id);
+                    HeldObject::Template(id, at - origin)
+                }
+                None => HeldObject::Marquee(at),
+            },
         };
 
-        if self.holding.is_sprite() {
-            self.start_move_group();
+        if self.holding.is_sprite() || self.holding.is_template() {
+            self.group(Grouping::Start);
         }
 
         self.changes.sprite_change();
@@ -617,6 +907,82 @@ impl Interactor {
         self.scene_event(event);
     }
 
+    fn update_held_template(&mut self, at: ScenePoint) {
+        let event = match self.holding {
+            HeldObject::Template(id, offset) => match self.scene.template(id) {
+                Some(t) => t.set_origin(at - offset),
+                None => return,
+            },
+            HeldObject::TemplateAim(id) => match self.scene.template(id) {
+                Some(t) => {
+                    let d = at - t.origin;
+                    let size = (d.x * d.x + d.y * d.y).sqrt();
+                    SceneEvent::EventSet(vec![t.face(at), t.set_shape(t.shape.with_size(size))])
+                }
+                None => return,
+            },
+            _ => return,
+        };
+        self.scene_event(event);
+    }
+
+    // Begin aiming a template with the template tool. Clicking an existing
+    // template re-aims it, otherwise a new template is placed at this point.
+    pub fn aim_template(&mut self, at: ScenePoint, shape: TemplateShape) {
+        self.group(Grouping::Start);
+
+        let id = match self.scene.template_at(at) {
+            Some(t) => t.id,
+            None => {
+                let opt = self
+                    .scene
+                    .new_template(at, shape, self.selected_layer, Some(self.user));
+                let id = opt.as_ref().and_then(|e| e.item());
+                self.scene_option(opt);
+                match id {
+                    Some(id) => id,
+                    None => return,
+                }
+            }
+        };
+
+        if self.scene.template_ref(id).is_some() {
+            self.clear_selection();
+            self.selected_template = Some(id);
+            self.holding = HeldObject::TemplateAim(id);
+        }
+        self.changes.sprite_change();
+    }
+
+    fn finish_template(&mut self, id: Id, snap_to_grid: bool) {
+        if snap_to_grid {
+            let grid = self.scene.grid;
+            let aiming = matches!(self.holding, HeldObject::TemplateAim(..));
+            if let Some(t) = self.scene.template(id) {
+                let mut events = vec![t.snap_origin(&grid)];
+                if aiming {
+                    events.push(t.snap_size(&grid));
+                }
+                self.scene_event(SceneEvent::EventSet(events));
+            }
+        }
+        self.group(Grouping::Template(id));
+    }
+
+    pub fn remove_template(&mut self) {
+        if let Some(id) = self.selected_template.take() {
+            let opt = self.scene.remove_template(id);
+            self.scene_option(opt);
+        }
+    }
+
+    // Sprites covered by the selected template.
+    pub fn template_sprites(&self) -> Vec<Id> {
+        self.selected_template
+            .map(|id| self.scene.template_sprites(id))
+            .unwrap_or_default()
+    }
+
     fn drag_selection(&mut self, to: ScenePoint) {
         let delta = if let HeldObject::Selection(from) = self.holding {
             to - from
@@ -637,6 +1003,7 @@ impl Interactor {
             HeldObject::None => {}
             HeldObject::Selection(_) => self.drag_selection(at),
             HeldObject::Sprite(_, _) | HeldObject::Anchor(_, _, _) => self.update_held_sprite(at),
+            HeldObject::Template(..) | HeldObject::TemplateAim(_) => self.update_held_template(at),
         };
     }
 
@@ -645,7 +1012,7 @@ impl Interactor {
     }
 
     pub fn sprite_at(&self, at: ScenePoint) -> Option<Id> {
-        let id = self.scene.sprite_at_ref(at).map(|s| s.id)?;
+        let id = self.scene.sprite_at_ref(at, self.user).map(|s| s.id)?;
         if self.is_selected(id) {
             Some(Self::SELECTION_ID)
         } else {
@@ -654,9 +1021,10 @@ impl Interactor {
     }
 
     fn finish_sprite_resize(&mut self, id: Id, snap_to_grid: bool) {
+        let grid = self.scene.grid;
         if let Some(s) = self.scene.sprite(id) {
             if snap_to_grid {
-                let event = s.snap_size();
+                let event = s.snap_size(&grid);
                 self.scene_event(event);
             } else {
                 let opt = s.enforce_min_size();
@@ -668,8 +1036,9 @@ impl Interactor {
 
     fn finish_sprite_drag(&mut self, id: Id, snap_to_grid: bool) {
         if snap_to_grid {
+            let grid = self.scene.grid;
             if let Some(s) = self.scene.sprite(id) {
-                let event = s.snap_pos();
+                let event = s.snap_pos(&grid);
                 self.scene_event(event);
             }
         }
@@ -678,7 +1047,8 @@ impl Interactor {
 
     fn finish_selection_drag(&mut self, snap_to_grid: bool) {
         if snap_to_grid {
-            self.selection_effect(|s| Some(s.snap_pos()));
+            let grid = self.scene.grid;
+            self.selection_effect(|s| Some(s.snap_pos(&grid)));
         }
         self.changes.sprite_selected_change();
     }
@@ -701,15 +1071,220 @@ impl Interactor {
             HeldObject::Selection(_) => self.finish_selection_drag(!alt),
             HeldObject::Sprite(id, _) => self.finish_sprite_drag(id, !alt),
             HeldObject::Anchor(id, _, _) => self.finish_sprite_resize(id, !alt),
+            HeldObject::Template(id, _) | HeldObject::TemplateAim(id) => {
+                self.finish_template(id, !alt)
+            }
         };
 
         if self.holding.is_sprite() {
-            self.end_move_group();
+            self.group(Grouping::Moves);
         }
 
         self.holding = HeldObject::None;
     }
 
+    // Group changes in the history, telling the server so that it groups them
+    // alike and accepts undoing the group.
+    fn group(&mut self, grouping: Grouping) {
+        self.history.group(grouping, &self.scene);
+        if let Some(client) = &self.client {
+            client.send_message(&ClientMessage {
+                id: 0,
+                event: ClientEvent::Group(grouping),
+            });
+        }
+    }
+
+    fn send_ruler(&self) {
+        if let Some(client) = &self.client {
+            if self.share_ruler {
+                client.send_message(&ClientMessage {
+                    id: 0,
+                    event: ClientEvent::Ruler(self.ruler.clone()),
+                });
+            }
+        }
+    }
+
+    pub fn start_ruler(&mut self, at: ScenePoint) {
+        self.ruler = vec![at, at];
+        self.send_ruler();
+        self.changes.sprite_change();
+    }
+
+    // Move the end of the ruler, if measuring.
+    pub fn drag_ruler(&mut self, at: ScenePoint) {
+        if let Some(end) = self.ruler.last_mut() {
+            *end = at;
+            self.send_ruler();
+            self.changes.sprite_change();
+        }
+    }
+
+    // Fix the current end of the ruler as a waypoint and continue measuring.
+    pub fn ruler_waypoint(&mut self) {
+        if let Some(&end) = self.ruler.last() {
+            if self.ruler.len() < ClientEvent::MAX_RULER_POINTS {
+                self.ruler.push(end);
+            }
+        }
+    }
+
+    pub fn end_ruler(&mut self) {
+        if !self.ruler.is_empty() {
+            self.ruler.clear();
+            self.send_ruler();
+            self.changes.sprite_change();
+        }
+    }
+
+    pub fn set_share_ruler(&mut self, share: bool) {
+        if self.share_ruler && !share && !self.ruler.is_empty() {
+            // Clear this ruler for other players before ceasing to share.
+            let ruler = std::mem::take(&mut self.ruler);
+            self.send_ruler();
+            self.ruler = ruler;
+        }
+        self.share_ruler = share;
+        self.send_ruler();
+    }
+
+    // All rulers to be drawn, with this user's ruler first.
+    pub fn rulers(&self) -> Vec<&[ScenePoint]> {
+        let mut rulers = vec![];
+        if !self.ruler.is_empty() {
+            rulers.push(&self.ruler[..]);
+        }
+        rulers.extend(self.shared_rulers.values().map(|r| &r[..]));
+        rulers
+    }
+
+    pub fn ruler_label(&self, path: &[ScenePoint]) -> String {
+        self.scene.grid.format_length(path)
+    }
+
+    // Roll dice, returning an error message if the expression is invalid.
+    // Rolls are made by the server when in a game, so that they can be
+    // trusted, and the result arrives as a server event.
+    pub fn roll(&mut self, request: RollRequest) -> Result<(), String> {
+        let expression = Expression::parse(&request.expression)?;
+        if let Some(client) = &self.client {
+            client.send_message(&ClientMessage {
+                id: 0,
+                event: ClientEvent::Roll(request.expression, request.audience),
+            });
+        } else {
+            let roll = expression
+                .roll(|sides| Ok::<u32, ()>((js_sys::Math::random() * sides as f64) as u32 + 1));
+            if let Ok(roll) = roll {
+                self.rolls
+                    .push(RollDetails::from(self.user, &request.audience, &roll));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn take_rolls(&mut self) -> Vec<RollDetails> {
+        std::mem::take(&mut self.rolls)
+    }
+
+    // Send a chat message, which the server timestamps and returns to each
+    // user in the audience, including this one.
+    pub fn chat(&mut self, request: ChatRequest) -> Result<(), String> {
+        let client = self
+            .client
+            .as_ref()
+            .ok_or("Chat is only available in games.")?;
+        if request.text.trim().is_empty() {
+            return Err("Message is empty.".to_string());
+        }
+
+        client.send_message(&ClientMessage {
+            id: 0,
+            event: ClientEvent::Chat(request.text, request.audience),
+        });
+        Ok(())
+    }
+
+    pub fn take_chat(&mut self) -> Vec<ChatMessage> {
+        std::mem::take(&mut self.chat)
+    }
+
+    pub fn take_players(&mut self) -> Option<Vec<(Id, String)>> {
+        self.players.take()
+    }
+
+    pub fn add_combatant(&mut self, request: CombatantRequest) {
+        let opt = self
+            .scene
+            .new_combatant(request.name, request.sprite, request.initiative);
+        self.scene_option(opt);
+    }
+
+    pub fn remove_combatant(&mut self, id: Id) {
+        let opt = self.scene.initiative.remove(id);
+        self.scene_option(opt);
+    }
+
+    pub fn move_combatant(&mut self, id: Id, position: usize) {
+        let opt = self.scene.initiative.move_to(id, position);
+        self.scene_option(opt);
+    }
+
+    pub fn set_initiative(&mut self, id: Id, initiative: Option<i32>) {
+        let opt = self.scene.initiative.set_initiative(id, initiative);
+        self.scene_option(opt);
+    }
+
+    pub fn next_turn(&mut self) {
+        let opt = self.scene.initiative.advance();
+        self.scene_option(opt);
+    }
+
+    pub fn previous_turn(&mut self) {
+        let opt = self.scene.initiative.back();
+        self.scene_option(opt);
+    }
+
+    pub fn initiative_details(&self) -> InitiativeDetails {
+        let initiative = &self.scene.initiative;
+        let combatants = initiative
+            .combatants
+            .iter()
+            .map(|c| {
+                let event = SceneEvent::InitiativeSet(c.id, c.initiative, c.initiative);
+                CombatantDetails {
+                    id: c.id,
+                    name: c.name.clone(),
+                    sprite: c.sprite,
+                    initiative: c.initiative,
+                    editable: self.perms.permitted(
+                        self.user,
+                        &event,
+                        None,
+                        &self.scene.event_owners(&event),
+                        now(),
+                        initiative,
+                    ),
+                }
+            })
+            .collect();
+
+        InitiativeDetails {
+            round: initiative.round,
+            turn: initiative.current().map(|c| c.id),
+            combatants,
+            controls: self.perms.permitted(
+                self.user,
+                &SceneEvent::InitiativeAdvance(initiative.round, initiative.turn),
+                None,
+                &[],
+                now(),
+                initiative,
+            ),
+        }
+    }
+
     #[must_use]
     pub fn layers(&self) -> &[Layer] {
         &self.scene.layers
@@ -729,6 +1304,12 @@ impl Interactor {
             selections.push(sprite.rect);
         }
 
+        for id in self.template_sprites() {
+            if let Some(s) = self.scene.sprite_ref(id) {
+                selections.push(s.rect);
+            }
+        }
+
         if let Some(rect) = self.selection_marquee {
             selections.push(rect);
         }
@@ -736,6 +1317,10 @@ impl Interactor {
     }
 
     #[must_use]
+    pub fn grid(&self) -> Grid {
+        self.scene.grid
+    }
+
     pub fn dimensions(&self) -> Rect {
         Rect {
             x: 0.0,
@@ -765,10 +1350,12 @@ impl Interactor {
 
     fn replace_perms(&mut self, new: Perms) {
         self.perms = new;
+        self.changes.initiative_change();
     }
 
     pub fn replace_scene(&mut self, new: Scene) {
         self.scene = new;
+        self.history = History::new();
         self.changes.all_change();
     }
 
@@ -778,6 +1365,12 @@ impl Interactor {
 
     pub fn scene_details(&mut self, details: SceneDetails) {
         details.update_scene(&mut self.scene);
+        if let Some(fog_of_war) = details.fog_of_war {
+            let opt = self.scene.set_fog_of_war(fog_of_war);
+            self.scene_option(opt);
+        }
+        let opt = details.update_grid(&mut self.scene);
+        self.scene_option(opt);
         self.changes.sprite_change();
     }
 
@@ -823,6 +1416,22 @@ impl Interactor {
         }
     }
 
+    pub fn set_layer_opacity(&mut self, layer: Id, opacity: f32) {
+        if let Some(l) = self.scene.layer(layer) {
+            let opt = l.set_opacity(opacity);
+            self.changes.sprite_change_if(opt.is_some());
+            self.scene_option(opt);
+        }
+    }
+
+    pub fn set_layer_blend(&mut self, layer: Id, blend: BlendMode) {
+        if let Some(l) = self.scene.layer(layer) {
+            let opt = l.set_blend(blend);
+            self.changes.sprite_change_if(opt.is_some());
+            self.scene_option(opt);
+        }
+    }
+
     pub fn move_layer(&mut self, layer: Id, up: bool) {
         let opt = self.scene.move_layer(layer, up);
         self.scene_option(opt);
@@ -871,6 +1480,16 @@ impl Interactor {
         }
     }
 
+    pub fn restack_sprite(&mut self, sprite: Id, order: ZOrder) {
+        if sprite == Self::SELECTION_ID {
+            let opt = self.scene.restack_sprites(&self.selected_sprites, order);
+            self.scene_option(opt);
+        } else {
+            let opt = self.scene.restack_sprite(sprite, order);
+            self.scene_option(opt);
+        }
+    }
+
     pub fn sprite_layer(&mut self, sprite: Id, layer: Id) {
         if sprite == Self::SELECTION_ID {
             let event = self.scene.sprites_layer(&self.selected_sprites, layer);
@@ -933,3 +1552,8 @@ impl Interactor {
         }
     }
 }
+
+// Unix time in seconds, against which override expiries are checked.
+fn now() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 use js_sys::Float32Array;
@@ -7,7 +7,7 @@ use web_sys::{
     HtmlImageElement, WebGlBuffer, WebGlProgram, WebGlShader, WebGlTexture, WebGlUniformLocation,
 };
 
-use scene::{Rect, Sprite, SpriteShape, SpriteVisual};
+use scene::{BlendMode, Grid, GridType, Rect, ScenePoint, Sprite, SpriteShape, SpriteVisual};
 
 use crate::bridge::{log, Gl, JsError};
 
@@ -376,6 +376,7 @@ struct TextureRenderer {
     texcoord_buffer: WebGlBuffer,
     texcoord_location: u32,
     texture_location: WebGlUniformLocation,
+    opacity_location: WebGlUniformLocation,
     shapes: Shapes,
 }
 
@@ -392,6 +393,7 @@ impl TextureRenderer {
         let texcoord_location = gl.get_attrib_location(&program, "a_texcoord") as u32;
         let texcoord_buffer = create_buffer(&gl, Some(&shapes.rectangle.coords))?;
         let texture_location = get_uniform_location(&gl, &program, "u_texture")?;
+        let opacity_location = get_uniform_location(&gl, &program, "u_opacity")?;
 
         Ok(TextureRenderer {
             gl,
@@ -399,6 +401,7 @@ impl TextureRenderer {
             texcoord_buffer,
             texcoord_location,
             texture_location,
+            opacity_location,
             shapes,
         })
     }
@@ -407,6 +410,7 @@ impl TextureRenderer {
         &self,
         shape: SpriteShape,
         texture: &WebGlTexture,
+        opacity: f32,
         viewport: Rect,
         position: Rect,
     ) {
@@ -419,6 +423,7 @@ impl TextureRenderer {
         gl.vertex_attrib_pointer_with_i32(self.texcoord_location, 2, Gl::FLOAT, false, 0, 0);
 
         gl.uniform1i(Some(&self.texture_location), 0);
+        gl.uniform1f(Some(&self.opacity_location), opacity);
         self.shapes.shape(shape).draw(gl, viewport, position);
     }
 }
@@ -496,6 +501,11 @@ impl LineRenderer {
         self.gl.draw_arrays(Gl::LINES, 0, self.point_count);
     }
 
+    fn render_line_strip(&self, colour: Option<Colour>) {
+        self.prepare_render(colour);
+        self.gl.draw_arrays(Gl::LINE_STRIP, 0, self.point_count);
+    }
+
     fn render_line_loop(&self, colour: Option<Colour>) {
         self.prepare_render(colour);
         self.gl.draw_arrays(Gl::LINE_LOOP, 0, self.point_count);
@@ -505,6 +515,11 @@ impl LineRenderer {
         self.prepare_render(colour);
         self.gl.draw_arrays(Gl::TRIANGLES, 0, self.point_count);
     }
+
+    fn render_fan(&self, colour: Option<Colour>) {
+        self.prepare_render(colour);
+        self.gl.draw_arrays(Gl::TRIANGLE_FAN, 0, self.point_count);
+    }
 }
 
 pub struct GridRenderer {
@@ -512,7 +527,7 @@ pub struct GridRenderer {
     current_vp: Option<Rect>,
     current_grid_rect: Option<Rect>,
     current_grid_size: Option<f32>,
-    current_line_count: Option<i32>,
+    current_grid: Option<Grid>,
 }
 
 impl GridRenderer {
@@ -522,101 +537,158 @@ impl GridRenderer {
             current_vp: None,
             current_grid_rect: None,
             current_grid_size: None,
-            current_line_count: None,
+            current_grid: None,
         })
     }
 
-    pub fn create_grid(&mut self, vp: Rect, dims: Rect, grid_size: f32) {
-        let mut verticals = Vec::new();
-        let mut horizontals = Vec::new();
-
-        let d = grid_size;
-        let dx = vp.x % grid_size;
-        let dy = vp.y % grid_size;
-
-        let w = vp.w;
-        let h = vp.h;
-
-        let sw = dims.w;
-        let sh = dims.h;
-
-        // Horizontal and vertical line start and endpoints, to ensure that we
-        // render only the tiles that are part of the scene as part of the
-        // grid.
-        let fx = if vp.x < 0.0 {
-            to_unit(vp.x.abs() / d, w / d)
-        } else {
-            -1.0
-        };
-        let tx = if (vp.x + w) / d > sw {
-            to_unit(sw - vp.x / d, w / d).clamp(-1.0, 1.0)
-        } else {
-            1.0
-        };
-        let fy = if vp.y < 0.0 {
-            -to_unit(vp.y.abs() / d, h / d)
-        } else {
-            1.0
-        };
-        let ty = if (vp.y + h) / d > sh {
-            -to_unit(sh - vp.y / d, h / d).clamp(-1.0, 1.0)
-        } else {
-            -1.0
+    pub fn create_grid(&mut self, vp: Rect, dims: Rect, grid: Grid, grid_size: f32) {
+        let lines = match grid.kind {
+            GridType::Square => square_grid_lines(vp, dims, &grid, grid_size),
+            GridType::HexFlat | GridType::HexPointy => hex_grid_lines(vp, dims, &grid, grid_size),
+            GridType::Gridless => vec![],
         };
 
-        let mut i = 0.0;
-        while i <= vp.w.max(vp.h) / d {
-            let sx = i + (vp.x - dx) / d;
-            let mut x = d * i - dx;
-            if x <= w && sx >= 0.0 && sx <= sw {
-                x = to_unit(x, w);
-
-                verticals.push(x);
-                verticals.push(fy);
-                verticals.push(x);
-                verticals.push(ty);
+        // WebGL implementations generally don't support line widths other
+        // than 1, so grid lines are drawn as thin rectangles instead.
+        let mut points = Vec::with_capacity(lines.len() * 12);
+        for (from, to) in lines {
+            let len = ((to.x - from.x).powi(2) + (to.y - from.y).powi(2)).sqrt();
+            if len == 0.0 {
+                continue;
             }
 
-            let sy = i + (vp.y - dy) / d;
-            let mut y = d * i - dy;
-            if y <= h && sy >= 0.0 && sy <= sh {
-                // I negate the expression here but not for the x because the OpenGL coordinate system naturally matches
-                // the browser coordinate system in the x direction, but opposes it in the y direction. By negating the
-                // two coordinate systems are aligned, which makes things a little easier to work with.
-                y = -to_unit(y, h);
-
-                horizontals.push(fx);
-                horizontals.push(y);
-                horizontals.push(tx);
-                horizontals.push(y);
-            }
-
-            i += 1.0;
+            let nx = (from.y - to.y) / len * grid.thickness / 2.0;
+            let ny = (to.x - from.x) / len * grid.thickness / 2.0;
+            points.extend_from_slice(&[
+                from.x + nx,
+                from.y + ny,
+                from.x - nx,
+                from.y - ny,
+                to.x + nx,
+                to.y + ny,
+                from.x - nx,
+                from.y - ny,
+                to.x - nx,
+                to.y - ny,
+                to.x + nx,
+                to.y + ny,
+            ]);
         }
 
-        verticals.append(&mut horizontals);
-        self.line_renderer.load_points(&verticals);
+        self.line_renderer
+            .scale_and_load_points(&mut points, vp.w, vp.h);
         self.current_vp = Some(vp);
         self.current_grid_rect = Some(dims);
         self.current_grid_size = Some(grid_size);
-        self.current_line_count = Some(verticals.len() as i32 / 2);
+        self.current_grid = Some(grid);
     }
 
-    pub fn render_grid(&mut self, vp: Rect, dims: Rect, grid_size: f32) {
+    pub fn render_grid(&mut self, vp: Rect, dims: Rect, grid: Grid, grid_size: f32) {
+        if !grid.visible {
+            return;
+        }
+
         if self.current_vp.is_none()
             || self.current_vp.unwrap() != vp
             || self.current_grid_rect.is_none()
             || self.current_grid_rect.unwrap() != dims
             || self.current_grid_size != Some(grid_size)
+            || self.current_grid != Some(grid)
         {
-            self.create_grid(vp, dims, grid_size);
+            self.create_grid(vp, dims, grid, grid_size);
         }
 
-        self.line_renderer.render_lines(None);
+        self.line_renderer.render_solid(Some(grid.colour));
+    }
+}
+
+// Lines of a square grid within the visible portion of the scene, as pairs of
+// endpoints in pixels relative to the viewport.
+fn square_grid_lines(
+    vp: Rect,
+    dims: Rect,
+    grid: &Grid,
+    grid_size: f32,
+) -> Vec<(ScenePoint, ScenePoint)> {
+    let d = grid_size;
+    let step = grid.size;
+
+    // Bounds of the visible portion of the scene, in scene units.
+    let x0 = (vp.x / d).max(0.0);
+    let x1 = ((vp.x + vp.w) / d).min(dims.w);
+    let y0 = (vp.y / d).max(0.0);
+    let y1 = ((vp.y + vp.h) / d).min(dims.h);
+
+    let mut lines = Vec::new();
+    if x0 > x1 || y0 > y1 {
+        return lines;
+    }
+
+    let point = |x: f32, y: f32| ScenePoint::new(x * d - vp.x, y * d - vp.y);
+
+    let mut x = grid.offset.x + ((x0 - grid.offset.x) / step).ceil() * step;
+    while x <= x1 {
+        lines.push((point(x, y0), point(x, y1)));
+        x += step;
+    }
+
+    let mut y = grid.offset.y + ((y0 - grid.offset.y) / step).ceil() * step;
+    while y <= y1 {
+        lines.push((point(x0, y), point(x1, y)));
+        y += step;
+    }
+
+    lines
+}
+
+fn hex_grid_lines(
+    vp: Rect,
+    dims: Rect,
+    grid: &Grid,
+    grid_size: f32,
+) -> Vec<(ScenePoint, ScenePoint)> {
+    let d = grid_size;
+
+    // Hexes partially in view are drawn, so extend the visible region by a
+    // cell on each side, but draw only hexes centred within the scene.
+    let x = (vp.x / d - grid.size).max(0.0);
+    let y = (vp.y / d - grid.size).max(0.0);
+    let region = Rect::new(
+        x,
+        y,
+        ((vp.x + vp.w) / d + grid.size).min(dims.w) - x,
+        ((vp.y + vp.h) / d + grid.size).min(dims.h) - y,
+    );
+
+    let point = |p: ScenePoint| ScenePoint::new(p.x * d - vp.x, p.y * d - vp.y);
+
+    // Adjacent hexes share edges, so skip edges which have already been added
+    // to avoid drawing them twice.
+    let mut drawn = HashSet::new();
+    let mut lines = Vec::new();
+    for centre in grid.cells_in(region) {
+        let corners = grid.cell_outline(centre);
+        for (i, &from) in corners.iter().enumerate() {
+            let to = corners[(i + 1) % corners.len()];
+            let key = (
+                ((from.x + to.x) * 500.0).round() as i64,
+                ((from.y + to.y) * 500.0).round() as i64,
+            );
+            if drawn.insert(key) {
+                lines.push((point(from), point(to)));
+            }
+        }
     }
+
+    lines
 }
 
 pub struct Renderer {
+    gl: Rc<Gl>,
+
+    // Opacity of the layer being drawn, applied to everything drawn in it.
+    opacity: f32,
+
     // Loads and stores references to textures
     texture_library: TextureManager,
 
@@ -635,6 +707,8 @@ pub struct Renderer {
 impl Renderer {
     pub fn new(gl: Rc<Gl>) -> Result<Renderer, JsError> {
         Ok(Renderer {
+            gl: gl.clone(),
+            opacity: 1.0,
             texture_library: TextureManager::new(gl.clone())?,
             solid_renderer: SolidRenderer::new(gl.clone())?,
             texture_renderer: TextureRenderer::new(gl.clone())?,
@@ -643,23 +717,41 @@ impl Renderer {
         })
     }
 
-    pub fn render_grid(&mut self, vp: Rect, dims: Rect, grid_size: f32) {
-        self.grid_renderer.render_grid(vp, dims, grid_size);
+    pub fn render_grid(&mut self, vp: Rect, dims: Rect, grid: Grid, grid_size: f32) {
+        self.grid_renderer.render_grid(vp, dims, grid, grid_size);
     }
 
     pub fn load_image(&mut self, image: &HtmlImageElement) -> scene::Id {
         self.texture_library.load_image(image)
     }
 
+    // Set the opacity and blend mode to draw with until the next call. Colours
+    // are output with premultiplied alpha, so each blend mode is a choice of
+    // factors for the source and destination.
+    pub fn set_layer_style(&mut self, opacity: f32, blend: BlendMode) {
+        self.opacity = opacity;
+        let (src, dst) = match blend {
+            BlendMode::Normal => (Gl::ONE, Gl::ONE_MINUS_SRC_ALPHA),
+            BlendMode::Multiply => (Gl::DST_COLOR, Gl::ONE_MINUS_SRC_ALPHA),
+            BlendMode::Screen => (Gl::ONE, Gl::ONE_MINUS_SRC_COLOR),
+        };
+        self.gl.blend_func(src, dst);
+    }
+
+    fn faded(&self, [r, g, b, a]: Colour) -> Colour {
+        [r, g, b, a * self.opacity]
+    }
+
     pub fn draw_sprite(&mut self, sprite: &Sprite, viewport: Rect, position: Rect) {
         match sprite.visual {
             SpriteVisual::Colour(colour) => {
                 self.solid_renderer
-                    .draw_shape(sprite.shape, colour, viewport, position)
+                    .draw_shape(sprite.shape, self.faded(colour), viewport, position)
             }
             SpriteVisual::Texture(id) => self.texture_renderer.draw_texture(
                 sprite.shape,
                 self.texture_library.get_texture(id),
+                self.opacity,
                 viewport,
                 position,
             ),
@@ -693,6 +785,34 @@ impl Renderer {
         self.line_renderer
             .render_line_loop(Some([0.5, 0.5, 1.0, 0.9]));
     }
+
+    // Draw a path through these points, which are in pixels.
+    pub fn draw_path(&mut self, vp: Rect, path: &[ScenePoint], colour: Colour) {
+        let mut points = path
+            .iter()
+            .flat_map(|p| [p.x - vp.x, p.y - vp.y])
+            .collect::<Vec<f32>>();
+        self.line_renderer
+            .scale_and_load_points(&mut points, vp.w, vp.h);
+        self.line_renderer
+            .render_line_strip(Some(self.faded(colour)));
+    }
+
+    // Fill a convex polygon with these vertices, which are in pixels, and
+    // outline it in the opaque colour.
+    pub fn draw_polygon(&mut self, vp: Rect, polygon: &[ScenePoint], colour: Colour) {
+        let mut points = polygon
+            .iter()
+            .flat_map(|p| [p.x - vp.x, p.y - vp.y])
+            .collect::<Vec<f32>>();
+        self.line_renderer
+            .scale_and_load_points(&mut points, vp.w, vp.h);
+        self.line_renderer.render_fan(Some(self.faded(colour)));
+
+        let [r, g, b, _] = colour;
+        self.line_renderer
+            .render_line_loop(Some(self.faded([r, g, b, 1.0])));
+    }
 }
 
 fn create_shader(gl: &Gl, src: &str, stype: u32) -> Result<WebGlShader, JsError> {
@@ -5,9 +5,9 @@ use parking_lot::Mutex;
 use wasm_bindgen::prelude::*;
 
 use crate::bridge::{
-    expose_closure, expose_closure_f64, expose_closure_f64_bool, expose_closure_f64_f64,
-    expose_closure_f64_string, expose_closure_string_in, expose_closure_string_out, log,
-    request_animation_frame,
+    expose_closure, expose_closure_bool, expose_closure_f64, expose_closure_f64_bool,
+    expose_closure_f64_f64, expose_closure_f64_string, expose_closure_string_in,
+    expose_closure_string_out, log, request_animation_frame,
 };
 use crate::client::Client;
 use crate::viewport::{Tool, Viewport};
@@ -107,6 +107,20 @@ pub fn start() -> Result<(), JsValue> {
     expose_closure_f64_f64("sprite_layer", &sprite_layer_closure);
     sprite_layer_closure.forget();
 
+    let vp_ref = vp.clone();
+    let restack_sprite_closure = Closure::wrap(Box::new(move |id: f64, order: String| {
+        let order = match order.as_str() {
+            "Raise" => scene::ZOrder::Raise,
+            "Lower" => scene::ZOrder::Lower,
+            "Front" => scene::ZOrder::Front,
+            "Back" => scene::ZOrder::Back,
+            _ => return,
+        };
+        vp_ref.lock().scene.restack_sprite(id as i64, order);
+    }) as Box<dyn FnMut(f64, String)>);
+    expose_closure_f64_string("restack_sprite", &restack_sprite_closure);
+    restack_sprite_closure.forget();
+
     let vp_ref = vp.clone();
     let sprite_details_closure = Closure::wrap(Box::new(move |id: f64, json: String| {
         let id = id as i64;
@@ -138,6 +152,29 @@ pub fn start() -> Result<(), JsValue> {
     expose_closure_f64_bool("layer_locked", &layer_locked_closure);
     layer_locked_closure.forget();
 
+    let vp_ref = vp.clone();
+    let layer_opacity_closure = Closure::wrap(Box::new(move |id: f64, opacity: f64| {
+        vp_ref
+            .lock()
+            .scene
+            .set_layer_opacity(id as i64, opacity as f32);
+    }) as Box<dyn FnMut(f64, f64)>);
+    expose_closure_f64_f64("layer_opacity", &layer_opacity_closure);
+    layer_opacity_closure.forget();
+
+    let vp_ref = vp.clone();
+    let layer_blend_closure = Closure::wrap(Box::new(move |id: f64, blend: String| {
+        let blend = match blend.as_str() {
+            "Normal" => scene::BlendMode::Normal,
+            "Multiply" => scene::BlendMode::Multiply,
+            "Screen" => scene::BlendMode::Screen,
+            _ => return,
+        };
+        vp_ref.lock().scene.set_layer_blend(id as i64, blend);
+    }) as Box<dyn FnMut(f64, String)>);
+    expose_closure_f64_string("layer_blend", &layer_blend_closure);
+    layer_blend_closure.forget();
+
     let vp_ref = vp.clone();
     let new_layer_closure = Closure::wrap(Box::new(move || {
         vp_ref.lock().scene.new_layer();
@@ -170,14 +207,111 @@ pub fn start() -> Result<(), JsValue> {
     let select_tool_closure = Closure::wrap(Box::new(move |tool: String| {
         vp_ref.lock().set_tool(match tool.as_str() {
             "Select" => Tool::Select,
+            "Ruler" => Tool::Ruler,
             "Rectangle" => Tool::Shape(scene::SpriteShape::Rectangle),
             "Ellipse" => Tool::Shape(scene::SpriteShape::Ellipse),
+            "Circle" => Tool::Template(scene::TemplateShape::Circle { radius: 0.0 }),
+            "Cone" => Tool::Template(scene::TemplateShape::Cone {
+                length: 0.0,
+                angle: 53.13,
+            }),
+            "Cube" => Tool::Template(scene::TemplateShape::Cube { size: 0.0 }),
+            "Line" => Tool::Template(scene::TemplateShape::Line {
+                length: 0.0,
+                width: 1.0,
+            }),
             _ => Tool::Select,
         });
     }) as Box<dyn FnMut(String)>);
     expose_closure_string_in("select_tool", &select_tool_closure);
     select_tool_closure.forget();
 
+    let vp_ref = vp.clone();
+    let share_ruler_closure = Closure::wrap(Box::new(move |share: bool| {
+        vp_ref.lock().scene.set_share_ruler(share);
+    }) as Box<dyn FnMut(bool)>);
+    expose_closure_bool("share_ruler", &share_ruler_closure);
+    share_ruler_closure.forget();
+
+    let vp_ref = vp.clone();
+    let roll_dice_closure = Closure::wrap(Box::new(move |json: String| {
+        match serde_json::from_str::<crate::interactor::RollRequest>(&json) {
+            Ok(request) => {
+                if let Err(message) = vp_ref.lock().scene.roll(request) {
+                    crate::bridge::roll_error(&message);
+                }
+            }
+            Err(_) => crate::bridge::roll_error("Invalid roll request."),
+        }
+    }) as Box<dyn FnMut(String)>);
+    expose_closure_string_in("roll_dice", &roll_dice_closure);
+    roll_dice_closure.forget();
+
+    let vp_ref = vp.clone();
+    let send_chat_closure = Closure::wrap(Box::new(move |json: String| {
+        match serde_json::from_str::<crate::interactor::ChatRequest>(&json) {
+            Ok(request) => {
+                if let Err(message) = vp_ref.lock().scene.chat(request) {
+                    crate::bridge::chat_error(&message);
+                }
+            }
+            Err(_) => crate::bridge::chat_error("Invalid chat message."),
+        }
+    }) as Box<dyn FnMut(String)>);
+    expose_closure_string_in("send_chat", &send_chat_closure);
+    send_chat_closure.forget();
+
+    let vp_ref = vp.clone();
+    let add_combatant_closure = Closure::wrap(Box::new(move |json: String| {
+        if let Ok(request) = serde_json::from_str::<crate::interactor::CombatantRequest>(&json) {
+            vp_ref.lock().scene.add_combatant(request);
+        }
+    }) as Box<dyn FnMut(String)>);
+    expose_closure_string_in("add_combatant", &add_combatant_closure);
+    add_combatant_closure.forget();
+
+    let vp_ref = vp.clone();
+    let remove_combatant_closure = Closure::wrap(Box::new(move |id: f64| {
+        vp_ref.lock().scene.remove_combatant(id as i64);
+    }) as Box<dyn FnMut(f64)>);
+    expose_closure_f64("remove_combatant", &remove_combatant_closure);
+    remove_combatant_closure.forget();
+
+    let vp_ref = vp.clone();
+    let move_combatant_closure = Closure::wrap(Box::new(move |id: f64, position: f64| {
+        vp_ref
+            .lock()
+            .scene
+            .move_combatant(id as i64, position.max(0.0) as usize);
+    }) as Box<dyn FnMut(f64, f64)>);
+    expose_closure_f64_f64("move_combatant", &move_combatant_closure);
+    move_combatant_closure.forget();
+
+    // An initiative which isn't a number clears the initiative.
+    let vp_ref = vp.clone();
+    let set_initiative_closure = Closure::wrap(Box::new(move |id: f64, initiative: String| {
+        vp_ref
+            .lock()
+            .scene
+            .set_initiative(id as i64, initiative.trim().parse().ok());
+    }) as Box<dyn FnMut(f64, String)>);
+    expose_closure_f64_string("set_initiative", &set_initiative_closure);
+    set_initiative_closure.forget();
+
+    let vp_ref = vp.clone();
+    let next_turn_closure = Closure::wrap(Box::new(move || {
+        vp_ref.lock().scene.next_turn();
+    }) as Box<dyn FnMut()>);
+    expose_closure("next_turn", &next_turn_closure);
+    next_turn_closure.forget();
+
+    let vp_ref = vp.clone();
+    let previous_turn_closure = Closure::wrap(Box::new(move || {
+        vp_ref.lock().scene.previous_turn();
+    }) as Box<dyn FnMut()>);
+    expose_closure("previous_turn", &previous_turn_closure);
+    previous_turn_closure.forget();
+
     let f = Rc::new(RefCell::new(None));
     let g = f.clone();
 
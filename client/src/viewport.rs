@@ -1,16 +1,20 @@
 use crate::{
     bridge::{
-        clear_selected_sprite, set_scene_details, set_selected_sprite, sprite_dropdown,
-        update_layers_list, Context, Input, JsError, Key, KeyboardAction, MouseAction, MouseButton,
+        add_chat_message, add_roll, add_ruler_label, add_token_label, clear_ruler_labels,
+        clear_selected_sprite, clear_token_labels, set_scene_details, set_selected_sprite,
+        sprite_dropdown, update_initiative, update_layers_list, update_players, Context, Input,
+        JsError, Key, KeyboardAction, MouseAction, MouseButton,
     },
     client::Client,
     interactor::Interactor,
 };
-use scene::{Rect, ScenePoint, SpriteShape};
+use scene::{BlendMode, Rect, ScenePoint, SpriteShape, TemplateShape, ZOrder};
 
 pub enum Tool {
+    Ruler,
     Select,
     Shape(SpriteShape),
+    Template(TemplateShape),
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -125,6 +129,8 @@ impl Viewport {
                 Tool::Shape(shape) => {
                     self.scene.new_sprite(None, Some(shape), None);
                 }
+                Tool::Ruler => self.scene.start_ruler(self.scene_point(at)),
+                Tool::Template(shape) => self.scene.aim_template(self.scene_point(at), shape),
             },
             MouseButton::Right => {
                 if let Some(id) = self.scene.sprite_at(self.scene_point(at)) {
@@ -143,6 +149,15 @@ impl Viewport {
 
     fn handle_mouse_up(&mut self, button: MouseButton, alt: bool, ctrl: bool) {
         match button {
+            // Holding ctrl when releasing the ruler adds a waypoint, allowing
+            // measurement along a path.
+            MouseButton::Left if matches!(self.tool, Tool::Ruler) => {
+                if ctrl {
+                    self.scene.ruler_waypoint();
+                } else {
+                    self.scene.end_ruler();
+                }
+            }
             MouseButton::Left => self.scene.release(alt, ctrl),
             MouseButton::Right => self.release_grab(),
             MouseButton::Middle => self.centre_viewport(),
@@ -153,6 +168,7 @@ impl Viewport {
     fn handle_mouse_move(&mut self, at: ViewportPoint) {
         self.scene
             .drag(at.scene_point(self.viewport, self.grid_zoom));
+        self.scene.drag_ruler(self.scene_point(at));
         if let Some(from) = self.grabbed_at {
             self.viewport.x += (from.x - at.x) / self.grid_zoom;
             self.viewport.y += (from.y - at.y) / self.grid_zoom;
@@ -202,12 +218,29 @@ impl Viewport {
     fn handle_key_down(&mut self, key: Key, ctrl: bool) {
         if matches!(key, Key::Delete) {
             self.scene.remove_sprite(Interactor::SELECTION_ID);
+            self.scene.remove_template();
         } else if ctrl {
             match key {
+                Key::LeftBracket => self
+                    .scene
+                    .restack_sprite(Interactor::SELECTION_ID, ZOrder::Back),
+                Key::RightBracket => self
+                    .scene
+                    .restack_sprite(Interactor::SELECTION_ID, ZOrder::Front),
                 Key::Y => self.scene.redo(),
                 Key::Z => self.scene.undo(),
                 _ => (),
             };
+        } else {
+            match key {
+                Key::LeftBracket => self
+                    .scene
+                    .restack_sprite(Interactor::SELECTION_ID, ZOrder::Lower),
+                Key::RightBracket => self
+                    .scene
+                    .restack_sprite(Interactor::SELECTION_ID, ZOrder::Raise),
+                _ => (),
+            };
         }
     }
 
@@ -246,26 +279,80 @@ impl Viewport {
         let mut background_drawn = false;
         for layer in self.scene.layers().iter().rev() {
             if !background_drawn && layer.z >= 0 {
-                self.context
-                    .draw_grid(vp, self.scene.dimensions(), self.grid_zoom);
+                self.context.draw_grid(
+                    vp,
+                    self.scene.dimensions(),
+                    self.scene.grid(),
+                    self.grid_zoom,
+                );
                 background_drawn = true;
             }
 
             if layer.visible {
+                self.context.set_layer_style(layer.opacity, layer.blend);
                 self.context
                     .draw_sprites(vp, &layer.sprites, self.grid_zoom);
+                self.context
+                    .draw_health_bars(vp, &layer.sprites, self.grid_zoom);
+                self.context
+                    .draw_templates(vp, &layer.templates, self.grid_zoom);
+                self.context.set_layer_style(1.0, BlendMode::Normal);
             }
         }
 
         if !background_drawn {
-            self.context
-                .draw_grid(vp, self.scene.dimensions(), self.grid_zoom);
+            self.context.draw_grid(
+                vp,
+                self.scene.dimensions(),
+                self.scene.grid(),
+                self.grid_zoom,
+            );
         }
 
         for rect in self.scene.selections() {
             self.context
                 .draw_outline(vp, Rect::scaled_from(rect, self.grid_zoom));
         }
+
+        self.draw_token_labels(vp);
+        self.draw_rulers(vp);
+    }
+
+    fn draw_token_labels(&mut self, vp: Rect) {
+        clear_token_labels();
+        for layer in self.scene.layers().iter().filter(|l| l.visible) {
+            for sprite in &layer.sprites {
+                let token = &sprite.token;
+                if token.name.is_none() && token.conditions.is_empty() {
+                    continue;
+                }
+
+                let rect = sprite.rect.positive_dimensions();
+                add_token_label(
+                    token,
+                    (rect.x + rect.w / 2.0) * self.grid_zoom - vp.x,
+                    (rect.y + rect.h) * self.grid_zoom - vp.y,
+                );
+            }
+        }
+    }
+
+    fn draw_rulers(&mut self, vp: Rect) {
+        const RULER_COLOUR: [f32; 4] = [1.0, 0.6, 0.0, 1.0];
+
+        clear_ruler_labels();
+        for ruler in self.scene.rulers() {
+            self.context
+                .draw_path(vp, ruler, self.grid_zoom, RULER_COLOUR);
+
+            if let Some(end) = ruler.last() {
+                add_ruler_label(
+                    &self.scene.ruler_label(ruler),
+                    end.x * self.grid_zoom - vp.x,
+                    end.y * self.grid_zoom - vp.y,
+                );
+            }
+        }
     }
 
     pub fn animation_frame(&mut self) {
@@ -280,6 +367,22 @@ impl Viewport {
             self.redraw_needed = false;
         }
 
+        for roll in self.scene.take_rolls() {
+            add_roll(roll);
+        }
+
+        for message in self.scene.take_chat() {
+            add_chat_message(message);
+        }
+
+        if let Some(players) = self.scene.take_players() {
+            update_players(players);
+        }
+
+        if self.scene.changes.handle_initiative_change() {
+            update_initiative(self.scene.initiative_details());
+        }
+
         if self.scene.changes.handle_layer_change() {
             update_layers_list(self.scene.layers());
         }
@@ -294,6 +397,7 @@ impl Viewport {
     }
 
     pub fn set_tool(&mut self, tool: Tool) {
+        self.scene.end_ruler();
         self.tool = tool;
     }
 }
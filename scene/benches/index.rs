@@ -0,0 +1,72 @@
+// Benchmarks for finding sprites on a large map. Run with cargo bench.
+#![feature(test)]
+
+extern crate test;
+
+use scene::{Rect, Scene, ScenePoint, Sprite};
+use test::{black_box, Bencher};
+
+const SPRITES: i64 = 50_000;
+const SIZE: f32 = 500.0;
+
+// A scene with tokens scattered across a large map, spread over its layers.
+fn scene() -> Scene {
+    let mut seed = 1u32;
+    let mut random = move || {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 8) as f32 / (1 << 24) as f32
+    };
+
+    let mut scene = Scene::new();
+    let layers = scene.layers.iter().map(|l| l.id).collect::<Vec<i64>>();
+    for id in 0..SPRITES {
+        let mut sprite = Sprite::new(1000 + id, None, None);
+        sprite.rect = Rect::new(random() * SIZE, random() * SIZE, 1.0, 1.0);
+        scene.add_sprite(sprite, layers[id as usize % layers.len()]);
+    }
+    scene
+}
+
+#[bench]
+fn bench_sprite_at(b: &mut Bencher) {
+    let scene = scene();
+    let mut x = 0.0;
+    b.iter(|| {
+        x = (x + 7.3) % SIZE;
+        black_box(scene.sprite_at_ref(ScenePoint::new(x, SIZE - x), 0));
+    });
+}
+
+#[bench]
+fn bench_sprites_in(b: &mut Bencher) {
+    let mut scene = scene();
+    let mut x = 0.0;
+    b.iter(|| {
+        x = (x + 7.3) % SIZE;
+        black_box(scene.sprites_in(Rect::new(x, SIZE - x, 20.0, -20.0), true));
+    });
+}
+
+#[bench]
+fn bench_sprite(b: &mut Bencher) {
+    let mut scene = scene();
+    let mut id = 0;
+    b.iter(|| {
+        id = (id + 7919) % SPRITES;
+        black_box(scene.sprite(1000 + id).map(|s| s.rect));
+    });
+}
+
+// Dragging a sprite looks it up, moves it and then hit-tests each frame.
+#[bench]
+fn bench_drag(b: &mut Bencher) {
+    let mut scene = scene();
+    let mut x = 0.0;
+    b.iter(|| {
+        x = (x + 0.1) % SIZE;
+        if let Some(sprite) = scene.sprite(1000) {
+            sprite.rect.x = x;
+        }
+        black_box(scene.sprite_at(ScenePoint::new(x + 0.5, 10.0), 0).is_some());
+    });
+}
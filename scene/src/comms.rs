@@ -1,41 +1,96 @@
 use serde_derive::{Deserialize, Serialize};
 
 use super::{
+    dice::Roll,
+    initiative::Combatant,
+    layer::BlendMode,
     perms::{Override, PermSet, Perms, Role},
     sprite::{SpriteShape, SpriteVisual},
-    Id, Rect, Scene, Sprite,
+    template::{Template, TemplateShape},
+    Grid, Id, Rect, Scene, ScenePoint, Sprite, Wall,
 };
 
 // Events processed by Scene
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum SceneEvent {
-    Dummy,                                        // To trigger redraws, etc
-    EventSet(Vec<SceneEvent>),                    // Collection of other events
-    LayerLocked(Id, bool),                        // (layer, status)
-    LayerMove(Id, i32, bool),                     // (layer, starting_z, up)
-    LayerNew(Id, String, i32),                    // (local_id, title, z)
-    LayerRemove(Id),                              // (layer)
-    LayerRename(Id, String, String),              // (layer, old_title, new_title)
-    LayerRestore(Id),                             // (layer)
-    LayerVisibility(Id, bool),                    // (layer, status)
-    SceneDimensions(u32, u32, u32, u32),          // (old_w, old_h, new_w, new_h)
-    SceneTitle(Option<String>, String),           // (old_title, new_title)
-    SpriteLayer(Id, Id, Id),                      // (sprite, old_layer, new_layer)
-    SpriteMove(Id, Rect, Rect),                   // (sprite, from, to)
-    SpriteNew(Sprite, Id),                        // (new_sprite, layer)
-    SpriteRemove(Id),                             // (sprite)
-    SpriteRestore(Id),                            // (sprite)
-    SpriteShape(Id, SpriteShape, SpriteShape),    // (sprite, old, new)
-    SpriteVisual(Id, SpriteVisual, SpriteVisual), // (sprite, old, new)
+    Dummy,                                                   // To trigger redraws, etc
+    EventSet(Vec<SceneEvent>),                               // Collection of other events
+    InitiativeAdd(Combatant, usize),                         // (combatant, position)
+    InitiativeAdvance(u32, usize),                           // (old_round, old_turn)
+    InitiativeBack(u32, usize),                              // (old_round, old_turn)
+    InitiativeCombatant(Combatant, Combatant),               // (old, new)
+    InitiativeMove(Id, usize, usize),                        // (combatant, from, to)
+    InitiativeRemove(Combatant, usize, u32, usize),          // (combatant, position, round, turn)
+    InitiativeRestore(Combatant, usize, u32, usize),         // (combatant, position, round, turn)
+    InitiativeSet(Id, Option<i32>, Option<i32>),             // (combatant, old, new)
+    LayerBlend(Id, BlendMode, BlendMode),                    // (layer, old, new)
+    LayerLocked(Id, bool),                                   // (layer, status)
+    LayerMove(Id, i32, bool),                                // (layer, starting_z, up)
+    LayerNew(Id, String, i32),                               // (local_id, title, z)
+    LayerOpacity(Id, f32, f32),                              // (layer, old, new)
+    LayerRemove(Id),                                         // (layer)
+    LayerRename(Id, String, String),                         // (layer, old_title, new_title)
+    LayerRestore(Id),                                        // (layer)
+    LayerVisibility(Id, bool),                               // (layer, status)
+    SceneDimensions(u32, u32, u32, u32),                     // (old_w, old_h, new_w, new_h)
+    SceneFogOfWar(bool),                                     // (enabled)
+    SceneGrid(Grid, Grid),                                   // (old_grid, new_grid)
+    SceneTitle(Option<String>, String),                      // (old_title, new_title)
+    SceneWalls(Vec<Wall>, Vec<Wall>),                        // (old_walls, new_walls)
+    SpriteCondition(Id, String, bool),                       // (sprite, condition, added)
+    SpriteField(Id, String, Option<String>, Option<String>), // (sprite, key, old, new)
+    SpriteHp(Id, Option<i32>, Option<i32>),                  // (sprite, old, new)
+    SpriteHpDelta(Id, i32),                                  // (sprite, delta)
+    SpriteLayer(Id, Id, Id),                                 // (sprite, old_layer, new_layer)
+    SpriteMaxHp(Id, Option<i32>, Option<i32>),               // (sprite, old, new)
+    SpriteMove(Id, Rect, Rect),                              // (sprite, from, to)
+    SpriteName(Id, Option<String>, Option<String>),          // (sprite, old, new)
+    SpriteNew(Sprite, Id),                                   // (new_sprite, layer)
+    SpriteOwners(Id, Vec<Id>, Vec<Id>),                      // (sprite, old, new)
+    SpriteRemove(Id),                                        // (sprite)
+    SpriteRestore(Id),                                       // (sprite)
+    SpriteShape(Id, SpriteShape, SpriteShape),               // (sprite, old, new)
+    SpriteTempHp(Id, i32, i32),                              // (sprite, old, new)
+    SpriteVision(Id, Option<f32>, Option<f32>),              // (sprite, old, new)
+    SpriteVisual(Id, SpriteVisual, SpriteVisual),            // (sprite, old, new)
+    SpriteZ(Id, Option<Id>, Option<Id>),                     // (sprite, old_below, new_below)
+    TemplateMove(Id, ScenePoint, ScenePoint),                // (template, from, to)
+    TemplateNew(Template, Id),                               // (new_template, layer)
+    TemplateRemove(Id),                                      // (template)
+    TemplateRestore(Id),                                     // (template)
+    TemplateRotate(Id, f32, f32),                            // (template, old, new)
+    TemplateShape(Id, TemplateShape, TemplateShape),         // (template, old, new)
 }
 
 impl SceneEvent {
+    pub fn is_initiative(&self) -> bool {
+        if matches!(
+            self,
+            Self::InitiativeAdd(..)
+                | Self::InitiativeAdvance(..)
+                | Self::InitiativeBack(..)
+                | Self::InitiativeCombatant(..)
+                | Self::InitiativeMove(..)
+                | Self::InitiativeRemove(..)
+                | Self::InitiativeRestore(..)
+                | Self::InitiativeSet(..)
+        ) {
+            true
+        } else if let Self::EventSet(events) = self {
+            events.iter().any(|e| e.is_initiative())
+        } else {
+            false
+        }
+    }
+
     pub fn is_layer(&self) -> bool {
         if matches!(
             self,
-            Self::LayerLocked(..)
+            Self::LayerBlend(..)
+                | Self::LayerLocked(..)
                 | Self::LayerMove(..)
                 | Self::LayerNew(..)
+                | Self::LayerOpacity(..)
                 | Self::LayerRemove(..)
                 | Self::LayerRename(..)
                 | Self::LayerRestore(..)
@@ -52,13 +107,23 @@ impl SceneEvent {
     pub fn is_sprite(&self) -> bool {
         if matches!(
             self,
-            Self::SpriteLayer(..)
+            Self::SpriteCondition(..)
+                | Self::SpriteField(..)
+                | Self::SpriteHp(..)
+                | Self::SpriteHpDelta(..)
+                | Self::SpriteLayer(..)
+                | Self::SpriteMaxHp(..)
                 | Self::SpriteMove(..)
+                | Self::SpriteName(..)
                 | Self::SpriteNew(..)
+                | Self::SpriteOwners(..)
                 | Self::SpriteRemove(..)
                 | Self::SpriteRestore(..)
                 | Self::SpriteShape(..)
+                | Self::SpriteTempHp(..)
+                | Self::SpriteVision(..)
                 | Self::SpriteVisual(..)
+                | Self::SpriteZ(..)
         ) {
             true
         } else if let Self::EventSet(events) = self {
@@ -68,42 +133,138 @@ impl SceneEvent {
         }
     }
 
-    // If is_sprite or is_layer is true, this will be safe to unwrap.
+    pub fn is_template(&self) -> bool {
+        if matches!(
+            self,
+            Self::TemplateMove(..)
+                | Self::TemplateNew(..)
+                | Self::TemplateRemove(..)
+                | Self::TemplateRestore(..)
+                | Self::TemplateRotate(..)
+                | Self::TemplateShape(..)
+        ) {
+            true
+        } else if let Self::EventSet(events) = self {
+            events.iter().any(|e| e.is_template())
+        } else {
+            false
+        }
+    }
+
+    // If is_sprite, is_template or is_layer is true, this will be safe to
+    // unwrap. For initiative events other than turn changes, this is the
+    // combatant.
     pub fn item(&self) -> Option<Id> {
         let id = match self {
+            Self::InitiativeAdd(c, ..) => &c.id,
+            Self::InitiativeCombatant(_, c) => &c.id,
+            Self::InitiativeMove(id, ..) => id,
+            Self::InitiativeRemove(c, ..) => &c.id,
+            Self::InitiativeRestore(c, ..) => &c.id,
+            Self::InitiativeSet(id, ..) => id,
+            Self::LayerBlend(id, ..) => id,
             Self::LayerLocked(id, ..) => id,
             Self::LayerMove(id, ..) => id,
             Self::LayerNew(id, ..) => id,
+            Self::LayerOpacity(id, ..) => id,
             Self::LayerRename(id, ..) => id,
             Self::LayerRestore(id) => id,
             Self::LayerVisibility(id, ..) => id,
+            Self::SpriteCondition(id, ..) => id,
+            Self::SpriteField(id, ..) => id,
+            Self::SpriteHp(id, ..) => id,
+            Self::SpriteHpDelta(id, ..) => id,
             Self::SpriteLayer(id, ..) => id,
+            Self::SpriteMaxHp(id, ..) => id,
             Self::SpriteMove(id, ..) => id,
+            Self::SpriteName(id, ..) => id,
             Self::SpriteNew(s, ..) => &s.id,
+            Self::SpriteOwners(id, ..) => id,
             Self::SpriteRemove(id) => id,
             Self::SpriteRestore(id) => id,
             Self::SpriteShape(id, ..) => id,
+            Self::SpriteTempHp(id, ..) => id,
+            Self::SpriteVision(id, ..) => id,
             Self::SpriteVisual(id, ..) => id,
+            Self::SpriteZ(id, ..) => id,
+            Self::TemplateMove(id, ..) => id,
+            Self::TemplateNew(t, ..) => &t.id,
+            Self::TemplateRemove(id) => id,
+            Self::TemplateRestore(id) => id,
+            Self::TemplateRotate(id, ..) => id,
+            Self::TemplateShape(id, ..) => id,
             _ => return None,
         };
         Some(*id)
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum PermsEvent {
     /// Update to the role of a user
     RoleChange(Id, Role),
     /// Replace the PermSet for an item
     ItemPerms(PermSet),
+    /// Remove the PermSet for an item
+    ResetItemPerms(Id),
     /// Issue a new Override
     NewOverride(Override),
+    /// Revoke an existing Override
+    RevokeOverride(Override),
+}
+
+/// Who is shown a chat message or dice roll. The sender always sees it.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Audience {
+    #[default]
+    Public,
+    /// Only shown to editors and owners.
+    Gm,
+    /// Only shown to these users.
+    Whisper(Vec<Id>),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChatMessage {
+    pub user: Id,
+    pub username: String,
+    pub audience: Audience,
+    pub text: String,
+    /// Seconds since the epoch, set by the server.
+    pub time: u64,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum ClientEvent {
     Ping,
     SceneUpdate(SceneEvent),
+    /// Share a ruler with other players. Empty to clear.
+    Ruler(Vec<ScenePoint>),
+    /// Roll a dice expression, e.g. 4d6kh3.
+    Roll(String, Audience),
+    /// Send a chat message.
+    Chat(String, Audience),
+    /// Change permissions, for editors.
+    PermsUpdate(PermsEvent),
+    /// Revert an earlier change by this user.
+    Undo(SceneEvent),
+    /// Mark the start or end of a group of changes to be undone together.
+    Group(Grouping),
+}
+
+/// Boundaries of a group of changes, such as the moves made while dragging.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Grouping {
+    Start,
+    /// End a group of sprite moves.
+    Moves,
+    /// End a group of changes to this template.
+    Template(Id),
+}
+
+impl ClientEvent {
+    /// Most points, including waypoints, which a shared ruler may have.
+    pub const MAX_RULER_POINTS: usize = 64;
 }
 
 // Events sent by Client. The client will keep track of these after sending them
@@ -125,4 +286,14 @@ pub enum ServerEvent {
     SceneChange(Scene),
     SceneUpdate(SceneEvent),
     UserId(Id),
+    /// A ruler shared by another user, which is empty if cleared.
+    Ruler(Id, Vec<ScenePoint>),
+    /// Result of a dice roll by a user.
+    Roll(Id, Audience, Roll),
+    /// A chat message from a user.
+    Chat(ChatMessage),
+    /// Recent chat messages, sent on joining a game.
+    ChatHistory(Vec<ChatMessage>),
+    /// Users who have joined the game, with their usernames.
+    Players(Vec<(Id, String)>),
 }
@@ -0,0 +1,367 @@
+use serde_derive::{Deserialize, Serialize};
+
+// Limits to stop a single expression from doing an unreasonable amount of
+// work on the server.
+const MAX_TERMS: usize = 20;
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+const MAX_EXPLOSIONS: usize = 100;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Keep {
+    Highest(u32),
+    Lowest(u32),
+}
+
+/// A group of identical dice, e.g. 4d6kh3.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Dice {
+    pub count: u32,
+    pub sides: u32,
+    pub keep: Option<Keep>,
+    // Each die that rolls its maximum adds another die.
+    pub explode: bool,
+    // Dice rolling this value or lower are rerolled once.
+    pub reroll: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Term {
+    Constant(i64),
+    Dice(Dice),
+}
+
+/// A parsed dice expression: a sum of dice and constants.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Expression {
+    // Each term along with whether it is subtracted.
+    pub terms: Vec<(bool, Term)>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct DieRoll {
+    pub value: u32,
+    // Dropped by a keep modifier and not counted.
+    pub dropped: bool,
+    // Value rolled before this die was rerolled.
+    pub rerolled: Option<u32>,
+    // Whether this die was added by an explosion.
+    pub exploded: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum TermRoll {
+    Constant(i64),
+    Dice(Dice, Vec<DieRoll>),
+}
+
+impl TermRoll {
+    pub fn value(&self) -> i64 {
+        match self {
+            Self::Constant(n) => *n,
+            Self::Dice(_, rolls) => rolls
+                .iter()
+                .filter(|r| !r.dropped)
+                .map(|r| r.value as i64)
+                .sum(),
+        }
+    }
+}
+
+/// The result of rolling an expression, with the result of each die.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Roll {
+    pub expression: String,
+    pub terms: Vec<(bool, TermRoll)>,
+    pub total: i64,
+}
+
+impl Roll {
+    // Human readable breakdown of the roll, e.g. "4d6kh3 [6, 5, (2), 4] + 2".
+    // Dropped dice are in parentheses, exploded dice are marked with ! and
+    // rerolled dice show the original roll, as in 1>4.
+    pub fn breakdown(&self) -> String {
+        let mut s = String::new();
+        for (i, (negative, term)) in self.terms.iter().enumerate() {
+            if i > 0 || *negative {
+                s.push_str(if *negative { " - " } else { " + " });
+            }
+
+            match term {
+                TermRoll::Constant(n) => s.push_str(&n.to_string()),
+                TermRoll::Dice(dice, rolls) => {
+                    let rolls = rolls
+                        .iter()
+                        .map(|r| {
+                            let mut die = r.value.to_string();
+                            if let Some(from) = r.rerolled {
+                                die = format!("{from}>{die}");
+                            }
+                            if r.exploded {
+                                die.push('!');
+                            }
+                            if r.dropped {
+                                die = format!("({die})");
+                            }
+                            die
+                        })
+                        .collect::<Vec<String>>()
+                        .join(", ");
+                    s.push_str(&format!("{} [{rolls}]", dice.notation()));
+                }
+            }
+        }
+        s.trim_start().to_string()
+    }
+}
+
+impl Dice {
+    fn notation(&self) -> String {
+        let mut s = format!("{}d{}", self.count, self.sides);
+        if let Some(n) = self.reroll {
+            s.push_str(&format!("r{n}"));
+        }
+        if self.explode {
+            s.push('!');
+        }
+        match self.keep {
+            Some(Keep::Highest(n)) => s.push_str(&format!("kh{n}")),
+            Some(Keep::Lowest(n)) => s.push_str(&format!("kl{n}")),
+            None => {}
+        }
+        s
+    }
+
+    fn roll_die<E>(
+        &self,
+        die: &mut impl FnMut(u32) -> Result<u32, E>,
+        exploded: bool,
+    ) -> Result<DieRoll, E> {
+        let mut roll = DieRoll {
+            value: die(self.sides)?,
+            dropped: false,
+            rerolled: None,
+            exploded,
+        };
+
+        if let Some(n) = self.reroll {
+            if roll.value <= n {
+                roll.rerolled = Some(roll.value);
+                roll.value = die(self.sides)?;
+            }
+        }
+
+        Ok(roll)
+    }
+
+    fn roll<E>(&self, die: &mut impl FnMut(u32) -> Result<u32, E>) -> Result<Vec<DieRoll>, E> {
+        let mut rolls = Vec::with_capacity(self.count as usize);
+        for _ in 0..self.count {
+            rolls.push(self.roll_die(die, false)?);
+        }
+
+        if self.explode {
+            let mut i = 0;
+            while i < rolls.len() && rolls.len() < self.count as usize + MAX_EXPLOSIONS {
+                if rolls[i].value == self.sides {
+                    rolls.push(self.roll_die(die, true)?);
+                }
+                i += 1;
+            }
+        }
+
+        if let Some(keep) = self.keep {
+            let mut order = (0..rolls.len()).collect::<Vec<usize>>();
+            let n = match keep {
+                Keep::Highest(n) => {
+                    order.sort_by_key(|&i| std::cmp::Reverse(rolls[i].value));
+                    n
+                }
+                Keep::Lowest(n) => {
+                    order.sort_by_key(|&i| rolls[i].value);
+                    n
+                }
+            };
+
+            for &i in order.iter().skip(n as usize) {
+                rolls[i].dropped = true;
+            }
+        }
+
+        Ok(rolls)
+    }
+}
+
+impl Expression {
+    /// Parse an expression such as "4d6kh3 + 2". Supported modifiers are kh
+    /// and kl (keep highest or lowest, k is short for kh), ! (exploding dice)
+    /// and rN (reroll dice showing N or lower once). d% is a d100.
+    pub fn parse(text: &str) -> Result<Expression, String> {
+        let mut parser = Parser {
+            chars: text.chars().filter(|c| !c.is_whitespace()).collect(),
+            i: 0,
+        };
+
+        let mut terms = vec![];
+        loop {
+            let negative = match parser.peek() {
+                Some('-') => true,
+                Some('+') if !terms.is_empty() => false,
+                None if !terms.is_empty() => break,
+                None => return Err("Empty dice expression.".to_string()),
+                _ if terms.is_empty() => {
+                    terms.push((false, parser.term()?));
+                    continue;
+                }
+                Some(c) => return Err(format!("Unexpected character '{c}'.")),
+            };
+            parser.i += 1;
+            terms.push((negative, parser.term()?));
+
+            if terms.len() > MAX_TERMS {
+                return Err(format!("Too many terms, maximum is {MAX_TERMS}."));
+            }
+        }
+
+        Ok(Expression { terms })
+    }
+
+    /// Roll this expression. The die function is given a number of sides and
+    /// should return a uniformly random value between 1 and that number.
+    pub fn roll<E>(&self, mut die: impl FnMut(u32) -> Result<u32, E>) -> Result<Roll, E> {
+        let mut terms = Vec::with_capacity(self.terms.len());
+        for &(negative, term) in &self.terms {
+            let roll = match term {
+                Term::Constant(n) => TermRoll::Constant(n),
+                Term::Dice(dice) => TermRoll::Dice(dice, dice.roll(&mut die)?),
+            };
+            terms.push((negative, roll));
+        }
+
+        let total = terms
+            .iter()
+            .map(|(negative, t)| if *negative { -t.value() } else { t.value() })
+            .sum();
+
+        Ok(Roll {
+            expression: self.notation(),
+            terms,
+            total,
+        })
+    }
+
+    // Canonical form of this expression, e.g. "1d20 + 5".
+    pub fn notation(&self) -> String {
+        let mut s = String::new();
+        for (i, (negative, term)) in self.terms.iter().enumerate() {
+            if i > 0 || *negative {
+                s.push_str(if *negative { " - " } else { " + " });
+            }
+            match term {
+                Term::Constant(n) => s.push_str(&n.to_string()),
+                Term::Dice(dice) => s.push_str(&dice.notation()),
+            }
+        }
+        s.trim_start().to_string()
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    i: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.i).map(|c| c.to_ascii_lowercase())
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.i += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        let start = self.i;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.i += 1;
+        }
+
+        if self.i == start {
+            None
+        } else {
+            // Saturate overly large numbers, which will then fail the limits.
+            Some(
+                self.chars[start..self.i]
+                    .iter()
+                    .collect::<String>()
+                    .parse()
+                    .unwrap_or(u32::MAX),
+            )
+        }
+    }
+
+    fn term(&mut self) -> Result<Term, String> {
+        let count = self.number();
+        if !self.eat('d') {
+            return match count {
+                Some(n) => Ok(Term::Constant(n as i64)),
+                None => Err("Expected a number or dice.".to_string()),
+            };
+        }
+
+        let count = count.unwrap_or(1);
+        let sides = if self.eat('%') {
+            100
+        } else {
+            self.number().ok_or("Expected number of sides.")?
+        };
+
+        if count == 0 || count > MAX_DICE {
+            return Err(format!("Number of dice must be from 1 to {MAX_DICE}."));
+        }
+        if sides == 0 || sides > MAX_SIDES {
+            return Err(format!("Number of sides must be from 1 to {MAX_SIDES}."));
+        }
+
+        let mut dice = Dice {
+            count,
+            sides,
+            keep: None,
+            explode: false,
+            reroll: None,
+        };
+
+        loop {
+            if self.eat('k') {
+                let keep = if self.eat('l') {
+                    Keep::Lowest
+                } else {
+                    self.eat('h');
+                    Keep::Highest
+                };
+                let n = self.number().ok_or("Expected number of dice to keep.")?;
+                dice.keep = Some(keep(n));
+            } else if self.eat('!') {
+                if sides == 1 {
+                    return Err("A d1 cannot explode.".to_string());
+                }
+                dice.explode = true;
+            } else if self.eat('r') {
+                let n = self.number().ok_or("Expected value to reroll.")?;
+                if n >= sides {
+                    return Err("Cannot reroll every value.".to_string());
+                }
+                dice.reroll = Some(n);
+            } else {
+                break;
+            }
+        }
+
+        Ok(Term::Dice(dice))
+    }
+}
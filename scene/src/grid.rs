@@ -0,0 +1,343 @@
+use serde_derive::{Deserialize, Serialize};
+
+use super::{sprite::Colour, Rect, ScenePoint};
+
+// Distance between the centres of adjacent cells across a row (pointy top) or
+// column (flat top) of a hex grid. Adjacent hexes are one unit apart, so rows
+// of pointy top hexes are sqrt(3) / 2 units apart.
+const HEX_ROW_SPACING: f32 = 0.866_025_4;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub enum GridType {
+    #[default]
+    Square,
+    HexFlat,
+    HexPointy,
+    Gridless,
+}
+
+// How diagonal movement is counted when measuring on a square grid.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub enum DistanceRule {
+    // Straight line distance.
+    #[default]
+    Euclidean,
+    // Diagonal steps cost the same as orthogonal steps.
+    Chebyshev,
+    // Every second diagonal step costs double, i.e. 5ft then 10ft.
+    Alternating,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub enum DistanceUnit {
+    #[default]
+    Feet,
+    Metres,
+}
+
+impl DistanceUnit {
+    pub fn abbreviation(&self) -> &'static str {
+        match self {
+            DistanceUnit::Feet => "ft",
+            DistanceUnit::Metres => "m",
+        }
+    }
+}
+
+// The grid of a scene, including its alignment and appearance. Cells are
+// `size` units across, with a cell corner (or hex centre) at `offset`, which
+// allows the grid to be aligned with a map image.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Grid {
+    pub kind: GridType,
+    pub size: f32,
+    pub offset: ScenePoint,
+    pub colour: Colour,
+    pub thickness: f32,
+    pub visible: bool,
+    pub rule: DistanceRule,
+    pub units_per_tile: f32,
+    pub unit: DistanceUnit,
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Grid {
+            kind: GridType::Square,
+            size: 1.0,
+            offset: ScenePoint::new(0.0, 0.0),
+            colour: [0.5, 0.5, 0.5, 0.75],
+            thickness: 1.0,
+            visible: true,
+            rule: DistanceRule::Euclidean,
+            units_per_tile: 5.0,
+            unit: DistanceUnit::Feet,
+        }
+    }
+}
+
+impl Grid {
+    // Smallest allowed cell size, to avoid degenerate grids.
+    pub const MIN_SIZE: f32 = 0.1;
+
+    pub fn new(kind: GridType) -> Self {
+        Grid {
+            kind,
+            ..Default::default()
+        }
+    }
+
+    // Whether this grid may be used in a scene. Its measurements must be
+    // finite, and its cells no smaller than MIN_SIZE.
+    pub fn is_valid(&self) -> bool {
+        self.size.is_finite()
+            && self.size >= Self::MIN_SIZE
+            && self.thickness.is_finite()
+            && self.thickness >= 0.0
+            && self.units_per_tile.is_finite()
+            && self.units_per_tile >= 0.0
+            && self.offset.x.is_finite()
+            && self.offset.y.is_finite()
+    }
+
+    // Convert a point in scene units into grid units, in which cells are
+    // one unit across and aligned with the origin.
+    pub fn grid_point(&self, p: ScenePoint) -> ScenePoint {
+        ScenePoint::new(
+            (p.x - self.offset.x) / self.size,
+            (p.y - self.offset.y) / self.size,
+        )
+    }
+
+    pub fn scene_point(&self, p: ScenePoint) -> ScenePoint {
+        ScenePoint::new(
+            p.x * self.size + self.offset.x,
+            p.y * self.size + self.offset.y,
+        )
+    }
+
+    pub fn grid_rect(&self, rect: Rect) -> Rect {
+        let ScenePoint { x, y } = self.grid_point(rect.top_left());
+        Rect::new(x, y, rect.w / self.size, rect.h / self.size)
+    }
+
+    pub fn scene_rect(&self, rect: Rect) -> Rect {
+        let ScenePoint { x, y } = self.scene_point(rect.top_left());
+        Rect::new(x, y, rect.w * self.size, rect.h * self.size)
+    }
+
+    pub fn cell_centre(&self, p: ScenePoint) -> ScenePoint {
+        self.scene_point(self.kind.cell_centre(self.grid_point(p)))
+    }
+
+    // Distance in cells between two points in scene units.
+    pub fn distance(&self, from: ScenePoint, to: ScenePoint) -> f32 {
+        self.path_distance(&[from, to])
+    }
+
+    // Distance in cells along a path through each of these points. On a
+    // square grid the distance rule determines how diagonals are counted,
+    // measuring between the cells containing each point. The alternating rule
+    // continues counting diagonals from one segment of the path to the next.
+    pub fn path_distance(&self, path: &[ScenePoint]) -> f32 {
+        let mut total = 0.0;
+        let mut diagonals = 0;
+        for segment in path.windows(2) {
+            let from = self.grid_point(segment[0]);
+            let to = self.grid_point(segment[1]);
+            total += match (self.kind, self.rule) {
+                (GridType::Square, DistanceRule::Chebyshev | DistanceRule::Alternating) => {
+                    let dx = (to.x.floor() - from.x.floor()).abs() as u32;
+                    let dy = (to.y.floor() - from.y.floor()).abs() as u32;
+                    let diagonal = dx.min(dy);
+                    let straight = dx.max(dy) - diagonal;
+                    if self.rule == DistanceRule::Alternating {
+                        let doubled = (diagonals % 2 + diagonal) / 2;
+                        diagonals += diagonal;
+                        (straight + diagonal + doubled) as f32
+                    } else {
+                        (straight + diagonal) as f32
+                    }
+                }
+                _ => self.kind.distance(from, to),
+            };
+        }
+        total
+    }
+
+    // Length of a path in the scene's distance units, e.g. feet.
+    pub fn path_length(&self, path: &[ScenePoint]) -> f32 {
+        self.path_distance(path) * self.units_per_tile
+    }
+
+    // Length of a path formatted for display, e.g. "35 ft".
+    pub fn format_length(&self, path: &[ScenePoint]) -> String {
+        let length = (self.path_length(path) * 10.0).round() / 10.0;
+        format!("{} {}", length, self.unit.abbreviation())
+    }
+
+    pub fn cells_in(&self, rect: Rect) -> Vec<ScenePoint> {
+        self.kind
+            .cells_in(self.grid_rect(rect))
+            .into_iter()
+            .map(|p| self.scene_point(p))
+            .collect()
+    }
+
+    pub fn cell_outline(&self, centre: ScenePoint) -> Vec<ScenePoint> {
+        self.kind
+            .cell_outline(self.grid_point(centre))
+            .into_iter()
+            .map(|p| self.scene_point(p))
+            .collect()
+    }
+}
+
+impl GridType {
+    pub fn is_hex(&self) -> bool {
+        matches!(self, GridType::HexFlat | GridType::HexPointy)
+    }
+
+    // Centre of the cell containing this point. On a square grid cells are
+    // the unit squares, while gridless scenes have no cells so the point is
+    // returned unchanged.
+    pub fn cell_centre(&self, p: ScenePoint) -> ScenePoint {
+        match self {
+            GridType::Square => ScenePoint::new(p.x.floor() + 0.5, p.y.floor() + 0.5),
+            GridType::HexFlat | GridType::HexPointy => {
+                let (q, r) = hex_round(self.axial(p));
+                self.axial_point((q as f32, r as f32))
+            }
+            GridType::Gridless => p,
+        }
+    }
+
+    // Number of tiles between two points. Hex grids count the steps between
+    // the cells containing the points, while square and gridless scenes use
+    // straight line distance.
+    pub fn distance(&self, from: ScenePoint, to: ScenePoint) -> f32 {
+        match self {
+            GridType::HexFlat | GridType::HexPointy => {
+                let (q1, r1) = hex_round(self.axial(from));
+                let (q2, r2) = hex_round(self.axial(to));
+                let (dq, dr) = (q1 - q2, r1 - r2);
+                ((dq.abs() + dr.abs() + (dq + dr).abs()) / 2) as f32
+            }
+            GridType::Square | GridType::Gridless => {
+                ((from.x - to.x).powi(2) + (from.y - to.y).powi(2)).sqrt()
+            }
+        }
+    }
+
+    // Centres of all cells with centres lying within the rect.
+    pub fn cells_in(&self, rect: Rect) -> Vec<ScenePoint> {
+        let Rect { x, y, w, h } = rect.positive_dimensions();
+        let mut cells = vec![];
+        match self {
+            GridType::Square => {
+                let mut cx = (x - 0.5).ceil() + 0.5;
+                while cx <= x + w {
+                    let mut cy = (y - 0.5).ceil() + 0.5;
+                    while cy <= y + h {
+                        cells.push(ScenePoint::new(cx, cy));
+                        cy += 1.0;
+                    }
+                    cx += 1.0;
+                }
+            }
+            GridType::HexFlat | GridType::HexPointy => {
+                // Flat top grids are pointy top grids with the axes swapped,
+                // so iterate over rows of the grid along the appropriate axis.
+                let (across, along, across_len, along_len) = if *self == GridType::HexPointy {
+                    (x, y, w, h)
+                } else {
+                    (y, x, h, w)
+                };
+
+                let first_row = (along / HEX_ROW_SPACING).floor() as i32;
+                let last_row = ((along + along_len) / HEX_ROW_SPACING).ceil() as i32;
+                for r in first_row..=last_row {
+                    let offset = r as f32 / 2.0;
+                    let first = (across - offset).floor() as i32;
+                    let last = (across + across_len - offset).ceil() as i32;
+                    for q in first..=last {
+                        let centre = self.axial_point((q as f32, r as f32));
+                        if rect.contains_point(centre) {
+                            cells.push(centre);
+                        }
+                    }
+                }
+            }
+            GridType::Gridless => {}
+        }
+        cells
+    }
+
+    // Corners of the cell centred at this point, in order around the cell.
+    pub fn cell_outline(&self, centre: ScenePoint) -> Vec<ScenePoint> {
+        match self {
+            GridType::Square => vec![
+                ScenePoint::new(centre.x - 0.5, centre.y - 0.5),
+                ScenePoint::new(centre.x + 0.5, centre.y - 0.5),
+                ScenePoint::new(centre.x + 0.5, centre.y + 0.5),
+                ScenePoint::new(centre.x - 0.5, centre.y + 0.5),
+            ],
+            GridType::HexFlat | GridType::HexPointy => {
+                // Distance from the centre of a hex to each corner.
+                let radius = 1.0 / 3.0_f32.sqrt();
+                let start = if *self == GridType::HexPointy {
+                    30.0
+                } else {
+                    0.0
+                };
+                (0..6)
+                    .map(|i| {
+                        let angle = (start + 60.0 * i as f32).to_radians();
+                        ScenePoint::new(
+                            centre.x + radius * angle.cos(),
+                            centre.y + radius * angle.sin(),
+                        )
+                    })
+                    .collect()
+            }
+            GridType::Gridless => vec![],
+        }
+    }
+
+    // Fractional axial hex coordinates of a point. Cell (0, 0) is centred on
+    // the scene origin.
+    fn axial(&self, p: ScenePoint) -> (f32, f32) {
+        let (across, along) = if *self == GridType::HexPointy {
+            (p.x, p.y)
+        } else {
+            (p.y, p.x)
+        };
+        let r = along / HEX_ROW_SPACING;
+        (across - r / 2.0, r)
+    }
+
+    fn axial_point(&self, (q, r): (f32, f32)) -> ScenePoint {
+        let across = q + r / 2.0;
+        let along = r * HEX_ROW_SPACING;
+        if *self == GridType::HexPointy {
+            ScenePoint::new(across, along)
+        } else {
+            ScenePoint::new(along, across)
+        }
+    }
+}
+
+// Round fractional axial coordinates to the hex containing them.
+fn hex_round((q, r): (f32, f32)) -> (i32, i32) {
+    let s = -q - r;
+    let (mut rq, mut rr, rs) = (q.round(), r.round(), s.round());
+    let (dq, dr, ds) = ((rq - q).abs(), (rr - r).abs(), (rs - s).abs());
+
+    if dq > dr && dq > ds {
+        rq = -rr - rs;
+    } else if dr > ds {
+        rr = -rq - rs;
+    }
+
+    (rq as i32, rr as i32)
+}
@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::mem::discriminant;
+
+use super::{
+    comms::{Grouping, SceneEvent},
+    Id, Scene, Template,
+};
+
+/// A single user's changes to a scene, which they may undo and redo. Changes
+/// made by other users are reported through handle_remote so that undoing
+/// never reverts someone else's work.
+#[derive(Debug, Default)]
+pub struct History {
+    undo: VecDeque<SceneEvent>,
+    redo: Vec<SceneEvent>,
+}
+
+impl History {
+    // Number of entries kept for undo.
+    pub const LIMIT: usize = 256;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.undo.iter().any(|e| !matches!(e, SceneEvent::Dummy))
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn push(&mut self, event: SceneEvent) {
+        // When adding a new entry to the history, all undone events are lost.
+        self.redo.clear();
+        self.undo.push_back(event);
+        if self.undo.len() > Self::LIMIT {
+            self.undo.pop_front();
+        }
+    }
+
+    // Undo the most recent change which can still be undone, returning the
+    // event to issue. Changes which can no longer be unwound are skipped.
+    pub fn undo(&mut self, scene: &mut Scene) -> Option<SceneEvent> {
+        while let Some(event) = self.undo.pop_back() {
+            if matches!(event, SceneEvent::Dummy) {
+                continue;
+            }
+
+            if let Some(unwind) = scene.unwind_event(event) {
+                self.redo.push(unwind.clone());
+                return Some(unwind);
+            }
+        }
+        None
+    }
+
+    pub fn redo(&mut self, scene: &mut Scene) -> Option<SceneEvent> {
+        while let Some(event) = self.redo.pop() {
+            if let Some(unwind) = scene.unwind_event(event) {
+                self.undo.push_back(unwind.clone());
+                return Some(unwind);
+            }
+        }
+        None
+    }
+
+    // Account for a change made by another user. Entries which it supersedes
+    // are dropped, while those which can be adjusted to apply on top of it
+    // are rebased.
+    pub fn handle_remote(&mut self, remote: &SceneEvent) {
+        if let SceneEvent::EventSet(events) = remote {
+            events.iter().for_each(|e| self.handle_remote(e));
+            return;
+        }
+
+        self.undo.retain_mut(|local| !rebase(local, remote));
+        self.redo.retain_mut(|local| !rebase(local, remote));
+    }
+
+    // The entry this event is an undo of, that is the most recent entry of
+    // which this event is the inverse. Whether the scene is still in the state
+    // the entry left it in is checked when the undo is applied.
+    pub fn undone_entry(&self, event: &SceneEvent) -> Option<usize> {
+        self.undo
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, e)| inverts(event, e))
+            .map(|(i, _)| i)
+    }
+
+    // Drop an entry which has been undone, so that it can't be undone again.
+    pub fn forget(&mut self, entry: usize) {
+        self.undo.remove(entry);
+    }
+
+    // Start or end a group of events as marked by the user who issued them.
+    pub fn group(&mut self, grouping: Grouping, scene: &Scene) {
+        match grouping {
+            Grouping::Start => self.start_group(),
+            Grouping::Moves => self.end_move_group(),
+            Grouping::Template(id) => self.end_template_group(id, scene.template_ref(id).copied()),
+        }
+    }
+
+    // Mark the start of a group of events, such as those issued while
+    // dragging, to be collapsed into a single entry at its end.
+    pub fn start_group(&mut self) {
+        self.undo.push_back(SceneEvent::Dummy);
+    }
+
+    // Collapse the moves issued since the start of the group into a single
+    // entry moving each sprite from its start to its finish.
+    pub fn end_move_group(&mut self) {
+        match self.undo.pop_back() {
+            Some(event @ SceneEvent::SpriteMove(..)) => self.group_moves_single(event),
+            Some(event @ SceneEvent::EventSet(..)) => self.group_moves_set(event),
+            Some(event) => self.undo.push_back(event),
+            None => {}
+        }
+    }
+
+    fn group_moves_single(&mut self, last: SceneEvent) {
+        let (sprite, mut start, finish) = if let SceneEvent::SpriteMove(id, from, to) = last {
+            (id, from, to)
+        } else {
+            return;
+        };
+
+        while let Some(e) = self.undo.pop_back() {
+            if let SceneEvent::SpriteMove(id, from, _) = e {
+                if id == sprite {
+                    start = from;
+                    continue;
+                }
+            }
+
+            if !matches!(e, SceneEvent::Dummy) {
+                self.undo.push_back(e);
+            }
+            break;
+        }
+
+        self.undo
+            .push_back(SceneEvent::SpriteMove(sprite, start, finish));
+    }
+
+    fn group_moves_set(&mut self, last: SceneEvent) {
+        self.undo.push_back(last);
+        // Ordered by sprite so that each user's history groups a drag alike.
+        let mut moves = BTreeMap::new();
+
+        while let Some(e) = self.undo.pop_back() {
+            if let SceneEvent::EventSet(v) = e {
+                for event in v {
+                    if let SceneEvent::SpriteMove(id, from, _) = event {
+                        if let Some(SceneEvent::SpriteMove(_, start, _)) = moves.get_mut(&id) {
+                            *start = from;
+                        } else {
+                            moves.insert(id, event);
+                        }
+                    }
+                }
+                continue;
+            }
+
+            if !matches!(e, SceneEvent::Dummy) {
+                self.undo.push_back(e);
+            }
+            break;
+        }
+
+        self.undo.push_back(SceneEvent::EventSet(
+            moves.into_values().collect::<Vec<SceneEvent>>(),
+        ));
+    }
+
+    // Collapse the events issued while holding a template into a single
+    // entry. A template created during the group is recorded as created in
+    // its final state, given as current.
+    pub fn end_template_group(&mut self, template: Id, current: Option<Template>) {
+        let mut created = None;
+        let mut moved = None;
+        let mut rotated = None;
+        let mut shaped = None;
+        let mut others = vec![];
+        let mut group = vec![];
+        while let Some(e) = self.undo.pop_back() {
+            match e {
+                SceneEvent::Dummy => break,
+                SceneEvent::EventSet(events) if e.is_template() => {
+                    group.extend(events.into_iter().rev())
+                }
+                e => group.push(e),
+            }
+        }
+
+        // Events are visited from most to least recent.
+        for e in group {
+            match e {
+                SceneEvent::TemplateNew(t, layer) if t.id == template => created = Some(layer),
+                SceneEvent::TemplateMove(id, from, to) if id == template => {
+                    moved = Some((from, moved.map_or(to, |(_, to)| to)));
+                }
+                SceneEvent::TemplateRotate(id, old, new) if id == template => {
+                    rotated = Some((old, rotated.map_or(new, |(_, new)| new)));
+                }
+                SceneEvent::TemplateShape(id, old, new) if id == template => {
+                    shaped = Some((old, shaped.map_or(new, |(_, new)| new)));
+                }
+                e => others.push(e),
+            }
+        }
+
+        while let Some(e) = others.pop() {
+            self.undo.push_back(e);
+        }
+
+        if let Some(layer) = created {
+            if let Some(t) = current {
+                self.undo.push_back(SceneEvent::TemplateNew(t, layer));
+            }
+            return;
+        }
+
+        let mut events = vec![];
+        if let Some((from, to)) = moved {
+            events.push(SceneEvent::TemplateMove(template, from, to));
+        }
+        if let Some((old, new)) = rotated {
+            events.push(SceneEvent::TemplateRotate(template, old, new));
+        }
+        if let Some((old, new)) = shaped {
+            events.push(SceneEvent::TemplateShape(template, old, new));
+        }
+
+        if !events.is_empty() {
+            self.undo.push_back(SceneEvent::EventSet(events));
+        }
+    }
+}
+
+// Whether undo is what unwinding event gives, as far as can be told without
+// the scene. Moves and HP changes are relative so only their extent is
+// compared, while turn changes are checked against the initiative order when
+// applied.
+fn inverts(undo: &SceneEvent, event: &SceneEvent) -> bool {
+    use SceneEvent as E;
+
+    match (undo, event) {
+        // Sets are unwound in reverse.
+        (E::EventSet(undos), E::EventSet(events)) => {
+            undos.len() == events.len()
+                && undos
+                    .iter()
+                    .zip(events.iter().rev())
+                    .all(|(u, e)| inverts(u, e))
+        }
+        (
+            E::InitiativeRemove(c, ..),
+            E::InitiativeAdd(added, _) | E::InitiativeRestore(added, ..),
+        ) => c.id == added.id,
+        (E::InitiativeRestore(c, pos, round, turn), E::InitiativeRemove(removed, p, r, t)) => {
+            (c, pos, round, turn) == (removed, p, r, t)
+        }
+        (E::InitiativeBack(..), E::InitiativeAdvance(..))
+        | (E::InitiativeAdvance(..), E::InitiativeBack(..)) => true,
+        (E::InitiativeCombatant(a, b), E::InitiativeCombatant(old, new)) => a == new && b == old,
+        (E::InitiativeMove(a, from, to), E::InitiativeMove(b, old, new)) => {
+            a == b && from == new && to == old
+        }
+        (E::InitiativeSet(a, x, y), E::InitiativeSet(b, old, new)) => {
+            a == b && x == new && y == old
+        }
+        (E::LayerBlend(a, x, y), E::LayerBlend(b, old, new)) => a == b && x == new && y == old,
+        (E::LayerLocked(a, x), E::LayerLocked(b, y))
+        | (E::LayerVisibility(a, x), E::LayerVisibility(b, y)) => a == b && x != y,
+        (E::LayerMove(a, _, x), E::LayerMove(b, _, y)) => a == b && x != y,
+        (E::LayerOpacity(a, x, y), E::LayerOpacity(b, old, new)) => a == b && x == new && y == old,
+        (E::LayerRemove(a), E::LayerNew(b, ..) | E::LayerRestore(b))
+        | (E::LayerRestore(a), E::LayerRemove(b)) => a == b,
+        (E::LayerRename(a, x, y), E::LayerRename(b, old, new)) => a == b && x == new && y == old,
+        (E::SceneDimensions(w, h, old_w, old_h), E::SceneDimensions(a, b, new_w, new_h)) => {
+            (w, h, old_w, old_h) == (new_w, new_h, a, b)
+        }
+        (E::SceneFogOfWar(x), E::SceneFogOfWar(y)) => x != y,
+        (E::SceneGrid(x, y), E::SceneGrid(old, new)) => x == new && y == old,
+        (E::SceneTitle(x, y), E::SceneTitle(old, new)) => {
+            x.as_ref() == Some(new) && Some(y) == old.as_ref()
+        }
+        (E::SceneWalls(x, y), E::SceneWalls(old, new)) => x == new && y == old,
+        (E::SpriteCondition(a, x, added), E::SpriteCondition(b, y, was_added)) => {
+            a == b && x == y && added != was_added
+        }
+        (E::SpriteField(a, x, v, w), E::SpriteField(b, y, old, new)) => {
+            a == b && x == y && v == new && w == old
+        }
+        (E::SpriteHp(a, x, y), E::SpriteHp(b, old, new))
+        | (E::SpriteMaxHp(a, x, y), E::SpriteMaxHp(b, old, new)) => a == b && x == new && y == old,
+        (E::SpriteHpDelta(a, x), E::SpriteHpDelta(b, delta)) => a == b && *x == -delta,
+        (E::SpriteLayer(a, x, y), E::SpriteLayer(b, old, new)) => a == b && x == new && y == old,
+        (E::SpriteMove(a, x, y), E::SpriteMove(b, from, to)) => a == b && *y == *x - (*to - *from),
+        (E::SpriteName(a, x, y), E::SpriteName(b, old, new)) => a == b && x == new && y == old,
+        (E::SpriteOwners(a, x, y), E::SpriteOwners(b, old, new)) => a == b && x == new && y == old,
+        (E::SpriteRemove(a), E::SpriteRestore(b)) | (E::SpriteRestore(a), E::SpriteRemove(b)) => {
+            a == b
+        }
+        (E::SpriteRemove(a), E::SpriteNew(s, _)) => *a == s.id,
+        (E::SpriteShape(a, x, y), E::SpriteShape(b, old, new)) => a == b && x == new && y == old,
+        (E::SpriteTempHp(a, x, y), E::SpriteTempHp(b, old, new)) => a == b && x == new && y == old,
+        (E::SpriteVision(a, x, y), E::SpriteVision(b, old, new)) => a == b && x == new && y == old,
+        (E::SpriteVisual(a, x, y), E::SpriteVisual(b, old, new)) => a == b && x == new && y == old,
+        (E::SpriteZ(a, x, y), E::SpriteZ(b, old, new)) => a == b && x == new && y == old,
+        (E::TemplateMove(a, x, y), E::TemplateMove(b, from, to)) => a == b && x == to && y == from,
+        (E::TemplateRemove(a), E::TemplateRestore(b))
+        | (E::TemplateRestore(a), E::TemplateRemove(b)) => a == b,
+        (E::TemplateRemove(a), E::TemplateNew(t, _)) => *a == t.id,
+        (E::TemplateRotate(a, x, y), E::TemplateRotate(b, old, new)) => {
+            a == b && x == new && y == old
+        }
+        (E::TemplateShape(a, x, y), E::TemplateShape(b, old, new)) => {
+            a == b && x == new && y == old
+        }
+        _ => false,
+    }
+}
+
+// Whether a remote change supersedes a local one, such that unwinding the
+// local change would revert it.
+fn overrides(remote: &SceneEvent, local: &SceneEvent) -> bool {
+    if remote.item().is_none() || remote.item() != local.item() {
+        return false;
+    }
+
+    match (remote, local) {
+        (
+            SceneEvent::LayerRemove(..)
+            | SceneEvent::SpriteRemove(..)
+            | SceneEvent::TemplateRemove(..),
+            _,
+        ) => true,
+
+        // Moves and HP changes are unwound relative to the current state, so
+        // remain valid after other changes.
+        (_, SceneEvent::SpriteMove(..) | SceneEvent::SpriteHpDelta(..)) => false,
+        (SceneEvent::SpriteHpDelta(..), SceneEvent::SpriteHp(..)) => false,
+
+        (SceneEvent::SpriteCondition(_, a, _), SceneEvent::SpriteCondition(_, b, _)) => a == b,
+        (SceneEvent::SpriteField(_, a, ..), SceneEvent::SpriteField(_, b, ..)) => a == b,
+        (remote, local) => discriminant(remote) == discriminant(local),
+    }
+}
+
+// Adjust a local change to apply on top of a remote one, returning true if
+// the local change is superseded and should be dropped.
+fn rebase(local: &mut SceneEvent, remote: &SceneEvent) -> bool {
+    match local {
+        SceneEvent::Dummy => false,
+        SceneEvent::EventSet(events) => {
+            events.retain_mut(|e| !rebase(e, remote));
+            events.is_empty()
+        }
+        SceneEvent::SpriteHp(id, old, new) => match remote {
+            SceneEvent::SpriteHpDelta(remote_id, delta) if remote_id == id => {
+                *old = old.map(|hp| hp + delta);
+                *new = new.map(|hp| hp + delta);
+                false
+            }
+            _ => overrides(remote, local),
+        },
+        _ => overrides(remote, local),
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{Id, Rect, ScenePoint};
+
+/// Uniform grid over the sprites of a layer, used to find the sprites near a
+/// point or region without checking every sprite. Candidates returned may not
+/// overlap the query, so must be checked against the sprites themselves.
+#[derive(Clone, Debug, Default)]
+pub struct SpatialIndex {
+    // Sprites overlapping each cell.
+    cells: HashMap<(i32, i32), Vec<Id>>,
+
+    // Rect each sprite was indexed with.
+    rects: HashMap<Id, Rect>,
+
+    // Sprites covering too many cells to index, which are always candidates.
+    large: HashSet<Id>,
+}
+
+impl SpatialIndex {
+    // Width and height of a cell, in tiles.
+    const CELL_SIZE: f32 = 4.0;
+
+    // Rects covering more cells than this aren't indexed by cell.
+    const MAX_CELLS: f32 = 256.0;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn cell(x: f32, y: f32) -> (i32, i32) {
+        (
+            (x / Self::CELL_SIZE).floor() as i32,
+            (y / Self::CELL_SIZE).floor() as i32,
+        )
+    }
+
+    // Cells overlapped by a rect, or None if there are too many.
+    fn cells(rect: Rect) -> Option<impl Iterator<Item = (i32, i32)>> {
+        let rect = rect.positive_dimensions();
+        let n =
+            ((rect.w / Self::CELL_SIZE).ceil() + 1.0) * ((rect.h / Self::CELL_SIZE).ceil() + 1.0);
+        if !n.is_finite() || n > Self::MAX_CELLS {
+            return None;
+        }
+
+        let (x0, y0) = Self::cell(rect.x, rect.y);
+        let (x1, y1) = Self::cell(rect.x + rect.w, rect.y + rect.h);
+        Some((x0..=x1).flat_map(move |x| (y0..=y1).map(move |y| (x, y))))
+    }
+
+    pub fn insert(&mut self, id: Id, rect: Rect) {
+        self.remove(id);
+        self.rects.insert(id, rect);
+        match Self::cells(rect) {
+            Some(cells) => cells.for_each(|cell| self.cells.entry(cell).or_default().push(id)),
+            None => {
+                self.large.insert(id);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: Id) {
+        let rect = match self.rects.remove(&id) {
+            Some(rect) => rect,
+            None => return,
+        };
+
+        if self.large.remove(&id) {
+            return;
+        }
+
+        for cell in Self::cells(rect).into_iter().flatten() {
+            if let Some(ids) = self.cells.get_mut(&cell) {
+                ids.retain(|&i| i != id);
+                if ids.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    // Reindex a sprite if it has moved since it was indexed.
+    pub fn update(&mut self, id: Id, rect: Rect) {
+        if self.rects.get(&id) != Some(&rect) {
+            self.insert(id, rect);
+        }
+    }
+
+    pub fn at(&self, at: ScenePoint) -> impl Iterator<Item = Id> + '_ {
+        self.cells
+            .get(&Self::cell(at.x, at.y))
+            .into_iter()
+            .flatten()
+            .chain(self.large.iter())
+            .copied()
+    }
+
+    // Sprites which may overlap this region. When the region covers more
+    // cells than are occupied, the occupied cells are checked instead.
+    pub fn in_region(&self, region: Rect) -> HashSet<Id> {
+        let region = region.positive_dimensions();
+        let (x0, y0) = Self::cell(region.x, region.y);
+        let (x1, y1) = Self::cell(region.x + region.w, region.y + region.h);
+        let area = (x1 as i64 - x0 as i64 + 1) * (y1 as i64 - y0 as i64 + 1);
+
+        let mut ids = self.large.clone();
+        if area > self.cells.len() as i64 {
+            for ((x, y), cell) in &self.cells {
+                if (x0..=x1).contains(x) && (y0..=y1).contains(y) {
+                    ids.extend(cell);
+                }
+            }
+        } else {
+            for x in x0..=x1 {
+                for y in y0..=y1 {
+                    if let Some(cell) = self.cells.get(&(x, y)) {
+                        ids.extend(cell);
+                    }
+                }
+            }
+        }
+        ids
+    }
+}
@@ -0,0 +1,265 @@
+use serde_derive::{Deserialize, Serialize};
+
+use super::{comms::SceneEvent, Id};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Combatant {
+    pub id: Id,
+    pub name: String,
+
+    // Token representing this combatant, if any.
+    pub sprite: Option<Id>,
+
+    // User who controls this combatant and may set its initiative.
+    pub owner: Option<Id>,
+
+    // Initiative score, if it has been rolled.
+    pub initiative: Option<i32>,
+}
+
+impl Combatant {
+    // Shown in place of the name of a combatant whose token a player can't
+    // see.
+    pub const HIDDEN_NAME: &'static str = "Unknown";
+
+    pub fn new(id: Id, name: String, sprite: Option<Id>, owner: Option<Id>) -> Self {
+        Combatant {
+            id,
+            name,
+            sprite,
+            owner,
+            initiative: None,
+        }
+    }
+
+    // This combatant as shown to a player who can't see its token, so that
+    // neither its name nor its token are given away.
+    pub fn redacted(&self) -> Combatant {
+        Combatant {
+            name: Self::HIDDEN_NAME.to_string(),
+            sprite: None,
+            ..self.clone()
+        }
+    }
+
+    // Whether this combatant acts after a combatant with this initiative.
+    // Combatants without an initiative act last.
+    fn after(&self, initiative: Option<i32>) -> bool {
+        match (self.initiative, initiative) {
+            (Some(a), Some(b)) => a < b,
+            (None, Some(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Turn order of an encounter.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Initiative {
+    // Combatants in turn order.
+    pub combatants: Vec<Combatant>,
+
+    // Index of the combatant whose turn it is.
+    pub turn: usize,
+
+    // Current round, or 0 if combat hasn't started.
+    pub round: u32,
+}
+
+impl Initiative {
+    pub fn combatant(&self, id: Id) -> Option<&Combatant> {
+        self.combatants.iter().find(|c| c.id == id)
+    }
+
+    pub fn position(&self, id: Id) -> Option<usize> {
+        self.combatants.iter().position(|c| c.id == id)
+    }
+
+    // The combatant whose turn it is, if combat has started.
+    pub fn current(&self) -> Option<&Combatant> {
+        if self.round > 0 {
+            self.combatants.get(self.turn)
+        } else {
+            None
+        }
+    }
+
+    // Position at which a combatant with this initiative should be placed,
+    // after any combatants it ties with.
+    pub fn sorted_position(&self, initiative: Option<i32>) -> usize {
+        self.combatants
+            .iter()
+            .position(|c| c.after(initiative))
+            .unwrap_or(self.combatants.len())
+    }
+
+    pub fn add(&mut self, combatant: Combatant, position: usize) -> Option<SceneEvent> {
+        if self.combatant(combatant.id).is_some() {
+            return None;
+        }
+
+        // Combatants added ahead of the current turn will act next round.
+        let position = position.min(self.combatants.len());
+        if self.round > 0 && position <= self.turn && self.turn < self.combatants.len() {
+            self.turn += 1;
+        }
+        self.combatants.insert(position, combatant.clone());
+        Some(SceneEvent::InitiativeAdd(combatant, position))
+    }
+
+    // If the current combatant is removed, it becomes the turn of the next,
+    // which is the first combatant of the next round if it was the last. The
+    // event records the round and turn from before the removal so that
+    // undoing it gives the turn back.
+    pub fn remove(&mut self, id: Id) -> Option<SceneEvent> {
+        let position = self.position(id)?;
+        let event = SceneEvent::InitiativeRemove(
+            self.combatants[position].clone(),
+            position,
+            self.round,
+            self.turn,
+        );
+        (self.round, self.turn) =
+            Self::turn_after_removal(self.round, self.turn, position, self.combatants.len());
+        self.combatants.remove(position);
+        Some(event)
+    }
+
+    // Put back a removed combatant. If the turn hasn't changed since the
+    // removal, it is returned to where it was, otherwise the combatant is
+    // added as by add.
+    pub fn restore(
+        &mut self,
+        combatant: Combatant,
+        position: usize,
+        round: u32,
+        turn: usize,
+    ) -> Option<SceneEvent> {
+        if self.combatant(combatant.id).is_some() {
+            return None;
+        }
+
+        let position = position.min(self.combatants.len());
+        let removed = Self::turn_after_removal(round, turn, position, self.combatants.len() + 1);
+        let event = SceneEvent::InitiativeRestore(combatant.clone(), position, round, turn);
+        if self.is_at(removed.0, removed.1) {
+            self.combatants.insert(position, combatant);
+            self.round = round;
+            self.turn = turn;
+        } else {
+            self.add(combatant, position);
+        }
+        Some(event)
+    }
+
+    // The round and turn after removing the combatant at this position from a
+    // list of this length.
+    fn turn_after_removal(round: u32, turn: usize, position: usize, len: usize) -> (u32, usize) {
+        if position < turn {
+            (round, turn - 1)
+        } else if round > 0 && position == turn && position + 1 == len && len > 1 {
+            (round + 1, 0)
+        } else {
+            (round, turn)
+        }
+    }
+
+    // Replace the details of a combatant, keeping its place in the order.
+    pub fn replace(&mut self, combatant: Combatant) -> Option<SceneEvent> {
+        let position = self.position(combatant.id)?;
+        let old = std::mem::replace(&mut self.combatants[position], combatant.clone());
+        Some(SceneEvent::InitiativeCombatant(old, combatant))
+    }
+
+    // Move a combatant to a new position in the turn order, leaving the turn
+    // with the current combatant.
+    pub fn move_to(&mut self, id: Id, position: usize) -> Option<SceneEvent> {
+        let from = self.position(id)?;
+        let to = position.min(self.combatants.len() - 1);
+        if from == to {
+            return None;
+        }
+
+        self.reposition(from, to);
+        Some(SceneEvent::InitiativeMove(id, from, to))
+    }
+
+    fn reposition(&mut self, from: usize, to: usize) {
+        let current = self.combatants.get(self.turn).map(|c| c.id);
+        let combatant = self.combatants.remove(from);
+        self.combatants.insert(to, combatant);
+        if let Some(pos) = current.and_then(|id| self.position(id)) {
+            self.turn = pos;
+        }
+    }
+
+    // Set the initiative of a combatant, moving it to its place in the order.
+    pub fn set_initiative(&mut self, id: Id, initiative: Option<i32>) -> Option<SceneEvent> {
+        let from = self.position(id)?;
+        let old = self.combatants[from].initiative;
+        if old == initiative {
+            return None;
+        }
+
+        self.combatants[from].initiative = initiative;
+        let to = self
+            .combatants
+            .iter()
+            .enumerate()
+            .position(|(i, c)| i != from && c.after(initiative))
+            .unwrap_or(self.combatants.len());
+
+        // Positions after this combatant shift down once it is taken out.
+        let to = if to > from { to - 1 } else { to };
+        self.reposition(from, to);
+        Some(SceneEvent::InitiativeSet(id, old, initiative))
+    }
+
+    pub fn advance(&mut self) -> Option<SceneEvent> {
+        if self.combatants.is_empty() {
+            return None;
+        }
+
+        let event = SceneEvent::InitiativeAdvance(self.round, self.turn);
+        (self.round, self.turn) = self.next_turn(self.round, self.turn);
+        Some(event)
+    }
+
+    // Step back a turn. Going back from the first turn of combat ends it.
+    pub fn back(&mut self) -> Option<SceneEvent> {
+        if self.round == 0 {
+            return None;
+        }
+
+        let event = SceneEvent::InitiativeBack(self.round, self.turn);
+        (self.round, self.turn) = self.previous_turn(self.round, self.turn);
+        Some(event)
+    }
+
+    // The round and turn which advancing from this round and turn leads to.
+    pub fn next_turn(&self, round: u32, turn: usize) -> (u32, usize) {
+        if round == 0 {
+            (1, 0)
+        } else if turn + 1 >= self.combatants.len() {
+            (round + 1, 0)
+        } else {
+            (round, turn + 1)
+        }
+    }
+
+    // The round and turn which stepping back from this round and turn leads
+    // to.
+    pub fn previous_turn(&self, round: u32, turn: usize) -> (u32, usize) {
+        if turn > 0 {
+            (round, turn.min(self.combatants.len()) - 1)
+        } else if round <= 1 {
+            (0, 0)
+        } else {
+            (round - 1, self.combatants.len().saturating_sub(1))
+        }
+    }
+
+    pub fn is_at(&self, round: u32, turn: usize) -> bool {
+        self.round == round && self.turn == turn
+    }
+}
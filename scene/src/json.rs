@@ -0,0 +1,237 @@
+use std::collections::HashSet;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{BlendMode, Grid, Id, Initiative, Layer, Scene, Sprite, Template, TemplateShape, Wall};
+
+// Version of the JSON representation written by to_json. When the
+// representation changes, this is incremented and an upgrade from the
+// previous version is appended to UPGRADES.
+pub const VERSION: u64 = 1;
+
+// Upgrades from each version to the next, such that UPGRADES[0] converts a
+// version 1 document to version 2. Each operates on the untyped document so
+// that old representations needn't be kept around as types.
+const UPGRADES: [Upgrade; VERSION as usize - 1] = [];
+
+type Upgrade = fn(&mut Value) -> Result<(), String>;
+
+// Largest width or height, in tiles, accepted for an imported scene.
+pub(crate) const MAX_DIMENSION: u32 = 1000;
+
+/// Human-readable representation of a scene, for import and export. Server
+/// details, such as the project the scene belongs to, and removed items are
+/// left out.
+#[derive(Deserialize, Serialize)]
+struct SceneJson {
+    version: u64,
+    #[serde(default)]
+    title: Option<String>,
+    width: u32,
+    height: u32,
+    #[serde(default)]
+    fog_of_war: bool,
+    #[serde(default)]
+    grid: Grid,
+    #[serde(default)]
+    walls: Vec<Wall>,
+    #[serde(default)]
+    initiative: Initiative,
+    layers: Vec<LayerJson>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LayerJson {
+    id: Id,
+    title: String,
+    z: i32,
+    #[serde(default = "default_true")]
+    visible: bool,
+    #[serde(default)]
+    locked: bool,
+    #[serde(default = "default_opacity")]
+    opacity: f32,
+    #[serde(default)]
+    blend: BlendMode,
+    #[serde(default)]
+    sprites: Vec<Sprite>,
+    #[serde(default)]
+    templates: Vec<Template>,
+}
+
+impl SceneJson {
+    // Reject documents that deserialise but couldn't have been exported by
+    // to_json, so that a bad import can't leave a scene that can't be drawn
+    // or addressed.
+    fn validate(&self) -> Result<(), String> {
+        let valid_dimension = |d: u32| d > 0 && d <= MAX_DIMENSION;
+        if !valid_dimension(self.width) || !valid_dimension(self.height) {
+            return Err(format!(
+                "Scene dimensions must be between 1 and {MAX_DIMENSION}."
+            ));
+        }
+
+        if !self.grid.is_valid() {
+            return Err("Invalid grid.".to_string());
+        }
+
+        let finite = |vals: &[f32]| vals.iter().all(|v| v.is_finite());
+        if !self
+            .walls
+            .iter()
+            .all(|w| finite(&[w.from.x, w.from.y, w.to.x, w.to.y]))
+        {
+            return Err("Invalid wall.".to_string());
+        }
+
+        let mut layers = HashSet::new();
+        let mut sprites = HashSet::new();
+        for layer in &self.layers {
+            if !layers.insert(layer.id) {
+                return Err(format!("Duplicate layer ID: {}.", layer.id));
+            }
+
+            for sprite in &layer.sprites {
+                if !sprites.insert(sprite.id) {
+                    return Err(format!("Duplicate sprite ID: {}.", sprite.id));
+                }
+
+                let r = sprite.rect;
+                if !finite(&[r.x, r.y, r.w, r.h]) {
+                    return Err(format!("Invalid sprite position: {}.", sprite.id));
+                }
+
+                if !sprite.vision.iter().all(|v| v.is_finite()) {
+                    return Err(format!("Invalid sprite vision: {}.", sprite.id));
+                }
+
+                if !sprite.token.is_valid() {
+                    return Err(format!("Invalid token details: {}.", sprite.id));
+                }
+            }
+        }
+
+        // Templates share IDs with sprites, as both are addressed by events
+        // on items.
+        let mut templates = HashSet::new();
+        for template in self.layers.iter().flat_map(|l| &l.templates) {
+            if sprites.contains(&template.id) || !templates.insert(template.id) {
+                return Err(format!("Duplicate template ID: {}.", template.id));
+            }
+
+            let sizes = match template.shape {
+                TemplateShape::Circle { radius } => vec![radius],
+                TemplateShape::Cone { length, angle } => vec![length, angle],
+                TemplateShape::Cube { size } => vec![size],
+                TemplateShape::Line { length, width } => vec![length, width],
+            };
+            let o = template.origin;
+            if !finite(&[o.x, o.y, template.rotation])
+                || !finite(&template.colour)
+                || !sizes.iter().all(|s| s.is_finite() && *s >= 0.0)
+            {
+                return Err(format!("Invalid template: {}.", template.id));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_opacity() -> f32 {
+    1.0
+}
+
+impl From<&Layer> for LayerJson {
+    fn from(layer: &Layer) -> Self {
+        LayerJson {
+            id: layer.id,
+            title: layer.title.clone(),
+            z: layer.z,
+            visible: layer.visible,
+            locked: layer.locked,
+            opacity: layer.opacity,
+            blend: layer.blend,
+            sprites: layer.sprites.clone(),
+            templates: layer.templates.clone(),
+        }
+    }
+}
+
+impl From<LayerJson> for Layer {
+    fn from(json: LayerJson) -> Self {
+        let mut layer = Layer::new(json.id, &json.title, json.z);
+        layer.visible = json.visible;
+        layer.locked = json.locked;
+        layer.set_opacity(json.opacity);
+        layer.blend = json.blend;
+        json.sprites.into_iter().for_each(|s| {
+            layer.add_sprite(s);
+        });
+        json.templates.into_iter().for_each(|t| {
+            layer.add_template(t);
+        });
+        layer
+    }
+}
+
+impl Scene {
+    pub fn to_json(&self) -> String {
+        let json = SceneJson {
+            version: VERSION,
+            title: self.title.clone(),
+            width: self.w,
+            height: self.h,
+            fog_of_war: self.fog_of_war,
+            grid: self.grid,
+            walls: self.walls.clone(),
+            initiative: self.initiative.clone(),
+            layers: self.layers.iter().map(LayerJson::from).collect(),
+        };
+
+        // Safe to unwrap as these types have only string keys.
+        serde_json::to_string_pretty(&json).unwrap()
+    }
+
+    // Parse a scene exported by to_json, upgrading it from older versions.
+    // The scene returned has no ID or project.
+    pub fn from_json(json: &str) -> Result<Scene, String> {
+        let mut value: Value =
+            serde_json::from_str(json).map_err(|e| format!("Invalid JSON: {e}"))?;
+
+        let version = value
+            .get("version")
+            .and_then(Value::as_u64)
+            .ok_or("Missing scene version.")?;
+        if version == 0 || version > VERSION {
+            return Err(format!("Unsupported scene version: {version}."));
+        }
+
+        for upgrade in &UPGRADES[version as usize - 1..] {
+            upgrade(&mut value)?;
+        }
+        value["version"] = VERSION.into();
+
+        let json: SceneJson =
+            serde_json::from_value(value).map_err(|e| format!("Invalid scene: {e}"))?;
+        json.validate()?;
+
+        let mut scene = Scene::new_with_layers(json.layers.into_iter().map(Layer::from).collect());
+        scene.title = json.title;
+        scene.w = json.width;
+        scene.h = json.height;
+        scene.fog_of_war = json.fog_of_war;
+        scene.grid = json.grid;
+        scene.walls = json.walls;
+        scene.initiative = json.initiative;
+
+        // Combatants may have IDs above those of any layer or sprite.
+        scene.minimise_next_id();
+        Ok(scene)
+    }
+}
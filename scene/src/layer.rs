@@ -1,8 +1,31 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
+
 use serde_derive::{Deserialize, Serialize};
 
-use crate::{comms::SceneEvent, Rect};
+use crate::{comms::SceneEvent, index::SpatialIndex, Rect, Tombstones};
+
+use super::{Id, ScenePoint, Sprite, Template};
+
+/// Ways to change the position of a sprite in its layer's stack.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ZOrder {
+    Raise,
+    Lower,
+    Front,
+    Back,
+}
 
-use super::{Id, ScenePoint, Sprite};
+/// How a layer is combined with those beneath it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Layer {
@@ -11,10 +34,28 @@ pub struct Layer {
     pub z: i32,
     pub visible: bool,
     pub locked: bool,
+    pub opacity: f32,
+    pub blend: BlendMode,
     pub sprites: Vec<Sprite>,
-    pub removed_sprites: Vec<Sprite>,
+    pub removed_sprites: Tombstones<Sprite>,
+    pub templates: Vec<Template>,
+    pub removed_templates: Tombstones<Template>,
     pub z_min: i32,
     pub z_max: i32,
+
+    // Position of each sprite in sprites, and where each sprite is in the
+    // scene. These aren't serialised, so are rebuilt when first needed.
+    #[serde(skip)]
+    positions: HashMap<Id, usize>,
+    #[serde(skip)]
+    index: SpatialIndex,
+    #[serde(skip)]
+    indexed: bool,
+
+    // Sprites which may have been moved through a mutable reference since
+    // they were last indexed.
+    #[serde(skip)]
+    moved: HashSet<Id>,
 }
 
 impl Layer {
@@ -25,10 +66,18 @@ impl Layer {
             z,
             visible: true,
             locked: false,
+            opacity: 1.0,
+            blend: BlendMode::Normal,
             sprites: vec![],
-            removed_sprites: vec![],
+            removed_sprites: Tombstones::new(),
+            templates: vec![],
+            removed_templates: Tombstones::new(),
             z_min: 0,
             z_max: 0,
+            positions: HashMap::new(),
+            index: SpatialIndex::new(),
+            indexed: true,
+            moved: HashSet::new(),
         }
     }
 
@@ -56,36 +105,186 @@ impl Layer {
         }
     }
 
+    // Opacity is clamped to [0, 1], while NaN and infinite values are
+    // refused.
+    pub fn set_opacity(&mut self, opacity: f32) -> Option<SceneEvent> {
+        if !opacity.is_finite() {
+            return None;
+        }
+
+        let opacity = opacity.clamp(0.0, 1.0);
+        if self.opacity != opacity {
+            let old = self.opacity;
+            self.opacity = opacity;
+            Some(SceneEvent::LayerOpacity(self.id, old, opacity))
+        } else {
+            None
+        }
+    }
+
+    pub fn set_blend(&mut self, blend: BlendMode) -> Option<SceneEvent> {
+        if self.blend != blend {
+            let old = self.blend;
+            self.blend = blend;
+            Some(SceneEvent::LayerBlend(self.id, old, blend))
+        } else {
+            None
+        }
+    }
+
     // Sprites can only be selected from a layer if it is both visible and
     // unlocked.
     pub fn selectable(&self) -> bool {
         self.visible && !self.locked
     }
 
+    fn position(&self, id: Id) -> Option<usize> {
+        if self.indexed {
+            self.positions.get(&id).copied()
+        } else {
+            self.sprites.iter().position(|s| s.id == id)
+        }
+    }
+
+    fn update_positions(&mut self) {
+        self.positions.clear();
+        self.update_positions_from(0);
+    }
+
+    fn update_positions_from(&mut self, start: usize) {
+        for (i, sprite) in self.sprites.iter().enumerate().skip(start) {
+            self.positions.insert(sprite.id, i);
+        }
+    }
+
+    // Bring the index up to date. Any references to sprites handed out have
+    // been dropped by now, so moved sprites are in their final positions.
+    fn update_index(&mut self) {
+        if !self.indexed {
+            self.update_positions();
+            self.index = SpatialIndex::new();
+            for sprite in &self.sprites {
+                self.index.insert(sprite.id, sprite.rect);
+            }
+            self.moved.clear();
+            self.indexed = true;
+        }
+
+        for id in self.moved.drain() {
+            if let Some(&i) = self.positions.get(&id) {
+                self.index.update(id, self.sprites[i].rect);
+            }
+        }
+    }
+
     pub fn sprite(&mut self, id: Id) -> Option<&mut Sprite> {
-        self.sprites.iter_mut().find(|s| s.id == id)
+        self.update_index();
+        let i = self.position(id)?;
+        self.moved.insert(id);
+        Some(&mut self.sprites[i])
     }
 
     pub fn sprite_ref(&self, id: Id) -> Option<&Sprite> {
-        self.sprites.iter().find(|s| s.id == id)
+        self.position(id).map(|i| &self.sprites[i])
+    }
+
+    fn update_z_bounds(&mut self) {
+        self.z_min = self.sprites.first().map(|s| s.z).unwrap_or(0);
+        self.z_max = self.sprites.last().map(|s| s.z).unwrap_or(0);
+    }
+
+    // Z value which places a sprite in front of all others on this layer.
+    pub fn top_z(&self) -> i32 {
+        if self.sprites.is_empty() {
+            0
+        } else {
+            self.z_max + 1
+        }
     }
 
-    fn sort_sprites(&mut self) {
-        self.sprites.sort_by(|a, b| a.z.cmp(&b.z));
+    // Number sprites from 0 at the back, so that the z of each sprite is its
+    // position in the stack.
+    fn renumber_z(&mut self) {
+        for (z, sprite) in self.sprites.iter_mut().enumerate() {
+            sprite.z = z as i32;
+        }
+        self.update_z_bounds();
+    }
+
+    // Position of a sprite in the stack, from 0 at the back.
+    pub fn sprite_z(&self, id: Id) -> Option<i32> {
+        self.position(id).map(|i| i as i32)
     }
 
-    fn update_z_bounds(&mut self, sprite: &Sprite) {
-        if sprite.z > self.z_max {
-            self.z_max = sprite.z;
-        } else if sprite.z < self.z_min {
-            self.z_min = sprite.z;
+    // The sprite directly beneath this one, or None if it is at the back.
+    pub fn sprite_below(&self, id: Id) -> Option<Option<Id>> {
+        let i = self.position(id)?;
+        Some(i.checked_sub(1).map(|j| self.sprites[j].id))
+    }
+
+    // Move a sprite to directly above another, or to the back if below is
+    // None, shifting those between. Events name the sprite beneath rather
+    // than a position so that they can be shared with players who can't see
+    // every sprite.
+    pub fn place_sprite(&mut self, id: Id, below: Option<Id>) -> Option<SceneEvent> {
+        let old = self.sprite_below(id)?;
+        if old == below || below == Some(id) {
+            return None;
+        }
+
+        let from = self.position(id)?;
+        let to = match below {
+            Some(below) => {
+                let i = self.position(below)?;
+                if i < from {
+                    i + 1
+                } else {
+                    i
+                }
+            }
+            None => 0,
+        };
+
+        let sprite = self.sprites.remove(from);
+        self.sprites.insert(to, sprite);
+        self.renumber_z();
+        self.update_positions_from(from.min(to));
+        Some(SceneEvent::SpriteZ(id, old, below))
+    }
+
+    // Move a sprite to this position in the stack.
+    pub fn set_sprite_z(&mut self, id: Id, z: i32) -> Option<SceneEvent> {
+        let old = self.sprite_z(id)?;
+        let new = z.clamp(0, self.sprites.len() as i32 - 1);
+        let below = match new.cmp(&old) {
+            Ordering::Equal => return None,
+            Ordering::Greater => Some(self.sprites[new as usize].id),
+            Ordering::Less if new == 0 => None,
+            Ordering::Less => Some(self.sprites[new as usize - 1].id),
+        };
+        self.place_sprite(id, below)
+    }
+
+    pub fn restack_sprite(&mut self, id: Id, order: ZOrder) -> Option<SceneEvent> {
+        let z = self.sprite_z(id)?;
+        match order {
+            ZOrder::Raise => self.set_sprite_z(id, z + 1),
+            ZOrder::Lower => self.set_sprite_z(id, z - 1),
+            ZOrder::Front => self.set_sprite_z(id, self.sprites.len() as i32 - 1),
+            ZOrder::Back => self.set_sprite_z(id, 0),
         }
     }
 
     pub fn add_sprite(&mut self, sprite: Sprite) -> SceneEvent {
-        self.update_z_bounds(&sprite);
-        self.sprites.push(sprite);
-        self.sort_sprites();
+        self.update_index();
+        self.index.insert(sprite.id, sprite.rect);
+
+        // Sprites are kept sorted by z, with new sprites above any others
+        // with the same z.
+        let i = self.sprites.partition_point(|s| s.z <= sprite.z);
+        self.sprites.insert(i, sprite.clone());
+        self.update_positions_from(i);
+        self.update_z_bounds();
         SceneEvent::SpriteNew(sprite, self.id)
     }
 
@@ -94,7 +293,7 @@ impl Layer {
     }
 
     pub fn restore_sprite(&mut self, id: Id) -> bool {
-        if let Some(s) = self.removed_sprites.drain_filter(|s| s.id == id).last() {
+        if let Some(s) = self.removed_sprites.take(|s| s.id == id) {
             self.add_sprite(s);
             true
         } else {
@@ -103,7 +302,22 @@ impl Layer {
     }
 
     pub fn take_sprite(&mut self, id: Id) -> Option<Sprite> {
-        self.sprites.drain_filter(|s| s.id == id).last()
+        self.update_index();
+        let i = self.position(id)?;
+        let sprite = self.sprites.remove(i);
+        self.positions.remove(&id);
+        self.update_positions_from(i);
+        self.index.remove(id);
+        self.update_z_bounds();
+        Some(sprite)
+    }
+
+    // Keep only the sprites for which the predicate holds.
+    pub fn retain_sprites(&mut self, f: impl FnMut(&Sprite) -> bool) {
+        self.sprites.retain(f);
+        self.indexed = false;
+        self.update_index();
+        self.update_z_bounds();
     }
 
     pub fn remove_sprite(&mut self, id: Id) -> Option<SceneEvent> {
@@ -115,36 +329,94 @@ impl Layer {
         }
     }
 
-    pub fn sprite_at(&mut self, at: ScenePoint) -> Option<&mut Sprite> {
-        // Reversing the iterator atm because the sprites are rendered from the
-        // front of the Vec to the back, hence the last Sprite in the Vec is
-        // rendered on top, and will be clicked first.
-        for sprite in self.sprites.iter_mut().rev() {
-            if sprite.rect.contains_point(at) {
-                return Some(sprite);
-            }
+    // Position of the topmost sprite at this point. Sprites are rendered
+    // from the front of the Vec to the back, hence the last Sprite in the Vec
+    // is rendered on top, and will be clicked first.
+    fn position_at(&self, at: ScenePoint, owner: Option<Id>) -> Option<usize> {
+        let hit = |&i: &usize| {
+            let sprite = &self.sprites[i];
+            sprite.rect.contains_point(at) && owner.iter().all(|&u| sprite.owned_by(u))
+        };
+
+        if !self.indexed {
+            return (0..self.sprites.len()).rev().find(hit);
         }
 
-        None
+        self.index
+            .at(at)
+            .chain(self.moved.iter().copied())
+            .filter_map(|id| self.positions.get(&id).copied())
+            .filter(hit)
+            .max()
     }
 
-    pub fn sprite_at_ref(&self, at: ScenePoint) -> Option<&Sprite> {
-        for sprite in self.sprites.iter().rev() {
-            if sprite.rect.contains_point(at) {
-                return Some(sprite);
-            }
-        }
+    // If owner is given, only sprites controlled by that user are considered.
+    pub fn sprite_at(&mut self, at: ScenePoint, owner: Option<Id>) -> Option<&mut Sprite> {
+        self.update_index();
+        let i = self.position_at(at, owner)?;
+        self.moved.insert(self.sprites[i].id);
+        Some(&mut self.sprites[i])
+    }
 
-        None
+    pub fn sprite_at_ref(&self, at: ScenePoint, owner: Option<Id>) -> Option<&Sprite> {
+        self.position_at(at, owner).map(|i| &self.sprites[i])
     }
 
     pub fn sprites_in(&self, region: Rect) -> Vec<Id> {
-        let mut ret = vec![];
-        for sprite in &self.sprites {
-            if region.contains_rect(sprite.rect) {
-                ret.push(sprite.id);
-            }
+        let mut positions = if self.indexed {
+            let mut ids = self.index.in_region(region);
+            ids.extend(&self.moved);
+            ids.into_iter()
+                .filter_map(|id| self.positions.get(&id).copied())
+                .collect()
+        } else {
+            (0..self.sprites.len()).collect::<Vec<usize>>()
+        };
+
+        positions.sort_unstable();
+        positions
+            .into_iter()
+            .map(|i| &self.sprites[i])
+            .filter(|s| region.contains_rect(s.rect))
+            .map(|s| s.id)
+            .collect()
+    }
+
+    // Prune removed sprites and templates which may no longer be restored,
+    // returning the number pruned.
+    pub fn compact(&mut self, now: u64) -> usize {
+        self.removed_sprites.compact(now) + self.removed_templates.compact(now)
+    }
+
+    pub fn template(&mut self, id: Id) -> Option<&mut Template> {
+        self.templates.iter_mut().find(|t| t.id == id)
+    }
+
+    pub fn template_ref(&self, id: Id) -> Option<&Template> {
+        self.templates.iter().find(|t| t.id == id)
+    }
+
+    pub fn add_template(&mut self, template: Template) -> SceneEvent {
+        self.templates.push(template);
+        SceneEvent::TemplateNew(template, self.id)
+    }
+
+    pub fn remove_template(&mut self, id: Id) -> Option<SceneEvent> {
+        let template = self.templates.drain_filter(|t| t.id == id).last()?;
+        self.removed_templates.push(template);
+        Some(SceneEvent::TemplateRemove(id))
+    }
+
+    pub fn restore_template(&mut self, id: Id) -> bool {
+        if let Some(t) = self.removed_templates.take(|t| t.id == id) {
+            self.add_template(t);
+            true
+        } else {
+            false
         }
-        ret
+    }
+
+    pub fn template_at(&self, at: ScenePoint) -> Option<&Template> {
+        self.templates.iter().rev().find(|t| t.contains_point(at))
     }
 }
@@ -2,21 +2,41 @@
 #![feature(drain_filter)]
 
 use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::ops::{Add, Sub};
 
 pub mod comms;
+pub mod dice;
 pub mod perms;
 
+mod grid;
+mod history;
+mod index;
+mod initiative;
+mod json;
 mod layer;
 mod rect;
 mod sprite;
+mod template;
+mod token;
+mod tombstone;
+mod uvtt;
+mod vision;
 
 #[cfg(test)]
 mod tests;
 
-pub use layer::Layer;
+pub use grid::{DistanceRule, DistanceUnit, Grid, GridType};
+pub use history::History;
+pub use initiative::{Combatant, Initiative};
+pub use layer::{BlendMode, Layer, ZOrder};
 pub use rect::{Dimension, Rect};
-pub use sprite::{Sprite, SpriteShape, SpriteVisual};
+pub use sprite::{Colour, Sprite, SpriteShape, SpriteVisual};
+pub use template::{Template, TemplateShape};
+pub use token::Token;
+pub use tombstone::Tombstones;
+pub use uvtt::UvttMap;
+pub use vision::Wall;
 
 use comms::SceneEvent;
 
@@ -72,11 +92,21 @@ pub struct Scene {
     next_id: Id,
     pub id: Option<Id>,
     pub layers: Vec<Layer>,
-    pub removed_layers: Vec<Layer>,
+    pub removed_layers: Tombstones<Layer>,
     pub title: Option<String>,
     pub project: Option<Id>,
     pub w: u32,
     pub h: u32,
+    pub walls: Vec<Wall>,
+    pub fog_of_war: bool,
+    pub grid: Grid,
+    pub initiative: Initiative,
+
+    // Layer each sprite is on. This isn't serialised and layers may be
+    // changed directly, so entries are checked against the layer when used
+    // and sprites not found through it are searched for.
+    #[serde(skip)]
+    sprite_layers: HashMap<Id, Id>,
 }
 
 impl Scene {
@@ -103,6 +133,12 @@ impl Scene {
             for s in &l.sprites {
                 max_id = max_id.max(s.id);
             }
+            for t in &l.templates {
+                max_id = max_id.max(t.id);
+            }
+        }
+        for c in &self.initiative.combatants {
+            max_id = max_id.max(c.id);
         }
         self.next_id = max_id + 1;
     }
@@ -114,9 +150,28 @@ impl Scene {
         };
         scene.minimise_next_id();
         scene.sort_layers();
+        scene.index_sprite_layers();
         scene
     }
 
+    fn index_sprite_layers(&mut self) {
+        self.sprite_layers = self
+            .layers
+            .iter()
+            .flat_map(|l| l.sprites.iter().map(move |s| (s.id, l.id)))
+            .collect();
+    }
+
+    // Position in layers of the layer this sprite is on.
+    fn sprite_layer_position(&self, id: Id) -> Option<usize> {
+        let indexed = self.sprite_layers.get(&id).and_then(|&layer| {
+            self.layers
+                .iter()
+                .position(|l| l.id == layer && l.sprite_ref(id).is_some())
+        });
+        indexed.or_else(|| self.layers.iter().position(|l| l.sprite_ref(id).is_some()))
+    }
+
     pub fn canon(&mut self) {
         self.canon = true;
     }
@@ -140,6 +195,8 @@ impl Scene {
     pub fn add_layer(&mut self, layer: Layer) -> Option<SceneEvent> {
         let id = layer.id;
         if self.layer(id).is_none() {
+            self.sprite_layers
+                .extend(layer.sprites.iter().map(|s| (s.id, id)));
             self.layers.push(layer);
             self.sort_layers();
 
@@ -158,13 +215,14 @@ impl Scene {
 
     pub fn remove_layer(&mut self, layer: Id) -> Option<SceneEvent> {
         let removed = self.layers.drain_filter(|l| l.id == layer).last()?;
+        self.sprite_layers.retain(|_, l| *l != layer);
         let event = SceneEvent::LayerRemove(removed.id);
         self.removed_layers.push(removed);
         Some(event)
     }
 
     fn restore_layer(&mut self, layer: Id) -> Option<SceneEvent> {
-        let l = self.removed_layers.drain_filter(|l| l.id == layer).last()?;
+        let l = self.removed_layers.take(|l| l.id == layer)?;
         self.add_layer(l);
         Some(SceneEvent::LayerRestore(layer))
     }
@@ -258,35 +316,26 @@ impl Scene {
     }
 
     pub fn sprite(&mut self, id: Id) -> Option<&mut Sprite> {
-        for layer in self.layers.iter_mut() {
-            let s_opt = layer.sprite(id);
-            if s_opt.is_some() {
-                return s_opt;
-            }
-        }
-
-        None
+        let i = self.sprite_layer_position(id)?;
+        self.sprite_layers.insert(id, self.layers[i].id);
+        self.layers[i].sprite(id)
     }
 
     pub fn sprite_ref(&self, id: Id) -> Option<&Sprite> {
-        for layer in self.layers.iter() {
-            let s_opt = layer.sprite_ref(id);
-            if s_opt.is_some() {
-                return s_opt;
-            }
-        }
-
-        None
+        let i = self.sprite_layer_position(id)?;
+        self.layers[i].sprite_ref(id)
     }
 
-    pub fn sprite_at(&mut self, at: ScenePoint) -> Option<&mut Sprite> {
+    pub fn sprite_at(&mut self, at: ScenePoint, user: Id) -> Option<&mut Sprite> {
         for layer in self.layers.iter_mut() {
-            // Sprites on locked or invisible layers cannot be grabbed.
-            if layer.locked || !layer.visible {
+            // Sprites on invisible layers cannot be grabbed, nor can sprites
+            // on locked layers unless they are this user's tokens.
+            if !layer.visible {
                 continue;
             }
 
-            let s_opt = layer.sprite_at(at);
+            let owner = if layer.locked { Some(user) } else { None };
+            let s_opt = layer.sprite_at(at, owner);
             if s_opt.is_some() {
                 return s_opt;
             }
@@ -295,12 +344,13 @@ impl Scene {
         None
     }
 
-    pub fn sprite_at_ref(&self, at: ScenePoint) -> Option<&Sprite> {
+    pub fn sprite_at_ref(&self, at: ScenePoint, user: Id) -> Option<&Sprite> {
         for layer in &self.layers {
-            if layer.locked || !layer.visible {
+            if !layer.visible {
                 continue;
             }
-            let s_opt = layer.sprite_at_ref(at);
+            let owner = if layer.locked { Some(user) } else { None };
+            let s_opt = layer.sprite_at_ref(at, owner);
             if s_opt.is_some() {
                 return s_opt;
             }
@@ -322,16 +372,20 @@ impl Scene {
     }
 
     pub fn add_sprite(&mut self, sprite: Sprite, layer: Id) -> Option<SceneEvent> {
-        self.layer(layer).map(|l| l.add_sprite(sprite))
+        let id = sprite.id;
+        let event = self.layer(layer).map(|l| l.add_sprite(sprite))?;
+        self.sprite_layers.insert(id, layer);
+        Some(event)
     }
 
     pub fn clone_sprite(&mut self, sprite: Id) -> Option<SceneEvent> {
         let l = self.get_sprite_layer(sprite)?;
         let s = self.sprite_ref(sprite)?;
-        let mut new = *s;
+        let mut new = s.clone();
         new.rect.x += new.rect.w;
         new.rect.y += new.rect.h;
         new.id = self.next_id();
+        new.z = self.layer_ref(l)?.top_z();
         self.add_sprite(new, l)
     }
 
@@ -342,21 +396,76 @@ impl Scene {
         layer: Id,
     ) -> Option<SceneEvent> {
         let id = self.next_id();
-        self.add_sprite(Sprite::new(id, visual, shape), layer)
+        let mut sprite = Sprite::new(id, visual, shape);
+        sprite.z = self.layer_ref(layer)?.top_z();
+        self.add_sprite(sprite, layer)
     }
 
-    pub fn add_sprites(&mut self, sprites: Vec<Sprite>, layer: Id) -> Option<SceneEvent> {
-        self.layer(layer).map(|l| l.add_sprites(sprites))
+    pub fn restack_sprite(&mut self, id: Id, order: ZOrder) -> Option<SceneEvent> {
+        let layer = self.get_sprite_layer(id)?;
+        self.layer(layer)?.restack_sprite(id, order)
     }
 
-    pub fn remove_sprite(&mut self, id: Id) -> Option<SceneEvent> {
-        for layer in &mut self.layers {
-            let opt = layer.remove_sprite(id);
-            if opt.is_some() {
-                return opt;
+    // Restack a selection of sprites, keeping their order relative to each
+    // other. Sprites are moved starting with the one nearest the destination
+    // so that they don't pass one another.
+    pub fn restack_sprites(&mut self, ids: &[Id], order: ZOrder) -> Option<SceneEvent> {
+        let mut sprites = vec![];
+        for &id in ids {
+            if let Some(layer) = self.get_sprite_layer(id) {
+                if let Some(z) = self.layer_ref(layer).and_then(|l| l.sprite_z(id)) {
+                    sprites.push((layer, z, id));
+                }
             }
         }
-        None
+        sprites.sort_unstable();
+        if matches!(order, ZOrder::Raise | ZOrder::Back) {
+            sprites.reverse();
+        }
+
+        let mut events = vec![];
+        let mut bound = None;
+        for (layer, z, id) in sprites {
+            let l = match self.layer(layer) {
+                Some(l) => l,
+                None => continue,
+            };
+
+            // Sprites stepping up or down stop when they reach the last sprite
+            // moved, so that the selection doesn't reorder at the end of the
+            // stack.
+            let event = match (order, bound) {
+                (ZOrder::Raise, Some((b, limit))) if b == layer => {
+                    l.set_sprite_z(id, (z + 1).min(limit - 1))
+                }
+                (ZOrder::Lower, Some((b, limit))) if b == layer => {
+                    l.set_sprite_z(id, (z - 1).max(limit + 1))
+                }
+                _ => l.restack_sprite(id, order),
+            };
+            events.extend(event);
+            bound = l.sprite_z(id).map(|z| (layer, z));
+        }
+
+        if events.len() > 1 {
+            Some(SceneEvent::EventSet(events))
+        } else {
+            events.pop()
+        }
+    }
+
+    pub fn add_sprites(&mut self, sprites: Vec<Sprite>, layer: Id) -> Option<SceneEvent> {
+        let ids = sprites.iter().map(|s| s.id).collect::<Vec<Id>>();
+        let event = self.layer(layer).map(|l| l.add_sprites(sprites))?;
+        self.sprite_layers
+            .extend(ids.into_iter().map(|id| (id, layer)));
+        Some(event)
+    }
+
+    pub fn remove_sprite(&mut self, id: Id) -> Option<SceneEvent> {
+        let i = self.sprite_layer_position(id)?;
+        self.sprite_layers.remove(&id);
+        self.layers[i].remove_sprite(id)
     }
 
     pub fn remove_sprites(&mut self, ids: &[Id]) -> SceneEvent {
@@ -370,6 +479,7 @@ impl Scene {
     fn restore_sprite(&mut self, sprite: Id) -> Option<SceneEvent> {
         for layer in &mut self.layers {
             if layer.restore_sprite(sprite) {
+                self.sprite_layers.insert(sprite, layer.id);
                 return Some(SceneEvent::SpriteRestore(sprite));
             }
         }
@@ -377,19 +487,14 @@ impl Scene {
     }
 
     pub fn sprite_layer(&mut self, sprite: Id, layer: Id) -> Option<SceneEvent> {
-        let mut s = None;
-        let mut from_id = None;
-        for l in &mut self.layers {
-            s = l.take_sprite(sprite);
-            if s.is_some() {
-                from_id = Some(l.id);
-                break;
-            }
-        }
+        let i = self.sprite_layer_position(sprite)?;
+        let from_id = self.layers[i].id;
+        let sprite = self.layers[i].take_sprite(sprite)?;
+        self.sprite_layers.remove(&sprite.id);
 
-        let sprite = s?;
+        let id = sprite.id;
         if let Some(SceneEvent::SpriteNew(_, new_layer)) = self.add_sprite(sprite, layer) {
-            Some(SceneEvent::SpriteLayer(sprite.id, from_id?, new_layer))
+            Some(SceneEvent::SpriteLayer(id, from_id, new_layer))
         } else {
             None
         }
@@ -405,22 +510,262 @@ impl Scene {
     }
 
     fn get_sprite_layer(&self, sprite: Id) -> Option<Id> {
+        self.sprite_layer_position(sprite)
+            .map(|i| self.layers[i].id)
+    }
+
+    pub fn template(&mut self, id: Id) -> Option<&mut Template> {
+        self.layers.iter_mut().find_map(|l| l.template(id))
+    }
+
+    pub fn template_ref(&self, id: Id) -> Option<&Template> {
+        self.layers.iter().find_map(|l| l.template_ref(id))
+    }
+
+    pub fn template_at(&self, at: ScenePoint) -> Option<&Template> {
+        self.layers
+            .iter()
+            .filter(|l| l.selectable())
+            .find_map(|l| l.template_at(at))
+    }
+
+    fn get_template_layer(&self, template: Id) -> Option<Id> {
         self.layers
             .iter()
-            .find(|l| l.sprite_ref(sprite).is_some())
+            .find(|l| l.template_ref(template).is_some())
             .map(|l| l.id)
     }
 
+    pub fn add_template(&mut self, template: Template, layer: Id) -> Option<SceneEvent> {
+        self.layer(layer).map(|l| l.add_template(template))
+    }
+
+    pub fn new_template(
+        &mut self,
+        origin: ScenePoint,
+        shape: TemplateShape,
+        layer: Id,
+        owner: Option<Id>,
+    ) -> Option<SceneEvent> {
+        let mut template = Template::new(self.next_id(), origin, shape);
+        template.owner = owner;
+        self.add_template(template, layer)
+    }
+
+    pub fn remove_template(&mut self, id: Id) -> Option<SceneEvent> {
+        self.layers.iter_mut().find_map(|l| l.remove_template(id))
+    }
+
+    fn restore_template(&mut self, template: Id) -> Option<SceneEvent> {
+        for layer in &mut self.layers {
+            if layer.restore_template(template) {
+                return Some(SceneEvent::TemplateRestore(template));
+            }
+        }
+        None
+    }
+
+    // Centres of the grid cells covered by a template.
+    pub fn template_cells(&self, template: Id) -> Vec<ScenePoint> {
+        self.template_ref(template)
+            .map(|t| t.cells(&self.grid))
+            .unwrap_or_default()
+    }
+
+    // Sprites on visible layers which overlap a template.
+    pub fn template_sprites(&self, template: Id) -> Vec<Id> {
+        let template = match self.template_ref(template) {
+            Some(t) => t,
+            None => return vec![],
+        };
+
+        self.layers
+            .iter()
+            .filter(|l| l.visible)
+            .flat_map(|l| l.sprites.iter())
+            .filter(|s| template.covers(s))
+            .map(|s| s.id)
+            .collect()
+    }
+
     pub fn event_layer(&self, event: &SceneEvent) -> Option<Id> {
         if event.is_layer() {
             event.item()
+        } else if let SceneEvent::SpriteNew(_, layer) = event {
+            Some(*layer)
         } else if event.is_sprite() {
             self.get_sprite_layer(event.item()?)
+        } else if let SceneEvent::TemplateNew(_, layer) = event {
+            Some(*layer)
+        } else if event.is_template() {
+            self.get_template_layer(event.item()?)
+        } else {
+            None
+        }
+    }
+
+    // The users who control the subject of this event. These are the owners
+    // of a sprite, the user who placed a template, or for a combatant either
+    // its own owner or the owners of its token.
+    pub fn event_owners(&self, event: &SceneEvent) -> Vec<Id> {
+        if let SceneEvent::TemplateNew(t, _) = event {
+            t.owner.into_iter().collect()
+        } else if event.is_template() {
+            // Removed templates are included so that they may be restored.
+            let id = event.item();
+            self.layers
+                .iter()
+                .flat_map(|l| l.templates.iter().chain(l.removed_templates.iter()))
+                .find(|t| Some(t.id) == id)
+                .and_then(|t| t.owner)
+                .into_iter()
+                .collect()
+        } else if event.is_sprite() {
+            event
+                .item()
+                .and_then(|id| self.sprite_ref(id))
+                .map(|s| s.owners.clone())
+                .unwrap_or_default()
+        } else if let SceneEvent::InitiativeSet(id, ..) = event {
+            match self.initiative.combatant(*id) {
+                Some(Combatant {
+                    owner: Some(owner), ..
+                }) => vec![*owner],
+                Some(Combatant {
+                    sprite: Some(sprite),
+                    ..
+                }) => self
+                    .sprite_ref(*sprite)
+                    .map(|s| s.owners.clone())
+                    .unwrap_or_default(),
+                _ => vec![],
+            }
+        } else {
+            vec![]
+        }
+    }
+
+    // Add a combatant to the initiative order, placing it by its initiative.
+    // A combatant for a token is controlled by whoever controls that token.
+    pub fn new_combatant(
+        &mut self,
+        name: String,
+        sprite: Option<Id>,
+        initiative: Option<i32>,
+    ) -> Option<SceneEvent> {
+        let mut combatant = Combatant::new(self.next_id(), name, sprite, None);
+        combatant.initiative = initiative;
+        let position = self.initiative.sorted_position(initiative);
+        self.initiative.add(combatant, position)
+    }
+
+    // Set of sprites and templates this user may see, or None if this scene
+    // has fog of war disabled and thus everything is visible.
+    pub fn visible_to(&self, user: Id) -> Option<HashSet<Id>> {
+        if self.fog_of_war {
+            Some(vision::visible_items(&self.layers, &self.walls, user))
+        } else {
+            None
+        }
+    }
+
+    // Whether this user may see a point in the scene, such as a point of a
+    // ruler.
+    pub fn point_visible_to(&self, user: Id, point: ScenePoint) -> bool {
+        !self.fog_of_war || vision::sees_point(&self.layers, &self.walls, user, point)
+    }
+
+    // Prune removed layers, sprites and templates which are past the undo
+    // horizon, after which they can no longer be restored. Returns the number
+    // of items pruned.
+    pub fn compact(&mut self, now: u64) -> usize {
+        self.removed_layers.compact(now)
+            + self
+                .layers
+                .iter_mut()
+                .map(|l| l.compact(now))
+                .sum::<usize>()
+    }
+
+    // Create a copy of this scene containing only the sprites and templates
+    // in the visible set. Removed items are dropped as they may have been
+    // hidden, and combatants with hidden tokens are redacted.
+    #[must_use]
+    pub fn filtered(&self, visible: &HashSet<Id>) -> Scene {
+        let mut scene = self.clone();
+        for layer in scene.layers.iter_mut() {
+            layer.retain_sprites(|s| visible.contains(&s.id));
+            layer.templates.retain(|t| visible.contains(&t.id));
+            layer.removed_sprites.clear();
+            layer.removed_templates.clear();
+        }
+        for combatant in scene.initiative.combatants.iter_mut() {
+            if combatant.sprite.is_some_and(|id| !visible.contains(&id)) {
+                *combatant = combatant.redacted();
+            }
+        }
+        scene.removed_layers.clear();
+        scene
+    }
+
+    // The nearest sprite at or beneath below in its layer which is in the
+    // visible set, other than sprite. A restack relative to a hidden sprite is
+    // shared relative to this one instead, so that filtered scenes stack
+    // their sprites in the same order as the full scene.
+    pub fn visible_below(
+        &self,
+        sprite: Id,
+        below: Option<Id>,
+        visible: &HashSet<Id>,
+    ) -> Option<Id> {
+        let below = below?;
+        let layer = self.layer_ref(self.get_sprite_layer(below)?)?;
+        let position = layer.sprite_z(below)? as usize;
+        layer.sprites[..=position]
+            .iter()
+            .rev()
+            .map(|s| s.id)
+            .find(|&id| id != sprite && visible.contains(&id))
+    }
+
+    pub fn set_fog_of_war(&mut self, fog_of_war: bool) -> Option<SceneEvent> {
+        if self.fog_of_war != fog_of_war {
+            self.fog_of_war = fog_of_war;
+            Some(SceneEvent::SceneFogOfWar(fog_of_war))
+        } else {
+            None
+        }
+    }
+
+    // Sizes below the minimum are raised to it, while grids which are still
+    // invalid, such as those with infinite sizes, are refused.
+    pub fn set_grid(&mut self, mut grid: Grid) -> Option<SceneEvent> {
+        grid.size = grid.size.max(Grid::MIN_SIZE);
+        grid.thickness = grid.thickness.max(0.0);
+        grid.units_per_tile = grid.units_per_tile.max(0.0);
+        if grid.is_valid() && self.grid != grid {
+            let old = self.grid;
+            self.grid = grid;
+            Some(SceneEvent::SceneGrid(old, grid))
+        } else {
+            None
+        }
+    }
+
+    pub fn set_walls(&mut self, walls: Vec<Wall>) -> Option<SceneEvent> {
+        if self.walls != walls {
+            let old = std::mem::replace(&mut self.walls, walls.clone());
+            Some(SceneEvent::SceneWalls(old, walls))
         } else {
             None
         }
     }
 
+    // Distance in grid cells between two points, following the scene's grid.
+    pub fn distance(&self, from: ScenePoint, to: ScenePoint) -> f32 {
+        self.grid.distance(from, to)
+    }
+
     pub fn first_layer(&self) -> Id {
         self.layers.get(0).map(|l| l.id).unwrap_or(0)
     }
@@ -431,6 +776,55 @@ impl Scene {
             SceneEvent::EventSet(events) => {
                 events.into_iter().map(|e| self.apply_event(e)).all(|b| b)
             }
+            SceneEvent::InitiativeAdd(c, position) => self.initiative.add(c, position).is_some(),
+            SceneEvent::InitiativeAdvance(round, turn) => {
+                self.initiative.is_at(round, turn) && self.initiative.advance().is_some()
+            }
+            SceneEvent::InitiativeBack(round, turn) => {
+                self.initiative.is_at(round, turn) && self.initiative.back().is_some()
+            }
+            SceneEvent::InitiativeCombatant(old, new) => {
+                let canon = self.canon;
+                match self.initiative.combatant(new.id) {
+                    Some(c) if *c == old || !canon => self.initiative.replace(new).is_some(),
+                    _ => false,
+                }
+            }
+            SceneEvent::InitiativeMove(id, from, to) => {
+                if self.initiative.combatants.get(from).map(|c| c.id) == Some(id) {
+                    self.initiative.move_to(id, to);
+                    true
+                } else {
+                    false
+                }
+            }
+            SceneEvent::InitiativeRemove(c, ..) => {
+                self.initiative.remove(c.id);
+
+                // As with sprites, a combatant which is already gone has
+                // been removed successfully.
+                true
+            }
+            SceneEvent::InitiativeRestore(c, position, round, turn) => {
+                self.initiative.restore(c, position, round, turn).is_some()
+            }
+            SceneEvent::InitiativeSet(id, old, new) => match self.initiative.combatant(id) {
+                Some(c) if c.initiative == old => {
+                    self.initiative.set_initiative(id, new);
+                    true
+                }
+                _ => false,
+            },
+            SceneEvent::LayerBlend(l, old, new) => {
+                let canon = self.canon;
+                match self.layer(l) {
+                    Some(layer) if layer.blend == old || !canon => {
+                        layer.set_blend(new);
+                        true
+                    }
+                    _ => false,
+                }
+            }
             SceneEvent::LayerLocked(l, locked) => {
                 self.layer(l).map(|l| l.set_locked(locked));
                 true
@@ -452,6 +846,16 @@ impl Scene {
                 self.add_layer(Layer::new(id, &title, z));
                 true
             }
+            SceneEvent::LayerOpacity(l, old, new) => {
+                let canon = self.canon;
+                match self.layer(l) {
+                    Some(layer) if new.is_finite() && (layer.opacity == old || !canon) => {
+                        layer.set_opacity(new);
+                        true
+                    }
+                    _ => false,
+                }
+            }
             SceneEvent::LayerRemove(l) => self.remove_layer(l).is_some(),
             SceneEvent::LayerRestore(l) => self.restore_layer(l).is_some(),
             SceneEvent::LayerRename(id, old_title, new_title) => {
@@ -476,6 +880,26 @@ impl Scene {
                     false
                 }
             }
+            SceneEvent::SceneFogOfWar(fog_of_war) => {
+                self.set_fog_of_war(fog_of_war);
+                true
+            }
+            SceneEvent::SceneGrid(old, new) => {
+                if self.grid == old && new.is_valid() {
+                    self.grid = new;
+                    true
+                } else {
+                    false
+                }
+            }
+            SceneEvent::SceneWalls(old, new) => {
+                if self.walls == old {
+                    self.walls = new;
+                    true
+                } else {
+                    false
+                }
+            }
             SceneEvent::SceneTitle(old, new) => {
                 if self.title == old {
                     self.title = Some(new);
@@ -531,6 +955,70 @@ impl Scene {
                 }
                 false
             }
+            SceneEvent::SpriteOwners(id, old, new) => {
+                if let Some(s) = self.sprite(id) {
+                    if s.owners == old {
+                        s.set_owners(new);
+                        return true;
+                    }
+                }
+                false
+            }
+            SceneEvent::SpriteCondition(id, condition, added) => match self.sprite(id) {
+                Some(s) if added => s.add_condition(condition).is_some(),
+                Some(s) => s.remove_condition(condition).is_some(),
+                None => false,
+            },
+            SceneEvent::SpriteField(id, key, old, new) => match self.sprite(id) {
+                Some(s)
+                    if s.token.fields.get(&key) == old.as_ref()
+                        && Token::valid_field(&key, new.as_deref()) =>
+                {
+                    s.set_field(key, new);
+                    true
+                }
+                _ => false,
+            },
+            SceneEvent::SpriteHp(id, old, new) => match self.sprite(id) {
+                Some(s) if s.token.hp == old => {
+                    s.set_hp(new);
+                    true
+                }
+                _ => false,
+            },
+            SceneEvent::SpriteHpDelta(id, delta) => {
+                self.sprite(id).and_then(|s| s.adjust_hp(delta)).is_some()
+            }
+            SceneEvent::SpriteMaxHp(id, old, new) => match self.sprite(id) {
+                Some(s) if s.token.max_hp == old => {
+                    s.set_max_hp(new);
+                    true
+                }
+                _ => false,
+            },
+            SceneEvent::SpriteName(id, old, new) => match self.sprite(id) {
+                Some(s) if s.token.name == old && Token::valid_name(new.as_deref()) => {
+                    s.set_name(new);
+                    true
+                }
+                _ => false,
+            },
+            SceneEvent::SpriteTempHp(id, old, new) => match self.sprite(id) {
+                Some(s) if s.token.temp_hp == old => {
+                    s.set_temp_hp(new);
+                    true
+                }
+                _ => false,
+            },
+            SceneEvent::SpriteVision(id, old, new) => {
+                if let Some(s) = self.sprite(id) {
+                    if s.vision == old {
+                        s.set_vision(new);
+                        return true;
+                    }
+                }
+                false
+            }
             SceneEvent::SpriteVisual(id, old, new) => {
                 if let Some(s) = self.sprite(id) {
                     if s.visual == old {
@@ -540,21 +1028,137 @@ impl Scene {
                 }
                 false
             }
+            SceneEvent::SpriteZ(id, old, new) => {
+                let canon = self.canon;
+                let layer = match self.get_sprite_layer(id).and_then(|l| self.layer(l)) {
+                    Some(l) => l,
+                    None => return false,
+                };
+
+                if layer.sprite_below(id) == Some(old) || !canon {
+                    layer.place_sprite(id, new);
+                    true
+                } else {
+                    false
+                }
+            }
+            SceneEvent::TemplateMove(id, from, to) => {
+                let canon = self.canon;
+                match self.template(id) {
+                    Some(t) if t.origin == from || !canon => {
+                        t.set_origin(to);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            SceneEvent::TemplateNew(t, l) => {
+                if self.template_ref(t.id).is_none() {
+                    self.add_template(t, l).is_some()
+                } else {
+                    false
+                }
+            }
+            SceneEvent::TemplateRemove(id) => {
+                self.remove_template(id);
+                true
+            }
+            SceneEvent::TemplateRestore(id) => self.restore_template(id).is_some(),
+            SceneEvent::TemplateRotate(id, old, new) => {
+                let canon = self.canon;
+                match self.template(id) {
+                    Some(t) if t.rotation == old || !canon => {
+                        t.set_rotation(new);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            SceneEvent::TemplateShape(id, old, new) => {
+                if let Some(t) = self.template(id) {
+                    if t.shape == old {
+                        t.set_shape(new);
+                        return true;
+                    }
+                }
+                false
+            }
         }
     }
 
     pub fn unwind_event(&mut self, event: SceneEvent) -> Option<SceneEvent> {
         match event {
             SceneEvent::Dummy => None,
+            // Later events may depend on earlier ones, as when restacking a
+            // selection, so are unwound first.
             SceneEvent::EventSet(events) => Some(SceneEvent::EventSet(
                 events
                     .into_iter()
+                    .rev()
                     .filter_map(|e| self.unwind_event(e))
                     .collect::<Vec<SceneEvent>>(),
             )),
+            SceneEvent::InitiativeAdd(c, _) => self.initiative.remove(c.id),
+            SceneEvent::InitiativeAdvance(round, turn) => {
+                let (round, turn) = self.initiative.next_turn(round, turn);
+                if self.initiative.is_at(round, turn) {
+                    self.initiative.back()
+                } else {
+                    None
+                }
+            }
+            SceneEvent::InitiativeBack(round, turn) => {
+                let (round, turn) = self.initiative.previous_turn(round, turn);
+                if self.initiative.is_at(round, turn) {
+                    self.initiative.advance()
+                } else {
+                    None
+                }
+            }
+            SceneEvent::InitiativeCombatant(old, new) => {
+                if *self.initiative.combatant(new.id)? == new {
+                    self.initiative.replace(old)
+                } else {
+                    None
+                }
+            }
+            SceneEvent::InitiativeMove(id, from, to) => {
+                if self.initiative.combatants.get(to).map(|c| c.id) == Some(id) {
+                    self.initiative.move_to(id, from)
+                } else {
+                    None
+                }
+            }
+            SceneEvent::InitiativeRemove(c, position, round, turn) => {
+                self.initiative.restore(c, position, round, turn)
+            }
+            SceneEvent::InitiativeRestore(c, ..) => self.initiative.remove(c.id),
+            SceneEvent::InitiativeSet(id, old, new) => {
+                if self.initiative.combatant(id)?.initiative == new {
+                    self.initiative.set_initiative(id, old)
+                } else {
+                    None
+                }
+            }
+            SceneEvent::LayerBlend(l, old, new) => {
+                let layer = self.layer(l)?;
+                if layer.blend == new {
+                    layer.set_blend(old)
+                } else {
+                    None
+                }
+            }
             SceneEvent::LayerLocked(l, locked) => self.layer(l)?.set_locked(!locked),
             SceneEvent::LayerMove(l, _, up) => self.move_layer(l, !up),
             SceneEvent::LayerNew(id, _, _) => self.remove_layer(id),
+            SceneEvent::LayerOpacity(l, old, new) => {
+                let layer = self.layer(l)?;
+                if layer.opacity == new {
+                    layer.set_opacity(old)
+                } else {
+                    None
+                }
+            }
             SceneEvent::LayerRemove(l) => self.restore_layer(l),
             SceneEvent::LayerRestore(l) => self.remove_layer(l),
             SceneEvent::LayerRename(id, old_title, _) => {
@@ -570,6 +1174,21 @@ impl Scene {
                     None
                 }
             }
+            SceneEvent::SceneFogOfWar(fog_of_war) => self.set_fog_of_war(!fog_of_war),
+            SceneEvent::SceneGrid(old, new) => {
+                if self.grid == new {
+                    self.set_grid(old)
+                } else {
+                    None
+                }
+            }
+            SceneEvent::SceneWalls(old, new) => {
+                if self.walls == new {
+                    self.set_walls(old)
+                } else {
+                    None
+                }
+            }
             SceneEvent::SceneTitle(old, new) => {
                 if self.title == Some(new.clone()) {
                     self.title = old.clone();
@@ -598,8 +1217,73 @@ impl Scene {
                 }
                 None
             }
+            SceneEvent::SpriteOwners(id, old, new) => {
+                let sprite = self.sprite(id)?;
+                if sprite.owners == new {
+                    Some(sprite.set_owners(old))
+                } else {
+                    None
+                }
+            }
+            SceneEvent::SpriteCondition(id, condition, added) => {
+                let sprite = self.sprite(id)?;
+                if added {
+                    sprite.remove_condition(condition)
+                } else {
+                    sprite.add_condition(condition)
+                }
+            }
+            SceneEvent::SpriteField(id, key, old, new) => {
+                let sprite = self.sprite(id)?;
+                if sprite.token.fields.get(&key) == new.as_ref() {
+                    sprite.set_field(key, old)
+                } else {
+                    None
+                }
+            }
+            SceneEvent::SpriteHp(id, old, new) => {
+                let sprite = self.sprite(id)?;
+                if sprite.token.hp == new {
+                    Some(sprite.set_hp(old))
+                } else {
+                    None
+                }
+            }
+            SceneEvent::SpriteHpDelta(id, delta) => self.sprite(id)?.adjust_hp(-delta),
+            SceneEvent::SpriteMaxHp(id, old, new) => {
+                let sprite = self.sprite(id)?;
+                if sprite.token.max_hp == new {
+                    Some(sprite.set_max_hp(old))
+                } else {
+                    None
+                }
+            }
+            SceneEvent::SpriteName(id, old, new) => {
+                let sprite = self.sprite(id)?;
+                if sprite.token.name == new {
+                    Some(sprite.set_name(old))
+                } else {
+                    None
+                }
+            }
+            SceneEvent::SpriteTempHp(id, old, new) => {
+                let sprite = self.sprite(id)?;
+                if sprite.token.temp_hp == new {
+                    Some(sprite.set_temp_hp(old))
+                } else {
+                    None
+                }
+            }
             SceneEvent::SpriteRemove(id) => self.restore_sprite(id),
             SceneEvent::SpriteRestore(id) => self.remove_sprite(id),
+            SceneEvent::SpriteVision(id, old, new) => {
+                let sprite = self.sprite(id)?;
+                if sprite.vision == new {
+                    Some(sprite.set_vision(old))
+                } else {
+                    None
+                }
+            }
             SceneEvent::SpriteVisual(id, old, new) => {
                 let sprite = self.sprite(id)?;
                 if sprite.visual == new {
@@ -608,6 +1292,42 @@ impl Scene {
                     None
                 }
             }
+            SceneEvent::SpriteZ(id, old, new) => {
+                let layer = self.get_sprite_layer(id)?;
+                let layer = self.layer(layer)?;
+                if layer.sprite_below(id) == Some(new) {
+                    layer.place_sprite(id, old)
+                } else {
+                    None
+                }
+            }
+            SceneEvent::TemplateMove(id, from, to) => {
+                let template = self.template(id)?;
+                if template.origin == to {
+                    Some(template.set_origin(from))
+                } else {
+                    None
+                }
+            }
+            SceneEvent::TemplateNew(t, _) => self.remove_template(t.id),
+            SceneEvent::TemplateRemove(id) => self.restore_template(id),
+            SceneEvent::TemplateRestore(id) => self.remove_template(id),
+            SceneEvent::TemplateRotate(id, old, new) => {
+                let template = self.template(id)?;
+                if template.rotation == new {
+                    Some(template.set_rotation(old))
+                } else {
+                    None
+                }
+            }
+            SceneEvent::TemplateShape(id, old, new) => {
+                let template = self.template(id)?;
+                if template.shape == new {
+                    Some(template.set_shape(old))
+                } else {
+                    None
+                }
+            }
         }
     }
 }
@@ -623,11 +1343,16 @@ impl Default for Scene {
                 Layer::new(2, "Scenery", -1),
                 Layer::new(3, "Background", -2),
             ],
-            removed_layers: vec![],
+            removed_layers: Tombstones::new(),
             title: None,
             project: None,
             w: Scene::DEFAULT_SIZE,
             h: Scene::DEFAULT_SIZE,
+            walls: vec![],
+            fog_of_war: false,
+            grid: Grid::default(),
+            initiative: Initiative::default(),
+            sprite_layers: HashMap::new(),
         }
     }
 }
@@ -4,13 +4,15 @@ use serde_derive::{Deserialize, Serialize};
 
 use crate::{
     comms::{PermsEvent, SceneEvent},
-    Id,
+    Id, Initiative,
 };
 
 pub const CANONICAL_UPDATER: Id = 0;
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
-enum Perm {
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Perm {
+    Initiative,
+    InitiativeSet,
     LayerNew,
     LayerRemove,
     LayerUpdate,
@@ -18,26 +20,74 @@ enum Perm {
     Special,
     SpriteNew,
     SpriteRemove,
+    SpriteToken,
     SpriteUpdate,
+    SpriteVision,
+    Template,
 }
 
 impl Perm {
+    const ALL: [Perm; 13] = [
+        Perm::Initiative,
+        Perm::InitiativeSet,
+        Perm::LayerNew,
+        Perm::LayerRemove,
+        Perm::LayerUpdate,
+        Perm::SceneDetails,
+        Perm::Special,
+        Perm::SpriteNew,
+        Perm::SpriteRemove,
+        Perm::SpriteToken,
+        Perm::SpriteUpdate,
+        Perm::SpriteVision,
+        Perm::Template,
+    ];
+
     pub fn of(event: &SceneEvent) -> Perm {
         match *event {
             SceneEvent::Dummy | SceneEvent::EventSet(..) => Perm::Special,
-            SceneEvent::LayerLocked(..)
+            SceneEvent::InitiativeAdd(..)
+            | SceneEvent::InitiativeAdvance(..)
+            | SceneEvent::InitiativeBack(..)
+            | SceneEvent::InitiativeCombatant(..)
+            | SceneEvent::InitiativeMove(..)
+            | SceneEvent::InitiativeRemove(..)
+            | SceneEvent::InitiativeRestore(..) => Perm::Initiative,
+            SceneEvent::InitiativeSet(..) => Perm::InitiativeSet,
+            SceneEvent::LayerBlend(..)
+            | SceneEvent::LayerLocked(..)
             | SceneEvent::LayerMove(..)
+            | SceneEvent::LayerOpacity(..)
             | SceneEvent::LayerRename(..)
             | SceneEvent::LayerVisibility(..) => Perm::LayerUpdate,
             SceneEvent::LayerRemove(..) => Perm::LayerRemove,
             SceneEvent::LayerNew(..) | SceneEvent::LayerRestore(..) => Perm::LayerNew,
-            SceneEvent::SceneDimensions(..) | SceneEvent::SceneTitle(..) => Perm::SceneDetails,
+            SceneEvent::SceneDimensions(..)
+            | SceneEvent::SceneFogOfWar(..)
+            | SceneEvent::SceneGrid(..)
+            | SceneEvent::SceneTitle(..)
+            | SceneEvent::SceneWalls(..) => Perm::SceneDetails,
             SceneEvent::SpriteLayer(..) => Perm::LayerUpdate,
             SceneEvent::SpriteMove(..)
             | SceneEvent::SpriteShape(..)
-            | SceneEvent::SpriteVisual(..) => Perm::SpriteUpdate,
+            | SceneEvent::SpriteVisual(..)
+            | SceneEvent::SpriteZ(..) => Perm::SpriteUpdate,
             SceneEvent::SpriteNew(..) | SceneEvent::SpriteRestore(..) => Perm::SpriteNew,
             SceneEvent::SpriteRemove(..) => Perm::SpriteRemove,
+            SceneEvent::SpriteCondition(..)
+            | SceneEvent::SpriteField(..)
+            | SceneEvent::SpriteHp(..)
+            | SceneEvent::SpriteHpDelta(..)
+            | SceneEvent::SpriteMaxHp(..)
+            | SceneEvent::SpriteName(..)
+            | SceneEvent::SpriteTempHp(..) => Perm::SpriteToken,
+            SceneEvent::SpriteOwners(..) | SceneEvent::SpriteVision(..) => Perm::SpriteVision,
+            SceneEvent::TemplateMove(..)
+            | SceneEvent::TemplateNew(..)
+            | SceneEvent::TemplateRemove(..)
+            | SceneEvent::TemplateRestore(..)
+            | SceneEvent::TemplateRotate(..)
+            | SceneEvent::TemplateShape(..) => Perm::Template,
         }
     }
 }
@@ -62,7 +112,7 @@ impl Role {
 
         match perm {
             Perm::Special => false,
-            Perm::SpriteUpdate => !matches!(self, Self::Spectator),
+            Perm::SpriteUpdate | Perm::Template => !matches!(self, Self::Spectator),
             _ => matches!(self, Self::Editor),
         }
     }
@@ -72,44 +122,212 @@ impl Role {
     }
 }
 
+/// Changes to sprites which may be allowed or denied on a layer or sprite
+/// by a PermSet rule.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Action {
+    Add,
+    /// Change a token's name, HP, conditions or fields.
+    Edit,
+    Layer,
+    Move,
+    Remove,
+    /// Bring forward or send back.
+    Reorder,
+    Resize,
+    Restyle,
+}
+
+impl Action {
+    pub fn of(event: &SceneEvent) -> Option<Action> {
+        match event {
+            SceneEvent::SpriteLayer(..) => Some(Action::Layer),
+            SceneEvent::SpriteMove(_, from, to) => {
+                if from.w == to.w && from.h == to.h {
+                    Some(Action::Move)
+                } else {
+                    Some(Action::Resize)
+                }
+            }
+            SceneEvent::SpriteNew(..) | SceneEvent::SpriteRestore(..) => Some(Action::Add),
+            SceneEvent::SpriteRemove(..) => Some(Action::Remove),
+            SceneEvent::SpriteShape(..) | SceneEvent::SpriteVisual(..) => Some(Action::Restyle),
+            SceneEvent::SpriteZ(..) => Some(Action::Reorder),
+            SceneEvent::SpriteCondition(..)
+            | SceneEvent::SpriteField(..)
+            | SceneEvent::SpriteHp(..)
+            | SceneEvent::SpriteHpDelta(..)
+            | SceneEvent::SpriteMaxHp(..)
+            | SceneEvent::SpriteName(..)
+            | SceneEvent::SpriteTempHp(..) => Some(Action::Edit),
+            _ => None,
+        }
+    }
+}
+
+/// Who a PermSet rule applies to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Subject {
+    /// All users with exactly this role.
+    Role(Role),
+    User(Id),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+struct Rule {
+    action: Action,
+    subject: Subject,
+    allow: bool,
+}
+
 /// For this item, only uses who are listed or have a role exceeding this role
-/// may interact.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// may interact. Rules may further allow or deny specific actions to a role
+/// or user, taking precedence over the role and user list.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct PermSet {
     item: Id,
     users: Vec<Id>,
     role: Role,
+    #[serde(default)]
+    rules: Vec<Rule>,
 }
 
 impl PermSet {
-    fn new(id: Id) -> Self {
+    pub fn new(item: Id, users: Vec<Id>, role: Role) -> Self {
         PermSet {
-            item: id,
-            users: vec![],
-            role: Role::Editor,
+            item,
+            users,
+            role,
+            rules: vec![],
         }
     }
 
+    /// Allow or deny an action to this subject, or with None remove the rule.
+    pub fn set_rule(&mut self, action: Action, subject: Subject, allow: Option<bool>) {
+        self.rules
+            .retain(|r| r.action != action || r.subject != subject);
+        if let Some(allow) = allow {
+            self.rules.push(Rule {
+                action,
+                subject,
+                allow,
+            });
+        }
+    }
+
+    // Whether the rules allow or deny this action to this user, if any apply.
+    // A rule for the user takes precedence over one for their role. The owner
+    // is never restricted.
+    fn rule(&self, user: Id, role: Role, action: Action) -> Option<bool> {
+        if role == Role::Owner {
+            return None;
+        }
+
+        let find = |subject: Subject| {
+            self.rules
+                .iter()
+                .find(|r| r.action == action && r.subject == subject)
+                .map(|r| r.allow)
+        };
+        find(Subject::User(user)).or_else(|| find(Subject::Role(role)))
+    }
+
     /// Whether this user is allowed to interact with this item
     fn allows(&self, user: Id, role: Role) -> bool {
         role >= self.role || self.users.contains(&user)
     }
+
+    // Whether this user may take this action on this item, or None if there
+    // is no rule for the action and the action is otherwise allowed.
+    fn permits(&self, user: Id, role: Role, action: Option<Action>) -> Option<bool> {
+        match action.and_then(|a| self.rule(user, role, a)) {
+            Some(allow) => Some(allow),
+            None if self.allows(user, role) => None,
+            None => Some(false),
+        }
+    }
+}
+
+/// When a temporary Override lapses.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Expiry {
+    /// At the end of this combatant's turn in this round of initiative.
+    Turn(Id, u32),
+    /// At this Unix time, in seconds.
+    Time(u64),
 }
 
-/// This user is granted this permission, optionally over a single item.
+impl Expiry {
+    // A turn expiry lapses once initiative has moved past the turn, so that
+    // stepping back a turn or reordering combatants doesn't end it early. It
+    // also lapses if the combatant is removed or combat ends.
+    fn expired(&self, now: u64, initiative: &Initiative) -> bool {
+        match *self {
+            Expiry::Turn(combatant, round) => match initiative.position(combatant) {
+                Some(_) if initiative.round == 0 => round > 0,
+                Some(turn) => (initiative.round, initiative.turn) > (round, turn),
+                None => true,
+            },
+            Expiry::Time(time) => now >= time,
+        }
+    }
+}
+
+/// This user is granted this permission, optionally over a single item and
+/// optionally until some expiry.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Override {
     user: Id,
     perm: Perm,
     item: Option<Id>,
+    expiry: Option<Expiry>,
 }
 
 impl Override {
-    fn allows(&self, user: Id, event: &SceneEvent) -> bool {
+    pub fn new(user: Id, perm: Perm, item: Option<Id>, expiry: Option<Expiry>) -> Self {
+        Override {
+            user,
+            perm,
+            item,
+            expiry,
+        }
+    }
+
+    fn allows(&self, user: Id, event: &SceneEvent, now: u64, initiative: &Initiative) -> bool {
         user == self.user
             && Perm::of(event) == self.perm
             && (self.item.is_none() || event.item() == self.item)
+            && !self.expired(now, initiative)
     }
+
+    fn expired(&self, now: u64, initiative: &Initiative) -> bool {
+        self.expiry.is_some_and(|e| e.expired(now, initiative))
+    }
+}
+
+/// A PermSet rule as it applies to a particular user.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct ItemRule {
+    pub item: Id,
+    pub action: Action,
+    pub allow: bool,
+}
+
+/// What a user may do in a scene, as reported by Perms::user_perms.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UserPerms {
+    pub role: Role,
+    /// Permissions granted by the user's role.
+    pub perms: Vec<Perm>,
+    /// Restricted items which the user may interact with.
+    pub allowed: Vec<Id>,
+    /// Restricted items which the user may not interact with.
+    pub denied: Vec<Id>,
+    /// Actions the user is allowed or denied on items by rules, which take
+    /// precedence over allowed and denied.
+    pub rules: Vec<ItemRule>,
+    /// Overrides granted to the user which haven't expired.
+    pub overrides: Vec<Override>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -130,7 +348,7 @@ impl Perms {
         }
     }
 
-    fn get_role(&self, user: Id) -> Role {
+    pub fn get_role(&self, user: Id) -> Role {
         *self.roles.get(&user).unwrap_or(&Role::lowest())
     }
 
@@ -138,31 +356,85 @@ impl Perms {
         self.roles.insert(user, role);
     }
 
-    fn allowed_by_role(&self, user: Id, event: &SceneEvent, layer: Option<Id>) -> bool {
+    fn allowed_by_role(
+        &self,
+        user: Id,
+        event: &SceneEvent,
+        layer: Option<Id>,
+        owners: &[Id],
+    ) -> bool {
         let role = self.get_role(user);
-        if let Some(id) = layer {
-            if let Some(ps) = self.items.get(&id) {
-                if !ps.allows(user, role) {
-                    return false;
-                }
-            }
+        let perm = Perm::of(event);
+
+        // The PermSets of the layer, the item itself and, for a sprite moving
+        // between layers, the destination layer must each permit the change.
+        let mut items = vec![layer];
+        if event.is_sprite() || event.is_template() {
+            items.push(event.item());
+        }
+        if let SceneEvent::SpriteLayer(_, _, new) = event {
+            items.push(Some(*new));
         }
+        let sets = items
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.items.get(&id))
+            .collect::<Vec<&PermSet>>();
 
-        if event.is_sprite() {
-            if let Some(id) = event.item() {
-                if let Some(ps) = self.items.get(&id) {
-                    if !ps.allows(user, role) {
-                        return false;
-                    }
-                }
+        // A rule denying the action takes precedence over everything else,
+        // including control of the token.
+        let action = Action::of(event);
+        if action.is_some_and(|a| sets.iter().any(|ps| ps.rule(user, role, a) == Some(false))) {
+            return false;
+        }
+
+        // Players may set the initiative of combatants they control, and move
+        // and edit their own tokens even on layers they otherwise can't.
+        let owned = matches!(
+            perm,
+            Perm::InitiativeSet | Perm::SpriteToken | Perm::SpriteUpdate
+        );
+        if owned && role >= Role::Player && owners.contains(&user) {
+            return true;
+        }
+
+        // Tokens controlled by other users can only be changed by editors.
+        if owned && role < Role::Editor && !owners.is_empty() {
+            return false;
+        }
+
+        // Likewise players may only place and change their own templates,
+        // unless granted an override.
+        if perm == Perm::Template && role < Role::Editor && !owners.contains(&user) {
+            return false;
+        }
+
+        // A rule allowing the action grants it even if the user's role
+        // wouldn't otherwise.
+        let mut granted = false;
+        for ps in sets {
+            match ps.permits(user, role, action) {
+                Some(false) => return false,
+                Some(true) => granted = true,
+                None => {}
             }
         }
 
-        role.allows(Perm::of(event))
+        granted || role.allows(perm)
     }
 
-    fn allowed_by_override(&self, user: Id, event: &SceneEvent) -> bool {
-        self.overrides.iter().any(|o| o.allows(user, event))
+    // An override which has expired no longer applies, even before it is
+    // revoked by expire_overrides.
+    fn allowed_by_override(
+        &self,
+        user: Id,
+        event: &SceneEvent,
+        now: u64,
+        initiative: &Initiative,
+    ) -> bool {
+        self.overrides
+            .iter()
+            .any(|o| o.allows(user, event, now, initiative))
     }
 
     pub fn set_owner(&mut self, owner: Id) {
@@ -208,17 +480,108 @@ impl Perms {
         }
     }
 
+    // Remove the PermSet for an item, so that it is governed by roles alone.
+    pub fn reset_item_perms(&mut self, updater: Id, item: Id) -> Option<PermsEvent> {
+        if self.get_role(updater) >= Role::Editor && self.items.remove(&item).is_some() {
+            Some(PermsEvent::ResetItemPerms(item))
+        } else {
+            None
+        }
+    }
+
+    pub fn revoke_override(&mut self, updater: Id, revoked: Override) -> Option<PermsEvent> {
+        if self.get_role(updater) < Role::Editor {
+            return None;
+        }
+
+        let i = self.overrides.iter().position(|o| *o == revoked)?;
+        Some(PermsEvent::RevokeOverride(self.overrides.remove(i)))
+    }
+
+    // Revoke each override which has expired, given the current time and
+    // initiative, returning the events to share with clients.
+    pub fn expire_overrides(&mut self, now: u64, initiative: &Initiative) -> Vec<PermsEvent> {
+        let expired = self
+            .overrides
+            .iter()
+            .filter(|o| o.expired(now, initiative))
+            .cloned()
+            .collect::<Vec<Override>>();
+
+        expired
+            .into_iter()
+            .filter_map(|o| self.revoke_override(CANONICAL_UPDATER, o))
+            .collect()
+    }
+
     pub fn handle_event(&mut self, updater: Id, event: PermsEvent) -> bool {
         match event {
             PermsEvent::RoleChange(user, role) => self.role_change(updater, user, role),
             PermsEvent::ItemPerms(perms) => self.item_perms(updater, perms),
+            PermsEvent::ResetItemPerms(item) => self.reset_item_perms(updater, item),
             PermsEvent::NewOverride(new) => self.new_override(updater, new),
+            PermsEvent::RevokeOverride(revoked) => self.revoke_override(updater, revoked),
         }
         .is_some()
     }
 
-    pub fn permitted(&self, user: Id, event: &SceneEvent, layer: Option<Id>) -> bool {
-        self.allowed_by_role(user, event, layer) || self.allowed_by_override(user, event)
+    /// The permissions this user has by their role, by item PermSets and by
+    /// overrides, given the current time and initiative as for permitted.
+    pub fn user_perms(&self, user: Id, now: u64, initiative: &Initiative) -> UserPerms {
+        let role = self.get_role(user);
+        let (mut allowed, mut denied): (Vec<&PermSet>, Vec<&PermSet>) =
+            self.items.values().partition(|ps| ps.allows(user, role));
+        allowed.sort_by_key(|ps| ps.item);
+        denied.sort_by_key(|ps| ps.item);
+
+        let mut rules = vec![];
+        for ps in allowed.iter().chain(&denied) {
+            for action in ps.rules.iter().map(|r| r.action) {
+                let rule = ps.rule(user, role, action).map(|allow| ItemRule {
+                    item: ps.item,
+                    action,
+                    allow,
+                });
+                if let Some(rule) = rule.filter(|r| !rules.contains(r)) {
+                    rules.push(rule);
+                }
+            }
+        }
+        rules.sort_by_key(|r| r.item);
+
+        UserPerms {
+            role,
+            perms: Perm::ALL
+                .into_iter()
+                .filter(|&perm| role.allows(perm))
+                .collect(),
+            allowed: allowed.iter().map(|ps| ps.item).collect(),
+            denied: denied.iter().map(|ps| ps.item).collect(),
+            rules,
+            overrides: self
+                .overrides
+                .iter()
+                .filter(|o| o.user == user && !o.expired(now, initiative))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Whether this user may make this change. The layer and owners are those
+    /// given for the event by Scene::event_layer and Scene::event_owners, while
+    /// override expiries are checked against the current Unix time and the
+    /// scene's initiative.
+    pub fn permitted(
+        &self,
+        user: Id,
+        event: &SceneEvent,
+        layer: Option<Id>,
+        owners: &[Id],
+        now: u64,
+        initiative: &Initiative,
+    ) -> bool {
+        self.allowed_by_role(user, event, layer, owners)
+            || self.allowed_by_override(user, event, now, initiative)
     }
 }
 
@@ -63,7 +63,7 @@ impl Rect {
     }
 
     #[must_use]
-    fn positive_dimensions(&self) -> Self {
+    pub fn positive_dimensions(&self) -> Self {
         let mut new = *self;
 
         if self.w < 0.0 {
@@ -129,6 +129,34 @@ impl Rect {
         b.x >= a.x && b.x + b.w <= a.x + a.w && b.y >= a.y && b.y + b.h <= a.y + a.h
     }
 
+    // Whether any part of the segment between a and b lies within this rect.
+    pub fn intersects_segment(&self, a: ScenePoint, b: ScenePoint) -> bool {
+        let Rect { x, y, w, h } = self.positive_dimensions();
+        let (dx, dy) = (b.x - a.x, b.y - a.y);
+
+        // Clip the segment, parameterised from 0 at a to 1 at b, against each
+        // edge in turn.
+        let mut start: f32 = 0.0;
+        let mut end: f32 = 1.0;
+        for (p, q) in [
+            (-dx, a.x - x),
+            (dx, x + w - a.x),
+            (-dy, a.y - y),
+            (dy, y + h - a.y),
+        ] {
+            if p == 0.0 {
+                if q < 0.0 {
+                    return false;
+                }
+            } else if p < 0.0 {
+                start = start.max(q / p);
+            } else {
+                end = end.min(q / p);
+            }
+        }
+        start <= end
+    }
+
     pub fn top_left(&self) -> ScenePoint {
         ScenePoint {
             x: self.x,
@@ -2,7 +2,7 @@ use serde_derive::{Deserialize, Serialize};
 
 use crate::Dimension;
 
-use super::{comms::SceneEvent, Id, Rect, ScenePoint};
+use super::{comms::SceneEvent, token::Token, Grid, GridType, Id, Rect, ScenePoint};
 
 pub type Colour = [f32; 4];
 
@@ -38,13 +38,23 @@ impl SpriteVisual {
     }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Sprite {
     pub id: Id,
     pub rect: Rect,
     pub z: i32,
     pub visual: SpriteVisual,
     pub shape: SpriteShape,
+
+    // Radius in scene units which this sprite can see, if it is a token.
+    pub vision: Option<f32>,
+
+    // Users who control this sprite as a token. Owners see what it sees and
+    // may move it and edit its details.
+    pub owners: Vec<Id>,
+
+    // Name, HP and so on, if this sprite is a token.
+    pub token: Token,
 }
 
 impl Sprite {
@@ -58,6 +68,9 @@ impl Sprite {
             z: 1,
             visual: visual.unwrap_or(Sprite::DEFAULT_VISUAL),
             shape: shape.unwrap_or(SpriteShape::Rectangle),
+            vision: None,
+            owners: vec![],
+            token: Token::default(),
             id,
         }
     }
@@ -99,18 +112,149 @@ impl Sprite {
         SceneEvent::SpriteVisual(self.id, old, new)
     }
 
-    pub fn snap_pos(&mut self) -> SceneEvent {
+    pub fn set_vision(&mut self, new: Option<f32>) -> SceneEvent {
+        let old = self.vision;
+        self.vision = new;
+        SceneEvent::SpriteVision(self.id, old, new)
+    }
+
+    pub fn set_owners(&mut self, new: Vec<Id>) -> SceneEvent {
+        let old = std::mem::replace(&mut self.owners, new.clone());
+        SceneEvent::SpriteOwners(self.id, old, new)
+    }
+
+    pub fn owned_by(&self, user: Id) -> bool {
+        self.owners.contains(&user)
+    }
+
+    pub fn set_name(&mut self, new: Option<String>) -> SceneEvent {
+        let old = std::mem::replace(&mut self.token.name, new.clone());
+        SceneEvent::SpriteName(self.id, old, new)
+    }
+
+    // HP is capped at the maximum HP, if set.
+    pub fn set_hp(&mut self, new: Option<i32>) -> SceneEvent {
+        let old = self.token.hp;
+        let new = new.map(|hp| self.token.max_hp.map_or(hp, |max| hp.min(max)));
+        self.token.hp = new;
+        SceneEvent::SpriteHp(self.id, old, new)
+    }
+
+    // Adjusting HP by a delta rather than setting it means that concurrent
+    // changes, such as two players healing a token, both take effect. Healing
+    // stops at the maximum HP, if set, and the event holds the change actually
+    // made so that undoing a capped heal removes only the HP gained.
+    pub fn adjust_hp(&mut self, delta: i32) -> Option<SceneEvent> {
+        let max = self.token.max_hp;
+        let hp = self.token.hp.as_mut()?;
+        let old = *hp;
+        *hp = match max {
+            Some(max) if delta > 0 => hp.saturating_add(delta).min(max.max(*hp)),
+            _ => hp.saturating_add(delta),
+        };
+        Some(SceneEvent::SpriteHpDelta(self.id, hp.saturating_sub(old)))
+    }
+
+    pub fn set_max_hp(&mut self, new: Option<i32>) -> SceneEvent {
+        let old = self.token.max_hp;
+        self.token.max_hp = new;
+        SceneEvent::SpriteMaxHp(self.id, old, new)
+    }
+
+    pub fn set_temp_hp(&mut self, new: i32) -> SceneEvent {
+        let old = self.token.temp_hp;
+        self.token.temp_hp = new;
+        SceneEvent::SpriteTempHp(self.id, old, new)
+    }
+
+    // Deal damage to this token, which is taken from temporary HP first.
+    // Negative damage heals, up to the maximum HP if set.
+    pub fn damage(&mut self, amount: i32) -> Option<SceneEvent> {
+        let hp = self.token.hp?;
+        let mut events = vec![];
+        let delta = if amount > 0 {
+            let absorbed = amount.min(self.token.temp_hp.max(0));
+            if absorbed > 0 {
+                events.push(self.set_temp_hp(self.token.temp_hp - absorbed));
+            }
+            absorbed - amount
+        } else {
+            let max = self.token.max_hp.unwrap_or(i32::MAX);
+            (-amount).min(max.saturating_sub(hp)).max(0)
+        };
+
+        if delta != 0 {
+            events.extend(self.adjust_hp(delta));
+        }
+
+        if events.len() > 1 {
+            Some(SceneEvent::EventSet(events))
+        } else {
+            events.pop()
+        }
+    }
+
+    pub fn add_condition(&mut self, condition: String) -> Option<SceneEvent> {
+        if self.token.add_condition(condition.clone()) {
+            Some(SceneEvent::SpriteCondition(self.id, condition, true))
+        } else {
+            None
+        }
+    }
+
+    pub fn remove_condition(&mut self, condition: String) -> Option<SceneEvent> {
+        if self.token.remove_condition(&condition) {
+            Some(SceneEvent::SpriteCondition(self.id, condition, false))
+        } else {
+            None
+        }
+    }
+
+    // Set the value of a field, or remove it if the value is None.
+    pub fn set_field(&mut self, key: String, new: Option<String>) -> Option<SceneEvent> {
+        if self.token.fields.get(&key) == new.as_ref() || !Token::valid_field(&key, new.as_deref())
+        {
+            return None;
+        }
+
+        let old = self.token.set_field(key.clone(), new.clone());
+        Some(SceneEvent::SpriteField(self.id, key, old, new))
+    }
+
+    pub fn centre(&self) -> ScenePoint {
+        ScenePoint {
+            x: self.rect.x + self.rect.w / 2.0,
+            y: self.rect.y + self.rect.h / 2.0,
+        }
+    }
+
+    pub fn snap_pos(&mut self, grid: &Grid) -> SceneEvent {
         let old = self.rect;
-        self.rect.x = round_to_nearest(old.x, determine_unit_size(old.w));
-        self.rect.y = round_to_nearest(old.y, determine_unit_size(old.h));
+        match grid.kind {
+            GridType::Square => {
+                let mut rect = grid.grid_rect(old);
+                rect.x = round_to_nearest(rect.x, determine_unit_size(rect.w));
+                rect.y = round_to_nearest(rect.y, determine_unit_size(rect.h));
+                self.rect = grid.scene_rect(rect);
+            }
+            // Tokens on a hex grid sit in the middle of the nearest hex.
+            GridType::HexFlat | GridType::HexPointy => {
+                let centre = grid.cell_centre(self.centre());
+                self.rect.x = centre.x - old.w / 2.0;
+                self.rect.y = centre.y - old.h / 2.0;
+            }
+            GridType::Gridless => {}
+        }
         SceneEvent::SpriteMove(self.id, old, self.rect)
     }
 
-    pub fn snap_size(&mut self) -> SceneEvent {
+    pub fn snap_size(&mut self, grid: &Grid) -> SceneEvent {
         let old = self.rect;
-        self.rect.w = round_dimension(old.w);
-        self.rect.h = round_dimension(old.h);
-        self.snap_pos();
+        if grid.kind != GridType::Gridless {
+            self.rect.w = round_dimension(old.w / grid.size) * grid.size;
+            self.rect.h = round_dimension(old.h / grid.size) * grid.size;
+        }
+        self.snap_pos(grid);
         SceneEvent::SpriteMove(self.id, old, self.rect)
     }
 
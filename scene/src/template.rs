@@ -0,0 +1,236 @@
+use serde_derive::{Deserialize, Serialize};
+
+use super::{comms::SceneEvent, sprite::Colour, Grid, GridType, Id, Rect, ScenePoint, Sprite};
+
+// Number of points used to approximate the curved edges of templates.
+const ARC_SEGMENTS: usize = 32;
+
+/// Shape and size of an area of effect. Sizes are in scene units.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum TemplateShape {
+    // Centred on the origin.
+    Circle { radius: f32 },
+    // Spreading from the origin; angle is the total width in degrees.
+    Cone { length: f32, angle: f32 },
+    // Square with the centre of one side at the origin.
+    Cube { size: f32 },
+    // Extending from the origin, centred on the line of the template.
+    Line { length: f32, width: f32 },
+}
+
+impl TemplateShape {
+    // Set the principal size of this shape, i.e. the length of a cone or
+    // line, the radius of a circle or the side length of a cube.
+    pub fn with_size(self, size: f32) -> Self {
+        match self {
+            Self::Circle { .. } => Self::Circle { radius: size },
+            Self::Cone { angle, .. } => Self::Cone {
+                length: size,
+                angle,
+            },
+            Self::Cube { .. } => Self::Cube { size },
+            Self::Line { width, .. } => Self::Line {
+                length: size,
+                width,
+            },
+        }
+    }
+}
+
+/// An area of effect placed on a layer, for example a spell's cone.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Template {
+    pub id: Id,
+    pub origin: ScenePoint,
+    // Direction the template faces, in degrees clockwise from the positive x
+    // axis. Has no effect on circles.
+    pub rotation: f32,
+    pub shape: TemplateShape,
+    pub colour: Colour,
+
+    // User who placed this template. Players may only change their own
+    // templates.
+    #[serde(default)]
+    pub owner: Option<Id>,
+}
+
+impl Template {
+    const DEFAULT_COLOUR: Colour = [1.0, 0.4, 0.0, 0.35];
+
+    pub fn new(id: Id, origin: ScenePoint, shape: TemplateShape) -> Self {
+        Template {
+            id,
+            origin,
+            rotation: 0.0,
+            shape,
+            colour: Template::DEFAULT_COLOUR,
+            owner: None,
+        }
+    }
+
+    pub fn set_origin(&mut self, origin: ScenePoint) -> SceneEvent {
+        let old = self.origin;
+        self.origin = origin;
+        SceneEvent::TemplateMove(self.id, old, origin)
+    }
+
+    pub fn set_rotation(&mut self, rotation: f32) -> SceneEvent {
+        let old = self.rotation;
+        self.rotation = rotation.rem_euclid(360.0);
+        SceneEvent::TemplateRotate(self.id, old, self.rotation)
+    }
+
+    pub fn set_shape(&mut self, shape: TemplateShape) -> SceneEvent {
+        let old = self.shape;
+        self.shape = shape;
+        SceneEvent::TemplateShape(self.id, old, shape)
+    }
+
+    // Move the origin to the nearest grid vertex on square grids, or the
+    // nearest cell centre on hex grids.
+    pub fn snap_origin(&mut self, grid: &Grid) -> SceneEvent {
+        let origin = match grid.kind {
+            GridType::Square => {
+                let p = grid.grid_point(self.origin);
+                grid.scene_point(ScenePoint::new(p.x.round(), p.y.round()))
+            }
+            GridType::HexFlat | GridType::HexPointy => grid.cell_centre(self.origin),
+            GridType::Gridless => self.origin,
+        };
+        self.set_origin(origin)
+    }
+
+    // Round the size of the template to a whole number of cells, at least
+    // one.
+    pub fn snap_size(&mut self, grid: &Grid) -> SceneEvent {
+        let size = match self.shape {
+            TemplateShape::Circle { radius } => radius,
+            TemplateShape::Cone { length, .. } => length,
+            TemplateShape::Cube { size } => size,
+            TemplateShape::Line { length, .. } => length,
+        };
+
+        let size = if grid.kind == GridType::Gridless {
+            size.max(grid.size)
+        } else {
+            (size / grid.size).round().max(1.0) * grid.size
+        };
+        self.set_shape(self.shape.with_size(size))
+    }
+
+    // Rotate the template to face this point.
+    pub fn face(&mut self, at: ScenePoint) -> SceneEvent {
+        let d = at - self.origin;
+        self.set_rotation(d.y.atan2(d.x).to_degrees())
+    }
+
+    // Convert a point to coordinates relative to the template, with the x
+    // axis in the direction the template faces.
+    fn local(&self, p: ScenePoint) -> ScenePoint {
+        let d = p - self.origin;
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        ScenePoint::new(d.x * cos + d.y * sin, d.y * cos - d.x * sin)
+    }
+
+    fn global(&self, p: ScenePoint) -> ScenePoint {
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        ScenePoint::new(
+            self.origin.x + p.x * cos - p.y * sin,
+            self.origin.y + p.x * sin + p.y * cos,
+        )
+    }
+
+    pub fn contains_point(&self, p: ScenePoint) -> bool {
+        let ScenePoint { x, y } = self.local(p);
+        let distance = (x * x + y * y).sqrt();
+        match self.shape {
+            TemplateShape::Circle { radius } => distance <= radius,
+            TemplateShape::Cone { length, angle } => {
+                distance <= length
+                    && (distance == 0.0 || y.abs().atan2(x) <= (angle / 2.0).to_radians())
+            }
+            TemplateShape::Cube { size } => (0.0..=size).contains(&x) && y.abs() <= size / 2.0,
+            TemplateShape::Line { length, width } => {
+                (0.0..=length).contains(&x) && y.abs() <= width / 2.0
+            }
+        }
+    }
+
+    // Outline of the template as a convex polygon.
+    pub fn outline(&self) -> Vec<ScenePoint> {
+        let arc = |radius: f32, from: f32, to: f32| {
+            (0..=ARC_SEGMENTS).map(move |i| {
+                let angle = (from + (to - from) * i as f32 / ARC_SEGMENTS as f32).to_radians();
+                ScenePoint::new(radius * angle.cos(), radius * angle.sin())
+            })
+        };
+
+        let local: Vec<ScenePoint> = match self.shape {
+            TemplateShape::Circle { radius } => arc(radius, 0.0, 360.0).skip(1).collect(),
+            TemplateShape::Cone { length, angle } => std::iter::once(ScenePoint::new(0.0, 0.0))
+                .chain(arc(length, -angle / 2.0, angle / 2.0))
+                .collect(),
+            TemplateShape::Cube { size } => rectangle(size, size),
+            TemplateShape::Line { length, width } => rectangle(length, width),
+        };
+
+        local.into_iter().map(|p| self.global(p)).collect()
+    }
+
+    pub fn bounding_rect(&self) -> Rect {
+        let outline = self.outline();
+        let min_x = outline.iter().map(|p| p.x).fold(f32::MAX, f32::min);
+        let min_y = outline.iter().map(|p| p.y).fold(f32::MAX, f32::min);
+        let max_x = outline.iter().map(|p| p.x).fold(f32::MIN, f32::max);
+        let max_y = outline.iter().map(|p| p.y).fold(f32::MIN, f32::max);
+        Rect::new(min_x, min_y, max_x - min_x, max_y - min_y)
+    }
+
+    // Centres of the grid cells covered by this template. A cell is covered
+    // if its centre lies within the template.
+    pub fn cells(&self, grid: &Grid) -> Vec<ScenePoint> {
+        grid.cells_in(self.bounding_rect())
+            .into_iter()
+            .filter(|&c| self.contains_point(c))
+            .collect()
+    }
+
+    // Whether any part of this template overlaps the sprite.
+    pub fn covers(&self, sprite: &Sprite) -> bool {
+        let rect = sprite.rect.positive_dimensions();
+        let Rect { x, y, w, h } = rect;
+        match self.shape {
+            TemplateShape::Circle { radius } => {
+                let nearest =
+                    ScenePoint::new(self.origin.x.clamp(x, x + w), self.origin.y.clamp(y, y + h));
+                let ScenePoint { x: dx, y: dy } = nearest - self.origin;
+                dx * dx + dy * dy <= radius * radius
+            }
+
+            // Either the sprite lies within the template, so that its corners
+            // do, or an edge of the template passes through the sprite.
+            _ => {
+                let corners = [(x, y), (x + w, y), (x, y + h), (x + w, y + h)];
+                let outline = self.outline();
+                corners
+                    .iter()
+                    .any(|&(x, y)| self.contains_point(ScenePoint::new(x, y)))
+                    || outline
+                        .iter()
+                        .zip(outline.iter().cycle().skip(1))
+                        .any(|(&a, &b)| rect.intersects_segment(a, b))
+            }
+        }
+    }
+}
+
+// Rectangle extending along the x axis with the origin at the centre of its
+// left side.
+fn rectangle(length: f32, width: f32) -> Vec<ScenePoint> {
+    vec![
+        ScenePoint::new(0.0, -width / 2.0),
+        ScenePoint::new(length, -width / 2.0),
+        ScenePoint::new(length, width / 2.0),
+        ScenePoint::new(0.0, width / 2.0),
+    ]
+}
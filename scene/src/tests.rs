@@ -16,3 +16,1557 @@ fn test_layer_move() {
     scene.unwind_event(event);
     assert_eq!(starting_zs, layer_zs(&scene));
 }
+
+#[test]
+fn test_token_vision() {
+    use crate::{Rect, ScenePoint, Sprite, TemplateShape, Wall};
+
+    let mut scene = Scene::new();
+    let layer = scene.first_layer();
+    let user = 7;
+
+    let mut token = Sprite::new(10, None, None);
+    token.owners = vec![user];
+    token.vision = Some(3.0);
+    scene.add_sprite(token, layer);
+
+    let mut near = Sprite::new(11, None, None);
+    near.set_rect(Rect::new(2.0, 0.0, 1.0, 1.0));
+    scene.add_sprite(near, layer);
+
+    let mut far = Sprite::new(12, None, None);
+    far.set_rect(Rect::new(10.0, 10.0, 1.0, 1.0));
+    scene.add_sprite(far, layer);
+
+    // Without fog of war everything is visible.
+    assert!(scene.visible_to(user).is_none());
+
+    scene.set_fog_of_war(true);
+    let visible = scene.visible_to(user).unwrap();
+    assert!(visible.contains(&10));
+    assert!(visible.contains(&11));
+    assert!(!visible.contains(&12));
+
+    // Another user sees nothing as they have no tokens.
+    assert!(scene.visible_to(8).unwrap().is_empty());
+
+    // A wall between the token and the near sprite blocks sight.
+    scene.walls.push(Wall::new(
+        ScenePoint::new(1.5, -5.0),
+        ScenePoint::new(1.5, 5.0),
+    ));
+    let visible = scene.visible_to(user).unwrap();
+    assert!(visible.contains(&10));
+    assert!(!visible.contains(&11));
+
+    // Templates are visible where their origin can be seen.
+    let seen = scene.new_template(
+        ScenePoint::new(0.5, 2.0),
+        TemplateShape::Circle { radius: 0.5 },
+        layer,
+        None,
+    );
+    let hidden = scene.new_template(
+        ScenePoint::new(2.5, 0.5),
+        TemplateShape::Circle { radius: 0.5 },
+        layer,
+        None,
+    );
+    let (seen, hidden) = (
+        seen.unwrap().item().unwrap(),
+        hidden.unwrap().item().unwrap(),
+    );
+
+    // Combatants for hidden tokens are redacted.
+    scene.new_combatant("Goblin".to_string(), Some(11), None);
+    scene.new_combatant("Hero".to_string(), Some(10), None);
+
+    let visible = scene.visible_to(user).unwrap();
+    assert!(visible.contains(&seen));
+    assert!(!visible.contains(&hidden));
+
+    let filtered = scene.filtered(&visible);
+    assert!(filtered.sprite_ref(10).is_some());
+    assert!(filtered.sprite_ref(11).is_none());
+    assert!(filtered.template_ref(seen).is_some());
+    assert!(filtered.template_ref(hidden).is_none());
+    let names = filtered
+        .initiative
+        .combatants
+        .iter()
+        .map(|c| (c.name.as_str(), c.sprite))
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec![("Unknown", None), ("Hero", Some(10))]);
+
+    // Walls are changed through an event, which can be unwound.
+    let event = scene.set_walls(vec![]).unwrap();
+    assert!(scene.visible_to(user).unwrap().contains(&11));
+    scene.unwind_event(event);
+    assert!(!scene.visible_to(user).unwrap().contains(&11));
+
+    // Points, such as those of rulers, are seen in the same way.
+    assert!(scene.point_visible_to(user, ScenePoint::new(0.5, 2.0)));
+    assert!(!scene.point_visible_to(user, ScenePoint::new(2.5, 0.5)));
+    assert!(!scene.point_visible_to(8, ScenePoint::new(0.5, 2.0)));
+}
+
+#[test]
+fn test_hex_grid() {
+    use crate::{Grid, GridType, Rect, ScenePoint, Sprite};
+
+    let mut scene = Scene::new();
+    let event = scene.set_grid(Grid::new(GridType::HexPointy)).unwrap();
+    assert_eq!(scene.grid.kind, GridType::HexPointy);
+
+    // Adjacent hexes are one tile apart, in any direction.
+    let origin = ScenePoint::new(0.0, 0.0);
+    let right = ScenePoint::new(1.0, 0.0);
+    let below = ScenePoint::new(0.5, 0.866);
+    assert_eq!(scene.distance(origin, right), 1.0);
+    assert_eq!(scene.distance(origin, below), 1.0);
+    assert_eq!(scene.distance(right, ScenePoint::new(-1.0, 0.0)), 2.0);
+    assert_eq!(scene.distance(ScenePoint::new(0.1, 0.1), below), 1.0);
+
+    let mut sprite = Sprite::new(1, None, None);
+    sprite.set_rect(Rect::new(0.1, 0.5, 1.0, 1.0));
+    sprite.snap_pos(&scene.grid);
+    assert!((sprite.centre().x - 0.5).abs() < 0.001);
+    assert!((sprite.centre().y - 0.866).abs() < 0.001);
+
+    let cells = GridType::HexFlat.cells_in(Rect::new(0.0, 0.0, 2.0, 2.0));
+    assert!(cells.contains(&ScenePoint::new(0.0, 0.0)));
+    assert!(cells.contains(&ScenePoint::new(0.0, 1.0)));
+    assert_eq!(GridType::HexFlat.cell_outline(cells[0]).len(), 6);
+
+    // Gridless scenes don't snap and measure straight line distance.
+    scene.unwind_event(event);
+    assert_eq!(scene.grid.kind, GridType::Square);
+    scene.set_grid(Grid::new(GridType::Gridless));
+    let before = sprite.rect;
+    sprite.snap_size(&scene.grid);
+    assert_eq!(sprite.rect, before);
+    assert_eq!(scene.distance(origin, ScenePoint::new(3.0, 4.0)), 5.0);
+}
+
+#[test]
+fn test_grid_alignment() {
+    use crate::{comms::SceneEvent, Grid, Rect, ScenePoint, Sprite};
+
+    let mut scene = Scene::new();
+    let grid = Grid {
+        size: 1.5,
+        offset: ScenePoint::new(0.25, 0.5),
+        ..Default::default()
+    };
+    let event = scene.set_grid(grid).unwrap();
+
+    // Sprites snap to the corners of the scaled and offset cells.
+    let mut sprite = Sprite::new(1, None, None);
+    sprite.set_rect(Rect::new(1.9, 2.2, 1.6, 1.4));
+    sprite.snap_size(&scene.grid);
+    assert_eq!(sprite.rect, Rect::new(1.75, 2.0, 1.5, 1.5));
+
+    // Distances are measured in cells rather than scene units.
+    assert_eq!(
+        scene.distance(ScenePoint::new(0.25, 0.5), ScenePoint::new(4.75, 0.5)),
+        3.0
+    );
+    assert_eq!(
+        scene.grid.cell_centre(ScenePoint::new(2.0, 2.0)),
+        ScenePoint::new(2.5, 2.75)
+    );
+
+    // Degenerate grids are prevented.
+    let resize = scene.set_grid(Grid { size: 0.0, ..grid }).unwrap();
+    assert_eq!(scene.grid.size, Grid::MIN_SIZE);
+    let infinite = Grid {
+        size: f32::INFINITY,
+        ..scene.grid
+    };
+    assert!(scene.set_grid(infinite).is_none());
+
+    // Invalid grids from other clients are rejected rather than stored.
+    for size in [0.0, 0.01, f32::NAN, f32::INFINITY] {
+        let event = SceneEvent::SceneGrid(scene.grid, Grid { size, ..grid });
+        assert!(!scene.apply_event(event));
+    }
+    assert_eq!(scene.grid.size, Grid::MIN_SIZE);
+
+    scene.unwind_event(resize);
+    scene.unwind_event(event);
+    assert_eq!(scene.grid, Grid::default());
+}
+
+#[test]
+fn test_distance_rules() {
+    use crate::{DistanceRule, DistanceUnit, Grid, GridType, ScenePoint};
+
+    let p = ScenePoint::new;
+    let diagonal = [p(0.5, 0.5), p(3.5, 3.5)];
+    let knight = [p(0.5, 0.5), p(2.5, 1.5)];
+
+    let mut grid = Grid::default();
+    assert!((grid.path_distance(&diagonal) - 18.0_f32.sqrt()).abs() < 0.001);
+    assert_eq!(grid.path_distance(&[p(0.0, 0.0), p(3.0, 4.0)]), 5.0);
+
+    grid.rule = DistanceRule::Chebyshev;
+    assert_eq!(grid.path_distance(&diagonal), 3.0);
+    assert_eq!(grid.path_distance(&knight), 2.0);
+
+    // 5ft, 10ft, 5ft.
+    grid.rule = DistanceRule::Alternating;
+    assert_eq!(grid.path_distance(&diagonal), 4.0);
+    assert_eq!(grid.path_length(&diagonal), 20.0);
+    assert_eq!(grid.path_distance(&knight), 2.0);
+
+    // Diagonal parity carries across waypoints; the second diagonal is the
+    // second leg's first, so costs double.
+    let path = [p(0.5, 0.5), p(1.5, 1.5), p(2.5, 2.5)];
+    assert_eq!(grid.path_distance(&path), 3.0);
+    let path = [p(0.5, 0.5), p(1.5, 1.5), p(4.5, 1.5), p(5.5, 2.5)];
+    assert_eq!(grid.path_distance(&path), 6.0);
+    assert_eq!(grid.format_length(&path), "30 ft");
+
+    grid.units_per_tile = 1.5;
+    grid.unit = DistanceUnit::Metres;
+    assert_eq!(grid.format_length(&path), "9 m");
+
+    // Distance rules apply only to square grids.
+    grid.kind = GridType::HexPointy;
+    assert_eq!(grid.path_distance(&[p(0.0, 0.0), p(2.0, 0.0)]), 2.0);
+    grid.kind = GridType::Gridless;
+    assert_eq!(grid.path_distance(&[p(0.0, 0.0), p(3.0, 4.0)]), 5.0);
+
+    // Cell size scales measurements.
+    grid.kind = GridType::Square;
+    grid.size = 2.0;
+    assert_eq!(grid.path_distance(&[p(0.5, 0.5), p(6.5, 0.5)]), 3.0);
+}
+
+#[test]
+fn test_templates() {
+    use crate::{
+        comms::SceneEvent,
+        perms::{Override, Perm, Perms, Role, CANONICAL_UPDATER},
+        Rect, ScenePoint, Sprite, TemplateShape,
+    };
+
+    let mut scene = Scene::new();
+    scene.canon();
+    let layer = scene.first_layer();
+    let p = ScenePoint::new;
+
+    let mut sprite = Sprite::new(10, None, None);
+    sprite.set_rect(Rect::new(6.0, 5.0, 1.0, 1.0));
+    scene.add_sprite(sprite, layer);
+
+    let new = scene
+        .new_template(
+            p(5.0, 5.0),
+            TemplateShape::Cube { size: 2.0 },
+            layer,
+            Some(2),
+        )
+        .unwrap();
+    let id = new.item().unwrap();
+    assert_eq!(scene.event_layer(&new), Some(layer));
+
+    // A cube extends from the centre of one side.
+    let cells = scene.template_cells(id);
+    assert_eq!(cells.len(), 4);
+    assert!(cells.contains(&p(6.5, 4.5)));
+    assert!(!cells.contains(&p(4.5, 4.5)));
+    assert_eq!(scene.template_sprites(id), vec![10]);
+
+    // Rotating to face away from the sprite uncovers it.
+    let rotate = scene.template(id).unwrap().face(p(0.0, 5.0));
+    assert!(scene.template_sprites(id).is_empty());
+    assert!(scene.template_cells(id).contains(&p(4.5, 4.5)));
+
+    let shape = scene
+        .template(id)
+        .unwrap()
+        .set_shape(TemplateShape::Circle { radius: 1.5 });
+    assert_eq!(scene.template_cells(id).len(), 4);
+    assert_eq!(scene.template_sprites(id), vec![10]);
+
+    let cone = TemplateShape::Cone {
+        length: 3.0,
+        angle: 90.0,
+    };
+    scene.template(id).unwrap().set_shape(cone);
+    scene.template(id).unwrap().face(p(5.0, 10.0));
+    assert!(scene.template_ref(id).unwrap().contains_point(p(5.0, 7.0)));
+    assert!(!scene.template_ref(id).unwrap().contains_point(p(7.0, 5.0)));
+    assert!(scene.template_at(p(5.5, 6.0)).is_some());
+
+    // Stale events are rejected.
+    assert!(!scene.apply_event(shape.clone()));
+
+    scene
+        .template(id)
+        .unwrap()
+        .set_shape(TemplateShape::Circle { radius: 1.5 });
+    scene.unwind_event(shape);
+    scene.template(id).unwrap().set_rotation(180.0);
+    scene.unwind_event(rotate);
+    let template = scene.template_ref(id).unwrap();
+    assert_eq!(template.shape, TemplateShape::Cube { size: 2.0 });
+    assert_eq!(template.rotation, 0.0);
+
+    let remove = scene.remove_template(id).unwrap();
+    assert!(scene.template_ref(id).is_none());
+    assert!(scene.template_sprites(id).is_empty());
+    scene.unwind_event(remove);
+    assert!(scene.template_ref(id).is_some());
+
+    // Players may only change their own templates, unless granted an
+    // override.
+    let mut perms = Perms::new();
+    perms.role_change(CANONICAL_UPDATER, 2, Role::Player);
+    perms.role_change(CANONICAL_UPDATER, 3, Role::Player);
+    perms.role_change(CANONICAL_UPDATER, 4, Role::Editor);
+    let permitted = |perms: &Perms, user, event: &SceneEvent| {
+        perms.permitted(
+            user,
+            event,
+            scene.event_layer(event),
+            &scene.event_owners(event),
+            0,
+            &Default::default(),
+        )
+    };
+    let drag = SceneEvent::TemplateMove(id, p(5.0, 5.0), p(6.0, 6.0));
+    assert!(permitted(&perms, 2, &drag));
+    assert!(!permitted(&perms, 3, &drag));
+    assert!(permitted(&perms, 4, &drag));
+    assert!(!permitted(&perms, 3, &new));
+    perms.new_override(
+        CANONICAL_UPDATER,
+        Override::new(3, Perm::Template, Some(id), None),
+    );
+    assert!(permitted(&perms, 3, &drag));
+
+    scene.unwind_event(new);
+    assert!(scene.template_ref(id).is_none());
+
+    // Thin lines and narrow cones cover sprites which they cross between the
+    // sprite's corners.
+    let line = scene
+        .new_template(
+            p(6.2, 0.0),
+            TemplateShape::Line {
+                length: 10.0,
+                width: 0.1,
+            },
+            layer,
+            None,
+        )
+        .unwrap()
+        .item()
+        .unwrap();
+    scene.template(line).unwrap().set_rotation(90.0);
+    assert_eq!(scene.template_sprites(line), vec![10]);
+    scene.template(line).unwrap().set_origin(p(7.2, 0.0));
+    assert!(scene.template_sprites(line).is_empty());
+
+    let narrow = TemplateShape::Cone {
+        length: 20.0,
+        angle: 0.5,
+    };
+    let cone = scene
+        .new_template(p(6.2, -10.0), narrow, layer, None)
+        .unwrap()
+        .item()
+        .unwrap();
+    scene.template(cone).unwrap().set_rotation(90.0);
+    assert_eq!(scene.template_sprites(cone), vec![10]);
+}
+
+#[test]
+fn test_dice() {
+    use crate::dice::{Dice, Expression, Keep, Term};
+    use std::convert::Infallible;
+
+    // Deterministic die which returns each of these values in turn.
+    fn die(values: &[u32]) -> impl FnMut(u32) -> Result<u32, Infallible> + '_ {
+        let mut i = 0;
+        move |sides| {
+            let value = values[i % values.len()].min(sides);
+            i += 1;
+            Ok(value)
+        }
+    }
+
+    let expr = Expression::parse("4d6kh3 + 2").unwrap();
+    assert_eq!(
+        expr.terms[0],
+        (
+            false,
+            Term::Dice(Dice {
+                count: 4,
+                sides: 6,
+                keep: Some(Keep::Highest(3)),
+                explode: false,
+                reroll: None
+            })
+        )
+    );
+    let roll = expr.roll(die(&[6, 5, 2, 4])).unwrap();
+    assert_eq!(roll.total, 17);
+    assert_eq!(roll.breakdown(), "4d6kh3 [6, 5, (2), 4] + 2");
+
+    let roll = Expression::parse("2D20KL1")
+        .unwrap()
+        .roll(die(&[17, 3]))
+        .unwrap();
+    assert_eq!(roll.total, 3);
+    assert_eq!(roll.expression, "2d20kl1");
+
+    // Each maximum adds another die.
+    let roll = Expression::parse("2d6!")
+        .unwrap()
+        .roll(die(&[6, 2, 6, 1]))
+        .unwrap();
+    assert_eq!(roll.total, 15);
+    assert_eq!(roll.breakdown(), "2d6! [6, 2, 6!, 1!]");
+
+    // Explosions are capped for dice which always explode.
+    let roll = Expression::parse("1d6!").unwrap().roll(die(&[6])).unwrap();
+    assert!(roll.total > 6 && roll.total < 1000);
+
+    // Rerolls happen once, keeping the second result.
+    let roll = Expression::parse("2d6r2")
+        .unwrap()
+        .roll(die(&[1, 2, 5]))
+        .unwrap();
+    assert_eq!(roll.total, 7);
+    assert_eq!(roll.breakdown(), "2d6r2 [1>2, 5]");
+
+    let roll = Expression::parse("-d% - 3 + 10")
+        .unwrap()
+        .roll(die(&[40]))
+        .unwrap();
+    assert_eq!(roll.total, -33);
+    assert_eq!(roll.expression, "- 1d100 - 3 + 10");
+
+    for bad in [
+        "", "d", "2d", "1d20 +", "1d20 x 2", "0d6", "1d1!", "1d6r6", "1000d6",
+    ] {
+        assert!(Expression::parse(bad).is_err(), "{bad} should not parse");
+    }
+}
+
+#[test]
+fn test_initiative() {
+    use crate::{
+        comms::SceneEvent,
+        perms::{Perms, Role, CANONICAL_UPDATER},
+        Sprite,
+    };
+
+    let mut scene = Scene::new();
+    scene.canon();
+    let layer = scene.first_layer();
+
+    let mut sprite = Sprite::new(10, None, None);
+    sprite.set_owners(vec![2]);
+    scene.add_sprite(sprite, layer);
+
+    let goblin = scene
+        .new_combatant("Goblin".to_string(), None, Some(12))
+        .unwrap()
+        .item()
+        .unwrap();
+    let add_hero = scene
+        .new_combatant("Hero".to_string(), Some(10), None)
+        .unwrap();
+    let hero = add_hero.item().unwrap();
+    let orc = scene
+        .new_combatant("Orc".to_string(), None, Some(15))
+        .unwrap()
+        .item()
+        .unwrap();
+
+    let order = |scene: &Scene| {
+        scene
+            .initiative
+            .combatants
+            .iter()
+            .map(|c| c.id)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(order(&scene), vec![orc, goblin, hero]);
+    assert!(scene.initiative.current().is_none());
+
+    // Only editors control turns, while players set their own initiative.
+    let mut perms = Perms::new();
+    perms.role_change(CANONICAL_UPDATER, 2, Role::Player);
+    perms.role_change(CANONICAL_UPDATER, 3, Role::Player);
+    perms.role_change(CANONICAL_UPDATER, 4, Role::Editor);
+    let set = SceneEvent::InitiativeSet(hero, None, Some(14));
+    let owners = scene.event_owners(&set);
+    assert_eq!(owners, vec![2]);
+    assert!(perms.permitted(2, &set, None, &owners, 0, &Default::default()));
+    assert!(!perms.permitted(3, &set, None, &owners, 0, &Default::default()));
+    assert!(perms.permitted(4, &set, None, &owners, 0, &Default::default()));
+    let advance = SceneEvent::InitiativeAdvance(0, 0);
+    assert!(!perms.permitted(
+        2,
+        &advance,
+        None,
+        &scene.event_owners(&advance),
+        0,
+        &Default::default()
+    ));
+    assert!(perms.permitted(
+        4,
+        &advance,
+        None,
+        &scene.event_owners(&advance),
+        0,
+        &Default::default()
+    ));
+
+    // Setting initiative places the combatant after any it ties with.
+    assert!(scene.apply_event(set));
+    assert!(scene.apply_event(SceneEvent::InitiativeSet(goblin, Some(12), Some(14))));
+    assert_eq!(order(&scene), vec![orc, hero, goblin]);
+    assert!(!scene.apply_event(SceneEvent::InitiativeSet(goblin, Some(12), Some(1))));
+
+    assert!(scene.apply_event(SceneEvent::InitiativeAdvance(0, 0)));
+    assert_eq!(scene.initiative.round, 1);
+    assert_eq!(scene.initiative.current().unwrap().id, orc);
+    scene.initiative.advance();
+    scene.initiative.advance();
+    assert_eq!(scene.initiative.current().unwrap().id, goblin);
+    let advance = scene.initiative.advance().unwrap();
+    assert_eq!(scene.initiative.round, 2);
+    assert_eq!(scene.initiative.current().unwrap().id, orc);
+    scene.unwind_event(advance.clone());
+    assert_eq!(scene.initiative.round, 1);
+    assert_eq!(scene.initiative.current().unwrap().id, goblin);
+
+    // Stale turn changes are rejected, and aren't unwound once the turn has
+    // moved on.
+    assert!(!scene.apply_event(SceneEvent::InitiativeAdvance(0, 0)));
+    assert!(scene.unwind_event(advance).is_none());
+    assert_eq!(scene.initiative.current().unwrap().id, goblin);
+
+    // The turn stays with the current combatant when the order changes.
+    let reorder = scene.initiative.move_to(goblin, 0).unwrap();
+    assert_eq!(order(&scene), vec![goblin, orc, hero]);
+    assert_eq!(scene.initiative.current().unwrap().id, goblin);
+    scene.unwind_event(reorder);
+    assert_eq!(order(&scene), vec![orc, hero, goblin]);
+    assert_eq!(scene.initiative.current().unwrap().id, goblin);
+
+    // Removing the current combatant passes the turn on, and undoing the
+    // removal gives it back.
+    let back = scene.initiative.back().unwrap();
+    let remove = scene.initiative.remove(hero).unwrap();
+    assert_eq!(scene.initiative.current().unwrap().id, goblin);
+    let restore = scene.unwind_event(remove).unwrap();
+    assert_eq!(order(&scene), vec![orc, hero, goblin]);
+    assert_eq!(scene.initiative.current().unwrap().id, hero);
+
+    // Removing the last combatant on their turn starts the next round.
+    scene.unwind_event(back);
+    let remove = scene.initiative.remove(goblin).unwrap();
+    assert_eq!(scene.initiative.round, 2);
+    assert_eq!(scene.initiative.current().unwrap().id, orc);
+    scene.unwind_event(remove);
+    assert_eq!(scene.initiative.round, 1);
+    assert_eq!(scene.initiative.current().unwrap().id, goblin);
+
+    // Once the turn has moved on, a restored combatant doesn't take it back.
+    scene.initiative.remove(hero);
+    scene.initiative.back();
+    assert!(scene.apply_event(restore));
+    assert_eq!(order(&scene), vec![orc, hero, goblin]);
+    assert_eq!(scene.initiative.current().unwrap().id, orc);
+
+    scene.unwind_event(add_hero);
+    assert!(scene.initiative.combatant(hero).is_none());
+    assert_eq!(scene.initiative.current().unwrap().id, orc);
+
+    // Going back past the first turn ends combat.
+    scene.initiative.back();
+    assert_eq!(scene.initiative.round, 0);
+    assert!(scene.initiative.back().is_none());
+}
+
+#[test]
+fn test_token_attributes() {
+    use crate::{
+        comms::SceneEvent,
+        perms::{Perms, Role, CANONICAL_UPDATER},
+        Sprite, Token,
+    };
+
+    let mut scene = Scene::new();
+    scene.canon();
+    let layer = scene.first_layer();
+
+    let mut sprite = Sprite::new(10, None, None);
+    sprite.set_owners(vec![2]);
+    scene.add_sprite(sprite, layer);
+
+    let token = scene.sprite(10).unwrap();
+    assert!(token.token.is_empty());
+    assert!(token.adjust_hp(-1).is_none());
+    let name = token.set_name(Some("Hero".to_string()));
+    token.set_hp(Some(10));
+    token.set_max_hp(Some(12));
+    token.set_temp_hp(3);
+
+    // Temporary HP is lost first and healing stops at the maximum.
+    let damage = token.damage(5).unwrap();
+    assert_eq!((token.token.hp, token.token.temp_hp), (Some(8), 0));
+    token.damage(-10);
+    assert_eq!(token.token.hp, Some(12));
+    assert_eq!(token.token.health(), Some(1.0));
+    // Undoing damage taken after healing to full doesn't exceed the maximum.
+    scene.unwind_event(damage);
+    let token = &scene.sprite_ref(10).unwrap().token;
+    assert_eq!((token.hp, token.temp_hp), (Some(12), 3));
+
+    // Undoing a capped heal removes only the HP gained, and undoing damage
+    // taken through temporary HP restores both.
+    let token = scene.sprite(10).unwrap();
+    token.set_hp(Some(10));
+    let heal = token.adjust_hp(5).unwrap();
+    assert_eq!(token.token.hp, Some(12));
+    scene.unwind_event(heal);
+    assert_eq!(scene.sprite_ref(10).unwrap().token.hp, Some(10));
+    let damage = scene.sprite(10).unwrap().damage(5).unwrap();
+    let token = &scene.sprite_ref(10).unwrap().token;
+    assert_eq!((token.hp, token.temp_hp), (Some(8), 0));
+    scene.unwind_event(damage);
+    let token = &scene.sprite_ref(10).unwrap().token;
+    assert_eq!((token.hp, token.temp_hp), (Some(10), 3));
+    scene.sprite(10).unwrap().set_hp(Some(12));
+
+    // Concurrent HP changes both apply, while stale sets are rejected.
+    assert!(scene.apply_event(SceneEvent::SpriteHpDelta(10, -4)));
+    assert!(scene.apply_event(SceneEvent::SpriteHpDelta(10, -4)));
+    assert_eq!(scene.sprite_ref(10).unwrap().token.hp, Some(4));
+    assert!(!scene.apply_event(SceneEvent::SpriteHp(10, Some(12), Some(1))));
+    assert!(scene.apply_event(SceneEvent::SpriteHp(10, Some(4), Some(20))));
+    assert_eq!(scene.sprite_ref(10).unwrap().token.hp, Some(12));
+
+    // Overly long text is rejected.
+    let long_name = "x".repeat(Token::MAX_NAME_LENGTH + 1);
+    let rename = SceneEvent::SpriteName(10, Some("Hero".to_string()), Some(long_name));
+    assert!(!scene.apply_event(rename));
+
+    let token = scene.sprite(10).unwrap();
+    let prone = token.add_condition("Prone".to_string()).unwrap();
+    assert!(token.add_condition("Prone".to_string()).is_none());
+    assert!(token.add_condition("Two\nLines".to_string()).is_none());
+    let long = "x".repeat(Token::MAX_FIELD_VALUE_LENGTH + 1);
+    assert!(token.add_condition(long.clone()).is_none());
+    assert!(token
+        .set_field("Notes".to_string(), Some(long.clone()))
+        .is_none());
+    assert!(token
+        .set_field(long.clone(), Some("1".to_string()))
+        .is_none());
+    for i in 1..Token::MAX_CONDITIONS {
+        token.add_condition(format!("Condition {i}")).unwrap();
+    }
+    assert!(token.add_condition("One too many".to_string()).is_none());
+    token.token.conditions.truncate(1);
+    let ac = token
+        .set_field("AC".to_string(), Some("15".to_string()))
+        .unwrap();
+    assert!(token
+        .set_field("AC".to_string(), Some("15".to_string()))
+        .is_none());
+    scene.unwind_event(prone);
+    scene.unwind_event(ac);
+    scene.unwind_event(name);
+    let token = &scene.sprite_ref(10).unwrap().token;
+    assert!(token.conditions.is_empty());
+    assert!(token.fields.is_empty());
+    assert!(token.name.is_none());
+
+    // Players may only edit their own tokens.
+    let mut perms = Perms::new();
+    perms.role_change(CANONICAL_UPDATER, 2, Role::Player);
+    perms.role_change(CANONICAL_UPDATER, 3, Role::Player);
+    perms.role_change(CANONICAL_UPDATER, 4, Role::Editor);
+    let event = SceneEvent::SpriteHpDelta(10, -1);
+    let owners = scene.event_owners(&event);
+    assert!(perms.permitted(2, &event, Some(layer), &owners, 0, &Default::default()));
+    assert!(!perms.permitted(3, &event, Some(layer), &owners, 0, &Default::default()));
+    assert!(perms.permitted(4, &event, Some(layer), &owners, 0, &Default::default()));
+}
+
+#[test]
+fn test_sprite_owners() {
+    use crate::{
+        comms::SceneEvent,
+        perms::{Perms, Role, CANONICAL_UPDATER},
+        Rect, ScenePoint, Sprite,
+    };
+
+    let mut scene = Scene::new();
+    scene.canon();
+    let layer = scene.first_layer();
+    scene.layer(layer).unwrap().set_locked(true);
+
+    let mut sprite = Sprite::new(10, None, None);
+    sprite.set_rect(Rect::new(0.0, 0.0, 1.0, 1.0));
+    scene.add_sprite(sprite, layer);
+
+    // A token may be shared between several players.
+    let event = scene.sprite(10).unwrap().set_owners(vec![2, 3]);
+    assert!(scene.sprite_ref(10).unwrap().owned_by(3));
+    scene.unwind_event(event.clone());
+    assert!(scene.sprite_ref(10).unwrap().owners.is_empty());
+    assert!(scene.apply_event(event.clone()));
+    assert!(!scene.apply_event(event));
+
+    // Owners may grab their tokens even on locked layers.
+    let at = ScenePoint::new(0.5, 0.5);
+    assert!(scene.sprite_at_ref(at, 2).is_some());
+    assert!(scene.sprite_at_ref(at, 4).is_none());
+
+    // Owners may move their tokens regardless of layer permissions, while
+    // other players may not move them at all.
+    let mut perms = Perms::new();
+    for user in [2, 3, 4] {
+        perms.role_change(CANONICAL_UPDATER, user, Role::Player);
+    }
+    perms.role_change(CANONICAL_UPDATER, 5, Role::Editor);
+    let event = SceneEvent::SpriteMove(
+        10,
+        Rect::new(0.0, 0.0, 1.0, 1.0),
+        Rect::new(1.0, 0.0, 1.0, 1.0),
+    );
+    let owners = scene.event_owners(&event);
+    assert!(perms.permitted(2, &event, Some(layer), &owners, 0, &Default::default()));
+    assert!(perms.permitted(3, &event, Some(layer), &owners, 0, &Default::default()));
+    assert!(!perms.permitted(4, &event, Some(layer), &owners, 0, &Default::default()));
+    assert!(perms.permitted(5, &event, Some(layer), &owners, 0, &Default::default()));
+
+    // Only editors may change who controls a token.
+    let event = SceneEvent::SpriteOwners(10, vec![2, 3], vec![4]);
+    assert!(!perms.permitted(2, &event, Some(layer), &owners, 0, &Default::default()));
+    assert!(perms.permitted(5, &event, Some(layer), &owners, 0, &Default::default()));
+}
+
+#[test]
+fn test_perms_overrides() {
+    use crate::{
+        comms::{PermsEvent, SceneEvent},
+        perms::{Expiry, Override, Perm, PermSet, Perms, Role, CANONICAL_UPDATER},
+        Combatant, Initiative, Rect,
+    };
+
+    let mut perms = Perms::new();
+    perms.role_change(CANONICAL_UPDATER, 2, Role::Player);
+    perms.role_change(CANONICAL_UPDATER, 3, Role::Editor);
+
+    let boulder = 10;
+    let event = SceneEvent::SpriteRemove(boulder);
+    assert!(!perms.permitted(2, &event, None, &[], 0, &Default::default()));
+
+    // Players can't grant themselves permissions.
+    let grant = Override::new(2, Perm::SpriteRemove, Some(boulder), None);
+    assert!(perms.new_override(2, grant.clone()).is_none());
+    assert!(perms.new_override(3, grant.clone()).is_some());
+    assert!(perms.permitted(2, &event, None, &[], 0, &Default::default()));
+    assert!(!perms.permitted(
+        2,
+        &SceneEvent::SpriteRemove(11),
+        None,
+        &[],
+        0,
+        &Default::default()
+    ));
+    assert_eq!(
+        perms.user_perms(2, 0, &Default::default()).overrides,
+        vec![grant.clone()]
+    );
+
+    // Overrides may be revoked by editors, but only once.
+    assert!(!perms.handle_event(2, PermsEvent::RevokeOverride(grant.clone())));
+    assert!(perms.handle_event(3, PermsEvent::RevokeOverride(grant.clone())));
+    assert!(!perms.handle_event(3, PermsEvent::RevokeOverride(grant)));
+    assert!(!perms.permitted(2, &event, None, &[], 0, &Default::default()));
+
+    // Temporary overrides lapse at the end of a combatant's turn or at a set
+    // time.
+    let mut initiative = Initiative::default();
+    initiative.add(Combatant::new(20, "Hero".to_string(), None, Some(2)), 0);
+    initiative.add(Combatant::new(21, "Orc".to_string(), None, None), 1);
+    initiative.advance();
+    initiative.advance();
+    let turn = Override::new(2, Perm::SpriteRemove, None, Some(Expiry::Turn(21, 1)));
+    let timed = Override::new(2, Perm::SpriteVision, None, Some(Expiry::Time(100)));
+    perms.new_override(3, turn.clone());
+    perms.new_override(3, timed.clone());
+
+    // Expired overrides no longer apply, even before they are revoked.
+    let remove = SceneEvent::SpriteRemove(11);
+    let vision = SceneEvent::SpriteVision(11, None, Some(5.0));
+    assert!(perms.permitted(2, &vision, None, &[], 99, &initiative));
+    assert!(!perms.permitted(2, &vision, None, &[], 100, &initiative));
+    assert_eq!(
+        perms.user_perms(2, 100, &initiative).overrides,
+        vec![turn.clone()]
+    );
+    assert!(perms.permitted(2, &remove, None, &[], 50, &initiative));
+    assert!(perms.expire_overrides(50, &initiative).is_empty());
+
+    // Going back a turn or moving the combatant doesn't end the turn early.
+    initiative.back();
+    assert!(perms.expire_overrides(50, &initiative).is_empty());
+    initiative.advance();
+    initiative.move_to(21, 0);
+    assert!(perms.expire_overrides(50, &initiative).is_empty());
+    initiative.advance();
+    assert_eq!(
+        perms.expire_overrides(50, &initiative),
+        vec![PermsEvent::RevokeOverride(turn)]
+    );
+    assert_eq!(
+        perms.expire_overrides(100, &initiative),
+        vec![PermsEvent::RevokeOverride(timed)]
+    );
+    assert!(perms
+        .user_perms(2, 0, &Default::default())
+        .overrides
+        .is_empty());
+
+    // Resetting a layer's permissions restores the role defaults.
+    let layer = 1;
+    let movement = SceneEvent::SpriteMove(
+        boulder,
+        Rect::new(0.0, 0.0, 1.0, 1.0),
+        Rect::new(1.0, 1.0, 1.0, 1.0),
+    );
+    perms.item_perms(3, PermSet::new(layer, vec![], Role::Editor));
+    assert!(!perms.permitted(2, &movement, Some(layer), &[], 0, &Default::default()));
+    assert_eq!(
+        perms.user_perms(2, 0, &Default::default()).denied,
+        vec![layer]
+    );
+    assert!(perms.reset_item_perms(2, layer).is_none());
+    assert!(perms.handle_event(3, PermsEvent::ResetItemPerms(layer)));
+    assert!(perms.permitted(2, &movement, Some(layer), &[], 0, &Default::default()));
+
+    let user = perms.user_perms(2, 0, &Default::default());
+    assert_eq!(user.role, Role::Player);
+    assert!(user.perms.contains(&Perm::SpriteUpdate));
+    assert!(!user.perms.contains(&Perm::SpriteRemove));
+    assert!(user.denied.is_empty());
+}
+
+#[test]
+fn test_layer_rules() {
+    use crate::{
+        comms::SceneEvent,
+        perms::{Action, ItemRule, PermSet, Perms, Role, Subject, CANONICAL_UPDATER},
+        Rect, Sprite, SpriteShape,
+    };
+
+    let (owner, player, other, spectator, editor) = (1, 2, 3, 4, 5);
+    let mut perms = Perms::new();
+    perms.set_owner(owner);
+    perms.role_change(CANONICAL_UPDATER, player, Role::Player);
+    perms.role_change(CANONICAL_UPDATER, other, Role::Player);
+    perms.role_change(CANONICAL_UPDATER, editor, Role::Editor);
+
+    let (layer, sprite) = (1, 10);
+    let rect = Rect::new(0.0, 0.0, 1.0, 1.0);
+    let events = [
+        (
+            Action::Add,
+            SceneEvent::SpriteNew(Sprite::new(sprite, None, None), layer),
+        ),
+        (Action::Edit, SceneEvent::SpriteHpDelta(sprite, -1)),
+        (Action::Layer, SceneEvent::SpriteLayer(sprite, layer, layer)),
+        (
+            Action::Move,
+            SceneEvent::SpriteMove(sprite, rect, rect + Rect::new(1.0, 1.0, 0.0, 0.0)),
+        ),
+        (Action::Remove, SceneEvent::SpriteRemove(sprite)),
+        (Action::Reorder, SceneEvent::SpriteZ(sprite, None, Some(11))),
+        (
+            Action::Resize,
+            SceneEvent::SpriteMove(sprite, rect, rect + Rect::new(0.0, 0.0, 1.0, 1.0)),
+        ),
+        (
+            Action::Restyle,
+            SceneEvent::SpriteShape(sprite, SpriteShape::Rectangle, SpriteShape::Ellipse),
+        ),
+    ];
+
+    // Every combination of rule for the player's role and for the player
+    // themselves, for every action.
+    let options = [None, Some(true), Some(false)];
+    for (action, event) in &events {
+        assert_eq!(Action::of(event), Some(*action));
+        perms.item_perms(CANONICAL_UPDATER, PermSet::new(layer, vec![], Role::Player));
+        let default = perms.permitted(player, event, Some(layer), &[], 0, &Default::default());
+        for role_rule in options {
+            for user_rule in options {
+                let mut ps = PermSet::new(layer, vec![], Role::Player);
+                ps.set_rule(*action, Subject::Role(Role::Player), role_rule);
+                ps.set_rule(*action, Subject::User(player), user_rule);
+                perms.item_perms(CANONICAL_UPDATER, ps);
+
+                let permitted =
+                    |user| perms.permitted(user, event, Some(layer), &[], 0, &Default::default());
+                let expected = user_rule.or(role_rule).unwrap_or(default);
+                assert_eq!(
+                    permitted(player),
+                    expected,
+                    "{action:?} {role_rule:?} {user_rule:?}"
+                );
+                assert_eq!(permitted(other), role_rule.unwrap_or(default));
+                assert!(!permitted(spectator));
+                assert!(permitted(editor));
+                assert!(permitted(owner));
+
+                let rules = user_rule.or(role_rule).map(|allow| ItemRule {
+                    item: layer,
+                    action: *action,
+                    allow,
+                });
+                assert_eq!(
+                    perms.user_perms(player, 0, &Default::default()).rules,
+                    Vec::from_iter(rules)
+                );
+            }
+        }
+    }
+
+    // Without rules, players may move, reorder, resize and restyle sprites but
+    // not add, remove, edit or move them between layers.
+    perms.item_perms(CANONICAL_UPDATER, PermSet::new(layer, vec![], Role::Player));
+    let allowed = events
+        .iter()
+        .filter(|(_, e)| perms.permitted(player, e, Some(layer), &[], 0, &Default::default()))
+        .map(|(a, _)| *a)
+        .collect::<Vec<Action>>();
+    assert_eq!(
+        allowed,
+        vec![
+            Action::Move,
+            Action::Reorder,
+            Action::Resize,
+            Action::Restyle
+        ]
+    );
+
+    // Rules may restrict editors and spectators too, but never the owner.
+    let mut ps = PermSet::new(layer, vec![], Role::Player);
+    ps.set_rule(Action::Move, Subject::Role(Role::Editor), Some(false));
+    ps.set_rule(Action::Move, Subject::Role(Role::Owner), Some(false));
+    ps.set_rule(Action::Move, Subject::User(spectator), Some(true));
+    perms.item_perms(CANONICAL_UPDATER, ps);
+    let (_, movement) = &events[3];
+    assert!(!perms.permitted(editor, movement, Some(layer), &[], 0, &Default::default()));
+    assert!(perms.permitted(owner, movement, Some(layer), &[], 0, &Default::default()));
+    assert!(perms.permitted(
+        spectator,
+        movement,
+        Some(layer),
+        &[],
+        0,
+        &Default::default()
+    ));
+
+    // A sprite may only be moved to a layer which allows it, and a sprite's
+    // own rules apply alongside its layer's.
+    let (other_layer, mut ps) = (2, PermSet::new(2, vec![], Role::Player));
+    ps.set_rule(Action::Layer, Subject::Role(Role::Player), Some(false));
+    perms.item_perms(CANONICAL_UPDATER, ps);
+    let mut ps = PermSet::new(layer, vec![], Role::Player);
+    ps.set_rule(Action::Layer, Subject::Role(Role::Player), Some(true));
+    perms.item_perms(CANONICAL_UPDATER, ps);
+    let change = SceneEvent::SpriteLayer(sprite, layer, other_layer);
+    assert!(!perms.permitted(player, &change, Some(layer), &[], 0, &Default::default()));
+    assert!(perms.permitted(
+        player,
+        &events[2].1,
+        Some(layer),
+        &[],
+        0,
+        &Default::default()
+    ));
+
+    let mut ps = PermSet::new(sprite, vec![], Role::Player);
+    ps.set_rule(Action::Layer, Subject::User(player), Some(false));
+    perms.item_perms(CANONICAL_UPDATER, ps);
+    assert!(!perms.permitted(
+        player,
+        &events[2].1,
+        Some(layer),
+        &[],
+        0,
+        &Default::default()
+    ));
+    assert!(perms.permitted(
+        other,
+        &events[2].1,
+        Some(layer),
+        &[],
+        0,
+        &Default::default()
+    ));
+
+    // A rule denying an action applies even to the players controlling the
+    // token, who are otherwise always allowed to move and edit it.
+    let (_, edit) = &events[1];
+    perms.item_perms(CANONICAL_UPDATER, PermSet::new(layer, vec![], Role::Editor));
+    assert!(perms.permitted(player, edit, Some(layer), &[player], 0, &Default::default()));
+    assert!(perms.permitted(
+        player,
+        movement,
+        Some(layer),
+        &[player],
+        0,
+        &Default::default()
+    ));
+    let mut ps = PermSet::new(layer, vec![], Role::Editor);
+    ps.set_rule(Action::Edit, Subject::User(player), Some(false));
+    ps.set_rule(Action::Move, Subject::Role(Role::Player), Some(false));
+    perms.item_perms(CANONICAL_UPDATER, ps);
+    assert!(!perms.permitted(player, edit, Some(layer), &[player], 0, &Default::default()));
+    assert!(!perms.permitted(
+        player,
+        movement,
+        Some(layer),
+        &[player],
+        0,
+        &Default::default()
+    ));
+    assert!(perms.permitted(other, edit, Some(layer), &[other], 0, &Default::default()));
+}
+
+#[test]
+fn test_history() {
+    use crate::{comms::SceneEvent, History, Rect, Sprite};
+
+    let mut scene = Scene::new();
+    let layer = scene.first_layer();
+    scene.add_sprite(Sprite::new(10, None, None), layer);
+    scene.add_sprite(Sprite::new(11, None, None), layer);
+
+    let rect = |x| Rect::new(x, 0.0, 1.0, 1.0);
+    let mut history = History::new();
+
+    // Moves made while dragging are collapsed into a single entry.
+    history.start_group();
+    for x in [1.0, 2.0, 3.0] {
+        history.push(scene.sprite(10).unwrap().set_rect(rect(x)));
+    }
+    history.end_move_group();
+    history.push(scene.sprite(10).unwrap().set_hp(Some(10)));
+    history.push(scene.sprite(11).unwrap().set_name(Some("Ally".to_string())));
+
+    // Only an event which exactly unwinds one of the user's changes is
+    // accepted as an undo.
+    let undone = |event| history.undone_entry(&event);
+    assert_eq!(
+        undone(SceneEvent::SpriteMove(10, rect(3.0), rect(0.0))),
+        Some(0)
+    );
+    assert_eq!(
+        undone(SceneEvent::SpriteName(11, Some("Ally".to_string()), None)),
+        Some(2)
+    );
+    assert_eq!(
+        undone(SceneEvent::SpriteMove(10, rect(3.0), rect(9.0))),
+        None
+    );
+    assert_eq!(undone(SceneEvent::SpriteRemove(12)), None);
+    assert_eq!(undone(SceneEvent::InitiativeBack(1, 0)), None);
+
+    // Another user renames the second sprite, damages the first and moves it
+    // along.
+    let remote = [
+        SceneEvent::SpriteName(11, Some("Ally".to_string()), Some("Foe".to_string())),
+        SceneEvent::SpriteHpDelta(10, -4),
+        SceneEvent::SpriteMove(10, rect(3.0), rect(5.0)),
+    ];
+    for event in remote {
+        history.handle_remote(&event);
+        assert!(scene.apply_event(event));
+    }
+
+    // The rename was overridden so is skipped, the HP change is rebased to
+    // keep the damage and the move is undone relative to where the sprite is.
+    assert!(matches!(
+        history.undo(&mut scene),
+        Some(SceneEvent::SpriteHp(10, Some(6), None))
+    ));
+    assert!(matches!(
+        history.undo(&mut scene),
+        Some(SceneEvent::SpriteMove(10, from, to)) if from == rect(5.0) && to == rect(2.0)
+    ));
+    assert!(history.undo(&mut scene).is_none());
+    assert!(!history.can_undo());
+    assert_eq!(
+        scene.sprite_ref(11).unwrap().token.name.as_deref(),
+        Some("Foe")
+    );
+
+    // Redo restores both changes, unless they have been superseded since.
+    history.handle_remote(&SceneEvent::SpriteHp(10, None, Some(3)));
+    assert!(scene.apply_event(SceneEvent::SpriteHp(10, None, Some(3))));
+    assert!(matches!(
+        history.redo(&mut scene),
+        Some(SceneEvent::SpriteMove(10, from, to)) if from == rect(2.0) && to == rect(5.0)
+    ));
+    assert!(history.redo(&mut scene).is_none());
+    assert_eq!(scene.sprite_ref(10).unwrap().token.hp, Some(3));
+
+    // Anything a removed sprite was involved in can no longer be undone.
+    history.handle_remote(&SceneEvent::SpriteRemove(10));
+    assert!(!history.can_undo());
+}
+
+#[test]
+fn test_sprite_z() {
+    use std::collections::HashSet;
+
+    use crate::{comms::SceneEvent, Id, ZOrder};
+
+    let mut scene = Scene::new();
+    scene.canon();
+    let layer = scene.first_layer();
+    let ids = (0..4)
+        .map(|_| scene.new_sprite(None, None, layer).unwrap().item().unwrap())
+        .collect::<Vec<Id>>();
+    let (a, b, c, d) = (ids[0], ids[1], ids[2], ids[3]);
+
+    let order = |scene: &Scene| {
+        scene
+            .layer_ref(layer)
+            .unwrap()
+            .sprites
+            .iter()
+            .map(|s| s.id)
+            .collect::<Vec<Id>>()
+    };
+    assert_eq!(order(&scene), vec![a, b, c, d]);
+
+    let front = scene.restack_sprite(b, ZOrder::Front).unwrap();
+    assert_eq!(order(&scene), vec![a, c, d, b]);
+    assert!(scene.restack_sprite(b, ZOrder::Raise).is_none());
+    scene.unwind_event(front);
+    assert_eq!(order(&scene), vec![a, b, c, d]);
+
+    // Selections keep their relative order.
+    let raise = scene.restack_sprites(&[c, b], ZOrder::Raise).unwrap();
+    assert_eq!(order(&scene), vec![a, d, b, c]);
+    scene.unwind_event(raise);
+    assert_eq!(order(&scene), vec![a, b, c, d]);
+    scene.restack_sprites(&[c, d], ZOrder::Back);
+    assert_eq!(order(&scene), vec![c, d, a, b]);
+    scene.restack_sprites(&[c, d], ZOrder::Lower);
+    assert_eq!(order(&scene), vec![c, d, a, b]);
+
+    // Zs are the position of each sprite in the stack, and the bounds shrink
+    // as sprites are removed.
+    let l = scene.layer_ref(layer).unwrap();
+    assert_eq!(
+        l.sprites.iter().map(|s| s.z).collect::<Vec<i32>>(),
+        vec![0, 1, 2, 3]
+    );
+    assert_eq!((l.z_min, l.z_max), (0, 3));
+    scene.remove_sprite(b);
+    assert_eq!(scene.layer_ref(layer).unwrap().z_max, 2);
+
+    // Changes are made relative to the sprite beneath, and those made against
+    // a stale order are rejected.
+    assert!(!scene.apply_event(SceneEvent::SpriteZ(a, Some(c), None)));
+    assert!(scene.apply_event(SceneEvent::SpriteZ(a, Some(d), None)));
+    assert_eq!(order(&scene), vec![a, c, d]);
+
+    // Players who can't see every sprite are sent changes relative to the
+    // nearest sprite they can see, keeping their stacks in the same order.
+    let visible = HashSet::from([a, d]);
+    let mut filtered = scene.filtered(&visible);
+    let back = scene.restack_sprite(d, ZOrder::Back).unwrap();
+    assert!(matches!(back, SceneEvent::SpriteZ(id, Some(below), None) if id == d && below == c));
+    assert_eq!(scene.visible_below(d, Some(c), &visible), Some(a));
+    assert!(filtered.apply_event(SceneEvent::SpriteZ(d, Some(a), None)));
+    assert_eq!(order(&filtered), vec![d, a]);
+}
+
+#[test]
+fn test_layer_style() {
+    use crate::{comms::SceneEvent, BlendMode};
+
+    let mut scene = Scene::new();
+    scene.canon();
+    let layer = scene.first_layer();
+
+    let dim = scene.layer(layer).unwrap().set_opacity(0.5).unwrap();
+    assert!(scene.layer(layer).unwrap().set_opacity(0.5).is_none());
+    let tint = scene
+        .layer(layer)
+        .unwrap()
+        .set_blend(BlendMode::Multiply)
+        .unwrap();
+
+    scene.unwind_event(dim);
+    assert_eq!(scene.layer(layer).unwrap().opacity, 1.0);
+    scene.unwind_event(tint);
+    assert_eq!(scene.layer(layer).unwrap().blend, BlendMode::Normal);
+
+    // Opacity is clamped, non-finite values are refused and stale changes are
+    // rejected.
+    assert!(scene.apply_event(SceneEvent::LayerOpacity(layer, 1.0, 2.0)));
+    assert_eq!(scene.layer(layer).unwrap().opacity, 1.0);
+    assert!(!scene.apply_event(SceneEvent::LayerOpacity(layer, 1.0, f32::NAN)));
+    assert!(scene
+        .layer(layer)
+        .unwrap()
+        .set_opacity(f32::INFINITY)
+        .is_none());
+    assert_eq!(scene.layer(layer).unwrap().opacity, 1.0);
+    assert!(!scene.apply_event(SceneEvent::LayerBlend(
+        layer,
+        BlendMode::Screen,
+        BlendMode::Normal
+    )));
+    assert!(scene.apply_event(SceneEvent::LayerBlend(
+        layer,
+        BlendMode::Normal,
+        BlendMode::Screen
+    )));
+    assert_eq!(scene.layer(layer).unwrap().blend, BlendMode::Screen);
+}
+
+#[test]
+fn test_spatial_index() {
+    use crate::{Id, Rect, ScenePoint, Sprite};
+
+    // Deterministic pseudo-random numbers in [0, 1).
+    let mut seed = 12345u32;
+    let mut random = move || {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 8) as f32 / (1 << 24) as f32
+    };
+
+    let mut scene = Scene::new();
+    let layer = scene.first_layer();
+    for id in 100..600 {
+        let mut sprite = Sprite::new(id, None, None);
+        sprite.rect = Rect::new(
+            random() * 100.0 - 20.0,
+            random() * 100.0 - 20.0,
+            random() * 6.0 - 3.0,
+            random() * 6.0 - 3.0,
+        );
+        sprite.z = (random() * 10.0) as i32;
+        scene.add_sprite(sprite, layer);
+    }
+
+    // A background covering the whole map.
+    let mut background = Sprite::new(600, None, None);
+    background.rect = Rect::new(-50.0, -50.0, 200.0, 200.0);
+    background.z = -1;
+    scene.add_sprite(background, layer);
+
+    let check = |scene: &Scene, random: &mut dyn FnMut() -> f32| {
+        let sprites = &scene.layer_ref(layer).unwrap().sprites;
+        for _ in 0..200 {
+            let at = ScenePoint::new(random() * 120.0 - 30.0, random() * 120.0 - 30.0);
+            let expected = sprites.iter().rev().find(|s| s.rect.contains_point(at));
+            assert_eq!(
+                scene.sprite_at_ref(at, 0).map(|s| s.id),
+                expected.map(|s| s.id)
+            );
+
+            let region = Rect::new(at.x, at.y, random() * 40.0 - 20.0, random() * 40.0 - 20.0);
+            let expected = sprites
+                .iter()
+                .filter(|s| region.contains_rect(s.rect))
+                .map(|s| s.id)
+                .collect::<Vec<Id>>();
+            assert_eq!(scene.layer_ref(layer).unwrap().sprites_in(region), expected);
+        }
+    };
+    check(&scene, &mut random);
+
+    // Sprites moved, removed and restacked are found in their new places.
+    for id in 100..300 {
+        let sprite = scene.sprite(id).unwrap();
+        sprite.rect.x += 10.0;
+        sprite.rect.w *= 2.0;
+    }
+    check(&scene, &mut random);
+    for id in 300..400 {
+        scene.remove_sprite(id);
+    }
+    scene.restack_sprites(&(400..450).collect::<Vec<Id>>(), crate::ZOrder::Front);
+    check(&scene, &mut random);
+    assert!(scene.sprite(350).is_none());
+    assert_eq!(scene.sprite_ref(450).unwrap().id, 450);
+
+    // Each sprite's layer is tracked through moves between layers, layers
+    // being moved and layers being removed and restored.
+    let other = scene.new_layer("Other", 2).unwrap().item().unwrap();
+    scene.sprites_layer(&(500..550).collect::<Vec<Id>>(), other);
+    scene.move_layer(other, false);
+    assert_eq!(scene.get_sprite_layer(520), Some(other));
+    assert_eq!(scene.get_sprite_layer(450), Some(layer));
+    scene.remove_layer(other);
+    assert!(scene.sprite_ref(520).is_none());
+    assert!(scene.sprite(520).is_none());
+    scene.restore_layer(other);
+    assert_eq!(scene.sprite(520).map(|s| s.id), Some(520));
+    scene.sprites_layer(&(500..550).collect::<Vec<Id>>(), layer);
+    assert_eq!(scene.get_sprite_layer(520), Some(layer));
+    assert!(scene.layer_ref(other).unwrap().sprites.is_empty());
+
+    // The index isn't serialised, so is rebuilt when needed.
+    let mut scene: Scene = bincode::deserialize(&bincode::serialize(&scene).unwrap()).unwrap();
+    check(&scene, &mut random);
+    scene.sprite(500).unwrap().rect.y -= 10.0;
+    check(&scene, &mut random);
+}
+
+#[test]
+fn test_tombstones() {
+    use crate::{comms::SceneEvent, Tombstones};
+
+    let mut scene = Scene::new();
+    scene.canon();
+    let layer = scene.first_layer();
+    let sprite = scene.new_sprite(None, None, layer).unwrap().item().unwrap();
+    let removal = scene.remove_sprite(sprite).unwrap();
+
+    // Removed items are kept until past the horizon from when first seen.
+    assert_eq!(scene.compact(1000), 0);
+    assert_eq!(scene.compact(1000 + Tombstones::<()>::HORIZON - 1), 0);
+    let restore = scene.unwind_event(removal.clone()).unwrap();
+    scene.unwind_event(restore);
+    assert_eq!(scene.compact(1000 + Tombstones::<()>::HORIZON), 0);
+    assert_eq!(scene.compact(1000 + Tombstones::<()>::HORIZON * 2), 1);
+
+    // Once pruned, restoring fails.
+    assert!(scene.unwind_event(removal).is_none());
+    assert!(!scene.apply_event(SceneEvent::SpriteRestore(sprite)));
+    assert!(scene.sprite(sprite).is_none());
+
+    // Only the most recent removals are kept.
+    let limit = Tombstones::<()>::LIMIT;
+    for _ in 0..limit + 10 {
+        let id = scene.new_sprite(None, None, layer).unwrap().item().unwrap();
+        scene.remove_sprite(id);
+    }
+    assert_eq!(scene.compact(5000), 10);
+    let removed = &scene.layer(layer).unwrap().removed_sprites;
+    assert_eq!(removed.len(), limit);
+    let oldest = removed.iter().next().unwrap().id;
+    assert!(!scene.apply_event(SceneEvent::SpriteRestore(oldest - 1)));
+    assert!(scene.apply_event(SceneEvent::SpriteRestore(oldest)));
+
+    let layer_removal = scene.remove_layer(layer).unwrap();
+    scene.compact(10000);
+    assert!(scene.unwind_event(layer_removal.clone()).is_some());
+    scene.unwind_event(SceneEvent::LayerRestore(layer));
+    scene.compact(20000);
+    scene.compact(20000 + Tombstones::<()>::HORIZON);
+    assert!(scene.unwind_event(layer_removal).is_none());
+    assert!(scene.removed_layers.is_empty());
+}
+
+#[test]
+fn test_json() {
+    use crate::{BlendMode, Combatant, ScenePoint, Template, TemplateShape, Wall};
+
+    let mut scene = Scene::new();
+    scene.title = Some("Cellar".to_string());
+    scene.w = 20;
+    scene.fog_of_war = true;
+    scene.walls.push(Wall::new(
+        ScenePoint::new(0.0, 0.0),
+        ScenePoint::new(4.0, 0.0),
+    ));
+    let layer = scene.first_layer();
+    scene.layer(layer).unwrap().set_opacity(0.5);
+    scene.layer(layer).unwrap().blend = BlendMode::Multiply;
+    let sprite = scene.new_sprite(None, None, layer).unwrap().item().unwrap();
+    scene.sprite(sprite).unwrap().token.name = Some("Rat".to_string());
+    scene.new_template(
+        ScenePoint::new(2.0, 2.0),
+        TemplateShape::Cube { size: 1.0 },
+        layer,
+        Some(3),
+    );
+    scene
+        .initiative
+        .combatants
+        .push(Combatant::new(100, "Rat".to_string(), Some(sprite), None));
+
+    let json = scene.to_json();
+    let mut imported = Scene::from_json(&json).unwrap();
+    assert_eq!(imported.to_json(), json);
+    assert_eq!(imported.title, scene.title);
+    assert_eq!(imported.w, 20);
+    assert!(imported.fog_of_war);
+    assert_eq!(imported.layer(layer).unwrap().opacity, 0.5);
+    assert_eq!(
+        imported.sprite(sprite).unwrap().token.name.as_deref(),
+        Some("Rat")
+    );
+
+    // New items don't reuse imported IDs.
+    let id = imported
+        .new_sprite(None, None, layer)
+        .unwrap()
+        .item()
+        .unwrap();
+    assert!(id > 100);
+
+    // Documents from future versions are rejected.
+    let future = json.replacen(
+        &format!("\"version\": {}", crate::json::VERSION),
+        &format!("\"version\": {}", crate::json::VERSION + 1),
+        1,
+    );
+    assert!(Scene::from_json(&future).is_err());
+    assert!(Scene::from_json("{}").is_err());
+
+    // Optional fields take their defaults.
+    let minimal = r#"{
+        "version": 1,
+        "width": 10,
+        "height": 12,
+        "layers": [{ "id": 4, "title": "Tokens", "z": 1 }]
+    }"#;
+    let scene = Scene::from_json(minimal).unwrap();
+    assert_eq!((scene.w, scene.h), (10, 12));
+    assert_eq!(scene.layers.len(), 1);
+    assert!(scene.layers[0].visible);
+    assert_eq!(scene.layers[0].opacity, 1.0);
+    assert!(scene.walls.is_empty());
+
+    // Documents that couldn't have been exported are rejected.
+    let invalid = |find: &str, replace: &str| {
+        let json = minimal.replacen(find, replace, 1);
+        assert_ne!(json, minimal);
+        assert!(Scene::from_json(&json).is_err());
+    };
+    invalid("\"width\": 10", "\"width\": 0");
+    invalid("\"height\": 12", "\"height\": 100000");
+    invalid(
+        "\"z\": 1 }",
+        "\"z\": 1 }, { \"id\": 4, \"title\": \"Again\", \"z\": 2 }",
+    );
+
+    let mut scene = Scene::new();
+    let layer = scene.first_layer();
+    let other = scene.new_layer("Other", 3).unwrap().item().unwrap();
+    let sprite = scene.new_sprite(None, None, layer).unwrap().item().unwrap();
+    let json = scene.to_json();
+    let copy = scene.sprite_ref(sprite).unwrap().clone();
+    scene.layer(other).unwrap().add_sprite(copy);
+    assert!(Scene::from_json(&scene.to_json()).is_err());
+    assert!(Scene::from_json(&json).is_ok());
+
+    let rejected = |change: &dyn Fn(&mut Scene)| {
+        let mut scene = Scene::from_json(&json).unwrap();
+        change(&mut scene);
+        Scene::from_json(&scene.to_json()).is_err()
+    };
+    assert!(rejected(&|scene| scene.grid.size = 0.0));
+    assert!(rejected(&|scene| {
+        scene.sprite(sprite).unwrap().token.name = Some("Rat".repeat(30));
+    }));
+    assert!(rejected(&|scene| {
+        let conditions = &mut scene.sprite(sprite).unwrap().token.conditions;
+        conditions.extend(["Prone".to_string(), "Prone".to_string()]);
+    }));
+    let template = |id, size| {
+        move |scene: &mut Scene| {
+            let shape = TemplateShape::Cube { size };
+            scene.add_template(Template::new(id, ScenePoint::new(0.0, 0.0), shape), layer);
+        }
+    };
+    assert!(!rejected(&template(sprite + 1, 1.0)));
+    assert!(rejected(&template(sprite, 1.0)));
+    assert!(rejected(&template(sprite + 1, -1.0)));
+}
+
+#[test]
+fn test_uvtt_import() {
+    use crate::{BlendMode, ScenePoint, SpriteShape, SpriteVisual, UvttMap, Wall};
+
+    let map = UvttMap::parse(include_str!("../testdata/sample.dd2vtt")).unwrap();
+    assert!(map.image().starts_with("iVBORw0KGgo"));
+
+    let texture = 77;
+    let scene = map.to_scene(texture);
+    assert_eq!((scene.w, scene.h), (6, 5));
+
+    // The map image covers the background.
+    let background = scene
+        .layers
+        .iter()
+        .find(|l| l.title == "Background")
+        .unwrap();
+    assert_eq!(background.sprites.len(), 1);
+    let image = &background.sprites[0];
+    assert_eq!(image.visual, SpriteVisual::Texture(texture));
+    assert_eq!(image.rect, crate::Rect::new(0.0, 0.0, 6.0, 4.5));
+
+    // Walls are relative to the map origin, and closed doors block sight.
+    let p = ScenePoint::new;
+    assert_eq!(
+        scene.walls,
+        vec![
+            Wall::new(p(0.0, 0.0), p(6.0, 0.0)),
+            Wall::new(p(6.0, 0.0), p(6.0, 4.5)),
+            Wall::new(p(3.0, 0.0), p(3.0, 2.0)),
+            Wall::new(p(3.0, 2.0), p(3.0, 3.0)),
+        ]
+    );
+
+    let lights = scene.layers.iter().find(|l| l.title == "Lights").unwrap();
+    assert_eq!(lights.blend, BlendMode::Screen);
+    assert_eq!(lights.sprites.len(), 1);
+    let light = &lights.sprites[0];
+    assert_eq!(light.shape, SpriteShape::Ellipse);
+    assert_eq!(light.rect, crate::Rect::new(0.0, 0.0, 4.0, 4.0));
+    assert_eq!(
+        light.visual,
+        SpriteVisual::Colour([128.0 / 255.0, 0.0, 1.0, 0.5])
+    );
+
+    // IDs of imported items aren't reused.
+    let (layer, max_id) = (lights.id, light.id.max(image.id));
+    let mut scene = scene;
+    let id = scene.new_sprite(None, None, layer).unwrap().item().unwrap();
+    assert!(id > max_id);
+
+    assert!(UvttMap::parse("{}").is_err());
+
+    // The image must cover the map at the resolution given.
+    assert!(map.check_image(384, 288).is_ok());
+    assert!(map.check_image(384, 384).is_err());
+
+    // Maps too large or with positions that can't be drawn are rejected.
+    let sample = include_str!("../testdata/sample.dd2vtt");
+    let invalid = |find: &str, replace: &str| {
+        let json = sample.replacen(find, replace, 1);
+        assert_ne!(json, sample);
+        assert!(UvttMap::parse(&json).is_err());
+    };
+    invalid("\"x\": 6, \"y\": 4.5", "\"x\": 6, \"y\": 1e9");
+    invalid("\"x\": 6, \"y\": 4.5", "\"x\": 0, \"y\": 4.5");
+    invalid("\"pixels_per_grid\": 64", "\"pixels_per_grid\": 0");
+    invalid("{ \"x\": 4, \"y\": 3 }", "{ \"x\": 4, \"y\": 1e39 }");
+    invalid("\"range\": ", "\"range\": -");
+}
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Game details of a sprite which represents a creature.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Token {
+    pub name: Option<String>,
+    pub hp: Option<i32>,
+    pub max_hp: Option<i32>,
+
+    // Temporary hit points, which are lost before HP when taking damage.
+    pub temp_hp: i32,
+
+    // Condition markers, such as "Prone", in the order applied.
+    pub conditions: Vec<String>,
+
+    // Free-form details, such as "AC" => "15".
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Token {
+    // Limits on the text stored with a token, in characters.
+    pub const MAX_NAME_LENGTH: usize = 64;
+    pub const MAX_CONDITION_LENGTH: usize = 32;
+    pub const MAX_FIELD_KEY_LENGTH: usize = 32;
+    pub const MAX_FIELD_VALUE_LENGTH: usize = 256;
+
+    pub const MAX_CONDITIONS: usize = 32;
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn valid_name(name: Option<&str>) -> bool {
+        name.is_none_or(|n| n.chars().count() <= Self::MAX_NAME_LENGTH)
+    }
+
+    // Conditions are stored one per line, so may not contain line breaks.
+    pub fn valid_condition(condition: &str) -> bool {
+        !condition.trim().is_empty()
+            && !condition.contains('\n')
+            && condition.chars().count() <= Self::MAX_CONDITION_LENGTH
+    }
+
+    pub fn valid_field(key: &str, value: Option<&str>) -> bool {
+        !key.trim().is_empty()
+            && key.chars().count() <= Self::MAX_FIELD_KEY_LENGTH
+            && value.is_none_or(|v| v.chars().count() <= Self::MAX_FIELD_VALUE_LENGTH)
+    }
+
+    // Whether this token keeps to the limits on its text, as a token changed
+    // only through the methods here does.
+    pub fn is_valid(&self) -> bool {
+        Self::valid_name(self.name.as_deref())
+            && self.conditions.len() <= Self::MAX_CONDITIONS
+            && self
+                .conditions
+                .iter()
+                .enumerate()
+                .all(|(i, c)| Self::valid_condition(c) && !self.conditions[..i].contains(c))
+            && self
+                .fields
+                .iter()
+                .all(|(k, v)| Self::valid_field(k, Some(v)))
+    }
+
+    pub fn has_condition(&self, condition: &str) -> bool {
+        self.conditions.iter().any(|c| c == condition)
+    }
+
+    pub fn add_condition(&mut self, condition: String) -> bool {
+        if Self::valid_condition(&condition)
+            && !self.has_condition(&condition)
+            && self.conditions.len() < Self::MAX_CONDITIONS
+        {
+            self.conditions.push(condition);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn remove_condition(&mut self, condition: &str) -> bool {
+        let len = self.conditions.len();
+        self.conditions.retain(|c| c != condition);
+        self.conditions.len() != len
+    }
+
+    pub fn set_field(&mut self, key: String, value: Option<String>) -> Option<String> {
+        match value {
+            Some(value) => self.fields.insert(key, value),
+            None => self.fields.remove(&key),
+        }
+    }
+
+    // Proportion of maximum HP remaining, for health bars.
+    pub fn health(&self) -> Option<f32> {
+        match (self.hp, self.max_hp) {
+            (Some(hp), Some(max)) if max > 0 => Some((hp as f32 / max as f32).clamp(0.0, 1.0)),
+            _ => None,
+        }
+    }
+}
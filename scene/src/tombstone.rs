@@ -0,0 +1,70 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// Removed items, kept so that their removal can be undone. Entries are
+/// stamped with the time they were first seen by compact, and pruned once
+/// past the undo horizon or when there are too many.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Tombstones<T> {
+    // Items in order of removal, with the time each was first compacted.
+    entries: Vec<(T, Option<u64>)>,
+}
+
+impl<T> Tombstones<T> {
+    // Seconds after which a removal can no longer be undone.
+    pub const HORIZON: u64 = 60 * 60;
+
+    // Number of removed items kept, which matches the number of changes
+    // each user may undo.
+    pub const LIMIT: usize = crate::History::LIMIT;
+
+    pub fn new() -> Self {
+        Self { entries: vec![] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.iter().map(|(item, _)| item)
+    }
+
+    pub fn push(&mut self, item: T) {
+        self.entries.push((item, None));
+    }
+
+    // Take the most recently removed item matching the predicate, if it
+    // hasn't been pruned.
+    pub fn take(&mut self, f: impl Fn(&T) -> bool) -> Option<T> {
+        let i = self.entries.iter().rposition(|(item, _)| f(item))?;
+        Some(self.entries.remove(i).0)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // Prune entries past the horizon, and then the oldest entries beyond the
+    // limit, returning the number pruned.
+    pub fn compact(&mut self, now: u64) -> usize {
+        let len = self.entries.len();
+        for (_, removed) in &mut self.entries {
+            removed.get_or_insert(now);
+        }
+        self.entries
+            .retain(|(_, removed)| now.saturating_sub(removed.unwrap_or(now)) < Self::HORIZON);
+        let excess = self.entries.len().saturating_sub(Self::LIMIT);
+        self.entries.drain(..excess);
+        len - self.entries.len()
+    }
+}
+
+impl<T> Default for Tombstones<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
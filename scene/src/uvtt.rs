@@ -0,0 +1,239 @@
+use serde_derive::Deserialize;
+
+use super::{
+    json::MAX_DIMENSION, BlendMode, Colour, Id, Layer, Rect, Scene, ScenePoint, Sprite,
+    SpriteShape, SpriteVisual, Wall,
+};
+
+/// A map in the Universal VTT format, as exported by Dungeondraft and other
+/// map makers as .dd2vtt or .uvtt files. Positions are in grid cells, which
+/// correspond to scene tiles.
+#[derive(Deserialize)]
+pub struct UvttMap {
+    resolution: Resolution,
+    #[serde(default)]
+    line_of_sight: Vec<Vec<Point>>,
+    #[serde(default)]
+    objects_line_of_sight: Vec<Vec<Point>>,
+    #[serde(default)]
+    portals: Vec<Portal>,
+    #[serde(default)]
+    lights: Vec<Light>,
+
+    // Base64 encoded map image.
+    image: String,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+struct Point {
+    x: f32,
+    y: f32,
+}
+
+#[derive(Deserialize)]
+struct Resolution {
+    map_origin: Point,
+    map_size: Point,
+    // Size of a grid cell in the map image, where given.
+    #[serde(default)]
+    pixels_per_grid: Option<f32>,
+}
+
+// A door or window, which blocks sight while closed.
+#[derive(Deserialize)]
+struct Portal {
+    bounds: Vec<Point>,
+    #[serde(default)]
+    closed: bool,
+}
+
+#[derive(Deserialize)]
+struct Light {
+    position: Point,
+    range: f32,
+    #[serde(default = "default_intensity")]
+    intensity: f32,
+    #[serde(default)]
+    color: Option<String>,
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+impl UvttMap {
+    // Light colour used when a light has none or it can't be parsed.
+    const LIGHT_COLOUR: Colour = [1.0, 0.9, 0.7, 1.0];
+
+    // Opacity of the layer lights are drawn on, so that they tint the map
+    // rather than hide it.
+    const LIGHT_OPACITY: f32 = 0.35;
+
+    pub fn parse(json: &str) -> Result<UvttMap, String> {
+        let map: UvttMap =
+            serde_json::from_str(json).map_err(|e| format!("Invalid map file: {e}"))?;
+        map.validate()?;
+        Ok(map)
+    }
+
+    // Reject maps which would give a scene that a JSON import would refuse,
+    // or which have positions that can't be drawn.
+    fn validate(&self) -> Result<(), String> {
+        let Resolution {
+            map_origin,
+            map_size,
+            pixels_per_grid,
+        } = self.resolution;
+        let valid_size = |s: f32| s > 0.0 && s <= MAX_DIMENSION as f32;
+        if !valid_size(map_size.x) || !valid_size(map_size.y) {
+            return Err(format!(
+                "Map dimensions must be between 1 and {MAX_DIMENSION}."
+            ));
+        }
+
+        if !pixels_per_grid.iter().all(|&p| p.is_finite() && p > 0.0) {
+            return Err("Invalid map resolution.".to_string());
+        }
+
+        let finite = |p: &Point| p.x.is_finite() && p.y.is_finite();
+        if !finite(&map_origin) {
+            return Err("Invalid map origin.".to_string());
+        }
+
+        let mut walls = self
+            .line_of_sight
+            .iter()
+            .chain(&self.objects_line_of_sight)
+            .flatten();
+        if !walls.all(finite) {
+            return Err("Invalid wall.".to_string());
+        }
+
+        if !self.portals.iter().flat_map(|p| &p.bounds).all(finite) {
+            return Err("Invalid portal.".to_string());
+        }
+
+        if !self.lights.iter().all(|l| {
+            finite(&l.position) && l.range.is_finite() && l.range >= 0.0 && l.intensity.is_finite()
+        }) {
+            return Err("Invalid light.".to_string());
+        }
+
+        Ok(())
+    }
+
+    // Check that the map image, of this size in pixels, covers the map at
+    // the resolution given, to within half a grid cell.
+    pub fn check_image(&self, width: u32, height: u32) -> Result<(), String> {
+        let ppg = match self.resolution.pixels_per_grid {
+            Some(ppg) => ppg,
+            None => return Ok(()),
+        };
+
+        let size = self.resolution.map_size;
+        let matches = |pixels: u32, cells: f32| (pixels as f32 / ppg - cells).abs() <= 0.5;
+        if matches(width, size.x) && matches(height, size.y) {
+            Ok(())
+        } else {
+            Err("Map image doesn't match the map size.".to_string())
+        }
+    }
+
+    pub fn image(&self) -> &str {
+        &self.image
+    }
+
+    fn point(&self, Point { x, y }: Point) -> ScenePoint {
+        let origin = self.resolution.map_origin;
+        ScenePoint::new(x - origin.x, y - origin.y)
+    }
+
+    // Walls along each edge of a line of points.
+    fn walls<'a>(&'a self, lines: &'a [Vec<Point>]) -> impl Iterator<Item = Wall> + 'a {
+        lines.iter().flat_map(move |line| {
+            line.windows(2)
+                .map(move |p| Wall::new(self.point(p[0]), self.point(p[1])))
+        })
+    }
+
+    // Create a scene from this map, with the map image, stored as the media
+    // item texture, as its background. Closed portals become walls and
+    // lights are drawn as translucent circles on a layer of their own.
+    pub fn to_scene(&self, texture: Id) -> Scene {
+        let mut lights = Layer::new(2, "Lights", -1);
+        lights.set_opacity(Self::LIGHT_OPACITY);
+        lights.blend = BlendMode::Screen;
+        let background = 4;
+
+        let mut scene = Scene::new_with_layers(vec![
+            Layer::new(1, "Foreground", 1),
+            lights,
+            Layer::new(3, "Scenery", -2),
+            Layer::new(background, "Background", -3),
+        ]);
+
+        // The size is checked by parse, so is within MAX_DIMENSION.
+        let size = self.resolution.map_size;
+        scene.w = size.x.ceil() as u32;
+        scene.h = size.y.ceil() as u32;
+
+        let mut map = Sprite::new(scene.next_id(), Some(SpriteVisual::Texture(texture)), None);
+        map.set_rect(Rect::new(0.0, 0.0, size.x, size.y));
+        scene.add_sprite(map, background);
+
+        scene.walls = self
+            .walls(&self.line_of_sight)
+            .chain(self.walls(&self.objects_line_of_sight))
+            .collect();
+        // Scenes have no doors that can be opened, so only closed portals
+        // become walls. Open ones are left out, so that sight through them
+        // isn't blocked when the map maker meant it to be clear.
+        for portal in self.portals.iter().filter(|p| p.closed) {
+            if let [from, to] = portal.bounds[..] {
+                scene
+                    .walls
+                    .push(Wall::new(self.point(from), self.point(to)));
+            }
+        }
+
+        for light in &self.lights {
+            let mut colour = light
+                .color
+                .as_deref()
+                .and_then(parse_colour)
+                .unwrap_or(Self::LIGHT_COLOUR);
+            colour[3] *= light.intensity.clamp(0.0, 1.0);
+
+            let centre = self.point(light.position);
+            let mut sprite = Sprite::new(
+                scene.next_id(),
+                Some(SpriteVisual::Colour(colour)),
+                Some(SpriteShape::Ellipse),
+            );
+            sprite.set_rect(Rect::new(
+                centre.x - light.range,
+                centre.y - light.range,
+                light.range * 2.0,
+                light.range * 2.0,
+            ));
+            scene.add_sprite(sprite, 2);
+        }
+
+        scene
+    }
+}
+
+// Parse a hex colour in the ARGB form used by Dungeondraft, or RGB.
+fn parse_colour(hex: &str) -> Option<Colour> {
+    let hex = hex.trim_start_matches('#');
+    let channel = |i: usize| {
+        let s = hex.get(i * 2..i * 2 + 2)?;
+        u8::from_str_radix(s, 16).ok().map(|c| c as f32 / 255.0)
+    };
+
+    match hex.len() {
+        6 => Some([channel(0)?, channel(1)?, channel(2)?, 1.0]),
+        8 => Some([channel(1)?, channel(2)?, channel(3)?, channel(0)?]),
+        _ => None,
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashSet;
+
+use serde_derive::{Deserialize, Serialize};
+
+use super::{Id, Layer, Rect, ScenePoint, Sprite};
+
+/// A segment which blocks line of sight between tokens and sprites.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Wall {
+    pub from: ScenePoint,
+    pub to: ScenePoint,
+}
+
+impl Wall {
+    pub fn new(from: ScenePoint, to: ScenePoint) -> Self {
+        Wall { from, to }
+    }
+
+    // Whether this wall crosses the segment between a and b. Walls touching
+    // the segment only at an endpoint do not block, so that a token standing
+    // against a wall can still see along it.
+    pub fn blocks(&self, a: ScenePoint, b: ScenePoint) -> bool {
+        let d1 = orientation(self.from, self.to, a);
+        let d2 = orientation(self.from, self.to, b);
+        let d3 = orientation(a, b, self.from);
+        let d4 = orientation(a, b, self.to);
+
+        d1 * d2 < 0.0 && d3 * d4 < 0.0
+    }
+}
+
+// Sign of the cross product of (b - a) and (c - a), indicating which side of
+// the line through a and b the point c lies on.
+fn orientation(a: ScenePoint, b: ScenePoint, c: ScenePoint) -> f32 {
+    (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
+
+fn distance(a: ScenePoint, b: ScenePoint) -> f32 {
+    ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt()
+}
+
+// Points on a sprite which a viewer may see; the point of the sprite closest
+// to the viewer, its centre and its corners.
+fn sample_points(rect: Rect, viewer: ScenePoint) -> [ScenePoint; 6] {
+    let Rect { x, y, w, h } = rect.positive_dimensions();
+    [
+        ScenePoint::new(viewer.x.clamp(x, x + w), viewer.y.clamp(y, y + h)),
+        ScenePoint::new(x + w / 2.0, y + h / 2.0),
+        ScenePoint::new(x, y),
+        ScenePoint::new(x + w, y),
+        ScenePoint::new(x, y + h),
+        ScenePoint::new(x + w, y + h),
+    ]
+}
+
+fn can_see_point(viewer: ScenePoint, radius: f32, p: ScenePoint, walls: &[Wall]) -> bool {
+    distance(viewer, p) <= radius && !walls.iter().any(|w| w.blocks(viewer, p))
+}
+
+fn can_see(viewer: ScenePoint, radius: f32, rect: Rect, walls: &[Wall]) -> bool {
+    sample_points(rect, viewer)
+        .iter()
+        .any(|&p| can_see_point(viewer, radius, p, walls))
+}
+
+fn sprites(layers: &[Layer]) -> impl Iterator<Item = &Sprite> {
+    layers.iter().flat_map(|l| l.sprites.iter())
+}
+
+// Position and vision radius of each of this user's tokens which can see.
+fn viewers(layers: &[Layer], user: Id) -> Vec<(ScenePoint, f32)> {
+    sprites(layers)
+        .filter(|s| s.owned_by(user))
+        .filter_map(|s| Some((s.centre(), s.vision.filter(|&r| r > 0.0)?)))
+        .collect()
+}
+
+/// Whether this user can see a point, as it lies on one of their tokens or
+/// within sight of one.
+pub fn sees_point(layers: &[Layer], walls: &[Wall], user: Id, point: ScenePoint) -> bool {
+    sprites(layers).any(|s| s.owned_by(user) && s.rect.positive_dimensions().contains_point(point))
+        || viewers(layers, user)
+            .iter()
+            .any(|&(at, radius)| can_see_point(at, radius, point, walls))
+}
+
+/// Determine the set of sprites and templates visible to the tokens owned by
+/// this user. A user can always see their own tokens, and additionally any
+/// sprite within the vision radius of one of those tokens which is not
+/// hidden behind a wall. Templates are visible to the user who placed them,
+/// and otherwise where their origin can be seen or where they cover one of
+/// the user's tokens, so that an effect centred on a hidden creature doesn't
+/// give it away.
+pub fn visible_items(layers: &[Layer], walls: &[Wall], user: Id) -> HashSet<Id> {
+    let viewers = viewers(layers, user);
+
+    let seen_sprites = sprites(layers)
+        .filter(|s| {
+            s.owned_by(user)
+                || viewers
+                    .iter()
+                    .any(|&(at, radius)| can_see(at, radius, s.rect, walls))
+        })
+        .map(|s: &Sprite| s.id);
+
+    let seen_templates = layers
+        .iter()
+        .flat_map(|l| l.templates.iter())
+        .filter(|t| {
+            t.owner == Some(user)
+                || viewers
+                    .iter()
+                    .any(|&(at, radius)| can_see_point(at, radius, t.origin, walls))
+                || sprites(layers).any(|s| s.owned_by(user) && t.covers(s))
+        })
+        .map(|t| t.id);
+
+    seen_sprites.chain(seen_templates).collect()
+}
@@ -18,6 +18,14 @@ CREATE TABLE IF NOT EXISTS user_sessions (
     end_time INTEGER
 );
 
+CREATE TABLE IF NOT EXISTS media_folders (
+    id INTEGER PRIMARY KEY,
+    folder_key CHAR(16) NOT NULL UNIQUE,
+    user INTEGER REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    parent INTEGER REFERENCES media_folders(id) ON DELETE CASCADE,
+    title TEXT NOT NULL
+);
+
 CREATE TABLE IF NOT EXISTS media (
     id INTEGER PRIMARY KEY,
     media_key CHAR(16) NOT NULL UNIQUE,
@@ -25,14 +33,22 @@ CREATE TABLE IF NOT EXISTS media (
     relative_path TEXT NOT NULL UNIQUE,
     title TEXT NOT NULL,
     hashed_value CHAR(64) NOT NULL,
+    folder INTEGER REFERENCES media_folders(id) ON DELETE SET NULL,
     UNIQUE(user, hashed_value)
 );
 
+CREATE TABLE IF NOT EXISTS media_tags (
+    media INTEGER REFERENCES media(id) ON DELETE CASCADE NOT NULL,
+    tag TEXT NOT NULL,
+    UNIQUE(media, tag)
+);
+
 CREATE TABLE IF NOT EXISTS projects (
     id INTEGER PRIMARY KEY,
     project_key CHAR(16) NOT NULL,
     user INTEGER REFERENCES users(id) ON DELETE CASCADE NOT NULL,
-    title TEXT
+    title TEXT,
+    deleted_time INTEGER
 );
 
 CREATE TABLE IF NOT EXISTS scenes (
@@ -41,7 +57,26 @@ CREATE TABLE IF NOT EXISTS scenes (
     project INTEGER REFERENCES projects(id) ON DELETE CASCADE NOT NULL,
     title TEXT,
     w INTEGER NOT NULL,
-    h INTEGER NOT NULL
+    h INTEGER NOT NULL,
+    fog_of_war BOOLEAN DEFAULT FALSE NOT NULL,
+    grid TEXT DEFAULT 'square' NOT NULL,
+    grid_size REAL DEFAULT 1.0 NOT NULL,
+    grid_x REAL DEFAULT 0.0 NOT NULL,
+    grid_y REAL DEFAULT 0.0 NOT NULL,
+    grid_r REAL DEFAULT 0.5 NOT NULL,
+    grid_g REAL DEFAULT 0.5 NOT NULL,
+    grid_b REAL DEFAULT 0.5 NOT NULL,
+    grid_a REAL DEFAULT 0.75 NOT NULL,
+    grid_thickness REAL DEFAULT 1.0 NOT NULL,
+    grid_visible BOOLEAN DEFAULT TRUE NOT NULL,
+    grid_rule TEXT DEFAULT 'euclidean' NOT NULL,
+    grid_units_per_tile REAL DEFAULT 5.0 NOT NULL,
+    grid_unit TEXT DEFAULT 'ft' NOT NULL,
+    initiative_round INTEGER DEFAULT 0 NOT NULL,
+    initiative_turn INTEGER DEFAULT 0 NOT NULL,
+    position INTEGER DEFAULT 0 NOT NULL,
+    updated_time INTEGER DEFAULT 0 NOT NULL,
+    deleted_time INTEGER
 );
 
 CREATE TABLE IF NOT EXISTS layers (
@@ -51,6 +86,8 @@ CREATE TABLE IF NOT EXISTS layers (
     z INTEGER,
     visible INTEGER,
     locked INTEGER,
+    opacity REAL DEFAULT 1.0 NOT NULL,
+    blend TEXT DEFAULT 'normal' NOT NULL,
     UNIQUE(id, scene)
 );
 
@@ -68,5 +105,76 @@ CREATE TABLE IF NOT EXISTS sprites (
     w REAL NOT NULL,
     h REAL NOT NULL,
     z INTEGER NOT NULL,
+    vision REAL,
+    name TEXT,
+    hp INTEGER,
+    max_hp INTEGER,
+    temp_hp INTEGER DEFAULT 0 NOT NULL,
+    conditions TEXT DEFAULT '' NOT NULL,
     UNIQUE(id, scene)
 );
+
+CREATE TABLE IF NOT EXISTS sprite_fields (
+    sprite INTEGER NOT NULL,
+    scene INTEGER NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    FOREIGN KEY(sprite, scene) REFERENCES sprites(id, scene) ON DELETE CASCADE,
+    UNIQUE(sprite, scene, key)
+);
+
+CREATE TABLE IF NOT EXISTS sprite_owners (
+    sprite INTEGER NOT NULL,
+    scene INTEGER NOT NULL,
+    user INTEGER REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    FOREIGN KEY(sprite, scene) REFERENCES sprites(id, scene) ON DELETE CASCADE,
+    UNIQUE(sprite, scene, user)
+);
+
+CREATE TABLE IF NOT EXISTS walls (
+    scene INTEGER REFERENCES scenes(id) ON DELETE CASCADE NOT NULL,
+    x1 REAL NOT NULL,
+    y1 REAL NOT NULL,
+    x2 REAL NOT NULL,
+    y2 REAL NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS templates (
+    id INTEGER NOT NULL,
+    scene INTEGER REFERENCES scenes(id) ON DELETE CASCADE NOT NULL,
+    layer INTEGER NOT NULL,
+    shape TEXT NOT NULL,
+    x REAL NOT NULL,
+    y REAL NOT NULL,
+    rotation REAL NOT NULL,
+    size REAL NOT NULL,
+    angle REAL,
+    width REAL,
+    r REAL NOT NULL,
+    g REAL NOT NULL,
+    b REAL NOT NULL,
+    a REAL NOT NULL,
+    owner INTEGER,
+    UNIQUE(id, scene)
+);
+
+CREATE TABLE IF NOT EXISTS combatants (
+    id INTEGER NOT NULL,
+    scene INTEGER REFERENCES scenes(id) ON DELETE CASCADE NOT NULL,
+    position INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    sprite INTEGER,
+    owner INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    initiative INTEGER,
+    UNIQUE(id, scene)
+);
+
+CREATE TABLE IF NOT EXISTS chat_messages (
+    id INTEGER PRIMARY KEY,
+    project INTEGER REFERENCES projects(id) ON DELETE CASCADE NOT NULL,
+    user INTEGER REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    audience TEXT NOT NULL,
+    recipients TEXT NOT NULL,
+    text TEXT NOT NULL,
+    time INTEGER NOT NULL
+);
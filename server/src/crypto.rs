@@ -18,6 +18,23 @@ pub fn generate_salt() -> anyhow::Result<Key> {
     }
 }
 
+// Uniformly random value from 1 to sides, inclusive.
+pub fn roll_die(sides: u32) -> anyhow::Result<u32> {
+    // Discard values from the incomplete final range so that every face is
+    // equally likely.
+    let limit = u32::MAX - u32::MAX % sides;
+    let rng = SystemRandom::new();
+    loop {
+        let mut bytes = [0u8; 4];
+        rng.fill(&mut bytes)
+            .map_err(|_| anyhow::anyhow!("Random byte generation failed."))?;
+        let value = u32::from_le_bytes(bytes);
+        if value < limit {
+            return Ok(value % sides + 1);
+        }
+    }
+}
+
 pub fn to_hex_string(key: &Key) -> anyhow::Result<String> {
     let mut s = String::with_capacity(KEY_LENGTH * 2);
     for byte in *key {
@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use scene::{Colour, GridType, Layer, Rect, Sprite, SpriteShape, SpriteVisual};
+use serde_derive::Serialize;
+
+#[cfg(test)]
+mod tests;
+
+// Directory media files are placed in within an exported archive, which
+// texture paths in the scene document are relative to.
+pub const MEDIA_DIR: &str = "media";
+
+// Name of the scene document within an exported archive.
+pub const SCENE_FILE: &str = "scene.json";
+
+// Width of a grid cell in Foundry, in pixels.
+const CELL_PIXELS: f32 = 100.0;
+
+// Foundry's wall sense type which blocks movement, sight and sound.
+const WALL_NORMAL: u8 = 20;
+
+// Icon used for tokens which have no texture.
+const DEFAULT_TOKEN_ICON: &str = "icons/svg/mystery-man.svg";
+
+/// A scene document in the format imported by Foundry VTT (v11). Scene
+/// units are converted to pixels, with a grid cell CELL_PIXELS across.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FoundryScene {
+    name: String,
+    width: u32,
+    height: u32,
+    padding: f64,
+    background: Texture,
+    grid: Grid,
+    token_vision: bool,
+    fog_exploration: bool,
+    tiles: Vec<Tile>,
+    tokens: Vec<Token>,
+    drawings: Vec<Drawing>,
+    walls: Vec<Wall>,
+}
+
+#[derive(Serialize)]
+struct Texture {
+    src: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Grid {
+    #[serde(rename = "type")]
+    kind: u8,
+    size: u32,
+    color: String,
+    alpha: f64,
+    distance: f64,
+    units: String,
+}
+
+#[derive(Serialize)]
+struct Tile {
+    texture: Texture,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    rotation: f64,
+    alpha: f64,
+    hidden: bool,
+    locked: bool,
+    z: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Token {
+    name: String,
+    texture: Texture,
+    x: f64,
+    y: f64,
+
+    // Size in grid cells.
+    width: f64,
+    height: f64,
+    alpha: f64,
+    hidden: bool,
+    locked: bool,
+    actor_link: bool,
+    sight: Sight,
+}
+
+#[derive(Serialize)]
+struct Sight {
+    enabled: bool,
+
+    // Range in grid distance units.
+    range: f64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Drawing {
+    shape: Shape,
+    x: f64,
+    y: f64,
+    z: u32,
+    fill_type: u8,
+    fill_color: String,
+    fill_alpha: f64,
+    stroke_width: f64,
+    hidden: bool,
+    locked: bool,
+}
+
+#[derive(Serialize)]
+struct Shape {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    width: f64,
+    height: f64,
+
+    // Flattened x, y pairs of polygon points, relative to the drawing.
+    points: Vec<f64>,
+}
+
+#[derive(Serialize)]
+struct Wall {
+    c: [f64; 4],
+    #[serde(rename = "move")]
+    movement: u8,
+    sense: u8,
+    sound: u8,
+    door: u8,
+}
+
+// Package a converted scene with the media files it uses, given as paths
+// within the archive and file contents, as a zip archive.
+pub fn archive(scene: &FoundryScene, media: &[(String, Vec<u8>)]) -> anyhow::Result<Vec<u8>> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    let options = zip::write::FileOptions::default();
+
+    zip.start_file(SCENE_FILE, options)?;
+    zip.write_all(serde_json::to_string_pretty(scene)?.as_bytes())?;
+
+    // Images are already compressed.
+    let options = options.compression_method(zip::CompressionMethod::Stored);
+    for (path, data) in media {
+        zip.start_file(path, options)?;
+        zip.write_all(data)?;
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+fn hex_colour(colour: Colour) -> String {
+    let [r, g, b, _] = colour.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+// Points of a regular polygon inscribed in a width by height rect, closed by
+// repeating the first point, matching the shapes drawn by the client.
+fn polygon(n: u32, width: f32, height: f32) -> Vec<f64> {
+    (0..=n)
+        .flat_map(|i| {
+            let theta = (i % n) as f32 / n as f32 * std::f32::consts::TAU;
+            [
+                round((0.5 + theta.cos() / 2.0) * width),
+                round((0.5 + theta.sin() / 2.0) * height),
+            ]
+        })
+        .collect()
+}
+
+// Round to hundredths, which is as precise as Foundry needs.
+fn round(v: f32) -> f64 {
+    (v as f64 * 100.0).round() / 100.0
+}
+
+impl FoundryScene {
+    // Convert a scene, with paths in the exported archive of the media
+    // items used as textures, keyed by texture ID. Sprites which are tokens
+    // become tokens, while other sprites become tiles or, if they have no
+    // texture, drawings.
+    pub fn new(scene: &scene::Scene, title: &str, textures: &HashMap<i64, String>) -> Self {
+        // Pixels per scene unit.
+        let scale = CELL_PIXELS / scene.grid.size;
+        let px = |v: f32| round(v * scale);
+
+        let mut foundry = FoundryScene {
+            name: title.to_string(),
+            width: (scene.w as f32 * scale).round() as u32,
+            height: (scene.h as f32 * scale).round() as u32,
+            padding: 0.0,
+            background: Texture { src: None },
+            grid: Grid {
+                kind: match scene.grid.kind {
+                    GridType::Gridless => 0,
+                    GridType::Square => 1,
+                    GridType::HexPointy => 2,
+                    GridType::HexFlat => 4,
+                },
+                size: CELL_PIXELS as u32,
+                color: hex_colour(scene.grid.colour),
+                alpha: if scene.grid.visible {
+                    round(scene.grid.colour[3])
+                } else {
+                    0.0
+                },
+                distance: round(scene.grid.units_per_tile),
+                units: scene.grid.unit.abbreviation().to_string(),
+            },
+            token_vision: scene.fog_of_war,
+            fog_exploration: scene.fog_of_war,
+            tiles: vec![],
+            tokens: vec![],
+            drawings: vec![],
+            walls: scene
+                .walls
+                .iter()
+                .map(|w| Wall {
+                    c: [px(w.from.x), px(w.from.y), px(w.to.x), px(w.to.y)],
+                    movement: WALL_NORMAL,
+                    sense: WALL_NORMAL,
+                    sound: WALL_NORMAL,
+                    door: 0,
+                })
+                .collect(),
+        };
+
+        // Layers are sorted from top to bottom, while Foundry draws in order
+        // of z.
+        let mut z = 0;
+        for layer in scene.layers.iter().rev() {
+            for sprite in &layer.sprites {
+                z += 1;
+                foundry.add_sprite(sprite, layer, z, &scene.grid, textures);
+            }
+        }
+
+        foundry
+    }
+
+    fn add_sprite(
+        &mut self,
+        sprite: &Sprite,
+        layer: &Layer,
+        z: u32,
+        grid: &scene::Grid,
+        textures: &HashMap<i64, String>,
+    ) {
+        let scale = CELL_PIXELS / grid.size;
+        let Rect { x, y, w, h } = sprite.rect.positive_dimensions();
+        let (x, y, width, height) = (x * scale, y * scale, w * scale, h * scale);
+        let src = sprite
+            .visual
+            .texture()
+            .and_then(|id| textures.get(&id))
+            .cloned();
+
+        let token = &sprite.token;
+        if !token.is_empty() || !sprite.owners.is_empty() || sprite.vision.is_some() {
+            self.tokens.push(Token {
+                name: token.name.clone().unwrap_or_default(),
+                texture: Texture {
+                    src: src.or_else(|| Some(DEFAULT_TOKEN_ICON.to_string())),
+                },
+                x: round(x),
+                y: round(y),
+                width: round(w / grid.size),
+                height: round(h / grid.size),
+                alpha: round(layer.opacity),
+                hidden: !layer.visible,
+                locked: layer.locked,
+                actor_link: false,
+                sight: Sight {
+                    enabled: sprite.vision.is_some(),
+                    range: round(sprite.vision.unwrap_or(0.0) / grid.size * grid.units_per_tile),
+                },
+            });
+            return;
+        }
+
+        match sprite.visual {
+            SpriteVisual::Texture(_) => self.tiles.push(Tile {
+                texture: Texture { src },
+                x: round(x),
+                y: round(y),
+                width: round(width),
+                height: round(height),
+                rotation: 0.0,
+                alpha: round(layer.opacity),
+                hidden: !layer.visible,
+                locked: layer.locked,
+                z,
+            }),
+            SpriteVisual::Colour(colour) => {
+                let (kind, points) = match sprite.shape {
+                    SpriteShape::Rectangle => ("r", vec![]),
+                    SpriteShape::Ellipse => ("e", vec![]),
+                    SpriteShape::Triangle => ("p", polygon(3, width, height)),
+                    SpriteShape::Hexagon => ("p", polygon(6, width, height)),
+                };
+
+                self.drawings.push(Drawing {
+                    shape: Shape {
+                        kind,
+                        width: round(width),
+                        height: round(height),
+                        points,
+                    },
+                    x: round(x),
+                    y: round(y),
+                    z,
+                    fill_type: 1,
+                    fill_color: hex_colour(colour),
+                    fill_alpha: round(colour[3] * layer.opacity),
+                    stroke_width: 0.0,
+                    hidden: !layer.visible,
+                    locked: layer.locked,
+                })
+            }
+        }
+    }
+}
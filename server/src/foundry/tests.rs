@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use scene::{DistanceUnit, GridType, Rect, Scene, ScenePoint, SpriteShape, SpriteVisual, Wall};
+
+use crate::test_util::sprite;
+
+use super::FoundryScene;
+
+// Compare a converted scene with the expected document.
+fn check(scene: &Scene, textures: &HashMap<i64, String>, golden: &str) {
+    let actual = serde_json::to_value(FoundryScene::new(scene, "Test", textures)).unwrap();
+    let expected: serde_json::Value = serde_json::from_str(golden).unwrap();
+    assert_eq!(
+        actual,
+        expected,
+        "{}",
+        serde_json::to_string_pretty(&actual).unwrap()
+    );
+}
+
+#[test]
+fn test_foundry_square() {
+    let mut scene = Scene::new();
+    scene.w = 10;
+    scene.h = 8;
+    scene.fog_of_war = true;
+    scene.walls.push(Wall::new(
+        ScenePoint::new(1.0, 1.0),
+        ScenePoint::new(4.5, 1.0),
+    ));
+
+    let background = scene.layers[2].id;
+    let map = 0x1234;
+    scene.add_sprite(
+        sprite(
+            10,
+            Rect::new(0.0, 0.0, 10.0, 8.0),
+            SpriteVisual::Texture(map),
+            SpriteShape::Rectangle,
+        ),
+        background,
+    );
+
+    let scenery = scene.layers[1].id;
+    scene.layer(scenery).unwrap().set_opacity(0.5);
+    scene.add_sprite(
+        sprite(
+            11,
+            Rect::new(2.0, 3.0, -1.0, 1.0),
+            SpriteVisual::Colour([1.0, 0.0, 0.5, 0.8]),
+            SpriteShape::Ellipse,
+        ),
+        scenery,
+    );
+    scene.add_sprite(
+        sprite(
+            12,
+            Rect::new(4.0, 4.0, 1.0, 2.0),
+            SpriteVisual::Colour([0.0, 1.0, 0.0, 1.0]),
+            SpriteShape::Triangle,
+        ),
+        scenery,
+    );
+
+    let foreground = scene.layers[0].id;
+    let mut token = sprite(
+        13,
+        Rect::new(5.0, 5.0, 1.0, 1.0),
+        SpriteVisual::Texture(0x5678),
+        SpriteShape::Rectangle,
+    );
+    token.token.name = Some("Goblin".to_string());
+    token.vision = Some(6.0);
+    scene.add_sprite(token, foreground);
+
+    // Tokens without a texture in the archive use the default icon.
+    let mut token = sprite(
+        14,
+        Rect::new(6.0, 5.0, 2.0, 2.0),
+        SpriteVisual::Colour([1.0, 1.0, 1.0, 1.0]),
+        SpriteShape::Ellipse,
+    );
+    token.owners = vec![3];
+    scene.add_sprite(token, foreground);
+
+    let textures = HashMap::from([
+        (map, "media/0000000000001234.png".to_string()),
+        (0x5678, "media/0000000000005678.jpeg".to_string()),
+    ]);
+    check(
+        &scene,
+        &textures,
+        include_str!("../../testdata/foundry/square.json"),
+    );
+}
+
+#[test]
+fn test_foundry_hex() {
+    let mut scene = Scene::new();
+    scene.w = 6;
+    scene.h = 4;
+    scene.grid.kind = GridType::HexFlat;
+    scene.grid.size = 2.0;
+    scene.grid.visible = false;
+    scene.grid.unit = DistanceUnit::Metres;
+    scene.grid.units_per_tile = 1.5;
+
+    let layer = scene.layers[0].id;
+    scene.layer(layer).unwrap().visible = false;
+    scene.layer(layer).unwrap().locked = true;
+    scene.add_sprite(
+        sprite(
+            20,
+            Rect::new(1.0, 1.0, 2.0, 2.0),
+            SpriteVisual::Colour([0.2, 0.4, 0.6, 1.0]),
+            SpriteShape::Hexagon,
+        ),
+        layer,
+    );
+
+    // Textures missing from the archive are left empty.
+    scene.add_sprite(
+        sprite(
+            21,
+            Rect::new(0.0, 0.0, 4.0, 4.0),
+            SpriteVisual::Texture(99),
+            SpriteShape::Rectangle,
+        ),
+        layer,
+    );
+
+    check(
+        &scene,
+        &HashMap::new(),
+        include_str!("../../testdata/foundry/hex.json"),
+    );
+}
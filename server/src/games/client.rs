@@ -1,14 +1,21 @@
 use tokio::sync::mpsc::UnboundedSender;
 use warp::ws::Message;
 
+use crate::models::User;
+
 pub struct Client {
     pub user: i64,
+    pub username: String,
     sender: Option<UnboundedSender<Message>>,
 }
 
 impl Client {
-    pub fn new(user: i64) -> Self {
-        Client { user, sender: None }
+    pub fn new(user: &User) -> Self {
+        Client {
+            user: user.id,
+            username: user.username.clone(),
+            sender: None,
+        }
     }
 
     pub fn send(&self, message: Message) {
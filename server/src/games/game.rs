@@ -1,12 +1,21 @@
+use std::collections::{HashMap, HashSet};
+
 use scene::{
-    comms::{PermsEvent, SceneEvent},
-    perms::{self, Perms},
-    Scene,
+    comms::{Audience, Grouping, PermsEvent, SceneEvent},
+    perms::{self, Perms, UserPerms},
+    Combatant, History, Id, Scene, ScenePoint,
 };
 
 pub struct Game {
     scene: Scene,
     perms: Perms,
+
+    // Sprites and templates currently visible to each user, or None for
+    // users who see the whole scene.
+    views: HashMap<i64, Option<HashSet<Id>>>,
+
+    // Changes made by each user, against which their undos are checked.
+    histories: HashMap<i64, History>,
 }
 
 impl Game {
@@ -14,34 +23,321 @@ impl Game {
         scene.canon();
         let mut perms = Perms::new();
         perms.set_owner(owner);
-        Self { scene, perms }
+        Self {
+            scene,
+            perms,
+            views: HashMap::new(),
+            histories: HashMap::new(),
+        }
+    }
+
+    // ID of the saved scene this game was started from, if any.
+    pub fn scene_id(&self) -> Option<Id> {
+        self.scene.id
+    }
+
+    // Textures used by sprites in the scene, including removed sprites which
+    // may yet be restored.
+    pub fn textures(&self) -> HashSet<Id> {
+        let layers = self
+            .scene
+            .layers
+            .iter()
+            .chain(self.scene.removed_layers.iter());
+        layers
+            .flat_map(|l| l.sprites.iter().chain(l.removed_sprites.iter()))
+            .filter_map(|s| s.visual.texture())
+            .collect()
+    }
+
+    pub fn is_editor(&self, user: i64) -> bool {
+        self.perms.get_role(user) >= perms::Role::Editor
+    }
+
+    // Whether this user should be shown a message sent by sender to this
+    // audience.
+    pub fn in_audience(&self, user: i64, sender: i64, audience: &Audience) -> bool {
+        user == sender
+            || match audience {
+                Audience::Public => true,
+                Audience::Gm => self.is_editor(user),
+                Audience::Whisper(users) => users.contains(&user),
+            }
     }
 
     pub fn handle_perms(&mut self, user: i64, event: PermsEvent) -> bool {
         self.perms.handle_event(user, event)
     }
 
+    // Revoke temporary overrides which have lapsed with time or at the end
+    // of a turn.
+    pub fn expire_overrides(&mut self) -> Vec<PermsEvent> {
+        let now = crate::handlers::current_time().unwrap_or(0);
+        self.perms.expire_overrides(now, &self.scene.initiative)
+    }
+
+    // Prune removed items which are past the undo horizon from the scene.
+    pub fn compact_scene(&mut self) -> usize {
+        let now = crate::handlers::current_time().unwrap_or(0);
+        self.scene.compact(now)
+    }
+
     pub fn add_player(&mut self, user: i64) -> Option<PermsEvent> {
         self.perms
             .role_change(perms::CANONICAL_UPDATER, user, perms::Role::Player)
     }
 
     pub fn handle_event(&mut self, user: i64, event: SceneEvent) -> bool {
-        if self
-            .perms
-            .permitted(user, &event, self.scene.event_layer(&event))
+        if self.apply_event(user, &event) {
+            self.histories.entry(user).or_default().push(event);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Group the user's changes as they have, so that their undo of the group
+    // can be matched.
+    pub fn handle_group(&mut self, user: i64, grouping: Grouping) {
+        self.histories
+            .entry(user)
+            .or_default()
+            .group(grouping, &self.scene);
+    }
+
+    // An undo may only revert a change the user made which hasn't since been
+    // changed by someone else. Once undone, that change is dropped from the
+    // user's history.
+    pub fn handle_undo(&mut self, user: i64, event: SceneEvent) -> bool {
+        let entry = self
+            .histories
+            .get(&user)
+            .and_then(|h| h.undone_entry(&event));
+
+        match entry {
+            Some(entry) if self.apply_event(user, &event) => {
+                if let Some(history) = self.histories.get_mut(&user) {
+                    history.forget(entry);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Apply an event if permitted, accounting for it in the histories of
+    // other users.
+    fn apply_event(&mut self, user: i64, event: &SceneEvent) -> bool {
+        if self.perms.permitted(
+            user,
+            event,
+            self.scene.event_layer(event),
+            &self.scene.event_owners(event),
+            crate::handlers::current_time().unwrap_or(0),
+            &self.scene.initiative,
+        ) && self.scene.apply_event(event.clone())
         {
-            self.scene.apply_event(event)
+            for (other, history) in self.histories.iter_mut() {
+                if *other != user {
+                    history.handle_remote(event);
+                }
+            }
+            true
         } else {
             false
         }
     }
 
-    pub fn client_scene(&mut self) -> Scene {
-        self.scene.non_canon()
+    // Editors see the whole scene, while players are limited to what their
+    // tokens can see when fog of war is enabled.
+    fn view(&self, user: i64) -> Option<HashSet<Id>> {
+        if self.is_editor(user) {
+            None
+        } else {
+            self.scene.visible_to(user)
+        }
+    }
+
+    pub fn client_scene(&mut self, user: i64) -> Scene {
+        let view = self.view(user);
+        let scene = match &view {
+            Some(visible) => self.scene.non_canon().filtered(visible),
+            None => self.scene.non_canon(),
+        };
+        self.views.insert(user, view);
+        scene
+    }
+
+    // The scene as this user currently sees it. Unlike client_scene, the
+    // view isn't tracked for updates and no IDs are set aside, as the scene
+    // won't be edited.
+    pub fn snapshot_scene(&self, user: i64) -> Scene {
+        match self.view(user) {
+            Some(visible) => self.scene.filtered(&visible),
+            None => self.scene.clone(),
+        }
     }
 
     pub fn client_perms(&mut self) -> Perms {
         self.perms.clone()
     }
+
+    // Users may look up their own permissions, while editors may look up
+    // anyone's.
+    pub fn user_perms(&self, viewer: i64, user: i64) -> Option<UserPerms> {
+        if viewer == user || self.is_editor(viewer) {
+            Some(self.perms.user_perms(
+                user,
+                crate::handlers::current_time().unwrap_or(0),
+                &self.scene.initiative,
+            ))
+        } else {
+            None
+        }
+    }
+
+    // Recompute the view of each user, returning for each user whose view has
+    // changed an event which reveals and hides sprites accordingly.
+    pub fn update_views(&mut self) -> HashMap<i64, SceneEvent> {
+        let users = self.views.keys().copied().collect::<Vec<i64>>();
+
+        let mut changes = HashMap::new();
+        for user in users {
+            let new = self.view(user);
+            let old = self.views.insert(user, new.clone()).flatten();
+            if old == new {
+                continue;
+            }
+
+            let mut events = vec![];
+            for layer in &self.scene.layers {
+                for sprite in &layer.sprites {
+                    let was_visible = sees(&old, sprite.id);
+                    let is_visible = sees(&new, sprite.id);
+                    if is_visible && !was_visible {
+                        events.push(SceneEvent::SpriteNew(sprite.clone(), layer.id));
+                    } else if was_visible && !is_visible {
+                        events.push(SceneEvent::SpriteRemove(sprite.id));
+                    }
+                }
+
+                for template in &layer.templates {
+                    let was_visible = sees(&old, template.id);
+                    let is_visible = sees(&new, template.id);
+                    if is_visible && !was_visible {
+                        events.push(SceneEvent::TemplateNew(*template, layer.id));
+                    } else if was_visible && !is_visible {
+                        events.push(SceneEvent::TemplateRemove(template.id));
+                    }
+                }
+            }
+
+            // Combatants are named once their token comes into view.
+            for combatant in &self.scene.initiative.combatants {
+                if let Some(sprite) = combatant.sprite {
+                    let was_visible = sees(&old, sprite);
+                    let is_visible = sees(&new, sprite);
+                    if is_visible && !was_visible {
+                        events.push(SceneEvent::InitiativeCombatant(
+                            combatant.redacted(),
+                            combatant.clone(),
+                        ));
+                    } else if was_visible && !is_visible {
+                        events.push(SceneEvent::InitiativeCombatant(
+                            combatant.clone(),
+                            combatant.redacted(),
+                        ));
+                    }
+                }
+            }
+
+            if !events.is_empty() {
+                changes.insert(user, SceneEvent::EventSet(events));
+            }
+        }
+        changes
+    }
+
+    // The portion of this event which the user is able to see. This is
+    // judged by the user's view before the event, as changes to the view
+    // are shared by update_views.
+    pub fn filter_event(&self, user: i64, event: &SceneEvent) -> Option<SceneEvent> {
+        match self.views.get(&user) {
+            Some(Some(visible)) => filter_event(&self.scene, visible, event),
+            _ => Some(event.clone()),
+        }
+    }
+
+    // The ruler shared by sender as this user may see it. Like events, rulers
+    // are limited by fog of war, so a ruler is only shown to a player who
+    // could see each of its points. Otherwise the ruler is cleared.
+    pub fn filter_ruler(&self, user: i64, sender: i64, points: &[ScenePoint]) -> Vec<ScenePoint> {
+        let limited = matches!(self.views.get(&user), Some(Some(_)));
+        if !limited
+            || user == sender
+            || points.iter().all(|&p| self.scene.point_visible_to(user, p))
+        {
+            points.to_vec()
+        } else {
+            vec![]
+        }
+    }
+}
+
+fn sees(view: &Option<HashSet<Id>>, id: Id) -> bool {
+    match view {
+        Some(visible) => visible.contains(&id),
+        None => true,
+    }
+}
+
+fn filter_event(scene: &Scene, visible: &HashSet<Id>, event: &SceneEvent) -> Option<SceneEvent> {
+    match event {
+        SceneEvent::EventSet(events) => {
+            let events = events
+                .iter()
+                .filter_map(|e| filter_event(scene, visible, e))
+                .collect::<Vec<SceneEvent>>();
+            if events.is_empty() {
+                None
+            } else {
+                Some(SceneEvent::EventSet(events))
+            }
+        }
+        SceneEvent::SpriteZ(id, old, new) if visible.contains(id) => Some(SceneEvent::SpriteZ(
+            *id,
+            scene.visible_below(*id, *old, visible),
+            scene.visible_below(*id, *new, visible),
+        )),
+        _ if event.is_sprite() || event.is_template() => match event.item() {
+            Some(id) if visible.contains(&id) => Some(event.clone()),
+            _ => None,
+        },
+        SceneEvent::InitiativeAdd(c, position) => {
+            Some(SceneEvent::InitiativeAdd(redact(visible, c), *position))
+        }
+        SceneEvent::InitiativeCombatant(old, new) => {
+            let (old, new) = (redact(visible, old), redact(visible, new));
+            if old == new {
+                None
+            } else {
+                Some(SceneEvent::InitiativeCombatant(old, new))
+            }
+        }
+        SceneEvent::InitiativeRemove(c, position, round, turn) => Some(
+            SceneEvent::InitiativeRemove(redact(visible, c), *position, *round, *turn),
+        ),
+        SceneEvent::InitiativeRestore(c, position, round, turn) => Some(
+            SceneEvent::InitiativeRestore(redact(visible, c), *position, *round, *turn),
+        ),
+        _ => Some(event.clone()),
+    }
+}
+
+// A combatant as seen by a user, which is redacted if its token is hidden.
+fn redact(visible: &HashSet<Id>, combatant: &Combatant) -> Combatant {
+    match combatant.sprite {
+        Some(sprite) if !visible.contains(&sprite) => combatant.redacted(),
+        _ => combatant.clone(),
+    }
 }
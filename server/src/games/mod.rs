@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 use bincode::deserialize;
@@ -12,6 +12,9 @@ mod client;
 mod game;
 mod server;
 
+#[cfg(test)]
+mod tests;
+
 pub use game::Game;
 pub use server::Server as GameServer;
 
@@ -20,6 +23,89 @@ pub type Games = Arc<RwLock<HashMap<String, GameRef>>>;
 
 pub const GAME_KEY_LENGTH: usize = 6;
 
+// How often running games are checked for expired permission overrides.
+const EXPIRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Periodically revoke overrides which have expired in each running game.
+pub async fn expire_overrides(games: Games) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        let running = games
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<GameRef>>();
+        for game in running {
+            game.read().await.expire_overrides().await;
+        }
+    }
+}
+
+// How often the scenes of running games are compacted.
+const COMPACTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+// Periodically prune removed sprites and layers which can no longer be
+// restored from the scene of each running game, so that long sessions don't
+// grow without bound.
+pub async fn compact_scenes(games: Games) {
+    let mut interval = tokio::time::interval(COMPACTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        let running = games
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<GameRef>>();
+        for game in running {
+            game.read().await.compact_scene().await;
+        }
+    }
+}
+
+// What running games are using, which can't be changed under them.
+#[derive(Default)]
+pub struct InUse {
+    // IDs of the saved scenes the games were started from.
+    pub scenes: HashSet<i64>,
+
+    // Textures of sprites in the games' scenes, including removed sprites.
+    pub textures: HashSet<i64>,
+}
+
+pub async fn in_use(games: &HashMap<String, GameRef>) -> InUse {
+    let mut in_use = InUse::default();
+    for game in games.values() {
+        let game = game.read().await;
+        if let Some(scene) = game.scene_id().await {
+            in_use.scenes.insert(scene);
+        }
+        in_use.textures.extend(game.textures().await);
+    }
+    in_use
+}
+
+// End running games of any of these scenes, disconnecting their players.
+pub async fn end_scene_games(games: &Games, scenes: &HashSet<i64>) {
+    let mut games = games.write().await;
+    let mut ended = vec![];
+    for (key, game) in games.iter() {
+        if let Some(scene) = game.read().await.scene_id().await {
+            if scenes.contains(&scene) {
+                ended.push(key.clone());
+            }
+        }
+    }
+
+    for key in ended {
+        if let Some(game) = games.remove(&key) {
+            game.read().await.close();
+        }
+    }
+}
+
 pub async fn client_connection(ws: WebSocket, key: String, game: GameRef) {
     let (mut client_ws_send, mut client_ws_recv) = ws.split();
     let (client_send, client_recv) = unbounded_channel();
@@ -1,27 +1,49 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use bincode::serialize;
-use scene::Scene;
+use scene::{perms::UserPerms, Scene, ScenePoint};
+use sqlx::SqlitePool;
 use tokio::sync::{mpsc::UnboundedSender, RwLock};
 use warp::ws::Message;
 
-use scene::comms::{ClientEvent, ClientMessage, ServerEvent};
+use scene::comms::{
+    Audience, ChatMessage, ClientEvent, ClientMessage, Grouping, PermsEvent, SceneEvent,
+    ServerEvent,
+};
+use scene::dice::Expression;
+
+use crate::{crypto, models::ChatRecord, models::User};
 
 use super::client::Client;
 use super::game::Game;
 
+// Where chat messages are saved, if the game is logging chat.
+struct ChatLog {
+    pool: SqlitePool,
+    project: i64,
+}
+
 pub struct Server {
     clients: HashMap<String, Client>,
     owner: i64,
     game: RwLock<Game>,
+    chat: RwLock<Vec<ChatMessage>>,
+    chat_log: Option<ChatLog>,
 }
 
 impl Server {
+    // Number of recent chat messages kept to send to players joining.
+    const CHAT_HISTORY: usize = 100;
+
+    const MAX_CHAT_LENGTH: usize = 2000;
+
     pub fn new(owner: i64, game: Game) -> Self {
         Server {
             clients: HashMap::new(),
             owner,
             game: RwLock::new(game),
+            chat: RwLock::new(Vec::new()),
+            chat_log: None,
         }
     }
 
@@ -29,7 +51,15 @@ impl Server {
         Self::new(owner, super::Game::new(scene, owner))
     }
 
-    pub fn add_client(&mut self, key: String, user: i64) {
+    // Save chat messages sent in this game to the project's log, loading the
+    // recent history from the log.
+    pub async fn log_chat(&mut self, pool: SqlitePool, project: i64) -> anyhow::Result<()> {
+        *self.chat.get_mut() = ChatRecord::load_recent(&pool, project, Self::CHAT_HISTORY).await?;
+        self.chat_log = Some(ChatLog { pool, project });
+        Ok(())
+    }
+
+    pub fn add_client(&mut self, key: String, user: &User) {
         self.clients.insert(key, Client::new(user));
     }
 
@@ -37,6 +67,44 @@ impl Server {
         self.clients.contains_key(key)
     }
 
+    pub fn owner(&self) -> i64 {
+        self.owner
+    }
+
+    // The scene as seen by this user, if they own or have joined the game.
+    pub async fn snapshot_scene(&self, user: i64) -> Option<Scene> {
+        if user != self.owner && !self.clients.values().any(|c| c.user == user) {
+            return None;
+        }
+
+        Some(self.game.read().await.snapshot_scene(user))
+    }
+
+    // The permissions of a user, if the viewer owns or has joined the game
+    // and may see them.
+    pub async fn user_perms(&self, viewer: i64, user: i64) -> Option<UserPerms> {
+        if viewer != self.owner && !self.clients.values().any(|c| c.user == viewer) {
+            return None;
+        }
+
+        self.game.read().await.user_perms(viewer, user)
+    }
+
+    pub async fn scene_id(&self) -> Option<i64> {
+        self.game.read().await.scene_id()
+    }
+
+    pub async fn textures(&self) -> HashSet<i64> {
+        self.game.read().await.textures()
+    }
+
+    // Disconnect all clients, as the game is ending.
+    pub fn close(&self) {
+        for client in self.clients.values() {
+            client.send(Message::close());
+        }
+    }
+
     pub fn drop_client(&mut self, key: &str) {
         self.clients.remove(key);
     }
@@ -52,8 +120,22 @@ impl Server {
             }
 
             self.send_to(ServerEvent::UserId(player), &key);
-            self.send_to(ServerEvent::SceneChange(lock.client_scene()), &key);
+            self.send_to(ServerEvent::SceneChange(lock.client_scene(player)), &key);
             self.send_to(ServerEvent::PermsChange(lock.client_perms()), &key);
+
+            let history = self
+                .chat
+                .read()
+                .await
+                .iter()
+                .filter(|m| lock.in_audience(player, m.user, &m.audience))
+                .cloned()
+                .collect::<Vec<ChatMessage>>();
+            if !history.is_empty() {
+                self.send_to(ServerEvent::ChatHistory(history), &key);
+            }
+
+            self.broadcast_event(ServerEvent::Players(self.players()), None);
             true
         } else {
             self.drop_client(&key);
@@ -61,6 +143,18 @@ impl Server {
         }
     }
 
+    // Each user in the game, once, with their username.
+    fn players(&self) -> Vec<(i64, String)> {
+        let mut players = self
+            .clients
+            .values()
+            .map(|c| (c.user, c.username.clone()))
+            .collect::<Vec<(i64, String)>>();
+        players.sort();
+        players.dedup();
+        players
+    }
+
     fn get_client_mut(&mut self, key: &str) -> Option<&mut Client> {
         self.clients.get_mut(key)
     }
@@ -91,6 +185,42 @@ impl Server {
         }
     }
 
+    // Send an approved scene event to each client, limited to the sprites that
+    // client can see, along with any change to what that client can see as a
+    // result of the event. The event is filtered by what each client could
+    // see beforehand, so that the removal of a visible sprite is shared and
+    // a sprite coming into view arrives in its new state.
+    fn broadcast_scene_event(&self, game: &mut Game, event: &SceneEvent, from: &str) {
+        let filtered = self
+            .clients
+            .iter()
+            .filter(|(key, _)| *key != from)
+            .filter_map(|(key, client)| Some((key, game.filter_event(client.user, event)?)))
+            .collect::<HashMap<&String, SceneEvent>>();
+
+        let view_changes = game.update_views();
+        for (key, client) in &self.clients {
+            let mut events = vec![];
+            if let Some(event) = filtered.get(key) {
+                events.push(event.clone());
+            }
+
+            if let Some(event) = view_changes.get(&client.user) {
+                events.push(event.clone());
+            }
+
+            let event = match events.len() {
+                0 => continue,
+                1 => events.remove(0),
+                _ => SceneEvent::EventSet(events),
+            };
+
+            if let Ok(data) = serialize(&ServerEvent::SceneUpdate(event)) {
+                client.send(Message::binary(data));
+            }
+        }
+    }
+
     fn send_approval(&self, event_id: i64, client_key: &str) {
         self.send_to(ServerEvent::Approval(event_id), client_key);
     }
@@ -99,26 +229,181 @@ impl Server {
         self.send_to(ServerEvent::Rejection(event_id), client_key);
     }
 
+    // Send an event to each client in the audience of a message from sender.
+    async fn send_to_audience(&self, event: ServerEvent, sender: i64, audience: &Audience) {
+        let data = match serialize(&event) {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+
+        let game = self.game.read().await;
+        for client in self.clients.values() {
+            if game.in_audience(client.user, sender, audience) {
+                client.send(Message::binary(data.clone()));
+            }
+        }
+    }
+
+    // Roll the expression and send the result to the roller and each user
+    // permitted to see it.
+    async fn handle_roll(&self, id: i64, expression: &str, audience: Audience, from: &str) {
+        let user = match self.clients.get(from) {
+            Some(client) => client.user,
+            None => return,
+        };
+
+        let roll = match Expression::parse(expression).map(|e| e.roll(crypto::roll_die)) {
+            Ok(Ok(roll)) => roll,
+            _ => {
+                self.send_rejection(id, from);
+                return;
+            }
+        };
+        self.send_approval(id, from);
+
+        let event = ServerEvent::Roll(user, audience.clone(), roll);
+        self.send_to_audience(event, user, &audience).await;
+    }
+
+    async fn handle_chat(&self, id: i64, text: String, audience: Audience, from: &str) {
+        let client = match self.clients.get(from) {
+            Some(client) => client,
+            None => return,
+        };
+
+        let text = text.trim();
+        let time = match crate::handlers::current_time() {
+            Ok(time) if !text.is_empty() && text.len() <= Self::MAX_CHAT_LENGTH => time,
+            _ => {
+                self.send_rejection(id, from);
+                return;
+            }
+        };
+        self.send_approval(id, from);
+
+        let message = ChatMessage {
+            user: client.user,
+            username: client.username.clone(),
+            audience,
+            text: text.to_string(),
+            time,
+        };
+
+        if let Some(log) = &self.chat_log {
+            if let Err(e) = ChatRecord::save(&log.pool, log.project, &message).await {
+                eprintln!("{e}");
+            }
+        }
+
+        {
+            let mut chat = self.chat.write().await;
+            if chat.len() >= Self::CHAT_HISTORY {
+                chat.remove(0);
+            }
+            chat.push(message.clone());
+        }
+
+        let (user, audience) = (message.user, message.audience.clone());
+        self.send_to_audience(ServerEvent::Chat(message), user, &audience)
+            .await;
+    }
+
+    // Share the revocation of any overrides which have expired.
+    pub async fn expire_overrides(&self) {
+        let events = self.game.write().await.expire_overrides();
+        for event in events {
+            self.broadcast_event(ServerEvent::PermsUpdate(event), None);
+        }
+    }
+
+    pub async fn compact_scene(&self) {
+        self.game.write().await.compact_scene();
+    }
+
+    async fn handle_perms(&self, id: i64, event: PermsEvent, from: &str) {
+        let user = match self.clients.get(from) {
+            Some(client) => client.user,
+            None => return,
+        };
+
+        if self.game.write().await.handle_perms(user, event.clone()) {
+            self.send_approval(id, from);
+            self.broadcast_event(ServerEvent::PermsUpdate(event), Some(from));
+        } else {
+            self.send_rejection(id, from);
+        }
+    }
+
+    async fn handle_scene_event(&self, id: i64, event: SceneEvent, undo: bool, from: &str) {
+        if let Some(client) = self.clients.get(from) {
+            let mut game = self.game.write().await;
+            let handled = if undo {
+                game.handle_undo(client.user, event.clone())
+            } else {
+                game.handle_event(client.user, event.clone())
+            };
+
+            if handled {
+                self.send_approval(id, from);
+                self.broadcast_scene_event(&mut game, &event, from);
+            } else {
+                self.send_rejection(id, from);
+            }
+        }
+
+        // Overrides lasting until the end of a turn lapse as soon as the turn
+        // changes.
+        if event.is_initiative() {
+            self.expire_overrides().await;
+        }
+    }
+
+    async fn handle_group(&self, grouping: Grouping, from: &str) {
+        if let Some(client) = self.clients.get(from) {
+            self.game.write().await.handle_group(client.user, grouping);
+        }
+    }
+
+    // Share a ruler with each other client, limited to what they can see.
+    async fn handle_ruler(&self, points: Vec<ScenePoint>, from: &str) {
+        let user = match self.clients.get(from) {
+            Some(client) if points.len() <= ClientEvent::MAX_RULER_POINTS => client.user,
+            _ => return,
+        };
+
+        let game = self.game.read().await;
+        for (key, client) in &self.clients {
+            if key != from {
+                let points = game.filter_ruler(client.user, user, &points);
+                self.send_to(ServerEvent::Ruler(user, points), key);
+            }
+        }
+    }
+
     pub async fn handle_message(&self, message: ClientMessage, from: &str) {
         match message.event {
             ClientEvent::Ping => {
                 self.send_approval(message.id, from);
             }
             ClientEvent::SceneUpdate(event) => {
-                if let Some(client) = self.clients.get(from) {
-                    if self
-                        .game
-                        .write()
-                        .await
-                        .handle_event(client.user, event.clone())
-                    {
-                        self.send_approval(message.id, from);
-                        self.broadcast_event(ServerEvent::SceneUpdate(event), Some(from));
-                    } else {
-                        self.send_rejection(message.id, from);
-                    }
-                }
+                self.handle_scene_event(message.id, event, false, from)
+                    .await;
+            }
+            ClientEvent::Undo(event) => {
+                self.handle_scene_event(message.id, event, true, from).await;
+            }
+            ClientEvent::Ruler(points) => self.handle_ruler(points, from).await,
+            ClientEvent::Roll(expression, audience) => {
+                self.handle_roll(message.id, &expression, audience, from)
+                    .await;
+            }
+            ClientEvent::Chat(text, audience) => {
+                self.handle_chat(message.id, text, audience, from).await;
+            }
+            ClientEvent::PermsUpdate(event) => {
+                self.handle_perms(message.id, event, from).await;
             }
+            ClientEvent::Group(grouping) => self.handle_group(grouping, from).await,
         };
     }
 }
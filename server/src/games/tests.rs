@@ -0,0 +1,133 @@
+use scene::{
+    comms::{Grouping, SceneEvent},
+    History, Rect, Scene, ScenePoint, SpriteShape, SpriteVisual, Template, TemplateShape,
+};
+
+use crate::test_util::sprite;
+
+use super::Game;
+
+const OWNER: i64 = 1;
+
+// A game alongside the owner's client, whose history each change and group
+// is recorded in as the interactor does.
+struct Session {
+    game: Game,
+    scene: Scene,
+    history: History,
+}
+
+impl Session {
+    fn new() -> Self {
+        let mut scene = Scene::new();
+        let layer = scene.first_layer();
+        for id in [10, 11] {
+            let visual = SpriteVisual::Colour([0.9, 0.9, 0.8, 1.0]);
+            scene.add_sprite(sprite(id, rect(0.0), visual, SpriteShape::Rectangle), layer);
+        }
+        scene.add_template(
+            Template::new(
+                20,
+                ScenePoint::new(0.0, 0.0),
+                TemplateShape::Circle { radius: 1.0 },
+            ),
+            layer,
+        );
+
+        Self {
+            game: Game::new(scene.clone(), OWNER),
+            scene,
+            history: History::new(),
+        }
+    }
+
+    fn change(&mut self, event: SceneEvent) {
+        assert!(self.game.handle_event(OWNER, event.clone()));
+        self.history.push(event);
+    }
+
+    fn group(&mut self, grouping: Grouping) {
+        self.history.group(grouping, &self.scene);
+        self.game.handle_group(OWNER, grouping);
+    }
+
+    fn undo(&mut self) -> bool {
+        let event = self.history.undo(&mut self.scene).unwrap();
+        self.game.handle_undo(OWNER, event)
+    }
+
+    fn rect(&self, id: i64) -> Rect {
+        self.game.snapshot_scene(OWNER).sprite_ref(id).unwrap().rect
+    }
+}
+
+fn rect(x: f32) -> Rect {
+    Rect::new(x, 0.0, 1.0, 1.0)
+}
+
+#[test]
+fn test_undo_groups() {
+    let mut session = Session::new();
+
+    // Dragging a sprite in several steps is undone as one.
+    session.group(Grouping::Start);
+    for x in [1.0, 2.0, 3.0] {
+        let event = session.scene.sprite(10).unwrap().set_rect(rect(x));
+        session.change(event);
+    }
+    session.group(Grouping::Moves);
+    assert!(session.undo());
+    assert_eq!(session.rect(10), rect(0.0));
+
+    // As is dragging a selection.
+    session.group(Grouping::Start);
+    for x in [1.0, 2.0] {
+        let events = [10, 11]
+            .into_iter()
+            .map(|id| session.scene.sprite(id).unwrap().set_rect(rect(x)))
+            .collect();
+        session.change(SceneEvent::EventSet(events));
+    }
+    session.group(Grouping::Moves);
+    assert!(session.undo());
+    assert_eq!(session.rect(10), rect(0.0));
+    assert_eq!(session.rect(11), rect(0.0));
+
+    // And moving and turning a template.
+    session.group(Grouping::Start);
+    for x in [1.0, 2.0] {
+        let template = session.scene.template(20).unwrap();
+        let events = vec![
+            template.set_origin(ScenePoint::new(x, 0.0)),
+            template.set_rotation(x),
+        ];
+        session.change(SceneEvent::EventSet(events));
+    }
+    session.group(Grouping::Template(20));
+    assert!(session.undo());
+    let scene = session.game.snapshot_scene(OWNER);
+    let template = scene.template_ref(20).unwrap();
+    assert_eq!(template.origin, ScenePoint::new(0.0, 0.0));
+    assert_eq!(template.rotation, 0.0);
+
+    // Having been undone, the drag can't be undone again.
+    assert!(!session
+        .game
+        .handle_undo(OWNER, SceneEvent::SpriteMove(10, rect(3.0), rect(0.0))));
+}
+
+#[test]
+fn test_textures() {
+    let mut scene = Scene::new();
+    let layer = scene.first_layer();
+    for (id, texture) in [(10, 1), (11, 2)] {
+        let visual = SpriteVisual::Texture(texture);
+        scene.add_sprite(sprite(id, rect(0.0), visual, SpriteShape::Rectangle), layer);
+    }
+    let mut game = Game::new(scene, OWNER);
+    assert_eq!(game.textures(), [1, 2].into());
+
+    // A removed sprite may be restored, so its texture is still in use.
+    assert!(game.handle_event(OWNER, SceneEvent::SpriteRemove(11)));
+    assert_eq!(game.textures(), [1, 2].into());
+}
@@ -10,7 +10,13 @@ pub fn routes(
     content_dir: &Path,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     new::filter(pool.clone(), games.clone())
-        .or(join::filter(pool, games.clone()))
+        .or(join::filter(pool.clone(), games.clone()))
+        .or(snapshot::filter(
+            pool.clone(),
+            games.clone(),
+            content_dir.to_string_lossy().into_owned(),
+        ))
+        .or(perms::filter(pool.clone(), games.clone()))
         .or(connect::filter(games))
         .or(html_route(content_dir))
 }
@@ -107,21 +113,21 @@ mod join {
         }
     }
 
-    async fn add_client(game: GameRef, user_id: i64) -> anyhow::Result<String> {
+    async fn add_client(game: GameRef, user: &User) -> anyhow::Result<String> {
         let client_key = generate_game_key()?;
 
-        game.write().await.add_client(client_key.clone(), user_id);
+        game.write().await.add_client(client_key.clone(), user);
 
         Ok(client_key)
     }
 
-    pub async fn join_game(games: Games, game_key: String, user_id: i64) -> ResultReply {
+    pub async fn join_game(games: Games, game_key: String, user: &User) -> ResultReply {
         let game = match games.read().await.get(&game_key) {
             Some(game_ref) => game_ref.clone(),
             None => return Binary::result_error("Game not found."),
         };
 
-        match add_client(game, user_id).await {
+        match add_client(game, user).await {
             Ok(client_key) => {
                 as_result(&JoinGameResponse::new(game_key, client_key), StatusCode::OK)
             }
@@ -140,7 +146,7 @@ mod join {
             _ => return Binary::result_failure("Bad session."),
         };
 
-        join_game(games, game_key, user.id).await
+        join_game(games, game_key, &user).await
     }
 
     pub fn filter(
@@ -175,6 +181,10 @@ mod new {
     #[derive(Deserialize)]
     struct NewGameRequest {
         scene_key: String,
+
+        // Whether to save chat messages to the project's chat log.
+        #[serde(default)]
+        save_chat: bool,
     }
 
     async fn new_game(
@@ -223,14 +233,20 @@ mod new {
             }
         };
 
-        games.write().await.insert(
-            game_key.clone(),
-            Arc::new(RwLock::new(games::GameServer::new_with_scene(
-                user.id, scene,
-            ))),
-        );
+        let project = scene.project;
+        let mut server = games::GameServer::new_with_scene(user.id, scene);
+        if let (true, Some(project)) = (req.save_chat, project) {
+            if server.log_chat(pool.clone(), project).await.is_err() {
+                return Binary::result_error("Failed to load chat log.");
+            }
+        }
+
+        games
+            .write()
+            .await
+            .insert(game_key.clone(), Arc::new(RwLock::new(server)));
 
-        super::join::join_game(games, game_key, user.id).await
+        super::join::join_game(games, game_key, &user).await
     }
 
     pub fn filter(
@@ -247,3 +263,153 @@ mod new {
             .and_then(new_game)
     }
 }
+
+mod snapshot {
+    use std::convert::Infallible;
+
+    use serde_derive::Deserialize;
+    use warp::{Filter, Reply};
+
+    use crate::games::Games;
+    use crate::handlers::{response::Binary, scene::render_png, with_db, with_session};
+    use crate::models::User;
+
+    const DEFAULT_SIZE: u32 = 1024;
+    const MAX_SIZE: u32 = 2048;
+
+    #[derive(Deserialize)]
+    struct SnapshotQuery {
+        width: Option<u32>,
+        height: Option<u32>,
+    }
+
+    // Reply with a PNG of the game's scene as the user sees it, fitted to
+    // the requested size.
+    async fn snapshot(
+        game_key: String,
+        games: Games,
+        pool: sqlx::SqlitePool,
+        skey: String,
+        content_dir: String,
+        query: SnapshotQuery,
+    ) -> Result<warp::reply::Response, Infallible> {
+        let failure = |msg: &str| Binary::result_failure(msg).map(Reply::into_response);
+
+        let user = match User::get_by_session(&pool, &skey).await {
+            Ok(Some(u)) => u,
+            _ => return failure("Bad session."),
+        };
+
+        let game = match games.read().await.get(&game_key) {
+            Some(game_ref) => game_ref.clone(),
+            None => return failure("Game not found."),
+        };
+
+        let (owner, scene) = {
+            let game = game.read().await;
+            match game.snapshot_scene(user.id).await {
+                Some(s) => (game.owner(), s),
+                None => return failure("Not a player in this game."),
+            }
+        };
+
+        let width = query.width.unwrap_or(DEFAULT_SIZE).clamp(1, MAX_SIZE);
+        let height = query.height.unwrap_or(DEFAULT_SIZE).clamp(1, MAX_SIZE);
+        match render_png(&pool, &content_dir, owner, scene, width, height).await {
+            Ok(png) => {
+                Ok(warp::reply::with_header(png, "Content-Type", "image/png").into_response())
+            }
+            Err(_) => Binary::result_error("Failed to render scene.").map(Reply::into_response),
+        }
+    }
+
+    pub fn filter(
+        pool: sqlx::SqlitePool,
+        games: Games,
+        content_dir: String,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("game" / String / "snapshot")
+            .and(warp::get())
+            .and(super::with_games(games))
+            .and(with_db(pool))
+            .and(with_session())
+            .and(warp::any().map(move || content_dir.clone()))
+            .and(warp::query::<SnapshotQuery>())
+            .and_then(snapshot)
+    }
+}
+
+mod perms {
+    use std::convert::Infallible;
+
+    use scene::perms::UserPerms;
+    use serde_derive::{Deserialize, Serialize};
+    use warp::{http::StatusCode, Filter};
+
+    use crate::games::Games;
+    use crate::handlers::{
+        response::{as_result, Binary},
+        with_db, with_session,
+    };
+    use crate::models::User;
+
+    #[derive(Deserialize)]
+    struct PermsQuery {
+        // Defaults to the requesting user.
+        user: Option<i64>,
+    }
+
+    #[derive(Serialize)]
+    struct PermsResponse {
+        message: String,
+        success: bool,
+        perms: UserPerms,
+    }
+
+    // Reply with what a user may do in the game, including their role,
+    // item permissions and any overrides they have been granted.
+    async fn user_perms(
+        game_key: String,
+        games: Games,
+        pool: sqlx::SqlitePool,
+        skey: String,
+        query: PermsQuery,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let user = match User::get_by_session(&pool, &skey).await {
+            Ok(Some(u)) => u,
+            _ => return Binary::result_failure("Bad session."),
+        };
+
+        let game = match games.read().await.get(&game_key) {
+            Some(game_ref) => game_ref.clone(),
+            None => return Binary::result_failure("Game not found."),
+        };
+
+        let target = query.user.unwrap_or(user.id);
+        let perms = game.read().await.user_perms(user.id, target).await;
+        match perms {
+            Some(perms) => as_result(
+                &PermsResponse {
+                    message: "Permissions retrieved.".to_string(),
+                    success: true,
+                    perms,
+                },
+                StatusCode::OK,
+            ),
+            None => Binary::result_failure("Not permitted."),
+        }
+    }
+
+    pub fn filter(
+        pool: sqlx::SqlitePool,
+        games: Games,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("game" / String / "perms")
+            .and(warp::get())
+            .and(super::with_games(games))
+            .and(with_db(pool))
+            .and(with_session())
+            .and(warp::query::<PermsQuery>())
+            .and_then(user_perms)
+    }
+}
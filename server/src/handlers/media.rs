@@ -1,6 +1,57 @@
+use std::collections::HashSet;
+
 use warp::Filter;
 
-use sqlx::SqlitePool;
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::games::{Games, InUse};
+use crate::models::Media;
+
+// Colour given to sprites which used a deleted media item as their texture,
+// if they are to be kept.
+const PLACEHOLDER_COLOUR: scene::Colour = [0.5, 0.5, 0.5, 1.0];
+
+// Check that these media items may be deleted, returning the keys of the
+// scenes with sprites which use them if those sprites are to be given the
+// placeholder colour. Items used in running games can't be deleted, nor can
+// the scenes being played be changed, as the game's copy of the scene would
+// keep the texture and restore it when next saved.
+async fn deleted_scenes(
+    conn: &mut SqliteConnection,
+    items: &[Media],
+    replace: bool,
+    in_use: &InUse,
+) -> anyhow::Result<Vec<String>> {
+    for media in items {
+        if in_use
+            .textures
+            .contains(&Media::key_to_id(&media.media_key)?)
+        {
+            return Err(anyhow::anyhow!(
+                "Media item {} is used in a running game.",
+                media.title
+            ));
+        }
+    }
+
+    if !replace {
+        return Ok(vec![]);
+    }
+
+    let mut scenes = HashSet::new();
+    for media in items {
+        for (id, key) in media.scenes(conn).await? {
+            if in_use.scenes.contains(&id) {
+                return Err(anyhow::anyhow!(
+                    "Media item {} is used by a scene in a running game.",
+                    media.title
+                ));
+            }
+            scenes.insert(key);
+        }
+    }
+    Ok(scenes.into_iter().collect())
+}
 
 #[derive(serde_derive::Serialize, sqlx::FromRow)]
 struct MediaItem {
@@ -114,6 +165,7 @@ mod details {
 
 mod list {
     use core::convert::Infallible;
+    use serde_derive::{Deserialize, Serialize};
     use sqlx::SqlitePool;
     use warp::Filter;
 
@@ -121,42 +173,96 @@ mod list {
         response::{as_result, Binary},
         with_db, with_session,
     };
-    use crate::models::User;
+    use crate::models::{Media, MediaFilter, User};
 
     use super::MediaItem;
 
-    #[derive(serde_derive::Serialize)]
+    #[derive(Deserialize)]
+    struct ListQuery {
+        // Key of the folder to list, or empty for the top level. Items in all
+        // folders are listed if absent.
+        folder: Option<String>,
+
+        // Only list items with titles containing this, ignoring case.
+        search: Option<String>,
+
+        // Only list items with this tag.
+        tag: Option<String>,
+
+        // Page of items to list. All items are listed if no limit is given.
+        limit: Option<u32>,
+        #[serde(default)]
+        offset: u32,
+    }
+
+    #[derive(Serialize)]
+    struct ListItem {
+        #[serde(flatten)]
+        item: MediaItem,
+        folder: Option<String>,
+        tags: Vec<String>,
+    }
+
+    #[derive(Serialize)]
     struct MediaListResponse {
-        items: Vec<MediaItem>,
+        items: Vec<ListItem>,
+
+        // Number of items matching the query, over all pages.
+        total: i64,
         success: bool,
     }
 
-    impl MediaListResponse {
-        fn new(items: Vec<MediaItem>) -> MediaListResponse {
-            MediaListResponse {
-                items,
-                success: true,
-            }
-        }
-    }
+    async fn user_media(
+        pool: &SqlitePool,
+        user_id: i64,
+        filter: &MediaFilter,
+    ) -> anyhow::Result<MediaListResponse> {
+        let conn = &mut pool.acquire().await?;
+        let (items, total) = Media::list(conn, user_id, filter).await?;
 
-    async fn user_media(pool: &SqlitePool, user_id: i64) -> anyhow::Result<Vec<MediaItem>> {
-        let results =
-            sqlx::query_as("SELECT media_key, title, relative_path FROM media WHERE user = ?1;")
-                .bind(user_id)
-                .fetch_all(pool)
-                .await?;
-        Ok(results)
+        Ok(MediaListResponse {
+            items: items
+                .into_iter()
+                .map(|listing| ListItem {
+                    tags: listing.tags(),
+                    item: MediaItem {
+                        media_key: listing.media_key,
+                        title: listing.title,
+                        url: listing.relative_path,
+                    },
+                    folder: listing.folder_key,
+                })
+                .collect(),
+            total,
+            success: true,
+        })
     }
 
-    async fn list_media(pool: SqlitePool, skey: String) -> Result<impl warp::Reply, Infallible> {
+    async fn list_media(
+        pool: SqlitePool,
+        skey: String,
+        query: ListQuery,
+    ) -> Result<impl warp::Reply, Infallible> {
         let user = match User::get_by_session(&pool, &skey).await {
             Ok(Some(user)) => user,
             _ => return Binary::result_failure("Invalid session."),
         };
 
-        match user_media(&pool, user.id).await {
-            Ok(media) => as_result(&MediaListResponse::new(media), warp::http::StatusCode::OK),
+        let tag = match query.tag.as_deref().map(Media::tag) {
+            Some(Some(tag)) => Some(tag),
+            Some(None) => return Binary::result_failure("Invalid tag."),
+            None => None,
+        };
+
+        let filter = MediaFilter {
+            folder: query.folder,
+            search: query.search,
+            tag,
+            limit: query.limit,
+            offset: query.offset,
+        };
+        match user_media(&pool, user.id, &filter).await {
+            Ok(response) => as_result(&response, warp::http::StatusCode::OK),
             Err(_) => Binary::result_error("Database error."),
         }
     }
@@ -168,12 +274,621 @@ mod list {
             .and(warp::path("list"))
             .and(with_db(pool))
             .and(with_session())
+            .and(warp::query::<ListQuery>())
             .and_then(list_media)
     }
 }
 
+mod delete {
+    use serde_derive::{Deserialize, Serialize};
+    use sqlx::SqlitePool;
+    use warp::Filter;
+
+    use crate::games::{self, Games};
+    use crate::handlers::response::{as_result, Binary, ResultReply};
+    use crate::handlers::{scene::refresh_thumbnails, with_db, with_session};
+    use crate::models::{Media, User};
+
+    #[derive(Deserialize)]
+    struct DeleteQuery {
+        // Whether to replace the texture of sprites using the item with a
+        // placeholder colour, rather than refusing to delete it.
+        #[serde(default)]
+        replace: bool,
+    }
+
+    #[derive(Serialize)]
+    struct DeleteResponse {
+        message: String,
+        success: bool,
+
+        // Number of sprites given the placeholder colour.
+        replaced: u64,
+    }
+
+    async fn delete_media(
+        key: String,
+        pool: SqlitePool,
+        skey: String,
+        games: Games,
+        content_dir: String,
+        query: DeleteQuery,
+    ) -> ResultReply {
+        let user = match User::get_by_session(&pool, &skey).await {
+            Ok(Some(user)) => user,
+            _ => return Binary::result_failure("Invalid session."),
+        };
+
+        let media = match Media::load(&pool, &key).await {
+            Ok(media) if media.user == user.id => media,
+            _ => return Binary::result_failure("Media not found."),
+        };
+
+        // No game can be started while this is held, so none can load an
+        // affected scene before the change is committed.
+        let games = games.read().await;
+        let in_use = games::in_use(&games).await;
+
+        let mut tx = match pool.begin().await {
+            Ok(tx) => tx,
+            Err(_) => return Binary::result_error("Database error."),
+        };
+
+        let items = std::slice::from_ref(&media);
+        let scenes = match super::deleted_scenes(&mut tx, items, query.replace, &in_use).await {
+            Ok(s) => s,
+            Err(e) => return Binary::result_failure(&e.to_string()),
+        };
+
+        let placeholder = query.replace.then_some(super::PLACEHOLDER_COLOUR);
+        let replaced = match media.delete(&mut tx, placeholder).await {
+            Ok(n) => n,
+            Err(e) => return Binary::result_failure(&e.to_string()),
+        };
+
+        if tx.commit().await.is_err() {
+            return Binary::result_error("Database error.");
+        }
+        drop(games);
+
+        refresh_thumbnails(pool, content_dir.clone(), scenes);
+
+        // The record is gone, so a file left behind is only wasted space.
+        tokio::fs::remove_file(format!("{content_dir}/{}", media.relative_path))
+            .await
+            .ok();
+
+        as_result(
+            &DeleteResponse {
+                message: "Media deleted.".to_string(),
+                success: true,
+                replaced,
+            },
+            warp::http::StatusCode::OK,
+        )
+    }
+
+    pub fn filter(
+        pool: SqlitePool,
+        games: Games,
+        content_dir: String,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("media" / String)
+            .and(warp::delete())
+            .and(with_db(pool))
+            .and(with_session())
+            .and(warp::any().map(move || games.clone()))
+            .and(warp::any().map(move || content_dir.clone()))
+            .and(warp::query::<DeleteQuery>())
+            .and_then(delete_media)
+    }
+}
+
+mod usage {
+    use serde_derive::Serialize;
+    use sqlx::SqlitePool;
+    use warp::Filter;
+
+    use crate::handlers::response::{as_result, Binary, ResultReply};
+    use crate::handlers::{with_db, with_session};
+    use crate::models::{Media, MediaUsage, User};
+
+    #[derive(Serialize)]
+    struct UsageResponse {
+        scenes: Vec<MediaUsage>,
+        success: bool,
+    }
+
+    async fn media_usage(key: String, pool: SqlitePool, skey: String) -> ResultReply {
+        let user = match User::get_by_session(&pool, &skey).await {
+            Ok(Some(user)) => user,
+            _ => return Binary::result_failure("Invalid session."),
+        };
+
+        let media = match Media::load(&pool, &key).await {
+            Ok(media) if media.user == user.id => media,
+            _ => return Binary::result_failure("Media not found."),
+        };
+
+        match media.usage(&pool, user.id).await {
+            Ok(scenes) => as_result(
+                &UsageResponse {
+                    scenes,
+                    success: true,
+                },
+                warp::http::StatusCode::OK,
+            ),
+            Err(_) => Binary::result_error("Database error."),
+        }
+    }
+
+    pub fn filter(
+        pool: SqlitePool,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("media" / "usage" / String)
+            .and(warp::get())
+            .and(with_db(pool))
+            .and(with_session())
+            .and_then(media_usage)
+    }
+}
+
+mod folders {
+    use serde_derive::{Deserialize, Serialize};
+    use sqlx::SqlitePool;
+    use warp::Filter;
+
+    use crate::handlers::response::{as_result, Binary, ResultReply};
+    use crate::handlers::{json_body, with_db, with_session};
+    use crate::models::{MediaFolder, User};
+
+    #[derive(Serialize, sqlx::FromRow)]
+    struct FolderItem {
+        folder_key: String,
+        title: String,
+
+        // Key of the folder this is in, or None at the top level.
+        parent: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    struct FolderListResponse {
+        folders: Vec<FolderItem>,
+        success: bool,
+    }
+
+    #[derive(Serialize)]
+    struct FolderResponse {
+        folder: FolderItem,
+        success: bool,
+    }
+
+    #[derive(Deserialize)]
+    struct NewFolderRequest {
+        title: String,
+        #[serde(default)]
+        parent: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct RenameRequest {
+        title: String,
+    }
+
+    #[derive(Deserialize)]
+    struct MoveRequest {
+        // Key of the folder to move into, or None for the top level.
+        parent: Option<String>,
+    }
+
+    async fn user_folders(pool: &SqlitePool, user_id: i64) -> anyhow::Result<Vec<FolderItem>> {
+        let results = sqlx::query_as(
+            r#"
+            SELECT folder.folder_key, folder.title, parent.folder_key AS parent
+            FROM media_folders folder
+            LEFT JOIN media_folders parent ON folder.parent = parent.id
+            WHERE folder.user = ?1 ORDER BY folder.title;
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+        Ok(results)
+    }
+
+    async fn list_folders(pool: SqlitePool, skey: String) -> ResultReply {
+        let user = match User::get_by_session(&pool, &skey).await {
+            Ok(Some(user)) => user,
+            _ => return Binary::result_failure("Invalid session."),
+        };
+
+        match user_folders(&pool, user.id).await {
+            Ok(folders) => as_result(
+                &FolderListResponse {
+                    folders,
+                    success: true,
+                },
+                warp::http::StatusCode::OK,
+            ),
+            Err(_) => Binary::result_error("Database error."),
+        }
+    }
+
+    async fn new_folder(pool: SqlitePool, skey: String, req: NewFolderRequest) -> ResultReply {
+        let user = match User::get_by_session(&pool, &skey).await {
+            Ok(Some(user)) => user,
+            _ => return Binary::result_failure("Invalid session."),
+        };
+
+        let title = req.title.trim();
+        if title.is_empty() {
+            return Binary::result_failure("Folder title may not be empty.");
+        }
+
+        let conn = &mut match pool.acquire().await {
+            Ok(c) => c,
+            Err(e) => return Binary::result_error(&format!("{e}")),
+        };
+
+        let parent = match &req.parent {
+            Some(key) => match MediaFolder::load(conn, user.id, key).await {
+                Ok(folder) => Some(folder),
+                Err(_) => return Binary::result_failure("Parent folder not found."),
+            },
+            None => None,
+        };
+
+        match MediaFolder::create(conn, user.id, parent.as_ref(), title).await {
+            Ok(folder) => as_result(
+                &FolderResponse {
+                    folder: FolderItem {
+                        folder_key: folder.folder_key,
+                        title: folder.title,
+                        parent: req.parent,
+                    },
+                    success: true,
+                },
+                warp::http::StatusCode::OK,
+            ),
+            Err(_) => Binary::result_error("Failed to create folder."),
+        }
+    }
+
+    async fn rename_folder(
+        key: String,
+        pool: SqlitePool,
+        skey: String,
+        req: RenameRequest,
+    ) -> ResultReply {
+        let user = match User::get_by_session(&pool, &skey).await {
+            Ok(Some(user)) => user,
+            _ => return Binary::result_failure("Invalid session."),
+        };
+
+        let title = req.title.trim();
+        if title.is_empty() {
+            return Binary::result_failure("Folder title may not be empty.");
+        }
+
+        let conn = &mut match pool.acquire().await {
+            Ok(c) => c,
+            Err(e) => return Binary::result_error(&format!("{e}")),
+        };
+
+        let mut folder = match MediaFolder::load(conn, user.id, &key).await {
+            Ok(folder) => folder,
+            Err(_) => return Binary::result_failure("Folder not found."),
+        };
+
+        match folder.rename(conn, title).await {
+            Ok(()) => Binary::result_success("Folder renamed."),
+            Err(_) => Binary::result_error("Failed to rename folder."),
+        }
+    }
+
+    async fn move_folder(
+        key: String,
+        pool: SqlitePool,
+        skey: String,
+        req: MoveRequest,
+    ) -> ResultReply {
+        let user = match User::get_by_session(&pool, &skey).await {
+            Ok(Some(user)) => user,
+            _ => return Binary::result_failure("Invalid session."),
+        };
+
+        let conn = &mut match pool.acquire().await {
+            Ok(c) => c,
+            Err(e) => return Binary::result_error(&format!("{e}")),
+        };
+
+        let mut folder = match MediaFolder::load(conn, user.id, &key).await {
+            Ok(folder) => folder,
+            Err(_) => return Binary::result_failure("Folder not found."),
+        };
+
+        let parent = match &req.parent {
+            Some(key) => match MediaFolder::load(conn, user.id, key).await {
+                Ok(folder) => Some(folder),
+                Err(_) => return Binary::result_failure("Parent folder not found."),
+            },
+            None => None,
+        };
+
+        match folder.move_to(conn, parent.as_ref()).await {
+            Ok(()) => Binary::result_success("Folder moved."),
+            Err(e) => Binary::result_failure(&e.to_string()),
+        }
+    }
+
+    async fn delete_folder(key: String, pool: SqlitePool, skey: String) -> ResultReply {
+        let user = match User::get_by_session(&pool, &skey).await {
+            Ok(Some(user)) => user,
+            _ => return Binary::result_failure("Invalid session."),
+        };
+
+        let mut tx = match pool.begin().await {
+            Ok(tx) => tx,
+            Err(_) => return Binary::result_error("Database error."),
+        };
+
+        let folder = match MediaFolder::load(&mut tx, user.id, &key).await {
+            Ok(folder) => folder,
+            Err(_) => return Binary::result_failure("Folder not found."),
+        };
+
+        match folder.delete(&mut tx).await {
+            Ok(()) if tx.commit().await.is_ok() => Binary::result_success("Folder deleted."),
+            _ => Binary::result_error("Failed to delete folder."),
+        }
+    }
+
+    pub fn filter(
+        pool: SqlitePool,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let list = warp::path!("media" / "folders")
+            .and(warp::get())
+            .and(with_db(pool.clone()))
+            .and(with_session())
+            .and_then(list_folders);
+
+        let create = warp::path!("media" / "folder")
+            .and(warp::post())
+            .and(with_db(pool.clone()))
+            .and(with_session())
+            .and(json_body())
+            .and_then(new_folder);
+
+        let rename = warp::path!("media" / "folder" / String / "title")
+            .and(warp::post())
+            .and(with_db(pool.clone()))
+            .and(with_session())
+            .and(json_body())
+            .and_then(rename_folder);
+
+        let move_into = warp::path!("media" / "folder" / String / "move")
+            .and(warp::post())
+            .and(with_db(pool.clone()))
+            .and(with_session())
+            .and(json_body())
+            .and_then(move_folder);
+
+        let delete = warp::path!("media" / "folder" / String)
+            .and(warp::delete())
+            .and(with_db(pool))
+            .and(with_session())
+            .and_then(delete_folder);
+
+        list.or(create).or(rename).or(move_into).or(delete)
+    }
+}
+
+mod bulk {
+    use serde_derive::{Deserialize, Serialize};
+    use sqlx::{SqliteConnection, SqlitePool};
+    use warp::Filter;
+
+    use crate::games::{self, Games, InUse};
+    use crate::handlers::response::{as_result, Binary, ResultReply};
+    use crate::handlers::{json_body, scene::refresh_thumbnails, with_db, with_session};
+    use crate::models::{Media, MediaFolder, User};
+
+    #[derive(Deserialize)]
+    #[serde(tag = "action", rename_all = "snake_case")]
+    enum BulkAction {
+        // Move into a folder, or to the top level if None.
+        Move {
+            folder: Option<String>,
+        },
+        Retitle {
+            title: String,
+        },
+        Tag {
+            tags: Vec<String>,
+        },
+        Untag {
+            tags: Vec<String>,
+        },
+
+        // As for deleting a single item, sprites using the items are replaced
+        // with a placeholder colour if replace is set, otherwise the items
+        // aren't deleted if any is in use.
+        Delete {
+            #[serde(default)]
+            replace: bool,
+        },
+    }
+
+    #[derive(Deserialize)]
+    struct BulkRequest {
+        media: Vec<String>,
+        #[serde(flatten)]
+        action: BulkAction,
+    }
+
+    #[derive(Serialize)]
+    struct BulkResponse {
+        message: String,
+        success: bool,
+
+        // Number of sprites given the placeholder colour by a delete.
+        replaced: u64,
+    }
+
+    // Items deleted by a bulk action, with the number of sprites given the
+    // placeholder colour and the keys of the scenes they are in.
+    #[derive(Default)]
+    struct Deleted {
+        items: Vec<Media>,
+        replaced: u64,
+        scenes: Vec<String>,
+    }
+
+    // Apply an action to each item, returning what was deleted if the action
+    // is a delete. Items used in running games aren't deleted.
+    async fn apply(
+        conn: &mut SqliteConnection,
+        user: i64,
+        req: &BulkRequest,
+        in_use: &InUse,
+    ) -> anyhow::Result<Deleted> {
+        let mut items = vec![];
+        for key in &req.media {
+            items.push(Media::load_owned(conn, user, key).await?);
+        }
+
+        match &req.action {
+            BulkAction::Move { folder } => {
+                let folder = match folder {
+                    Some(key) => Some(MediaFolder::load(conn, user, key).await?.id),
+                    None => None,
+                };
+                for media in &items {
+                    media.set_folder(conn, folder).await?;
+                }
+            }
+            BulkAction::Retitle { title } => {
+                let title = title.trim();
+                if title.is_empty() {
+                    return Err(anyhow::anyhow!("Media title may not be empty."));
+                }
+                for media in &items {
+                    media.set_title(conn, title).await?;
+                }
+            }
+            BulkAction::Tag { tags } | BulkAction::Untag { tags } => {
+                let tags = tags
+                    .iter()
+                    .map(|t| Media::tag(t).ok_or_else(|| anyhow::anyhow!("Invalid tag: {t}")))
+                    .collect::<anyhow::Result<Vec<String>>>()?;
+                let add = matches!(req.action, BulkAction::Tag { .. });
+                for media in &items {
+                    if add {
+                        media.add_tags(conn, &tags).await?;
+                    } else {
+                        media.remove_tags(conn, &tags).await?;
+                    }
+                }
+            }
+            BulkAction::Delete { replace } => {
+                let scenes = super::deleted_scenes(conn, &items, *replace, in_use).await?;
+
+                let placeholder = replace.then_some(super::PLACEHOLDER_COLOUR);
+                let mut replaced = 0;
+                for media in &items {
+                    replaced += media.delete(conn, placeholder).await?;
+                }
+                return Ok(Deleted {
+                    items,
+                    replaced,
+                    scenes,
+                });
+            }
+        }
+
+        Ok(Deleted::default())
+    }
+
+    // Apply an action to many media items at once. If the action fails for
+    // any item, none are changed.
+    async fn bulk_action(
+        pool: SqlitePool,
+        skey: String,
+        games: Games,
+        content_dir: String,
+        req: BulkRequest,
+    ) -> ResultReply {
+        let user = match User::get_by_session(&pool, &skey).await {
+            Ok(Some(user)) => user,
+            _ => return Binary::result_failure("Invalid session."),
+        };
+
+        // As for a single delete, no game can load an affected scene until
+        // the change is committed.
+        let games = games.read().await;
+        let in_use = games::in_use(&games).await;
+
+        let mut tx = match pool.begin().await {
+            Ok(tx) => tx,
+            Err(_) => return Binary::result_error("Database error."),
+        };
+
+        let deleted = match apply(&mut tx, user.id, &req, &in_use).await {
+            Ok(result) => result,
+            Err(e) => return Binary::result_failure(&e.to_string()),
+        };
+
+        if tx.commit().await.is_err() {
+            return Binary::result_error("Database error.");
+        }
+        drop(games);
+
+        refresh_thumbnails(pool, content_dir.clone(), deleted.scenes);
+        for media in deleted.items {
+            tokio::fs::remove_file(format!("{content_dir}/{}", media.relative_path))
+                .await
+                .ok();
+        }
+
+        as_result(
+            &BulkResponse {
+                message: format!("Updated {} media items.", req.media.len()),
+                success: true,
+                replaced: deleted.replaced,
+            },
+            warp::http::StatusCode::OK,
+        )
+    }
+
+    pub fn filter(
+        pool: SqlitePool,
+        games: Games,
+        content_dir: String,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("media" / "bulk")
+            .and(warp::post())
+            .and(with_db(pool))
+            .and(with_session())
+            .and(warp::any().map(move || games.clone()))
+            .and(warp::any().map(move || content_dir.clone()))
+            .and(json_body())
+            .and_then(bulk_action)
+    }
+}
+
 pub fn filter(
     pool: SqlitePool,
+    games: Games,
+    content_dir: String,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    list::filter(pool.clone()).or(details::filter(pool))
+    list::filter(pool.clone())
+        .or(details::filter(pool.clone()))
+        .or(usage::filter(pool.clone()))
+        .or(folders::filter(pool.clone()))
+        .or(bulk::filter(
+            pool.clone(),
+            games.clone(),
+            content_dir.clone(),
+        ))
+        .or(delete::filter(pool, games, content_dir))
 }
@@ -13,8 +13,11 @@ mod media;
 mod project;
 mod register;
 mod scene;
+mod trash;
 mod upload;
 
+pub use trash::purge_trash;
+
 pub fn routes(
     pool: SqlitePool,
     games: crate::games::Games,
@@ -25,9 +28,14 @@ pub fn routes(
         .or(login::filter(pool.clone()))
         .or(register::filter(pool.clone()))
         .or(logout::filter(pool.clone()))
-        .or(upload::filter(pool.clone(), content_dir))
-        .or(media::filter(pool.clone()))
-        .or(project::filter(pool.clone()))
+        .or(upload::filter(pool.clone(), content_dir.clone()))
+        .or(media::filter(
+            pool.clone(),
+            games.clone(),
+            content_dir.clone(),
+        ))
+        .or(project::filter(pool.clone(), content_dir.clone()))
+        .or(trash::routes(pool.clone(), games.clone(), content_dir))
         .or(game::routes(pool.clone(), games, &content_path))
         .or(scene::routes(pool, &content_path))
 }
@@ -1,5 +1,5 @@
 use serde_derive::Serialize;
-use sqlx::SqlitePool;
+use sqlx::{SqliteConnection, SqlitePool};
 use warp::{hyper::StatusCode, Filter};
 
 use crate::models::{Project, User};
@@ -13,6 +13,10 @@ use super::{
 struct SceneListEntry {
     scene_key: String,
     title: String,
+
+    // URL of the thumbnail rendered when the scene was last saved. Scenes
+    // which haven't been saved since thumbnails were introduced have none.
+    thumbnail: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -30,7 +34,7 @@ struct ProjectListResponse {
     list: Vec<ProjectListEntry>,
 }
 
-async fn list_projects(pool: SqlitePool, session_key: String) -> ResultReply {
+async fn list_projects(pool: SqlitePool, session_key: String, content_dir: String) -> ResultReply {
     let user = match User::get_by_session(&pool, &session_key).await {
         Ok(Some(user)) => user,
         _ => return Binary::result_failure("Invalid session."),
@@ -48,16 +52,19 @@ async fn list_projects(pool: SqlitePool, session_key: String) -> ResultReply {
 
     let mut project_list = vec![];
     while let Some(project) = projects.pop() {
-        let scene_list = match project.list_scenes(conn).await {
-            Ok(scenes) => scenes
-                .iter()
-                .map(|s| SceneListEntry {
-                    scene_key: s.scene_key.clone(),
-                    title: s.title.clone(),
-                })
-                .collect(),
+        let scenes = match project.list_scenes(conn).await {
+            Ok(scenes) => scenes,
             Err(e) => return Binary::result_error(&format!("Database error. {e}")),
         };
+
+        let mut scene_list = vec![];
+        for s in scenes {
+            scene_list.push(SceneListEntry {
+                thumbnail: super::scene::thumbnail_url(&content_dir, &s.scene_key).await,
+                scene_key: s.scene_key,
+                title: s.title,
+            });
+        }
         project_list.push(ProjectListEntry {
             id: project.id,
             project_key: project.project_key,
@@ -76,12 +83,318 @@ async fn list_projects(pool: SqlitePool, session_key: String) -> ResultReply {
     )
 }
 
+// Load the project with this key, checking that it belongs to the user with
+// this session.
+pub async fn owned_project(
+    pool: &SqlitePool,
+    conn: &mut SqliteConnection,
+    skey: &str,
+    project_key: &str,
+) -> Result<Project, &'static str> {
+    let user = match User::get_by_session(pool, skey).await {
+        Ok(Some(u)) => u,
+        _ => return Err("Invalid session."),
+    };
+
+    let project = match Project::load_from_key(conn, project_key).await {
+        Ok(p) => p,
+        Err(_) => return Err("Project not found."),
+    };
+
+    if project.user != user.id {
+        return Err("Project belongs to different user.");
+    }
+
+    Ok(project)
+}
+
 pub fn filter(
     pool: SqlitePool,
+    content_dir: String,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list_content_dir = content_dir.clone();
     warp::path!("project" / "list")
         .and(warp::get())
-        .and(with_db(pool))
+        .and(with_db(pool.clone()))
         .and(with_session())
+        .and(warp::any().map(move || list_content_dir.clone()))
         .and_then(list_projects)
+        .or(overview::filter(pool.clone(), content_dir))
+        .or(update::filter(pool))
+}
+
+mod overview {
+    use serde_derive::Serialize;
+    use sqlx::SqlitePool;
+    use warp::{hyper::StatusCode, Filter};
+
+    use crate::handlers::{
+        response::{as_result, Binary, ResultReply},
+        scene::thumbnail_url,
+        with_db, with_session,
+    };
+
+    #[derive(Serialize)]
+    struct SceneDetails {
+        scene_key: String,
+        title: String,
+        width: u32,
+        height: u32,
+        sprites: u32,
+        thumbnail: Option<String>,
+
+        // Time the scene was last saved, in seconds since the epoch.
+        updated_time: i64,
+    }
+
+    #[derive(Serialize)]
+    struct MediaDetails {
+        media_key: String,
+        title: String,
+        url: String,
+    }
+
+    #[derive(Serialize)]
+    struct ProjectResponse {
+        message: String,
+        success: bool,
+        id: i64,
+        project_key: String,
+        title: String,
+        scenes: Vec<SceneDetails>,
+        media: Vec<MediaDetails>,
+    }
+
+    async fn project_overview(
+        project_key: String,
+        pool: SqlitePool,
+        skey: String,
+        content_dir: String,
+    ) -> ResultReply {
+        let conn = &mut match pool.acquire().await {
+            Ok(c) => c,
+            Err(e) => return Binary::result_error(&format!("{e}")),
+        };
+
+        let project = match super::owned_project(&pool, conn, &skey, &project_key).await {
+            Ok(p) => p,
+            Err(msg) => return Binary::result_failure(msg),
+        };
+
+        let (scenes, sprites, media) = match (
+            project.list_scenes(conn).await,
+            project.sprite_counts(conn).await,
+            project.media(conn).await,
+        ) {
+            (Ok(scenes), Ok(sprites), Ok(media)) => (scenes, sprites, media),
+            _ => return Binary::result_error("Failed to load project details."),
+        };
+
+        let mut details = vec![];
+        for s in scenes {
+            details.push(SceneDetails {
+                thumbnail: thumbnail_url(&content_dir, &s.scene_key).await,
+                sprites: sprites.get(&s.id).copied().unwrap_or(0),
+                scene_key: s.scene_key,
+                title: s.title,
+                width: s.w,
+                height: s.h,
+                updated_time: s.updated_time,
+            });
+        }
+
+        as_result(
+            &ProjectResponse {
+                message: "Project retrieved.".to_string(),
+                success: true,
+                id: project.id,
+                project_key: project.project_key,
+                title: project.title,
+                scenes: details,
+                media: media
+                    .into_iter()
+                    .map(|m| MediaDetails {
+                        media_key: m.media_key,
+                        title: m.title,
+                        url: m.relative_path,
+                    })
+                    .collect(),
+            },
+            StatusCode::OK,
+        )
+    }
+
+    pub fn filter(
+        pool: SqlitePool,
+        content_dir: String,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("project" / String)
+            .and(warp::get())
+            .and(with_db(pool))
+            .and(with_session())
+            .and(warp::any().map(move || content_dir.clone()))
+            .and_then(project_overview)
+    }
+}
+
+mod update {
+    use serde_derive::{Deserialize, Serialize};
+    use sqlx::SqlitePool;
+    use warp::{hyper::StatusCode, Filter};
+
+    use crate::handlers::{
+        json_body,
+        response::{as_result, Binary, ResultReply},
+        with_db, with_session,
+    };
+
+    const DEFAULT_SCENE_TITLE: &str = "Untitled";
+
+    #[derive(Deserialize)]
+    struct RenameRequest {
+        title: String,
+    }
+
+    #[derive(Deserialize)]
+    struct ReorderRequest {
+        // Keys of all of the project's scenes, in their new order.
+        scenes: Vec<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct NewSceneRequest {
+        #[serde(default)]
+        title: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    struct NewSceneResponse {
+        message: String,
+        success: bool,
+        scene_key: String,
+        url: String,
+    }
+
+    async fn rename_project(
+        project_key: String,
+        pool: SqlitePool,
+        skey: String,
+        req: RenameRequest,
+    ) -> ResultReply {
+        let conn = &mut match pool.acquire().await {
+            Ok(c) => c,
+            Err(e) => return Binary::result_error(&format!("{e}")),
+        };
+
+        let mut project = match super::owned_project(&pool, conn, &skey, &project_key).await {
+            Ok(p) => p,
+            Err(msg) => return Binary::result_failure(msg),
+        };
+
+        let title = req.title.trim();
+        if title.is_empty() {
+            return Binary::result_failure("Project title may not be empty.");
+        }
+
+        match project.update_title(conn, title.to_string()).await {
+            Ok(()) => Binary::result_success("Project renamed."),
+            Err(_) => Binary::result_error("Failed to update project title."),
+        }
+    }
+
+    async fn reorder_scenes(
+        project_key: String,
+        pool: SqlitePool,
+        skey: String,
+        req: ReorderRequest,
+    ) -> ResultReply {
+        let conn = &mut match pool.acquire().await {
+            Ok(c) => c,
+            Err(e) => return Binary::result_error(&format!("{e}")),
+        };
+
+        let project = match super::owned_project(&pool, conn, &skey, &project_key).await {
+            Ok(p) => p,
+            Err(msg) => return Binary::result_failure(msg),
+        };
+
+        // Positions are updated one scene at a time, so a failure part way
+        // through mustn't leave the order half applied.
+        let mut tx = match pool.begin().await {
+            Ok(tx) => tx,
+            Err(_) => return Binary::result_error("Database error."),
+        };
+
+        if let Err(e) = project.reorder_scenes(&mut tx, &req.scenes).await {
+            return Binary::result_failure(&e.to_string());
+        }
+
+        match tx.commit().await {
+            Ok(()) => Binary::result_success("Scenes reordered."),
+            Err(_) => Binary::result_error("Database error."),
+        }
+    }
+
+    // Create an empty scene at the end of the project's scene list.
+    async fn new_scene(
+        project_key: String,
+        pool: SqlitePool,
+        skey: String,
+        req: NewSceneRequest,
+    ) -> ResultReply {
+        let conn = &mut match pool.acquire().await {
+            Ok(c) => c,
+            Err(e) => return Binary::result_error(&format!("{e}")),
+        };
+
+        let project = match super::owned_project(&pool, conn, &skey, &project_key).await {
+            Ok(p) => p,
+            Err(msg) => return Binary::result_failure(msg),
+        };
+
+        let title = match req.title.as_deref().map(str::trim) {
+            Some(title) if !title.is_empty() => title.to_string(),
+            _ => DEFAULT_SCENE_TITLE.to_string(),
+        };
+
+        match project.update_scene(conn, scene::Scene::new(), title).await {
+            Ok(record) => as_result(
+                &NewSceneResponse {
+                    message: "Scene created.".to_string(),
+                    success: true,
+                    url: format!("/project/{project_key}/scene/{}", record.scene_key),
+                    scene_key: record.scene_key,
+                },
+                StatusCode::OK,
+            ),
+            Err(e) => Binary::result_failure(&format!("Failed to create scene: {e}")),
+        }
+    }
+
+    pub fn filter(
+        pool: SqlitePool,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let rename = warp::path!("project" / String / "title")
+            .and(warp::post())
+            .and(with_db(pool.clone()))
+            .and(with_session())
+            .and(json_body())
+            .and_then(rename_project);
+
+        let reorder = warp::path!("project" / String / "order")
+            .and(warp::post())
+            .and(with_db(pool.clone()))
+            .and(with_session())
+            .and(json_body())
+            .and_then(reorder_scenes);
+
+        let create = warp::path!("project" / String / "scene")
+            .and(warp::post())
+            .and(with_db(pool))
+            .and(with_session())
+            .and(json_body())
+            .and_then(new_scene);
+
+        rename.or(reorder).or(create)
+    }
 }
@@ -1,21 +1,40 @@
-use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
 
 use serde_derive::Serialize;
+use sqlx::{SqliteConnection, SqlitePool};
 use warp::{hyper::StatusCode, Filter};
 
 use crate::{
     handlers::response::{as_result, Binary, ResultReply},
-    models::Project,
+    models::{Media, Project, SceneRecord, User},
+    render::{self, Image},
 };
 
 pub const SCENE_EDITOR_FILE: &str = "scene.html";
 
+// Directory within the content directory thumbnails are saved to, named by
+// scene key.
+pub const THUMBNAIL_DIR: &str = "thumbnails";
+
+const THUMBNAIL_SIZE: u32 = 256;
+
 pub fn routes(
     pool: sqlx::SqlitePool,
     content_dir: &Path,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    save::filter(pool.clone())
-        .or(load::filter(pool))
+    save::filter(pool.clone(), content_dir.to_string_lossy().into_owned())
+        .or(load::filter(pool.clone()))
+        .or(export::filter(
+            pool.clone(),
+            content_dir.to_string_lossy().into_owned(),
+        ))
+        .or(import::filter(
+            pool,
+            content_dir.to_string_lossy().into_owned(),
+        ))
         .or(page_route(content_dir))
 }
 
@@ -78,6 +97,150 @@ impl SceneResponse {
     }
 }
 
+// Load the scene with this key, checking that it belongs to the user with
+// this session.
+pub async fn owned_scene(
+    pool: &SqlitePool,
+    conn: &mut SqliteConnection,
+    skey: &str,
+    scene_key: &str,
+) -> Result<(SceneRecord, Project), &'static str> {
+    let user = match User::get_by_session(pool, skey).await {
+        Ok(Some(u)) => u,
+        _ => return Err("Invalid session."),
+    };
+
+    let record = match SceneRecord::load_from_key(conn, scene_key).await {
+        Ok(r) => r,
+        Err(_) => return Err("Scene not found."),
+    };
+
+    let project = match Project::load(conn, record.project).await {
+        Ok(p) => p,
+        Err(_) => return Err("Project not found."),
+    };
+
+    if project.user != user.id {
+        return Err("Project belongs to different user.");
+    }
+
+    Ok((record, project))
+}
+
+// Render a scene to a PNG image, with the media it uses as textures. Only
+// media belonging to the user who owns the scene is used; media which has
+// been deleted or can't be decoded is drawn as a missing texture.
+pub async fn render_png(
+    pool: &SqlitePool,
+    content_dir: &str,
+    user: i64,
+    scene: scene::Scene,
+    width: u32,
+    height: u32,
+) -> anyhow::Result<Vec<u8>> {
+    let ids = scene
+        .layers
+        .iter()
+        .flat_map(|l| l.sprites.iter().filter_map(|s| s.visual.texture()))
+        .collect::<HashSet<i64>>();
+
+    let conn = &mut pool.acquire().await?;
+    let mut files = vec![];
+    for id in ids {
+        if let Ok(media) = Media::load_owned(conn, user, &Media::id_to_key(id)).await {
+            let path = format!("{content_dir}/{}", media.relative_path);
+            if let Ok(data) = tokio::fs::read(path).await {
+                files.push((id, data));
+            }
+        }
+    }
+
+    // Decoding and rasterising are slow enough to hold up other requests.
+    tokio::task::spawn_blocking(move || {
+        let textures = files
+            .into_iter()
+            .filter_map(|(id, data)| Some((id, Image::decode(&data).ok()?)))
+            .collect::<HashMap<i64, Image>>();
+        render::render(&scene, &textures, width, height).encode_png()
+    })
+    .await?
+}
+
+// Path, relative to the content directory, of the thumbnail of a scene.
+pub fn thumbnail_path(scene_key: &str) -> String {
+    format!("{THUMBNAIL_DIR}/{scene_key}.png")
+}
+
+// URL of the thumbnail of a scene, if one has been rendered.
+pub async fn thumbnail_url(content_dir: &str, scene_key: &str) -> Option<String> {
+    let path = thumbnail_path(scene_key);
+    tokio::fs::metadata(format!("{content_dir}/{path}"))
+        .await
+        .ok()
+        .map(|_| format!("/{path}"))
+}
+
+// Replace the thumbnail of a scene in the background, so that saving isn't
+// held up.
+fn update_thumbnail(
+    pool: SqlitePool,
+    content_dir: String,
+    user: i64,
+    scene_key: String,
+    scene: scene::Scene,
+) {
+    tokio::spawn(async move {
+        let result: anyhow::Result<()> = async {
+            let png = render_png(
+                &pool,
+                &content_dir,
+                user,
+                scene,
+                THUMBNAIL_SIZE,
+                THUMBNAIL_SIZE,
+            )
+            .await?;
+            tokio::fs::create_dir_all(format!("{content_dir}/{THUMBNAIL_DIR}")).await?;
+            tokio::fs::write(format!("{content_dir}/{}", thumbnail_path(&scene_key)), png).await?;
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            eprintln!("Failed to render thumbnail for {scene_key}: {e}");
+        }
+    });
+}
+
+// Render the thumbnails of these scenes again in the background, after they
+// have been changed other than by saving them. Scenes in the trash can't be
+// loaded, so keep their thumbnails.
+pub fn refresh_thumbnails(pool: SqlitePool, content_dir: String, scene_keys: Vec<String>) {
+    tokio::spawn(async move {
+        for scene_key in scene_keys {
+            let result: anyhow::Result<()> = async {
+                let conn = &mut pool.acquire().await?;
+                let record = SceneRecord::load_from_key(conn, &scene_key).await?;
+                let user = record.user(conn).await?;
+                let scene = record.load_scene(conn).await?;
+                update_thumbnail(
+                    pool.clone(),
+                    content_dir.clone(),
+                    user,
+                    scene_key.clone(),
+                    scene,
+                );
+                Ok(())
+            }
+            .await;
+
+            if let Err(e) = result {
+                eprintln!("Failed to refresh thumbnail for {scene_key}: {e}");
+            }
+        }
+    });
+}
+
 mod save {
     use std::convert::Infallible;
 
@@ -101,6 +264,7 @@ mod save {
     async fn save_scene(
         pool: sqlx::SqlitePool,
         skey: String,
+        content_dir: String,
         req: SceneSaveRequest,
     ) -> Result<impl warp::Reply, Infallible> {
         let scene: scene::Scene = match base64::decode(req.encoded) {
@@ -127,7 +291,7 @@ mod save {
         };
 
         if project.title != req.project_title
-            && matches!(project.update_title(conn, req.project_title).await, Err(_))
+            && project.update_title(conn, req.project_title).await.is_err()
         {
             return Binary::result_failure("Failed to update project title.");
         }
@@ -142,7 +306,16 @@ mod save {
             .await
         {
             Ok(r) => match r.load_scene(conn).await {
-                Ok(s) => super::SceneResponse::reply(s, r.scene_key, project),
+                Ok(s) => {
+                    super::update_thumbnail(
+                        pool.clone(),
+                        content_dir,
+                        project.user,
+                        r.scene_key.clone(),
+                        s.clone(),
+                    );
+                    super::SceneResponse::reply(s, r.scene_key, project)
+                }
                 Err(s) => Binary::result_failure(&format!(
                     "Failed to load saved scene: {}",
                     &s.to_string()
@@ -154,11 +327,13 @@ mod save {
 
     pub fn filter(
         pool: sqlx::SqlitePool,
+        content_dir: String,
     ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path!("scene" / "save")
             .and(warp::post())
             .and(with_db(pool))
             .and(with_session())
+            .and(warp::any().map(move || content_dir.clone()))
             .and(json_body())
             .and_then(save_scene)
     }
@@ -167,40 +342,23 @@ mod save {
 mod load {
     use warp::Filter;
 
-    use crate::{
-        handlers::{response::Binary, with_db, with_session},
-        models::{Project, SceneRecord, User},
-    };
+    use crate::handlers::{response::Binary, with_db, with_session};
 
     async fn load_scene(
         scene_key: String,
         pool: sqlx::SqlitePool,
         skey: String,
     ) -> Result<impl warp::Reply, std::convert::Infallible> {
-        let user = match User::get_by_session(&pool, &skey).await {
-            Ok(Some(u)) => u,
-            _ => return Binary::result_failure("Invalid session."),
-        };
-
         let conn = &mut match pool.acquire().await {
             Ok(c) => c,
             Err(e) => return Binary::result_error(&format!("{e}")),
         };
 
-        let record = match SceneRecord::load_from_key(conn, &scene_key).await {
+        let (record, project) = match super::owned_scene(&pool, conn, &skey, &scene_key).await {
             Ok(r) => r,
-            Err(_) => return Binary::result_failure("Scene not found."),
-        };
-
-        let project = match Project::load(conn, record.project).await {
-            Ok(p) => p,
-            Err(_) => return Binary::result_failure("Project not found."),
+            Err(msg) => return Binary::result_failure(msg),
         };
 
-        if project.user != user.id {
-            return Binary::result_failure("Project belongs to different user.");
-        }
-
         match record.load_scene(conn).await {
             Ok(s) => super::SceneResponse::reply(s, scene_key, project),
             _ => Binary::result_failure("Failed to load scene."),
@@ -217,3 +375,373 @@ mod load {
             .and_then(load_scene)
     }
 }
+
+mod export {
+    use std::{
+        collections::{HashMap, HashSet},
+        convert::Infallible,
+    };
+
+    use warp::{Filter, Reply};
+
+    use crate::{
+        foundry::{self, FoundryScene},
+        handlers::{response::Binary, with_db, with_session},
+        models::Media,
+    };
+
+    // Reply with the scene as a JSON file, or a JSON failure message.
+    async fn export_scene(
+        scene_key: String,
+        pool: sqlx::SqlitePool,
+        skey: String,
+    ) -> Result<warp::reply::Response, Infallible> {
+        let failure = |msg: &str| Binary::result_failure(msg).map(Reply::into_response);
+
+        let conn = &mut match pool.acquire().await {
+            Ok(c) => c,
+            Err(e) => return Binary::result_error(&format!("{e}")).map(Reply::into_response),
+        };
+
+        let (record, _) = match super::owned_scene(&pool, conn, &skey, &scene_key).await {
+            Ok(r) => r,
+            Err(msg) => return failure(msg),
+        };
+
+        let scene = match record.load_scene(conn).await {
+            Ok(s) => s,
+            Err(_) => return failure("Failed to load scene."),
+        };
+
+        let disposition = format!("attachment; filename=\"{scene_key}.json\"");
+        Ok(warp::reply::with_header(
+            warp::reply::with_header(scene.to_json(), "Content-Type", "application/json"),
+            "Content-Disposition",
+            disposition,
+        )
+        .into_response())
+    }
+
+    // Reply with the scene converted for Foundry VTT, packaged in a zip
+    // archive with the media it uses.
+    async fn export_foundry(
+        scene_key: String,
+        pool: sqlx::SqlitePool,
+        skey: String,
+        content_dir: String,
+    ) -> Result<warp::reply::Response, Infallible> {
+        let failure = |msg: &str| Binary::result_failure(msg).map(Reply::into_response);
+
+        let conn = &mut match pool.acquire().await {
+            Ok(c) => c,
+            Err(e) => return Binary::result_error(&format!("{e}")).map(Reply::into_response),
+        };
+
+        let (record, project) = match super::owned_scene(&pool, conn, &skey, &scene_key).await {
+            Ok(r) => r,
+            Err(msg) => return failure(msg),
+        };
+
+        let scene = match record.load_scene(conn).await {
+            Ok(s) => s,
+            Err(_) => return failure("Failed to load scene."),
+        };
+
+        let textures = scene
+            .layers
+            .iter()
+            .flat_map(|l| l.sprites.iter().filter_map(|s| s.visual.texture()))
+            .collect::<HashSet<i64>>();
+
+        // Media which has since been deleted, or belongs to another user, is
+        // left out.
+        let mut paths = HashMap::new();
+        let mut media = vec![];
+        for texture in textures {
+            if let Ok(m) = Media::load_owned(conn, project.user, &Media::id_to_key(texture)).await {
+                let file = format!("{content_dir}/{}", m.relative_path);
+                if let Ok(data) = tokio::fs::read(file).await {
+                    let name = m.relative_path.rsplit('/').next().unwrap_or_default();
+                    let path = format!("{}/{}", foundry::MEDIA_DIR, name);
+                    paths.insert(texture, path.clone());
+                    media.push((path, data));
+                }
+            }
+        }
+
+        let converted = FoundryScene::new(&scene, &record.title, &paths);
+        let archive = match foundry::archive(&converted, &media) {
+            Ok(a) => a,
+            Err(_) => {
+                return Binary::result_error("Failed to create archive.").map(Reply::into_response)
+            }
+        };
+
+        let disposition = format!("attachment; filename=\"{scene_key}.zip\"");
+        Ok(warp::reply::with_header(
+            warp::reply::with_header(archive, "Content-Type", "application/zip"),
+            "Content-Disposition",
+            disposition,
+        )
+        .into_response())
+    }
+
+    pub fn filter(
+        pool: sqlx::SqlitePool,
+        content_dir: String,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let json = warp::path!("scene" / "export" / String)
+            .and(warp::get())
+            .and(with_db(pool.clone()))
+            .and(with_session())
+            .and_then(export_scene);
+
+        let foundry = warp::path!("scene" / "export" / "foundry" / String)
+            .and(warp::get())
+            .and(with_db(pool))
+            .and(with_session())
+            .and(warp::any().map(move || content_dir.clone()))
+            .and_then(export_foundry);
+
+        json.or(foundry)
+    }
+}
+
+mod import {
+    use std::{collections::HashSet, convert::Infallible};
+
+    use serde_derive::Deserialize;
+    use warp::Filter;
+
+    use crate::{
+        handlers::{
+            response::{Binary, ResultReply},
+            upload::{hash_file, store_media},
+            with_db, with_session,
+        },
+        models::{Media, Project, User},
+        render::Image,
+    };
+
+    // Exported scenes include every sprite, so may be much larger than other
+    // requests.
+    const MAX_IMPORT_SIZE: u64 = 1024 * 1024 * 4;
+
+    // Universal VTT maps embed the map image.
+    const MAX_MAP_SIZE: u64 = 1024 * 1024 * 32;
+
+    const DEFAULT_SCENE_TITLE: &str = "Imported";
+
+    #[derive(Deserialize)]
+    struct ImportQuery {
+        // Project to add the scene to. A new project is created if absent.
+        project: Option<i64>,
+
+        // Title for the scene, overriding any in the imported file.
+        title: Option<String>,
+    }
+
+    // An imported scene may only use the importing user's media, and may only
+    // give control of tokens, combatants and templates to existing users.
+    async fn check_references(
+        pool: &sqlx::SqlitePool,
+        conn: &mut sqlx::SqliteConnection,
+        user: i64,
+        scene: &scene::Scene,
+    ) -> Result<(), String> {
+        let sprites = || scene.layers.iter().flat_map(|l| &l.sprites);
+        let textures = sprites()
+            .filter_map(|s| s.visual.texture())
+            .collect::<HashSet<i64>>();
+        for texture in textures {
+            let key = Media::id_to_key(texture);
+            if Media::load_owned(conn, user, &key).await.is_err() {
+                return Err(format!("Unknown texture: {key}."));
+            }
+        }
+
+        let owners = sprites()
+            .flat_map(|s| s.owners.iter().copied())
+            .chain(scene.initiative.combatants.iter().filter_map(|c| c.owner))
+            .chain(
+                scene
+                    .layers
+                    .iter()
+                    .flat_map(|l| l.templates.iter().filter_map(|t| t.owner)),
+            )
+            .collect::<HashSet<i64>>();
+        for owner in owners {
+            match User::get_by_id(pool, owner).await {
+                Ok(Some(_)) => {}
+                Ok(None) => return Err(format!("Unknown user: {owner}.")),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Save an imported scene as a new scene in the requested project.
+    async fn save_import(
+        pool: &sqlx::SqlitePool,
+        content_dir: String,
+        user: User,
+        query: ImportQuery,
+        mut scene: scene::Scene,
+    ) -> ResultReply {
+        let conn = &mut match pool.acquire().await {
+            Ok(c) => c,
+            Err(e) => return Binary::result_error(&format!("{e}")),
+        };
+
+        let project = match Project::get_or_create(conn, query.project, user.id).await {
+            Ok(p) if p.user == user.id => p,
+            _ => return Binary::result_failure("Missing project."),
+        };
+        scene.id = None;
+        scene.project = Some(project.id);
+
+        if let Err(e) = check_references(pool, conn, user.id, &scene).await {
+            return Binary::result_failure(&format!("Failed to import scene: {e}"));
+        }
+
+        let title = match query.title.as_deref().or(scene.title.as_deref()) {
+            Some(title) if !title.trim().is_empty() => title.trim().to_string(),
+            _ => DEFAULT_SCENE_TITLE.to_string(),
+        };
+
+        match project.update_scene(conn, scene, title).await {
+            Ok(r) => match r.load_scene(conn).await {
+                Ok(s) => {
+                    super::update_thumbnail(
+                        pool.clone(),
+                        content_dir,
+                        project.user,
+                        r.scene_key.clone(),
+                        s.clone(),
+                    );
+                    super::SceneResponse::reply(s, r.scene_key, project)
+                }
+                Err(e) => Binary::result_failure(&format!("Failed to load imported scene: {e}")),
+            },
+            Err(e) => Binary::result_failure(&format!("Failed to save scene: {e}")),
+        }
+    }
+
+    async fn import_scene(
+        pool: sqlx::SqlitePool,
+        skey: String,
+        content_dir: String,
+        query: ImportQuery,
+        body: bytes::Bytes,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let scene = match std::str::from_utf8(&body)
+            .map_err(|e| e.to_string())
+            .and_then(scene::Scene::from_json)
+        {
+            Ok(s) => s,
+            Err(e) => return Binary::result_failure(&format!("Failed to import scene: {e}")),
+        };
+
+        let user = match User::get_by_session(&pool, &skey).await {
+            Ok(Some(u)) => u,
+            _ => return Binary::result_failure("Invalid session."),
+        };
+
+        save_import(&pool, content_dir, user, query, scene).await
+    }
+
+    // Import a Universal VTT map, storing its image as a media item.
+    async fn import_uvtt(
+        pool: sqlx::SqlitePool,
+        skey: String,
+        content_dir: String,
+        query: ImportQuery,
+        body: bytes::Bytes,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let map = match std::str::from_utf8(&body)
+            .map_err(|e| e.to_string())
+            .and_then(scene::UvttMap::parse)
+        {
+            Ok(m) => m,
+            Err(e) => return Binary::result_failure(&format!("Failed to import map: {e}")),
+        };
+
+        let image = match base64::decode(map.image()) {
+            Ok(b) => b,
+            Err(_) => return Binary::result_failure("Decoding failure."),
+        };
+
+        let ext = match Media::extension(&image) {
+            Some(ext) => ext,
+            None => return Binary::result_failure("Unsupported map image type."),
+        };
+
+        let checked = Image::dimensions(&image)
+            .map_err(|e| e.to_string())
+            .and_then(|(w, h)| map.check_image(w, h));
+        if let Err(e) = checked {
+            return Binary::result_failure(&format!("Failed to import map: {e}"));
+        }
+
+        let user = match User::get_by_session(&pool, &skey).await {
+            Ok(Some(u)) => u,
+            _ => return Binary::result_failure("Invalid session."),
+        };
+
+        let hash = match hash_file(&image) {
+            Ok(h) => h,
+            Err(_) => return Binary::result_error("Failed to hash file."),
+        };
+
+        // Reuse the map image if it has been uploaded before, as the same
+        // file can't be stored twice.
+        let media = match Media::load_hashed(&pool, user.id, &hash).await {
+            Ok(Some(m)) => m,
+            Ok(None) => {
+                let title = query.title.as_deref().unwrap_or("map");
+                let title = format!("{}.{}", title.trim(), ext);
+                match store_media(&pool, &user, &content_dir, image, ext, &title, &hash).await {
+                    Ok(m) => m,
+                    Err(e) => return Binary::result_error(&e.to_string()),
+                }
+            }
+            Err(_) => return Binary::result_error("Database error checking for duplicate."),
+        };
+
+        let texture = match Media::key_to_id(&media.media_key) {
+            Ok(id) => id,
+            Err(_) => return Binary::result_error("Invalid media key."),
+        };
+
+        save_import(&pool, content_dir, user, query, map.to_scene(texture)).await
+    }
+
+    pub fn filter(
+        pool: sqlx::SqlitePool,
+        content_dir: String,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let json_content_dir = content_dir.clone();
+        let json = warp::path!("scene" / "import")
+            .and(warp::post())
+            .and(with_db(pool.clone()))
+            .and(with_session())
+            .and(warp::any().map(move || json_content_dir.clone()))
+            .and(warp::query::<ImportQuery>())
+            .and(warp::body::content_length_limit(MAX_IMPORT_SIZE))
+            .and(warp::body::bytes())
+            .and_then(import_scene);
+
+        let uvtt = warp::path!("scene" / "import" / "uvtt")
+            .and(warp::post())
+            .and(with_db(pool))
+            .and(with_session())
+            .and(warp::any().map(move || content_dir.clone()))
+            .and(warp::query::<ImportQuery>())
+            .and(warp::body::content_length_limit(MAX_MAP_SIZE))
+            .and(warp::body::bytes())
+            .and_then(import_uvtt);
+
+        json.or(uvtt)
+    }
+}
@@ -0,0 +1,255 @@
+use std::{collections::HashSet, convert::Infallible};
+
+use serde_derive::Serialize;
+use sqlx::SqlitePool;
+use warp::{hyper::StatusCode, Filter};
+
+use crate::{
+    games::{self, Games},
+    handlers::{
+        current_time,
+        response::{as_result, Binary, ResultReply},
+        scene::thumbnail_path,
+        with_db, with_session,
+    },
+    models::{TrashItem, User},
+};
+
+// How long deleted projects and scenes are kept in the trash before being
+// permanently deleted, in seconds.
+const RETENTION: u64 = 30 * 24 * 60 * 60;
+
+// How often the trash is checked for items past the retention period.
+const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+pub fn routes(
+    pool: SqlitePool,
+    games: Games,
+    content_dir: String,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let games = warp::any().map(move || games.clone());
+    let content_dir = warp::any().map(move || content_dir.clone());
+
+    let delete_project = warp::path!("project" / String)
+        .and(warp::delete())
+        .and(with_db(pool.clone()))
+        .and(with_session())
+        .and(games.clone())
+        .and_then(delete_project);
+
+    let delete_scene = warp::path!("scene" / String)
+        .and(warp::delete())
+        .and(with_db(pool.clone()))
+        .and(with_session())
+        .and(games)
+        .and_then(delete_scene);
+
+    let restore_project = warp::path!("project" / String / "restore")
+        .and(warp::post())
+        .and(with_db(pool.clone()))
+        .and(with_session())
+        .and_then(restore_project);
+
+    let restore_scene = warp::path!("scene" / String / "restore")
+        .and(warp::post())
+        .and(with_db(pool.clone()))
+        .and(with_session())
+        .and_then(restore_scene);
+
+    let list = warp::path!("trash")
+        .and(warp::get())
+        .and(with_db(pool.clone()))
+        .and(with_session())
+        .and_then(list_trash);
+
+    let empty = warp::path!("trash")
+        .and(warp::delete())
+        .and(with_db(pool))
+        .and(with_session())
+        .and(content_dir)
+        .and_then(empty_trash);
+
+    delete_project
+        .or(delete_scene)
+        .or(restore_project)
+        .or(restore_scene)
+        .or(list)
+        .or(empty)
+}
+
+// Periodically purge projects and scenes which have been in the trash for
+// longer than the retention period.
+pub async fn purge_trash(pool: SqlitePool, content_dir: String) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let before = current_time().unwrap_or(0).saturating_sub(RETENTION) as i64;
+        if let Err(e) = purge(&pool, &content_dir, before, None).await {
+            eprintln!("Failed to purge trash: {e}");
+        }
+    }
+}
+
+async fn purge(
+    pool: &SqlitePool,
+    content_dir: &str,
+    before: i64,
+    user: Option<i64>,
+) -> anyhow::Result<()> {
+    let conn = &mut pool.acquire().await?;
+    for key in TrashItem::purge(conn, before, user).await? {
+        // Scenes saved before thumbnails were introduced have none.
+        tokio::fs::remove_file(format!("{content_dir}/{}", thumbnail_path(&key)))
+            .await
+            .ok();
+    }
+    Ok(())
+}
+
+async fn delete_project(
+    project_key: String,
+    pool: SqlitePool,
+    skey: String,
+    games: Games,
+) -> Result<impl warp::Reply, Infallible> {
+    let conn = &mut match pool.acquire().await {
+        Ok(c) => c,
+        Err(e) => return Binary::result_error(&format!("{e}")),
+    };
+
+    let project = match super::project::owned_project(&pool, conn, &skey, &project_key).await {
+        Ok(p) => p,
+        Err(msg) => return Binary::result_failure(msg),
+    };
+
+    let scenes = match project.list_scenes(conn).await {
+        Ok(scenes) => scenes.iter().map(|s| s.id).collect::<HashSet<i64>>(),
+        Err(_) => return Binary::result_error("Failed to load scene list."),
+    };
+
+    if project.trash(conn).await.is_err() {
+        return Binary::result_error("Failed to delete project.");
+    }
+
+    games::end_scene_games(&games, &scenes).await;
+    Binary::result_success("Project moved to trash.")
+}
+
+async fn delete_scene(
+    scene_key: String,
+    pool: SqlitePool,
+    skey: String,
+    games: Games,
+) -> Result<impl warp::Reply, Infallible> {
+    let conn = &mut match pool.acquire().await {
+        Ok(c) => c,
+        Err(e) => return Binary::result_error(&format!("{e}")),
+    };
+
+    let (record, _) = match super::scene::owned_scene(&pool, conn, &skey, &scene_key).await {
+        Ok(r) => r,
+        Err(msg) => return Binary::result_failure(msg),
+    };
+
+    if record.trash(conn).await.is_err() {
+        return Binary::result_error("Failed to delete scene.");
+    }
+
+    games::end_scene_games(&games, &HashSet::from([record.id])).await;
+    Binary::result_success("Scene moved to trash.")
+}
+
+async fn restore_project(project_key: String, pool: SqlitePool, skey: String) -> ResultReply {
+    let user = match User::get_by_session(&pool, &skey).await {
+        Ok(Some(u)) => u,
+        _ => return Binary::result_failure("Invalid session."),
+    };
+
+    let conn = &mut match pool.acquire().await {
+        Ok(c) => c,
+        Err(e) => return Binary::result_error(&format!("{e}")),
+    };
+
+    match TrashItem::restore_project(conn, user.id, &project_key).await {
+        Ok(()) => Binary::result_success("Project restored."),
+        Err(e) => Binary::result_failure(&e.to_string()),
+    }
+}
+
+async fn restore_scene(scene_key: String, pool: SqlitePool, skey: String) -> ResultReply {
+    let user = match User::get_by_session(&pool, &skey).await {
+        Ok(Some(u)) => u,
+        _ => return Binary::result_failure("Invalid session."),
+    };
+
+    let conn = &mut match pool.acquire().await {
+        Ok(c) => c,
+        Err(e) => return Binary::result_error(&format!("{e}")),
+    };
+
+    match TrashItem::restore_scene(conn, user.id, &scene_key).await {
+        Ok(()) => Binary::result_success("Scene restored."),
+        Err(e) => Binary::result_failure(&e.to_string()),
+    }
+}
+
+#[derive(Serialize)]
+struct TrashEntry {
+    #[serde(flatten)]
+    item: TrashItem,
+
+    // Time, in seconds since the epoch, the item will be permanently deleted.
+    expiry_time: i64,
+}
+
+#[derive(Serialize)]
+struct TrashResponse {
+    message: String,
+    success: bool,
+    items: Vec<TrashEntry>,
+}
+
+async fn list_trash(pool: SqlitePool, skey: String) -> ResultReply {
+    let user = match User::get_by_session(&pool, &skey).await {
+        Ok(Some(u)) => u,
+        _ => return Binary::result_failure("Invalid session."),
+    };
+
+    let conn = &mut match pool.acquire().await {
+        Ok(c) => c,
+        Err(e) => return Binary::result_error(&format!("{e}")),
+    };
+
+    let items = match TrashItem::list(conn, user.id).await {
+        Ok(items) => items,
+        Err(_) => return Binary::result_error("Failed to load trash."),
+    };
+
+    as_result(
+        &TrashResponse {
+            message: "Trash retrieved.".to_string(),
+            success: true,
+            items: items
+                .into_iter()
+                .map(|item| TrashEntry {
+                    expiry_time: item.deleted_time + RETENTION as i64,
+                    item,
+                })
+                .collect(),
+        },
+        StatusCode::OK,
+    )
+}
+
+// Permanently delete everything in the user's trash.
+async fn empty_trash(pool: SqlitePool, skey: String, content_dir: String) -> ResultReply {
+    let user = match User::get_by_session(&pool, &skey).await {
+        Ok(Some(u)) => u,
+        _ => return Binary::result_failure("Invalid session."),
+    };
+
+    match purge(&pool, &content_dir, i64::MAX, Some(user.id)).await {
+        Ok(()) => Binary::result_success("Trash emptied."),
+        Err(_) => Binary::result_error("Failed to empty trash."),
+    }
+}
@@ -34,7 +34,7 @@ impl UploadResponse {
     }
 }
 
-fn hash_file(raw: &[u8]) -> anyhow::Result<String> {
+pub fn hash_file(raw: &[u8]) -> anyhow::Result<String> {
     to_hex_string_unsized(digest::digest(&digest::SHA256, raw).as_ref())
 }
 
@@ -130,43 +130,54 @@ async fn upload(
             _ => (),
         };
 
-        let key = match Media::generate_key() {
-            Ok(s) => s,
-            Err(_) => return Binary::result_error("File name generation failed."),
-        };
-
-        let relative_path = format!("{}/{}.{}", &user.relative_dir(), key, ext);
-        if tokio::fs::create_dir_all(user.upload_dir(&content_dir))
-            .await
-            .is_err()
-        {
-            return Binary::result_error("Failed to create upload dir.");
-        }
-
-        let real_path = format!("{}/{}", content_dir, &relative_path);
-        if tokio::fs::write(&real_path, data).await.is_err() {
-            return Binary::result_error("Failed to write file.");
-        };
-
-        return match Media::create(&pool, &key, user.id, &relative_path, &title, &hash).await {
+        return match store_media(&pool, &user, &content_dir, data, ext, &title, &hash).await {
             Ok(media) => {
-                let url = format!("/static/{}", &relative_path);
+                let url = format!("/static/{}", &media.relative_path);
                 as_result(
                     &UploadResponse::new(media.media_key, url),
                     warp::http::StatusCode::OK,
                 )
             }
-            Err(_) => {
-                // Remove file as part of cleanup.
-                tokio::fs::remove_file(&real_path).await.ok();
-                Binary::result_error("Database error.")
-            }
+            Err(e) => Binary::result_error(&e.to_string()),
         };
     }
 
     Binary::result_failure("No image provided.")
 }
 
+// Write an image to the user's upload directory and create a media item for
+// it. The hash is used to detect duplicate uploads.
+pub async fn store_media(
+    pool: &SqlitePool,
+    user: &User,
+    content_dir: &str,
+    data: Vec<u8>,
+    ext: &str,
+    title: &str,
+    hash: &str,
+) -> anyhow::Result<Media> {
+    let key = Media::generate_key().map_err(|_| anyhow::anyhow!("File name generation failed."))?;
+
+    let relative_path = format!("{}/{}.{}", user.relative_dir(), key, ext);
+    tokio::fs::create_dir_all(user.upload_dir(content_dir))
+        .await
+        .map_err(|_| anyhow::anyhow!("Failed to create upload dir."))?;
+
+    let real_path = format!("{}/{}", content_dir, relative_path);
+    tokio::fs::write(&real_path, data)
+        .await
+        .map_err(|_| anyhow::anyhow!("Failed to write file."))?;
+
+    match Media::create(pool, &key, user.id, &relative_path, title, hash).await {
+        Ok(media) => Ok(media),
+        Err(_) => {
+            // Remove file as part of cleanup.
+            tokio::fs::remove_file(&real_path).await.ok();
+            Err(anyhow::anyhow!("Database error."))
+        }
+    }
+}
+
 fn with_string(string: String) -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
     warp::any().map(move || string.clone())
 }
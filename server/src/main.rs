@@ -8,9 +8,14 @@ use sqlx::sqlite::SqlitePool;
 use tokio::sync::RwLock;
 
 mod crypto;
+mod foundry;
 mod games;
 mod handlers;
 mod models;
+mod render;
+
+#[cfg(test)]
+mod test_util;
 
 use games::Games;
 
@@ -29,6 +34,9 @@ async fn main() -> anyhow::Result<()> {
     let games: Games = Arc::new(RwLock::new(HashMap::new()));
     let pool = connect_to_db().await;
     let content_dir = std::env::args().nth(1).expect("Usage: ./server content/");
+    tokio::spawn(games::expire_overrides(games.clone()));
+    tokio::spawn(games::compact_scenes(games.clone()));
+    tokio::spawn(handlers::purge_trash(pool.clone(), content_dir.clone()));
     let route = handlers::routes(pool, games, content_dir);
 
     warp::serve(route).run(([127, 0, 0, 1], 3030)).await;
@@ -0,0 +1,102 @@
+use scene::comms::{Audience, ChatMessage};
+use sqlx::SqlitePool;
+
+#[derive(sqlx::FromRow)]
+pub struct ChatRecord {
+    pub id: i64,
+    pub project: i64,
+    pub user: i64,
+    pub username: String,
+    pub audience: String,
+    pub recipients: String,
+    pub text: String,
+    pub time: i64,
+}
+
+impl ChatRecord {
+    pub async fn save(
+        pool: &SqlitePool,
+        project: i64,
+        message: &ChatMessage,
+    ) -> anyhow::Result<()> {
+        let (audience, recipients) = audience_columns(&message.audience);
+        sqlx::query(
+            r#"
+            INSERT INTO chat_messages (project, user, audience, recipients, text, time)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6);
+            "#,
+        )
+        .bind(project)
+        .bind(message.user)
+        .bind(audience)
+        .bind(recipients)
+        .bind(&message.text)
+        .bind(message.time as i64)
+        .execute(pool)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to save chat message: {e}"))?;
+        Ok(())
+    }
+
+    // The most recent messages sent in this project, oldest first.
+    pub async fn load_recent(
+        pool: &SqlitePool,
+        project: i64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        let records: Vec<ChatRecord> = sqlx::query_as(
+            r#"
+            SELECT c.id, c.project, c.user, u.username, c.audience, c.recipients, c.text, c.time
+            FROM chat_messages c LEFT JOIN users u ON c.user = u.id
+            WHERE c.project = ?1 ORDER BY c.id DESC LIMIT ?2;
+            "#,
+        )
+        .bind(project)
+        .bind(limit as i64)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to load chat messages: {e}"))?;
+
+        Ok(records.into_iter().rev().map(|r| r.to_message()).collect())
+    }
+
+    fn to_message(&self) -> ChatMessage {
+        ChatMessage {
+            user: self.user,
+            username: self.username.clone(),
+            audience: audience(&self.audience, &self.recipients),
+            text: self.text.clone(),
+            time: self.time as u64,
+        }
+    }
+}
+
+// Whispers are stored with their recipients as a comma separated list of
+// user IDs.
+pub fn audience_columns(audience: &Audience) -> (&'static str, String) {
+    match audience {
+        Audience::Public => ("public", String::new()),
+        Audience::Gm => ("gm", String::new()),
+        Audience::Whisper(users) => (
+            "whisper",
+            users
+                .iter()
+                .map(|u| u.to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+        ),
+    }
+}
+
+pub fn audience(audience: &str, recipients: &str) -> Audience {
+    match audience {
+        "gm" => Audience::Gm,
+        "whisper" => Audience::Whisper(
+            recipients
+                .split(',')
+                .filter_map(|u| u.parse().ok())
+                .collect(),
+        ),
+        _ => Audience::Public,
+    }
+}
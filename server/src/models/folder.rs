@@ -0,0 +1,122 @@
+use anyhow::anyhow;
+use sqlx::SqliteConnection;
+
+use crate::crypto::random_hex_string;
+
+const KEY_LENGTH: usize = 16;
+
+/// A folder in a user's media library. Folders may be nested, and media
+/// items not in a folder are at the top level.
+#[derive(sqlx::FromRow)]
+pub struct MediaFolder {
+    pub id: i64,
+    pub folder_key: String,
+    pub user: i64,
+    pub parent: Option<i64>,
+    pub title: String,
+}
+
+impl MediaFolder {
+    pub async fn create(
+        conn: &mut SqliteConnection,
+        user: i64,
+        parent: Option<&MediaFolder>,
+        title: &str,
+    ) -> anyhow::Result<MediaFolder> {
+        sqlx::query_as(
+            "INSERT INTO media_folders (folder_key, user, parent, title) VALUES (?1, ?2, ?3, ?4) RETURNING *;",
+        )
+        .bind(random_hex_string(KEY_LENGTH)?)
+        .bind(user)
+        .bind(parent.map(|p| p.id))
+        .bind(title)
+        .fetch_one(conn)
+        .await
+        .map_err(|e| anyhow!("Failed to create folder: {e}"))
+    }
+
+    // Load a folder, checking that it belongs to this user.
+    pub async fn load(
+        conn: &mut SqliteConnection,
+        user: i64,
+        key: &str,
+    ) -> anyhow::Result<MediaFolder> {
+        sqlx::query_as("SELECT * FROM media_folders WHERE folder_key = ?1 AND user = ?2;")
+            .bind(key)
+            .bind(user)
+            .fetch_optional(conn)
+            .await?
+            .ok_or_else(|| anyhow!("Folder not found."))
+    }
+
+    pub async fn rename(&mut self, conn: &mut SqliteConnection, title: &str) -> anyhow::Result<()> {
+        sqlx::query("UPDATE media_folders SET title = ?1 WHERE id = ?2;")
+            .bind(title)
+            .bind(self.id)
+            .execute(conn)
+            .await
+            .map_err(|e| anyhow!("Failed to rename folder: {e}"))?;
+        self.title = title.to_string();
+        Ok(())
+    }
+
+    // Move this folder into another, or to the top level if None. A folder
+    // can't be moved into itself or any folder within it.
+    pub async fn move_to(
+        &mut self,
+        conn: &mut SqliteConnection,
+        parent: Option<&MediaFolder>,
+    ) -> anyhow::Result<()> {
+        if let Some(parent) = parent {
+            let (within,): (i64,) = sqlx::query_as(
+                r#"
+                WITH RECURSIVE subtree(id) AS (
+                    SELECT ?1
+                    UNION SELECT media_folders.id FROM media_folders
+                    JOIN subtree ON media_folders.parent = subtree.id
+                )
+                SELECT COUNT(*) FROM subtree WHERE id = ?2;
+                "#,
+            )
+            .bind(self.id)
+            .bind(parent.id)
+            .fetch_one(&mut *conn)
+            .await?;
+
+            if within > 0 {
+                return Err(anyhow!("A folder can't be moved into itself."));
+            }
+        }
+
+        let parent = parent.map(|p| p.id);
+        sqlx::query("UPDATE media_folders SET parent = ?1 WHERE id = ?2;")
+            .bind(parent)
+            .bind(self.id)
+            .execute(conn)
+            .await
+            .map_err(|e| anyhow!("Failed to move folder: {e}"))?;
+        self.parent = parent;
+        Ok(())
+    }
+
+    // Delete this folder. The media and folders within it are moved into
+    // its parent rather than deleted.
+    pub async fn delete(self, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        sqlx::query("UPDATE media SET folder = ?1 WHERE folder = ?2;")
+            .bind(self.parent)
+            .bind(self.id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("UPDATE media_folders SET parent = ?1 WHERE parent = ?2;")
+            .bind(self.parent)
+            .bind(self.id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("DELETE FROM media_folders WHERE id = ?1;")
+            .bind(self.id)
+            .execute(conn)
+            .await
+            .map_err(|e| anyhow!("Failed to delete folder: {e}"))?;
+        Ok(())
+    }
+}
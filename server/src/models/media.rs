@@ -1,3 +1,6 @@
+use scene::Colour;
+use sqlx::SqliteConnection;
+
 use crate::crypto::random_hex_string;
 
 #[derive(sqlx::FromRow)]
@@ -8,11 +11,88 @@ pub struct Media {
     pub relative_path: String,
     pub title: String,
     pub hashed_value: String,
+
+    // Folder in the user's media library the item is in, or None if it is
+    // at the top level.
+    pub folder: Option<i64>,
+}
+
+/// A scene with sprites which use a media item as their texture.
+#[derive(sqlx::FromRow, serde_derive::Serialize)]
+pub struct MediaUsage {
+    pub project_key: String,
+    pub project_title: String,
+    pub scene_key: String,
+    pub scene_title: String,
+    pub sprites: i64,
+
+    // Whether the scene, or its project, is in the trash.
+    pub trashed: bool,
+}
+
+/// Conditions on the items listed from a user's media library.
+#[derive(Default)]
+pub struct MediaFilter {
+    // Key of the folder to list, or empty for the top level. Items in all
+    // folders are listed if None.
+    pub folder: Option<String>,
+
+    // Only list items with titles containing this, ignoring case.
+    pub search: Option<String>,
+
+    // Only list items with this tag, which should be normalised with
+    // Media::tag.
+    pub tag: Option<String>,
+
+    // Page of items to list. All items are listed if no limit is given.
+    pub limit: Option<u32>,
+    pub offset: u32,
+}
+
+/// A media item as listed from a user's library.
+#[derive(sqlx::FromRow)]
+pub struct MediaListing {
+    pub media_key: String,
+    pub title: String,
+    pub relative_path: String,
+    pub folder_key: Option<String>,
+
+    // Newline separated.
+    pub tags: Option<String>,
+}
+
+impl MediaListing {
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags = self
+            .tags
+            .as_deref()
+            .map(|t| t.lines().map(str::to_string).collect::<Vec<String>>())
+            .unwrap_or_default();
+        tags.sort();
+        tags
+    }
 }
 
 impl Media {
     const KEY_LENGTH: usize = 16;
 
+    // Most items which may be listed at once.
+    const MAX_LIST_LIMIT: u32 = 500;
+
+    // Conditions on media items matching a MediaFilter, with ?1 the user.
+    const LIST_FILTER: &'static str = r#"
+        FROM media LEFT JOIN media_folders ON media.folder = media_folders.id
+        WHERE media.user = ?1
+            AND (?2 IS NULL OR (?2 = '' AND media.folder IS NULL)
+                OR media_folders.folder_key = ?2)
+            AND (?3 IS NULL OR instr(lower(media.title), lower(?3)) > 0)
+            AND (?4 IS NULL OR EXISTS (
+                SELECT 1 FROM media_tags WHERE media_tags.media = media.id AND tag = ?4
+            ))
+    "#;
+
+    const MAX_TAG_LENGTH: usize = 32;
+
     pub async fn create(
         pool: &sqlx::SqlitePool,
         key: &str,
@@ -42,6 +122,247 @@ impl Media {
             .map_err(|e| anyhow::anyhow!("Media item not found: {e}"))
     }
 
+    // Load this user's media item with the given file hash, if they have
+    // already uploaded the file.
+    pub async fn load_hashed(
+        pool: &sqlx::SqlitePool,
+        user: i64,
+        hash: &str,
+    ) -> anyhow::Result<Option<Media>> {
+        sqlx::query_as("SELECT * FROM media WHERE user = ?1 AND hashed_value = ?2;")
+            .bind(user)
+            .bind(hash)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| anyhow::anyhow!("Database error: {e}"))
+    }
+
+    // A page of this user's media items matching the filter, in upload
+    // order, with the number of items matching over all pages.
+    pub async fn list(
+        conn: &mut SqliteConnection,
+        user: i64,
+        filter: &MediaFilter,
+    ) -> anyhow::Result<(Vec<MediaListing>, i64)> {
+        let items = sqlx::query_as(&format!(
+            r#"
+            SELECT media.media_key, media.title, media.relative_path,
+                media_folders.folder_key,
+                (SELECT GROUP_CONCAT(tag, char(10)) FROM media_tags
+                    WHERE media_tags.media = media.id) AS tags
+            {}
+            ORDER BY media.id LIMIT ?5 OFFSET ?6;
+            "#,
+            Self::LIST_FILTER
+        ))
+        .bind(user)
+        .bind(&filter.folder)
+        .bind(&filter.search)
+        .bind(&filter.tag)
+        .bind(
+            filter
+                .limit
+                .map(|l| l.min(Self::MAX_LIST_LIMIT) as i64)
+                .unwrap_or(-1),
+        )
+        .bind(filter.offset)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) {};", Self::LIST_FILTER))
+            .bind(user)
+            .bind(&filter.folder)
+            .bind(&filter.search)
+            .bind(&filter.tag)
+            .fetch_one(conn)
+            .await?;
+
+        Ok((items, total))
+    }
+
+    // Scenes belonging to this user which use this media item.
+    pub async fn usage(
+        &self,
+        pool: &sqlx::SqlitePool,
+        user: i64,
+    ) -> anyhow::Result<Vec<MediaUsage>> {
+        sqlx::query_as(
+            r#"
+            SELECT
+                projects.project_key, projects.title AS project_title,
+                scenes.scene_key, scenes.title AS scene_title, COUNT(*) AS sprites,
+                scenes.deleted_time IS NOT NULL OR projects.deleted_time IS NOT NULL
+                    AS trashed
+            FROM sprites
+            JOIN scenes ON sprites.scene = scenes.id
+            JOIN projects ON scenes.project = projects.id
+            WHERE sprites.media_key = ?1 AND projects.user = ?2
+            GROUP BY scenes.id ORDER BY projects.id, scenes.position;
+            "#,
+        )
+        .bind(&self.media_key)
+        .bind(user)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| anyhow::anyhow!("Database error: {e}"))
+    }
+
+    // Load a media item, checking that it belongs to this user.
+    pub async fn load_owned(
+        conn: &mut SqliteConnection,
+        user: i64,
+        key: &str,
+    ) -> anyhow::Result<Media> {
+        sqlx::query_as("SELECT * FROM media WHERE media_key = ?1 AND user = ?2;")
+            .bind(key)
+            .bind(user)
+            .fetch_optional(conn)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Media item not found: {key}"))
+    }
+
+    // Scenes, in any user's project, with sprites which use this media item,
+    // as their ID and key.
+    pub async fn scenes(&self, conn: &mut SqliteConnection) -> anyhow::Result<Vec<(i64, String)>> {
+        sqlx::query_as(
+            r#"
+            SELECT DISTINCT scenes.id, scenes.scene_key FROM sprites
+            JOIN scenes ON sprites.scene = scenes.id
+            WHERE sprites.media_key = ?1;
+            "#,
+        )
+        .bind(&self.media_key)
+        .fetch_all(conn)
+        .await
+        .map_err(|e| anyhow::anyhow!("Database error: {e}"))
+    }
+
+    // Delete this media item's record. If any sprite, in any user's scene,
+    // uses the item, it isn't deleted unless a placeholder colour is given,
+    // in which case those sprites are changed to that colour. Returns the
+    // number of sprites changed.
+    pub async fn delete(
+        &self,
+        conn: &mut SqliteConnection,
+        placeholder: Option<Colour>,
+    ) -> anyhow::Result<u64> {
+        let replaced = match placeholder {
+            Some([r, g, b, a]) => sqlx::query(
+                "UPDATE sprites SET media_key = NULL, r = ?1, g = ?2, b = ?3, a = ?4 WHERE media_key = ?5;",
+            )
+            .bind(r)
+            .bind(g)
+            .bind(b)
+            .bind(a)
+            .bind(&self.media_key)
+            .execute(&mut *conn)
+            .await?
+            .rows_affected(),
+            None => {
+                let (uses,): (i64,) =
+                    sqlx::query_as("SELECT COUNT(*) FROM sprites WHERE media_key = ?1;")
+                        .bind(&self.media_key)
+                        .fetch_one(&mut *conn)
+                        .await?;
+                if uses > 0 {
+                    return Err(anyhow::anyhow!(
+                        "Media item {} is used by {uses} sprites.",
+                        self.title
+                    ));
+                }
+                0
+            }
+        };
+
+        sqlx::query("DELETE FROM media WHERE id = ?1;")
+            .bind(self.id)
+            .execute(conn)
+            .await?;
+        Ok(replaced)
+    }
+
+    pub async fn set_title(&self, conn: &mut SqliteConnection, title: &str) -> anyhow::Result<()> {
+        sqlx::query("UPDATE media SET title = ?1 WHERE id = ?2;")
+            .bind(title)
+            .bind(self.id)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_folder(
+        &self,
+        conn: &mut SqliteConnection,
+        folder: Option<i64>,
+    ) -> anyhow::Result<()> {
+        sqlx::query("UPDATE media SET folder = ?1 WHERE id = ?2;")
+            .bind(folder)
+            .bind(self.id)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    // Add tags, which should be normalised with Media::tag, to this item.
+    pub async fn add_tags(
+        &self,
+        conn: &mut SqliteConnection,
+        tags: &[String],
+    ) -> anyhow::Result<()> {
+        for tag in tags {
+            sqlx::query("INSERT OR IGNORE INTO media_tags (media, tag) VALUES (?1, ?2);")
+                .bind(self.id)
+                .bind(tag)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn remove_tags(
+        &self,
+        conn: &mut SqliteConnection,
+        tags: &[String],
+    ) -> anyhow::Result<()> {
+        for tag in tags {
+            sqlx::query("DELETE FROM media_tags WHERE media = ?1 AND tag = ?2;")
+                .bind(self.id)
+                .bind(tag)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Normalise a tag so that tags differing only in case or surrounding
+    // whitespace are the same. Returns None for tags which are empty, too long
+    // or contain control characters.
+    pub fn tag(tag: &str) -> Option<String> {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty()
+            || tag.chars().count() > Self::MAX_TAG_LENGTH
+            || tag.chars().any(char::is_control)
+        {
+            None
+        } else {
+            Some(tag)
+        }
+    }
+
+    // Extension of a supported image type, detected from the file contents.
+    pub fn extension(data: &[u8]) -> Option<&'static str> {
+        const PNG: &[u8] = &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+        const JPEG: &[u8] = &[0xFF, 0xD8, 0xFF];
+
+        if data.starts_with(PNG) {
+            Some("png")
+        } else if data.starts_with(JPEG) {
+            Some("jpeg")
+        } else {
+            None
+        }
+    }
+
     pub fn generate_key() -> anyhow::Result<String> {
         let key = random_hex_string(Media::KEY_LENGTH)?;
 
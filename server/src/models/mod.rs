@@ -3,13 +3,19 @@ use sqlx::{FromRow, SqlitePool};
 #[cfg(test)]
 mod tests;
 
+mod chat;
+mod folder;
 mod media;
 mod project;
+mod trash;
 mod user;
 
-pub use media::Media;
+pub use chat::ChatRecord;
+pub use folder::MediaFolder;
+pub use media::{Media, MediaFilter, MediaUsage};
 pub use project::Project;
 pub use project::SceneRecord;
+pub use trash::TrashItem;
 pub use user::User;
 
 #[derive(FromRow)]
@@ -1,10 +1,16 @@
-use sqlx::SqliteConnection;
+use std::collections::{HashMap, HashSet};
+
+use sqlx::{Connection, Row, SqliteConnection};
 
 use crate::crypto;
+use crate::models::Media;
 
+use self::combatant::CombatantRecord;
 use self::layer::LayerRecord;
 pub use self::scene_record::SceneRecord;
 use self::sprite::SpriteRecord;
+use self::template::TemplateRecord;
+use self::wall::WallRecord;
 
 const RECORD_KEY_LENGTH: usize = 16;
 
@@ -30,7 +36,7 @@ impl Project {
     }
 
     pub async fn load(conn: &mut SqliteConnection, id: i64) -> anyhow::Result<Project> {
-        let res = sqlx::query_as("SELECT * FROM projects WHERE id = ?1;")
+        let res = sqlx::query_as("SELECT * FROM projects WHERE id = ?1 AND deleted_time IS NULL;")
             .bind(id)
             .fetch_optional(conn)
             .await;
@@ -42,8 +48,17 @@ impl Project {
         }
     }
 
+    pub async fn load_from_key(conn: &mut SqliteConnection, key: &str) -> anyhow::Result<Project> {
+        sqlx::query_as("SELECT * FROM projects WHERE project_key = ?1 AND deleted_time IS NULL;")
+            .bind(key)
+            .fetch_optional(conn)
+            .await
+            .map_err(|_| anyhow::anyhow!("Database error."))?
+            .ok_or_else(|| anyhow::anyhow!("Project not found."))
+    }
+
     pub async fn list(conn: &mut SqliteConnection, user: i64) -> anyhow::Result<Vec<Project>> {
-        sqlx::query_as("SELECT * FROM projects WHERE user = ?1;")
+        sqlx::query_as("SELECT * FROM projects WHERE user = ?1 AND deleted_time IS NULL;")
             .bind(user)
             .fetch_all(conn)
             .await
@@ -88,6 +103,11 @@ impl Project {
         scene: scene::Scene,
         scene_title: String,
     ) -> anyhow::Result<SceneRecord> {
+        // Saved in a single transaction so that a failure part way through
+        // can't leave a partly saved scene.
+        let mut tx = conn.begin().await?;
+        let conn: &mut SqliteConnection = &mut tx;
+
         let s = scene_record::SceneRecord::get_or_create(
             conn,
             scene.id,
@@ -97,18 +117,23 @@ impl Project {
             scene.h,
         )
         .await?;
+        s.update_details(conn, &scene).await?;
+        WallRecord::save_scene_walls(conn, &scene.walls, s.id).await?;
+        TemplateRecord::save_scene_templates(conn, &scene.layers, s.id).await?;
+        CombatantRecord::save_scene_combatants(conn, &scene.initiative, s.id).await?;
 
-        for layer in scene.removed_layers.iter() {
-            for sprite in &layer.sprites {
-                SpriteRecord::delete(conn, sprite.id, s.id).await?;
-            }
-        }
+        // Removed layers and sprites may have been pruned from the scene, so
+        // anything which isn't in the scene is deleted.
+        let layers = scene.layers.iter().map(|l| l.id).collect::<HashSet<i64>>();
+        LayerRecord::delete_others(conn, s.id, &layers).await?;
+        let sprites = scene
+            .layers
+            .iter()
+            .flat_map(|l| l.sprites.iter().map(|s| s.id))
+            .collect::<HashSet<i64>>();
+        SpriteRecord::delete_others(conn, s.id, &sprites).await?;
 
         for layer in &scene.layers {
-            for sprite in &layer.removed_sprites {
-                SpriteRecord::delete(conn, sprite.id, s.id).await?;
-            }
-
             let l = LayerRecord::update_or_create(conn, layer, s.id).await?;
 
             for sprite in &layer.sprites {
@@ -116,6 +141,7 @@ impl Project {
             }
         }
 
+        tx.commit().await?;
         Ok(s)
     }
 
@@ -125,6 +151,87 @@ impl Project {
     ) -> anyhow::Result<Vec<SceneRecord>> {
         SceneRecord::project_scenes(conn, self.id).await
     }
+
+    // Move this project, with its scenes, to the trash, from which it may be
+    // restored until it is purged.
+    pub async fn trash(&self, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        sqlx::query("UPDATE projects SET deleted_time = ?1 WHERE id = ?2;")
+            .bind(crate::handlers::current_time()? as i64)
+            .bind(self.id)
+            .execute(conn)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete project: {e}"))?;
+        Ok(())
+    }
+
+    // Set the order of the scenes in this project. The scene keys given must
+    // be exactly those of the project's scenes.
+    pub async fn reorder_scenes(
+        &self,
+        conn: &mut SqliteConnection,
+        scene_keys: &[String],
+    ) -> anyhow::Result<()> {
+        let scenes = self.list_scenes(conn).await?;
+        let existing = scenes
+            .iter()
+            .map(|s| s.scene_key.as_str())
+            .collect::<HashSet<&str>>();
+        let ordered = scene_keys
+            .iter()
+            .map(String::as_str)
+            .collect::<HashSet<&str>>();
+        if ordered.len() != scene_keys.len() || ordered != existing {
+            return Err(anyhow::anyhow!("Scene order must include each scene once."));
+        }
+
+        for (position, key) in scene_keys.iter().enumerate() {
+            sqlx::query("UPDATE scenes SET position = ?1 WHERE scene_key = ?2 AND project = ?3;")
+                .bind(position as i64)
+                .bind(key)
+                .bind(self.id)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to reorder scenes: {e}"))?;
+        }
+        Ok(())
+    }
+
+    // Number of sprites in each scene in this project, by scene ID.
+    pub async fn sprite_counts(
+        &self,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<HashMap<i64, u32>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT sprites.scene, COUNT(*) FROM sprites
+            JOIN scenes ON sprites.scene = scenes.id
+            WHERE scenes.project = ?1 AND scenes.deleted_time IS NULL
+            GROUP BY sprites.scene;
+            "#,
+        )
+        .bind(self.id)
+        .fetch_all(conn)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to count sprites: {e}"))?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    // Media used as textures by sprites in any scene in this project.
+    pub async fn media(&self, conn: &mut SqliteConnection) -> anyhow::Result<Vec<Media>> {
+        sqlx::query_as(
+            r#"
+            SELECT DISTINCT media.* FROM media
+            JOIN sprites ON sprites.media_key = media.media_key
+            JOIN scenes ON sprites.scene = scenes.id
+            WHERE scenes.project = ?1 AND scenes.deleted_time IS NULL;
+            "#,
+        )
+        .bind(self.id)
+        .fetch_all(conn)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to load project media: {e}"))
+    }
 }
 
 mod scene_record {
@@ -133,7 +240,10 @@ mod scene_record {
 
     use crate::crypto;
 
-    use super::{layer::LayerRecord, sprite::SpriteRecord, RECORD_KEY_LENGTH};
+    use super::{
+        combatant::CombatantRecord, layer::LayerRecord, sprite::SpriteRecord,
+        template::TemplateRecord, wall::WallRecord, RECORD_KEY_LENGTH,
+    };
 
     #[derive(sqlx::FromRow)]
     pub struct SceneRecord {
@@ -143,11 +253,39 @@ mod scene_record {
         pub title: String,
         pub w: u32,
         pub h: u32,
+        pub fog_of_war: bool,
+        pub grid: String,
+        pub grid_size: f32,
+        pub grid_x: f32,
+        pub grid_y: f32,
+        pub grid_r: f32,
+        pub grid_g: f32,
+        pub grid_b: f32,
+        pub grid_a: f32,
+        pub grid_thickness: f32,
+        pub grid_visible: bool,
+        pub grid_rule: String,
+        pub grid_units_per_tile: f32,
+        pub grid_unit: String,
+        pub initiative_round: u32,
+        pub initiative_turn: u32,
+
+        // Position of the scene in its project's scene list.
+        pub position: i64,
+
+        // Time, in seconds since the epoch, the scene was last saved.
+        pub updated_time: i64,
     }
 
     impl SceneRecord {
+        // Scenes which are in the trash, or whose project is, aren't loaded.
+        const LOAD_QUERY: &str = r#"
+            SELECT scenes.* FROM scenes JOIN projects ON scenes.project = projects.id
+            WHERE scenes.deleted_time IS NULL AND projects.deleted_time IS NULL
+        "#;
+
         pub async fn load(conn: &mut SqliteConnection, id: i64) -> anyhow::Result<SceneRecord> {
-            sqlx::query_as("SELECT * FROM scenes WHERE id = ?1;")
+            sqlx::query_as(&format!("{} AND scenes.id = ?1;", Self::LOAD_QUERY))
                 .bind(id)
                 .fetch_one(conn)
                 .await
@@ -158,7 +296,7 @@ mod scene_record {
             conn: &mut SqliteConnection,
             scene_key: &str,
         ) -> anyhow::Result<SceneRecord> {
-            sqlx::query_as("SELECT * FROM scenes WHERE scene_key = ?1")
+            sqlx::query_as(&format!("{} AND scenes.scene_key = ?1;", Self::LOAD_QUERY))
                 .bind(scene_key)
                 .fetch_one(conn)
                 .await
@@ -172,17 +310,29 @@ mod scene_record {
             width: u32,
             height: u32,
         ) -> anyhow::Result<SceneRecord> {
-            sqlx::query_as(
-                "INSERT INTO scenes (scene_key, project, title, w, h) VALUES (?1, ?2, ?3, ?4, ?5) RETURNING *;",
+            // Scenes have REAL columns, so RETURNING * can't be used; see the
+            // note in the sprite module.
+            // New scenes are placed at the end of the project's scene list.
+            let id = sqlx::query(
+                r#"
+                INSERT INTO scenes (scene_key, project, title, w, h, position)
+                VALUES (
+                    ?1, ?2, ?3, ?4, ?5,
+                    (SELECT COALESCE(MAX(position) + 1, 0) FROM scenes WHERE project = ?2)
+                ) RETURNING id;
+                "#,
             )
             .bind(crypto::random_hex_string(RECORD_KEY_LENGTH)?)
             .bind(project)
             .bind(title)
             .bind(width)
             .bind(height)
-            .fetch_one(conn)
+            .fetch_one(&mut *conn)
             .await
-            .map_err(|e| anyhow!("Failed to create scene: {e}"))
+            .map(|row: sqlx::sqlite::SqliteRow| row.get(0))
+            .map_err(|e| anyhow!("Failed to create scene: {e}"))?;
+
+            SceneRecord::load(conn, id).await
         }
 
         pub async fn get_or_create(
@@ -205,6 +355,18 @@ mod scene_record {
             Ok(record)
         }
 
+        // Move this scene to the trash, from which it may be restored until
+        // it is purged.
+        pub async fn trash(&self, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+            sqlx::query("UPDATE scenes SET deleted_time = ?1 WHERE id = ?2;")
+                .bind(crate::handlers::current_time()? as i64)
+                .bind(self.id)
+                .execute(conn)
+                .await
+                .map_err(|e| anyhow!("Failed to delete scene: {e}"))?;
+            Ok(())
+        }
+
         async fn update_title(
             &self,
             conn: &mut SqliteConnection,
@@ -219,12 +381,58 @@ mod scene_record {
             Ok(())
         }
 
+        pub async fn update_details(
+            &self,
+            conn: &mut SqliteConnection,
+            scene: &scene::Scene,
+        ) -> anyhow::Result<()> {
+            let grid = &scene.grid;
+            let [r, g, b, a] = grid.colour;
+            sqlx::query(
+                r#"
+                UPDATE scenes SET
+                    w = ?1, h = ?2, fog_of_war = ?3, grid = ?4, grid_size = ?5,
+                    grid_x = ?6, grid_y = ?7, grid_r = ?8, grid_g = ?9, grid_b = ?10,
+                    grid_a = ?11, grid_thickness = ?12, grid_visible = ?13,
+                    grid_rule = ?14, grid_units_per_tile = ?15, grid_unit = ?16,
+                    initiative_round = ?17, initiative_turn = ?18, updated_time = ?19
+                WHERE id = ?20;
+                "#,
+            )
+            .bind(scene.w)
+            .bind(scene.h)
+            .bind(scene.fog_of_war)
+            .bind(grid_name(grid.kind))
+            .bind(grid.size)
+            .bind(grid.offset.x)
+            .bind(grid.offset.y)
+            .bind(r)
+            .bind(g)
+            .bind(b)
+            .bind(a)
+            .bind(grid.thickness)
+            .bind(grid.visible)
+            .bind(rule_name(grid.rule))
+            .bind(grid.units_per_tile)
+            .bind(grid.unit.abbreviation())
+            .bind(scene.initiative.round)
+            .bind(scene.initiative.turn as u32)
+            .bind(crate::handlers::current_time()? as i64)
+            .bind(self.id)
+            .execute(conn)
+            .await
+            .map_err(|_| anyhow!("Failed to update scene details."))?;
+            Ok(())
+        }
+
         pub async fn load_scene(
             &self,
             conn: &mut SqliteConnection,
         ) -> anyhow::Result<scene::Scene> {
             let layers = LayerRecord::load_scene_layers(conn, self.id).await?;
             let mut sprites = SpriteRecord::load_scene_sprites(conn, self.id).await?;
+            let mut fields = SpriteRecord::load_scene_fields(conn, self.id).await?;
+            let mut owners = SpriteRecord::load_scene_owners(conn, self.id).await?;
             let mut layers = layers
                 .iter()
                 .map(|lr| lr.to_layer())
@@ -232,7 +440,16 @@ mod scene_record {
 
             while let Some(s) = sprites.pop() {
                 if let Some(l) = layers.iter_mut().find(|l| l.id == s.layer) {
-                    l.add_sprite(s.to_sprite());
+                    let mut sprite = s.to_sprite();
+                    sprite.token.fields = fields.remove(&sprite.id).unwrap_or_default();
+                    sprite.owners = owners.remove(&sprite.id).unwrap_or_default();
+                    l.add_sprite(sprite);
+                }
+            }
+
+            for t in TemplateRecord::load_scene_templates(conn, self.id).await? {
+                if let Some(l) = layers.iter_mut().find(|l| l.id == t.layer) {
+                    l.add_template(t.to_template());
                 }
             }
 
@@ -242,6 +459,36 @@ mod scene_record {
             scene.project = Some(self.project);
             scene.w = self.w;
             scene.h = self.h;
+            scene.fog_of_war = self.fog_of_war;
+            scene.grid = scene::Grid {
+                kind: grid_type(&self.grid),
+                size: self.grid_size,
+                offset: scene::ScenePoint::new(self.grid_x, self.grid_y),
+                colour: [self.grid_r, self.grid_g, self.grid_b, self.grid_a],
+                thickness: self.grid_thickness,
+                visible: self.grid_visible,
+                rule: distance_rule(&self.grid_rule),
+                units_per_tile: self.grid_units_per_tile,
+                unit: match self.grid_unit.as_str() {
+                    "m" => scene::DistanceUnit::Metres,
+                    _ => scene::DistanceUnit::Feet,
+                },
+            };
+            scene.walls = WallRecord::load_scene_walls(conn, self.id)
+                .await?
+                .iter()
+                .map(|w| w.to_wall())
+                .collect();
+            scene.initiative = scene::Initiative {
+                combatants: CombatantRecord::load_scene_combatants(conn, self.id)
+                    .await?
+                    .iter()
+                    .map(|c| c.to_combatant())
+                    .collect(),
+                turn: self.initiative_turn as usize,
+                round: self.initiative_round,
+            };
+            scene.minimise_next_id();
             Ok(scene)
         }
 
@@ -260,16 +507,52 @@ mod scene_record {
             conn: &mut SqliteConnection,
             project: i64,
         ) -> anyhow::Result<Vec<SceneRecord>> {
-            sqlx::query_as("SELECT * FROM scenes WHERE project = ?1;")
+            sqlx::query_as("SELECT * FROM scenes WHERE project = ?1 AND deleted_time IS NULL ORDER BY position, id;")
                 .bind(project)
                 .fetch_all(conn)
                 .await
                 .map_err(|e| anyhow::anyhow!(format!("Failed to load scene list: {e}")))
         }
     }
+
+    fn grid_name(grid: scene::GridType) -> &'static str {
+        match grid {
+            scene::GridType::Square => "square",
+            scene::GridType::HexFlat => "hex_flat",
+            scene::GridType::HexPointy => "hex_pointy",
+            scene::GridType::Gridless => "gridless",
+        }
+    }
+
+    fn grid_type(name: &str) -> scene::GridType {
+        match name {
+            "hex_flat" => scene::GridType::HexFlat,
+            "hex_pointy" => scene::GridType::HexPointy,
+            "gridless" => scene::GridType::Gridless,
+            _ => scene::GridType::Square,
+        }
+    }
+
+    fn rule_name(rule: scene::DistanceRule) -> &'static str {
+        match rule {
+            scene::DistanceRule::Euclidean => "euclidean",
+            scene::DistanceRule::Chebyshev => "chebyshev",
+            scene::DistanceRule::Alternating => "alternating",
+        }
+    }
+
+    fn distance_rule(name: &str) -> scene::DistanceRule {
+        match name {
+            "chebyshev" => scene::DistanceRule::Chebyshev,
+            "alternating" => scene::DistanceRule::Alternating,
+            _ => scene::DistanceRule::Euclidean,
+        }
+    }
 }
 
 mod layer {
+    use std::collections::HashSet;
+
     use anyhow::anyhow;
     use sqlx::SqliteConnection;
 
@@ -281,21 +564,22 @@ mod layer {
         z: i64,
         visible: bool,
         locked: bool,
+        opacity: f32,
+        blend: String,
     }
 
     impl LayerRecord {
         pub fn to_layer(&self) -> scene::Layer {
-            scene::Layer {
-                id: self.id,
-                title: self.title.clone(),
-                z: self.z as i32,
-                visible: self.visible,
-                locked: self.locked,
-                sprites: vec![],
-                removed_sprites: vec![],
-                z_min: 0,
-                z_max: 0,
-            }
+            let mut layer = scene::Layer::new(self.id, &self.title, self.z as i32);
+            layer.visible = self.visible;
+            layer.locked = self.locked;
+            layer.opacity = self.opacity;
+            layer.blend = match self.blend.as_str() {
+                "multiply" => scene::BlendMode::Multiply,
+                "screen" => scene::BlendMode::Screen,
+                _ => scene::BlendMode::Normal,
+            };
+            layer
         }
 
         async fn load(
@@ -316,16 +600,32 @@ mod layer {
             layer: &scene::Layer,
             scene: i64,
         ) -> anyhow::Result<LayerRecord> {
-            sqlx::query_as("INSERT INTO layers (id, scene, title, z, visible, locked) VALUES (?1, ?2, ?3, ?4, ?5, ?6) RETURNING *;")
-                .bind(layer.id)
-                .bind(scene)
-                .bind(&layer.title)
-                .bind(layer.z as i64)
-                .bind(layer.visible)
-                .bind(layer.locked)
-                .fetch_one(conn)
-                .await
-                .map_err(|_| anyhow!("Failed to create layer."))
+            let blend = match layer.blend {
+                scene::BlendMode::Normal => "normal",
+                scene::BlendMode::Multiply => "multiply",
+                scene::BlendMode::Screen => "screen",
+            };
+
+            // Can't use RETURNING * as opacity is REAL; see SpriteRecord.
+            sqlx::query(
+                r#"
+                INSERT INTO layers (id, scene, title, z, visible, locked, opacity, blend)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);
+                "#,
+            )
+            .bind(layer.id)
+            .bind(scene)
+            .bind(&layer.title)
+            .bind(layer.z as i64)
+            .bind(layer.visible)
+            .bind(layer.locked)
+            .bind(layer.opacity)
+            .bind(blend)
+            .execute(&mut *conn)
+            .await
+            .map_err(|_| anyhow!("Failed to create layer."))?;
+
+            LayerRecord::load(conn, scene, layer.id).await
         }
 
         pub async fn delete(
@@ -342,6 +642,25 @@ mod layer {
                 .map_err(|e| anyhow!("Failed to delete layer: {e}"))
         }
 
+        pub async fn delete_others(
+            conn: &mut SqliteConnection,
+            scene: i64,
+            keep: &HashSet<i64>,
+        ) -> anyhow::Result<()> {
+            let ids: Vec<(i64,)> = sqlx::query_as("SELECT id FROM layers WHERE scene = ?1;")
+                .bind(scene)
+                .fetch_all(&mut *conn)
+                .await
+                .map_err(|e| anyhow!("Failed to load layer IDs: {e}"))?;
+
+            for (id,) in ids {
+                if !keep.contains(&id) {
+                    LayerRecord::delete(conn, id, scene).await?;
+                }
+            }
+            Ok(())
+        }
+
         pub async fn update_or_create(
             conn: &mut SqliteConnection,
             layer: &scene::Layer,
@@ -365,6 +684,8 @@ mod layer {
 }
 
 mod sprite {
+    use std::collections::{BTreeMap, HashMap, HashSet};
+
     use anyhow::anyhow;
     use sqlx::{Row, SqliteConnection};
 
@@ -389,6 +710,19 @@ mod sprite {
         w: f32,
         h: f32,
         z: i64,
+        vision: Option<f32>,
+        name: Option<String>,
+        hp: Option<i32>,
+        max_hp: Option<i32>,
+        temp_hp: i32,
+        conditions: String,
+    }
+
+    #[derive(sqlx::FromRow)]
+    struct SpriteFieldRecord {
+        sprite: i64,
+        key: String,
+        value: String,
     }
 
     impl SpriteRecord {
@@ -406,6 +740,12 @@ mod sprite {
                 w: sprite.rect.w,
                 h: sprite.rect.h,
                 z: sprite.z as i64,
+                vision: sprite.vision,
+                name: sprite.token.name.clone(),
+                hp: sprite.token.hp,
+                max_hp: sprite.token.max_hp,
+                temp_hp: sprite.token.temp_hp,
+                conditions: sprite.token.conditions.join("\n"),
             };
 
             match sprite.visual {
@@ -437,6 +777,12 @@ mod sprite {
             let mut sprite = scene::Sprite::new(self.id, self.visual(), None);
             sprite.set_rect(scene::Rect::new(self.x, self.y, self.w, self.h));
             sprite.z = self.z as i32;
+            sprite.vision = self.vision;
+            sprite.token.name = self.name.clone();
+            sprite.token.hp = self.hp;
+            sprite.token.max_hp = self.max_hp;
+            sprite.token.temp_hp = self.temp_hp;
+            sprite.token.conditions = self.conditions.lines().map(str::to_string).collect();
             sprite
         }
 
@@ -446,12 +792,14 @@ mod sprite {
             layer: i64,
             scene: i64,
         ) -> anyhow::Result<i64> {
-            sqlx::query(
+            let id = sqlx::query(
                 r#"
                 INSERT INTO sprites (
-                    id, scene, layer, media_key, r, g, b, a, x, y, w, h, z
+                    id, scene, layer, media_key, r, g, b, a, x, y, w, h, z,
+                    vision, name, hp, max_hp, temp_hp, conditions
                 ) VALUES (
-                    ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13
+                    ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13,
+                    ?14, ?15, ?16, ?17, ?18, ?19
                 ) RETURNING id;
                 "#,
             )
@@ -468,10 +816,41 @@ mod sprite {
             .bind(sprite.rect.w)
             .bind(sprite.rect.h)
             .bind(sprite.z)
-            .fetch_one(conn)
+            .bind(sprite.vision)
+            .bind(&sprite.token.name)
+            .bind(sprite.token.hp)
+            .bind(sprite.token.max_hp)
+            .bind(sprite.token.temp_hp)
+            .bind(sprite.token.conditions.join("\n"))
+            .fetch_one(&mut *conn)
             .await
             .map(|row: sqlx::sqlite::SqliteRow| row.get(0))
-            .map_err(|e| anyhow!("Failed to create sprite: {e}"))
+            .map_err(|e| anyhow!("Failed to create sprite: {e}"))?;
+
+            for (key, value) in &sprite.token.fields {
+                sqlx::query(
+                    "INSERT INTO sprite_fields (sprite, scene, key, value) VALUES (?1, ?2, ?3, ?4);",
+                )
+                .bind(sprite.id)
+                .bind(scene)
+                .bind(key)
+                .bind(value)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| anyhow!("Failed to create sprite field: {e}"))?;
+            }
+
+            for user in &sprite.owners {
+                sqlx::query("INSERT INTO sprite_owners (sprite, scene, user) VALUES (?1, ?2, ?3);")
+                    .bind(sprite.id)
+                    .bind(scene)
+                    .bind(user)
+                    .execute(&mut *conn)
+                    .await
+                    .map_err(|e| anyhow!("Failed to create sprite owner: {e}"))?;
+            }
+
+            Ok(id)
         }
 
         pub async fn delete(
@@ -488,6 +867,25 @@ mod sprite {
                 .map_err(|e| anyhow!("Failed to delete sprite: {e}"))
         }
 
+        pub async fn delete_others(
+            conn: &mut SqliteConnection,
+            scene: i64,
+            keep: &HashSet<i64>,
+        ) -> anyhow::Result<()> {
+            let ids: Vec<(i64,)> = sqlx::query_as("SELECT id FROM sprites WHERE scene = ?1;")
+                .bind(scene)
+                .fetch_all(&mut *conn)
+                .await
+                .map_err(|e| anyhow!("Failed to load sprite IDs: {e}"))?;
+
+            for (id,) in ids {
+                if !keep.contains(&id) {
+                    SpriteRecord::delete(conn, id, scene).await?;
+                }
+            }
+            Ok(())
+        }
+
         pub async fn save(
             conn: &mut SqliteConnection,
             sprite: &scene::Sprite,
@@ -509,5 +907,301 @@ mod sprite {
                 .await
                 .map_err(|_| anyhow::anyhow!("Failed to load sprite list."))
         }
+
+        // Fields of each sprite in the scene which has any.
+        pub async fn load_scene_fields(
+            conn: &mut SqliteConnection,
+            scene: i64,
+        ) -> anyhow::Result<HashMap<i64, BTreeMap<String, String>>> {
+            let records: Vec<SpriteFieldRecord> =
+                sqlx::query_as("SELECT sprite, key, value FROM sprite_fields WHERE scene = ?1;")
+                    .bind(scene)
+                    .fetch_all(conn)
+                    .await
+                    .map_err(|e| anyhow!("Failed to load sprite fields: {e}"))?;
+
+            let mut fields: HashMap<i64, BTreeMap<String, String>> = HashMap::new();
+            for record in records {
+                fields
+                    .entry(record.sprite)
+                    .or_default()
+                    .insert(record.key, record.value);
+            }
+            Ok(fields)
+        }
+
+        // Users controlling each sprite in the scene which has owners.
+        pub async fn load_scene_owners(
+            conn: &mut SqliteConnection,
+            scene: i64,
+        ) -> anyhow::Result<HashMap<i64, Vec<i64>>> {
+            let records: Vec<(i64, i64)> =
+                sqlx::query_as("SELECT sprite, user FROM sprite_owners WHERE scene = ?1;")
+                    .bind(scene)
+                    .fetch_all(conn)
+                    .await
+                    .map_err(|e| anyhow!("Failed to load sprite owners: {e}"))?;
+
+            let mut owners: HashMap<i64, Vec<i64>> = HashMap::new();
+            for (sprite, user) in records {
+                owners.entry(sprite).or_default().push(user);
+            }
+            Ok(owners)
+        }
+    }
+}
+
+mod wall {
+    use anyhow::anyhow;
+    use sqlx::SqliteConnection;
+
+    #[derive(sqlx::FromRow)]
+    pub struct WallRecord {
+        scene: i64,
+        x1: f32,
+        y1: f32,
+        x2: f32,
+        y2: f32,
+    }
+
+    impl WallRecord {
+        pub fn to_wall(&self) -> scene::Wall {
+            scene::Wall::new(
+                scene::ScenePoint::new(self.x1, self.y1),
+                scene::ScenePoint::new(self.x2, self.y2),
+            )
+        }
+
+        // Walls have no identity of their own, so saving replaces all of the
+        // walls of the scene.
+        pub async fn save_scene_walls(
+            conn: &mut SqliteConnection,
+            walls: &[scene::Wall],
+            scene: i64,
+        ) -> anyhow::Result<()> {
+            sqlx::query("DELETE FROM walls WHERE scene = ?1;")
+                .bind(scene)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| anyhow!("Failed to delete walls: {e}"))?;
+
+            for wall in walls {
+                sqlx::query(
+                    "INSERT INTO walls (scene, x1, y1, x2, y2) VALUES (?1, ?2, ?3, ?4, ?5);",
+                )
+                .bind(scene)
+                .bind(wall.from.x)
+                .bind(wall.from.y)
+                .bind(wall.to.x)
+                .bind(wall.to.y)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| anyhow!("Failed to create wall: {e}"))?;
+            }
+
+            Ok(())
+        }
+
+        pub async fn load_scene_walls(
+            conn: &mut SqliteConnection,
+            scene: i64,
+        ) -> anyhow::Result<Vec<WallRecord>> {
+            sqlx::query_as("SELECT * FROM walls WHERE scene = ?1;")
+                .bind(scene)
+                .fetch_all(conn)
+                .await
+                .map_err(|e| anyhow!("Failed to load scene walls: {e}"))
+        }
+    }
+}
+
+mod template {
+    use anyhow::anyhow;
+    use sqlx::SqliteConnection;
+
+    #[derive(sqlx::FromRow)]
+    pub struct TemplateRecord {
+        id: i64,
+        scene: i64,
+        pub layer: i64,
+        shape: String,
+        x: f32,
+        y: f32,
+        rotation: f32,
+        size: f32,
+        angle: Option<f32>,
+        width: Option<f32>,
+        r: f32,
+        g: f32,
+        b: f32,
+        a: f32,
+        owner: Option<i64>,
+    }
+
+    impl TemplateRecord {
+        pub fn to_template(&self) -> scene::Template {
+            let shape = match self.shape.as_str() {
+                "cone" => scene::TemplateShape::Cone {
+                    length: self.size,
+                    angle: self.angle.unwrap_or(90.0),
+                },
+                "cube" => scene::TemplateShape::Cube { size: self.size },
+                "line" => scene::TemplateShape::Line {
+                    length: self.size,
+                    width: self.width.unwrap_or(1.0),
+                },
+                _ => scene::TemplateShape::Circle { radius: self.size },
+            };
+
+            let mut template =
+                scene::Template::new(self.id, scene::ScenePoint::new(self.x, self.y), shape);
+            template.rotation = self.rotation;
+            template.colour = [self.r, self.g, self.b, self.a];
+            template.owner = self.owner;
+            template
+        }
+
+        // As with walls, saving replaces all of the templates of the scene,
+        // which takes care of templates removed along with their layers.
+        pub async fn save_scene_templates(
+            conn: &mut SqliteConnection,
+            layers: &[scene::Layer],
+            scene: i64,
+        ) -> anyhow::Result<()> {
+            sqlx::query("DELETE FROM templates WHERE scene = ?1;")
+                .bind(scene)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| anyhow!("Failed to delete templates: {e}"))?;
+
+            for layer in layers {
+                for template in &layer.templates {
+                    let (shape, size, angle, width) = match template.shape {
+                        scene::TemplateShape::Circle { radius } => ("circle", radius, None, None),
+                        scene::TemplateShape::Cone { length, angle } => {
+                            ("cone", length, Some(angle), None)
+                        }
+                        scene::TemplateShape::Cube { size } => ("cube", size, None, None),
+                        scene::TemplateShape::Line { length, width } => {
+                            ("line", length, None, Some(width))
+                        }
+                    };
+                    let [r, g, b, a] = template.colour;
+
+                    sqlx::query(
+                        r#"
+                        INSERT INTO templates (
+                            id, scene, layer, shape, x, y, rotation, size, angle, width,
+                            r, g, b, a, owner
+                        ) VALUES (
+                            ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15
+                        );
+                        "#,
+                    )
+                    .bind(template.id)
+                    .bind(scene)
+                    .bind(layer.id)
+                    .bind(shape)
+                    .bind(template.origin.x)
+                    .bind(template.origin.y)
+                    .bind(template.rotation)
+                    .bind(size)
+                    .bind(angle)
+                    .bind(width)
+                    .bind(r)
+                    .bind(g)
+                    .bind(b)
+                    .bind(a)
+                    .bind(template.owner)
+                    .execute(&mut *conn)
+                    .await
+                    .map_err(|e| anyhow!("Failed to create template: {e}"))?;
+                }
+            }
+
+            Ok(())
+        }
+
+        pub async fn load_scene_templates(
+            conn: &mut SqliteConnection,
+            scene: i64,
+        ) -> anyhow::Result<Vec<TemplateRecord>> {
+            sqlx::query_as("SELECT * FROM templates WHERE scene = ?1;")
+                .bind(scene)
+                .fetch_all(conn)
+                .await
+                .map_err(|e| anyhow!("Failed to load scene templates: {e}"))
+        }
+    }
+}
+
+mod combatant {
+    use anyhow::anyhow;
+    use sqlx::SqliteConnection;
+
+    #[derive(sqlx::FromRow)]
+    pub struct CombatantRecord {
+        id: i64,
+        scene: i64,
+        position: u32,
+        name: String,
+        sprite: Option<i64>,
+        owner: Option<i64>,
+        initiative: Option<i32>,
+    }
+
+    impl CombatantRecord {
+        pub fn to_combatant(&self) -> scene::Combatant {
+            let mut combatant =
+                scene::Combatant::new(self.id, self.name.clone(), self.sprite, self.owner);
+            combatant.initiative = self.initiative;
+            combatant
+        }
+
+        // Saving replaces the whole turn order, storing the position of each
+        // combatant in it.
+        pub async fn save_scene_combatants(
+            conn: &mut SqliteConnection,
+            initiative: &scene::Initiative,
+            scene: i64,
+        ) -> anyhow::Result<()> {
+            sqlx::query("DELETE FROM combatants WHERE scene = ?1;")
+                .bind(scene)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| anyhow!("Failed to delete combatants: {e}"))?;
+
+            for (position, combatant) in initiative.combatants.iter().enumerate() {
+                sqlx::query(
+                    r#"
+                    INSERT INTO combatants (id, scene, position, name, sprite, owner, initiative)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);
+                    "#,
+                )
+                .bind(combatant.id)
+                .bind(scene)
+                .bind(position as u32)
+                .bind(&combatant.name)
+                .bind(combatant.sprite)
+                .bind(combatant.owner)
+                .bind(combatant.initiative)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| anyhow!("Failed to create combatant: {e}"))?;
+            }
+
+            Ok(())
+        }
+
+        pub async fn load_scene_combatants(
+            conn: &mut SqliteConnection,
+            scene: i64,
+        ) -> anyhow::Result<Vec<CombatantRecord>> {
+            sqlx::query_as("SELECT * FROM combatants WHERE scene = ?1 ORDER BY position;")
+                .bind(scene)
+                .fetch_all(conn)
+                .await
+                .map_err(|e| anyhow!("Failed to load scene combatants: {e}"))
+        }
     }
 }
@@ -8,3 +8,276 @@ fn test_media_key_conversions() {
     let key = super::Media::id_to_key(id);
     assert_eq!(super::Media::key_to_id(&key).unwrap(), id);
 }
+
+#[test]
+fn test_chat_audience_columns() {
+    use scene::comms::Audience;
+
+    for audience in [
+        Audience::Public,
+        Audience::Gm,
+        Audience::Whisper(vec![3]),
+        Audience::Whisper(vec![1, 22, 333]),
+    ] {
+        let (kind, recipients) = super::chat::audience_columns(&audience);
+        assert_eq!(super::chat::audience(kind, &recipients), audience);
+    }
+}
+
+#[test]
+fn test_media_tag() {
+    assert_eq!(super::Media::tag("  Forest "), Some("forest".to_string()));
+    assert_eq!(super::Media::tag("   "), None);
+    assert_eq!(super::Media::tag("a\tb"), None);
+    assert_eq!(super::Media::tag(&"x".repeat(33)), None);
+}
+
+// Create a media item for this user with a distinct path and hash.
+async fn create_media(pool: &sqlx::SqlitePool, user: i64, title: &str) -> super::Media {
+    let key = super::Media::generate_key().unwrap();
+    super::Media::create(pool, &key, user, &format!("{key}.png"), title, &key)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_folder_moves() {
+    use super::MediaFolder;
+
+    let pool = crate::test_util::memory_pool().await;
+    let user = crate::test_util::create_user(&pool, "alice").await;
+    let bob = crate::test_util::create_user(&pool, "bob").await;
+    let conn = &mut pool.acquire().await.unwrap();
+
+    let mut outer = MediaFolder::create(conn, user, None, "Maps").await.unwrap();
+    let mut inner = MediaFolder::create(conn, user, Some(&outer), "Caves")
+        .await
+        .unwrap();
+    let other = MediaFolder::create(conn, user, None, "Tokens")
+        .await
+        .unwrap();
+
+    // A folder can't be moved into itself or a folder within it.
+    assert!(outer.move_to(conn, Some(&inner)).await.is_err());
+    let copy = MediaFolder::load(conn, user, &outer.folder_key)
+        .await
+        .unwrap();
+    assert!(outer.move_to(conn, Some(&copy)).await.is_err());
+    assert_eq!(outer.parent, None);
+
+    inner.move_to(conn, Some(&other)).await.unwrap();
+    assert_eq!(inner.parent, Some(other.id));
+    outer.move_to(conn, Some(&inner)).await.unwrap();
+    let reloaded = MediaFolder::load(conn, user, &outer.folder_key)
+        .await
+        .unwrap();
+    assert_eq!(reloaded.parent, Some(inner.id));
+
+    // Folders belong to a single user.
+    assert!(MediaFolder::load(conn, bob, &outer.folder_key)
+        .await
+        .is_err());
+
+    // Deleting a folder moves its contents into its parent.
+    inner.delete(conn).await.unwrap();
+    let reloaded = MediaFolder::load(conn, user, &outer.folder_key)
+        .await
+        .unwrap();
+    assert_eq!(reloaded.parent, Some(other.id));
+}
+
+#[tokio::test]
+async fn test_bulk_transaction() {
+    use super::{Media, MediaFolder};
+
+    let pool = crate::test_util::memory_pool().await;
+    let user = crate::test_util::create_user(&pool, "alice").await;
+    let items = [
+        create_media(&pool, user, "Goblin").await,
+        create_media(&pool, user, "Orc").await,
+        create_media(&pool, user, "Cave").await,
+    ];
+
+    // The cave map is used by a sprite, so can't be deleted without a
+    // placeholder.
+    sqlx::query("INSERT INTO projects (project_key, user) VALUES ('p', ?1);")
+        .bind(user)
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO scenes (scene_key, project, w, h) VALUES ('s', 1, 8, 8);")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query(
+        "INSERT INTO sprites (id, scene, layer, media_key, x, y, w, h, z) VALUES (1, 1, 1, ?1, 0, 0, 1, 1, 0);",
+    )
+    .bind(&items[2].media_key)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let folder = {
+        let conn = &mut pool.acquire().await.unwrap();
+        MediaFolder::create(conn, user, None, "Monsters")
+            .await
+            .unwrap()
+    };
+    let tags = vec!["monster".to_string()];
+
+    // A failure part way through leaves every item unchanged.
+    {
+        let mut tx = pool.begin().await.unwrap();
+        for media in &items {
+            media.set_folder(&mut tx, Some(folder.id)).await.unwrap();
+            media.add_tags(&mut tx, &tags).await.unwrap();
+        }
+        items[0].delete(&mut tx, None).await.unwrap();
+        assert!(items[2].delete(&mut tx, None).await.is_err());
+    }
+
+    let mut conn = pool.acquire().await.unwrap();
+    let (listed, total) = Media::list(&mut conn, user, &Default::default())
+        .await
+        .unwrap();
+    drop(conn);
+    assert_eq!(total, 3);
+    assert!(listed
+        .iter()
+        .all(|m| m.folder_key.is_none() && m.tags().is_empty()));
+
+    // Otherwise every item is changed together.
+    let mut tx = pool.begin().await.unwrap();
+    for media in &items {
+        media.set_folder(&mut tx, Some(folder.id)).await.unwrap();
+        media.add_tags(&mut tx, &tags).await.unwrap();
+    }
+    items[0].delete(&mut tx, None).await.unwrap();
+    assert_eq!(items[2].delete(&mut tx, Some([0.5; 4])).await.unwrap(), 1);
+    tx.commit().await.unwrap();
+
+    let conn = &mut pool.acquire().await.unwrap();
+    let (listed, total) = Media::list(conn, user, &Default::default()).await.unwrap();
+    assert_eq!(total, 1);
+    assert_eq!(listed[0].media_key, items[1].media_key);
+    assert_eq!(listed[0].folder_key, Some(folder.folder_key.clone()));
+    assert_eq!(listed[0].tags(), tags);
+    let (texture,): (Option<String>,) = sqlx::query_as("SELECT media_key FROM sprites;")
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap();
+    assert_eq!(texture, None);
+}
+
+// Titles of the media items listed with this filter, and the total.
+async fn list(
+    conn: &mut sqlx::SqliteConnection,
+    user: i64,
+    filter: super::MediaFilter,
+) -> (Vec<String>, i64) {
+    let (listed, total) = super::Media::list(conn, user, &filter).await.unwrap();
+    (listed.into_iter().map(|m| m.title).collect(), total)
+}
+
+#[tokio::test]
+async fn test_media_list() {
+    use super::{MediaFilter, MediaFolder};
+
+    let pool = crate::test_util::memory_pool().await;
+    let user = crate::test_util::create_user(&pool, "alice").await;
+    let bob = crate::test_util::create_user(&pool, "bob").await;
+    let mut items = vec![];
+    for title in ["Goblin", "Goblin King", "Orc", "Cave"] {
+        items.push(create_media(&pool, user, title).await);
+    }
+    create_media(&pool, bob, "Goblin").await;
+
+    let conn = &mut pool.acquire().await.unwrap();
+    let folder = MediaFolder::create(conn, user, None, "Monsters")
+        .await
+        .unwrap();
+    for media in &items[..3] {
+        media.set_folder(conn, Some(folder.id)).await.unwrap();
+    }
+    items[1]
+        .add_tags(conn, &["boss".to_string(), "goblin".to_string()])
+        .await
+        .unwrap();
+
+    // Only the user's own items are listed, in upload order.
+    let (titles, total) = list(conn, user, MediaFilter::default()).await;
+    assert_eq!(titles, ["Goblin", "Goblin King", "Orc", "Cave"]);
+    assert_eq!(total, 4);
+
+    let (titles, _) = list(
+        conn,
+        user,
+        MediaFilter {
+            folder: Some(folder.folder_key.clone()),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(titles, ["Goblin", "Goblin King", "Orc"]);
+
+    let (titles, _) = list(
+        conn,
+        user,
+        MediaFilter {
+            folder: Some(String::new()),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(titles, ["Cave"]);
+
+    let (titles, _) = list(
+        conn,
+        user,
+        MediaFilter {
+            search: Some("GOB".to_string()),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(titles, ["Goblin", "Goblin King"]);
+
+    let (titles, _) = list(
+        conn,
+        user,
+        MediaFilter {
+            tag: Some("boss".to_string()),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(titles, ["Goblin King"]);
+
+    // Pages give the total over all pages.
+    let (titles, total) = list(
+        conn,
+        user,
+        MediaFilter {
+            limit: Some(2),
+            offset: 1,
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(titles, ["Goblin King", "Orc"]);
+    assert_eq!(total, 4);
+
+    let (titles, total) = list(
+        conn,
+        user,
+        MediaFilter {
+            search: Some("o".to_string()),
+            limit: Some(2),
+            offset: 2,
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(titles, ["Orc"]);
+    assert_eq!(total, 3);
+}
@@ -0,0 +1,147 @@
+use anyhow::anyhow;
+use sqlx::{Row, SqliteConnection};
+
+/// A project or scene in a user's trash. Scenes in a deleted project aren't
+/// listed separately, as they are restored with the project.
+#[derive(sqlx::FromRow, serde_derive::Serialize)]
+pub struct TrashItem {
+    // Either "project" or "scene".
+    pub kind: String,
+    pub key: String,
+    pub title: String,
+
+    // Key of the project, or of the project the scene belongs to.
+    pub project_key: String,
+
+    // Time, in seconds since the epoch, the item was deleted.
+    pub deleted_time: i64,
+}
+
+impl TrashItem {
+    pub async fn list(conn: &mut SqliteConnection, user: i64) -> anyhow::Result<Vec<TrashItem>> {
+        sqlx::query_as(
+            r#"
+            SELECT 'project' AS kind, project_key AS key, title, project_key, deleted_time
+            FROM projects WHERE user = ?1 AND deleted_time IS NOT NULL
+            UNION ALL
+            SELECT 'scene', scenes.scene_key, scenes.title, projects.project_key,
+                scenes.deleted_time
+            FROM scenes JOIN projects ON scenes.project = projects.id
+            WHERE projects.user = ?1 AND scenes.deleted_time IS NOT NULL
+                AND projects.deleted_time IS NULL
+            ORDER BY deleted_time DESC;
+            "#,
+        )
+        .bind(user)
+        .fetch_all(conn)
+        .await
+        .map_err(|e| anyhow!("Failed to load trash: {e}"))
+    }
+
+    pub async fn restore_project(
+        conn: &mut SqliteConnection,
+        user: i64,
+        project_key: &str,
+    ) -> anyhow::Result<()> {
+        let res = sqlx::query(
+            r#"
+            UPDATE projects SET deleted_time = NULL
+            WHERE project_key = ?1 AND user = ?2 AND deleted_time IS NOT NULL;
+            "#,
+        )
+        .bind(project_key)
+        .bind(user)
+        .execute(conn)
+        .await
+        .map_err(|e| anyhow!("Failed to restore project: {e}"))?;
+
+        if res.rows_affected() == 0 {
+            return Err(anyhow!("Project not in trash."));
+        }
+        Ok(())
+    }
+
+    // Restore a scene, along with its project if that is also in the trash.
+    pub async fn restore_scene(
+        conn: &mut SqliteConnection,
+        user: i64,
+        scene_key: &str,
+    ) -> anyhow::Result<()> {
+        let res = sqlx::query(
+            r#"
+            UPDATE scenes SET deleted_time = NULL
+            WHERE scene_key = ?1 AND deleted_time IS NOT NULL
+                AND project IN (SELECT id FROM projects WHERE user = ?2);
+            "#,
+        )
+        .bind(scene_key)
+        .bind(user)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| anyhow!("Failed to restore scene: {e}"))?;
+
+        if res.rows_affected() == 0 {
+            return Err(anyhow!("Scene not in trash."));
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE projects SET deleted_time = NULL
+            WHERE id = (SELECT project FROM scenes WHERE scene_key = ?1);
+            "#,
+        )
+        .bind(scene_key)
+        .execute(conn)
+        .await
+        .map_err(|e| anyhow!("Failed to restore project: {e}"))?;
+        Ok(())
+    }
+
+    // Permanently delete projects and scenes which were moved to the trash
+    // before this time, for one user or, if None, all users. Layers, sprites
+    // and so on are deleted with their scene. Returns the keys of the scenes
+    // deleted.
+    pub async fn purge(
+        conn: &mut SqliteConnection,
+        before: i64,
+        user: Option<i64>,
+    ) -> anyhow::Result<Vec<String>> {
+        let keys = sqlx::query(
+            r#"
+            SELECT scenes.scene_key FROM scenes
+            JOIN projects ON scenes.project = projects.id
+            WHERE (scenes.deleted_time < ?1 OR projects.deleted_time < ?1)
+                AND (?2 IS NULL OR projects.user = ?2);
+            "#,
+        )
+        .bind(before)
+        .bind(user)
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| anyhow!("Failed to find expired scenes: {e}"))?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+
+        sqlx::query(
+            r#"
+            DELETE FROM scenes WHERE deleted_time < ?1
+                AND (?2 IS NULL OR project IN (SELECT id FROM projects WHERE user = ?2));
+            "#,
+        )
+        .bind(before)
+        .bind(user)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| anyhow!("Failed to purge scenes: {e}"))?;
+
+        sqlx::query("DELETE FROM projects WHERE deleted_time < ?1 AND (?2 IS NULL OR user = ?2);")
+            .bind(before)
+            .bind(user)
+            .execute(conn)
+            .await
+            .map_err(|e| anyhow!("Failed to purge projects: {e}"))?;
+
+        Ok(keys)
+    }
+}
@@ -0,0 +1,503 @@
+use std::collections::{HashMap, HashSet};
+
+use scene::{
+    BlendMode, Colour, Grid, GridType, Id, Rect, Scene, ScenePoint, SpriteShape, SpriteVisual,
+};
+
+use crate::models::Media;
+
+#[cfg(test)]
+mod tests;
+
+// Each pixel is sampled on a grid this many samples across to find how much
+// of it a shape covers.
+const SAMPLES: u32 = 4;
+
+// Drawn for textures which haven't been loaded, as the client does.
+const MISSING_TEXTURE: Colour = [0.0, 0.0, 1.0, 1.0];
+
+// Number of sides of the polygons the client draws for each shape.
+const TRIANGLE_SIDES: u32 = 3;
+const HEXAGON_SIDES: u32 = 6;
+
+// Images with more pixels than this aren't decoded, as the decoded image
+// would take more memory than a render should.
+const MAX_DECODE_PIXELS: u64 = 64 * 1024 * 1024;
+
+// Grids with more cells than this across the scene aren't drawn, which caps
+// the number of lines and cells generated. At the sizes scenes are rendered,
+// such a grid would cover the scene in any case.
+const MAX_GRID_CELLS: f32 = 256.0;
+
+// Larger images are reduced on decode, as scenes are never rendered at a size
+// where the detail would be visible.
+const MAX_TEXTURE_SIZE: u32 = 2048;
+
+/// An RGBA image with premultiplied alpha.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pixels: Vec<Colour>,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32) -> Self {
+        Image {
+            width,
+            height,
+            pixels: vec![[0.0; 4]; (width * height) as usize],
+        }
+    }
+
+    // Create an image from 8 bit RGBA data without premultiplied alpha.
+    pub fn from_rgba(width: u32, height: u32, data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() != (width * height * 4) as usize {
+            return Err(anyhow::anyhow!("Image data has wrong length."));
+        }
+
+        let pixels = data
+            .chunks_exact(4)
+            .map(|p| {
+                let a = p[3] as f32 / 255.0;
+                [
+                    p[0] as f32 / 255.0 * a,
+                    p[1] as f32 / 255.0 * a,
+                    p[2] as f32 / 255.0 * a,
+                    a,
+                ]
+            })
+            .collect();
+        Ok(Image {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    // Decode a PNG or JPEG image, as accepted for upload.
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let (width, height, rgba) = match Media::extension(data) {
+            Some("png") => Self::decode_png(data)?,
+            Some("jpeg") => Self::decode_jpeg(data)?,
+            _ => return Err(anyhow::anyhow!("Unsupported image type.")),
+        };
+
+        let scale = (MAX_TEXTURE_SIZE as f32 / width.max(height) as f32).min(1.0);
+        if scale == 1.0 {
+            return Self::from_rgba(width, height, &rgba);
+        }
+
+        // Nearest neighbour is good enough given the sampling on render.
+        let w = ((width as f32 * scale) as u32).max(1);
+        let h = ((height as f32 * scale) as u32).max(1);
+        let reduced = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let i = ((y * height / h) * width + x * width / w) as usize * 4;
+                [rgba[i], rgba[i + 1], rgba[i + 2], rgba[i + 3]]
+            })
+            .collect::<Vec<u8>>();
+        Self::from_rgba(w, h, &reduced)
+    }
+
+    // Read the dimensions of a PNG or JPEG image from its header, without
+    // decoding it.
+    pub fn dimensions(data: &[u8]) -> anyhow::Result<(u32, u32)> {
+        match Media::extension(data) {
+            Some("png") => {
+                let reader = png::Decoder::new(data).read_info()?;
+                let info = reader.info();
+                Ok((info.width, info.height))
+            }
+            Some("jpeg") => {
+                let mut decoder = jpeg_decoder::Decoder::new(data);
+                decoder.read_info()?;
+                let info = decoder
+                    .info()
+                    .ok_or_else(|| anyhow::anyhow!("Missing JPEG metadata."))?;
+                Ok((info.width as u32, info.height as u32))
+            }
+            _ => Err(anyhow::anyhow!("Unsupported image type.")),
+        }
+    }
+
+    // Decode a PNG to its dimensions and 8 bit RGBA data.
+    fn decode_png(data: &[u8]) -> anyhow::Result<(u32, u32, Vec<u8>)> {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf)?;
+        let buf = &buf[..info.buffer_size()];
+
+        let rgba: Vec<u8> = match info.color_type {
+            png::ColorType::Rgba => buf.to_vec(),
+            png::ColorType::Rgb => buf
+                .chunks_exact(3)
+                .flat_map(|p| [p[0], p[1], p[2], 255])
+                .collect(),
+            png::ColorType::GrayscaleAlpha => buf
+                .chunks_exact(2)
+                .flat_map(|p| [p[0], p[0], p[0], p[1]])
+                .collect(),
+            png::ColorType::Grayscale => buf.iter().flat_map(|&p| [p, p, p, 255]).collect(),
+            png::ColorType::Indexed => return Err(anyhow::anyhow!("Unexpanded PNG palette.")),
+        };
+        Ok((info.width, info.height, rgba))
+    }
+
+    fn decode_jpeg(data: &[u8]) -> anyhow::Result<(u32, u32, Vec<u8>)> {
+        // The header is read first so that oversized images are rejected
+        // before any memory is allocated for them.
+        let mut decoder = jpeg_decoder::Decoder::new(data);
+        decoder.read_info()?;
+        let info = decoder
+            .info()
+            .ok_or_else(|| anyhow::anyhow!("Missing JPEG metadata."))?;
+        if info.width as u64 * info.height as u64 > MAX_DECODE_PIXELS {
+            return Err(anyhow::anyhow!("JPEG image too large."));
+        }
+        let pixels = decoder.decode()?;
+
+        let rgba: Vec<u8> = match info.pixel_format {
+            jpeg_decoder::PixelFormat::RGB24 => pixels
+                .chunks_exact(3)
+                .flat_map(|p| [p[0], p[1], p[2], 255])
+                .collect(),
+            jpeg_decoder::PixelFormat::L8 => pixels.iter().flat_map(|&p| [p, p, p, 255]).collect(),
+            _ => return Err(anyhow::anyhow!("Unsupported JPEG pixel format.")),
+        };
+        Ok((info.width as u32, info.height as u32, rgba))
+    }
+
+    pub fn encode_png(&self) -> anyhow::Result<Vec<u8>> {
+        let data = self
+            .pixels
+            .iter()
+            .flat_map(|&[r, g, b, a]| {
+                let byte = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+                let channel = |c: f32| byte(if a > 0.0 { c / a } else { 0.0 });
+                [channel(r), channel(g), channel(b), byte(a)]
+            })
+            .collect::<Vec<u8>>();
+
+        let mut png = vec![];
+        let mut encoder = png::Encoder::new(&mut png, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&data)?;
+        Ok(png)
+    }
+
+    // Colour at a point, given as fractions of the width and height.
+    fn sample(&self, u: f32, v: f32) -> Colour {
+        if self.pixels.is_empty() {
+            return MISSING_TEXTURE;
+        }
+
+        let x = ((u * self.width as f32) as u32).min(self.width - 1);
+        let y = ((v * self.height as f32) as u32).min(self.height - 1);
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    // Draw a premultiplied colour over a pixel, combining them as the client
+    // does for each blend mode.
+    fn blend(&mut self, x: u32, y: u32, src: Colour, mode: BlendMode) {
+        let dst = &mut self.pixels[(y * self.width + x) as usize];
+        let sa = src[3];
+        for i in 0..4 {
+            dst[i] = match mode {
+                BlendMode::Normal => src[i] + dst[i] * (1.0 - sa),
+                BlendMode::Multiply => src[i] * dst[i] + dst[i] * (1.0 - sa),
+                BlendMode::Screen => src[i] + dst[i] * (1.0 - src[i]),
+            };
+        }
+    }
+}
+
+// Whether a point, relative to a unit square, is inside a shape drawn in that
+// square.
+fn in_shape(shape: SpriteShape, u: f32, v: f32) -> bool {
+    match shape {
+        SpriteShape::Rectangle => true,
+        SpriteShape::Ellipse => (u - 0.5).powi(2) + (v - 0.5).powi(2) <= 0.25,
+        SpriteShape::Triangle => in_polygon(&ngon(TRIANGLE_SIDES), ScenePoint::new(u, v)),
+        SpriteShape::Hexagon => in_polygon(&ngon(HEXAGON_SIDES), ScenePoint::new(u, v)),
+    }
+}
+
+// Corners of the regular polygon the client draws in a unit square.
+fn ngon(n: u32) -> Vec<ScenePoint> {
+    (0..n)
+        .map(|i| {
+            let theta = i as f32 / n as f32 * std::f32::consts::TAU;
+            ScenePoint::new(0.5 + theta.cos() / 2.0, 0.5 + theta.sin() / 2.0)
+        })
+        .collect()
+}
+
+// Even-odd test of whether a point is inside a polygon.
+fn in_polygon(points: &[ScenePoint], p: ScenePoint) -> bool {
+    let mut inside = false;
+    for (i, a) in points.iter().enumerate() {
+        let b = points[(i + 1) % points.len()];
+        if (a.y > p.y) != (b.y > p.y) && p.x < (b.x - a.x) * (p.y - a.y) / (b.y - a.y) + a.x {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+fn distance_to_segment(p: ScenePoint, a: ScenePoint, b: ScenePoint) -> f32 {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let len = dx * dx + dy * dy;
+    let t = if len > 0.0 {
+        (((p.x - a.x) * dx + (p.y - a.y) * dy) / len).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    ((p.x - a.x - t * dx).powi(2) + (p.y - a.y - t * dy).powi(2)).sqrt()
+}
+
+fn premultiplied([r, g, b, a]: Colour, opacity: f32) -> Colour {
+    let a = a * opacity;
+    [r * a, g * a, b * a, a]
+}
+
+/// Draws a scene into an image, scaled to fit and centred.
+struct Canvas {
+    image: Image,
+
+    // Pixels per scene unit.
+    scale: f32,
+
+    // Position of the scene origin in the image, in pixels.
+    origin: ScenePoint,
+}
+
+impl Canvas {
+    fn pixel(&self, p: ScenePoint) -> ScenePoint {
+        ScenePoint::new(
+            self.origin.x + p.x * self.scale,
+            self.origin.y + p.y * self.scale,
+        )
+    }
+
+    // Pixels overlapping a region given in pixels.
+    fn pixels_in(&self, x0: f32, y0: f32, x1: f32, y1: f32) -> impl Iterator<Item = (u32, u32)> {
+        let clamp = |v: f32, max: u32| (v.max(0.0) as u32).min(max);
+        let (x0, x1) = (
+            clamp(x0.floor(), self.image.width),
+            clamp(x1.ceil(), self.image.width),
+        );
+        let (y0, y1) = (
+            clamp(y0.floor(), self.image.height),
+            clamp(y1.ceil(), self.image.height),
+        );
+        (y0..y1).flat_map(move |y| (x0..x1).map(move |x| (x, y)))
+    }
+
+    // Fraction of the samples of a pixel for which f is true.
+    fn coverage(x: u32, y: u32, f: impl Fn(f32, f32) -> bool) -> f32 {
+        let step = 1.0 / SAMPLES as f32;
+        let mut hits = 0;
+        for i in 0..SAMPLES {
+            for j in 0..SAMPLES {
+                if f(
+                    x as f32 + (i as f32 + 0.5) * step,
+                    y as f32 + (j as f32 + 0.5) * step,
+                ) {
+                    hits += 1;
+                }
+            }
+        }
+        hits as f32 / (SAMPLES * SAMPLES) as f32
+    }
+
+    // Fill a shape in a rect, colouring each pixel with paint, which is given
+    // the pixel's position as fractions of the rect.
+    fn fill_shape(
+        &mut self,
+        rect: Rect,
+        shape: SpriteShape,
+        blend: BlendMode,
+        paint: impl Fn(f32, f32) -> Colour,
+    ) {
+        let rect = rect.positive_dimensions();
+        let from = self.pixel(ScenePoint::new(rect.x, rect.y));
+        let (w, h) = (rect.w * self.scale, rect.h * self.scale);
+        if w <= 0.0 || h <= 0.0 {
+            return;
+        }
+
+        let pixels = self
+            .pixels_in(from.x, from.y, from.x + w, from.y + h)
+            .collect::<Vec<_>>();
+        for (x, y) in pixels {
+            let uv = |px: f32, py: f32| ((px - from.x) / w, (py - from.y) / h);
+            let coverage = Self::coverage(x, y, |px, py| {
+                let (u, v) = uv(px, py);
+                (0.0..1.0).contains(&u) && (0.0..1.0).contains(&v) && in_shape(shape, u, v)
+            });
+
+            if coverage > 0.0 {
+                let (u, v) = uv(x as f32 + 0.5, y as f32 + 0.5);
+                let colour = paint(u.clamp(0.0, 1.0), v.clamp(0.0, 1.0)).map(|c| c * coverage);
+                self.image.blend(x, y, colour, blend);
+            }
+        }
+    }
+
+    fn fill_polygon(&mut self, points: &[ScenePoint], colour: Colour, blend: BlendMode) {
+        let points = points.iter().map(|&p| self.pixel(p)).collect::<Vec<_>>();
+        let (mut x0, mut y0, mut x1, mut y1) = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
+        for p in &points {
+            (x0, y0, x1, y1) = (x0.min(p.x), y0.min(p.y), x1.max(p.x), y1.max(p.y));
+        }
+
+        let pixels = self.pixels_in(x0, y0, x1, y1).collect::<Vec<_>>();
+        for (x, y) in pixels {
+            let coverage =
+                Self::coverage(x, y, |px, py| in_polygon(&points, ScenePoint::new(px, py)));
+            if coverage > 0.0 {
+                self.image.blend(x, y, colour.map(|c| c * coverage), blend);
+            }
+        }
+    }
+
+    // Draw a line between points in scene units, with a width in pixels.
+    fn line(&mut self, from: ScenePoint, to: ScenePoint, width: f32, colour: Colour) {
+        let (a, b) = (self.pixel(from), self.pixel(to));
+        let r = width / 2.0;
+        let pixels = self
+            .pixels_in(
+                a.x.min(b.x) - r,
+                a.y.min(b.y) - r,
+                a.x.max(b.x) + r,
+                a.y.max(b.y) + r,
+            )
+            .collect::<Vec<_>>();
+        for (x, y) in pixels {
+            let coverage = Self::coverage(x, y, |px, py| {
+                distance_to_segment(ScenePoint::new(px, py), a, b) <= r
+            });
+            if coverage > 0.0 {
+                self.image
+                    .blend(x, y, colour.map(|c| c * coverage), BlendMode::Normal);
+            }
+        }
+    }
+
+    fn grid(&mut self, grid: &Grid, dims: Rect) {
+        if !grid.visible || grid.kind == GridType::Gridless {
+            return;
+        }
+
+        let colour = premultiplied(grid.colour, 1.0);
+        for (from, to) in grid_lines(grid, dims) {
+            self.line(from, to, grid.thickness, colour);
+        }
+    }
+}
+
+// Lines of a grid across the scene, in scene units. Like the client, hex
+// edges shared by two cells are only included once.
+fn grid_lines(grid: &Grid, dims: Rect) -> Vec<(ScenePoint, ScenePoint)> {
+    let mut lines = vec![];
+    let step = grid.size;
+    if !step.is_finite()
+        || step <= 0.0
+        || !(dims.w / step <= MAX_GRID_CELLS && dims.h / step <= MAX_GRID_CELLS)
+    {
+        return lines;
+    }
+
+    if grid.kind == GridType::Square {
+        let mut x = grid.offset.x + (-grid.offset.x / step).ceil() * step;
+        while x <= dims.w {
+            lines.push((ScenePoint::new(x, 0.0), ScenePoint::new(x, dims.h)));
+            x += step;
+        }
+
+        let mut y = grid.offset.y + (-grid.offset.y / step).ceil() * step;
+        while y <= dims.h {
+            lines.push((ScenePoint::new(0.0, y), ScenePoint::new(dims.w, y)));
+            y += step;
+        }
+    } else {
+        let mut drawn = HashSet::new();
+        for centre in grid.cells_in(dims) {
+            let corners = grid.cell_outline(centre);
+            for (i, &from) in corners.iter().enumerate() {
+                let to = corners[(i + 1) % corners.len()];
+                let key = (
+                    ((from.x + to.x) * 500.0).round() as i64,
+                    ((from.y + to.y) * 500.0).round() as i64,
+                );
+                if drawn.insert(key) {
+                    lines.push((from, to));
+                }
+            }
+        }
+    }
+    lines
+}
+
+// Render the visible layers of a scene, fitted to and centred in an image of
+// the given size. Textures are looked up by ID, and drawn blue if missing, as
+// in the client.
+pub fn render(scene: &Scene, textures: &HashMap<Id, Image>, width: u32, height: u32) -> Image {
+    let (w, h) = (scene.w.max(1) as f32, scene.h.max(1) as f32);
+    let scale = (width as f32 / w).min(height as f32 / h);
+    let mut canvas = Canvas {
+        image: Image::new(width, height),
+        scale,
+        origin: ScenePoint::new(
+            (width as f32 - w * scale) / 2.0,
+            (height as f32 - h * scale) / 2.0,
+        ),
+    };
+    let dims = Rect::new(0.0, 0.0, w, h);
+
+    // As in the client, the grid is drawn above layers with negative z.
+    let mut grid_drawn = false;
+    for layer in scene.layers.iter().rev() {
+        if !grid_drawn && layer.z >= 0 {
+            canvas.grid(&scene.grid, dims);
+            grid_drawn = true;
+        }
+
+        if !layer.visible {
+            continue;
+        }
+
+        let opacity = layer.opacity;
+        for sprite in &layer.sprites {
+            match sprite.visual {
+                SpriteVisual::Colour(colour) => {
+                    let colour = premultiplied(colour, opacity);
+                    canvas.fill_shape(sprite.rect, sprite.shape, layer.blend, |_, _| colour);
+                }
+                SpriteVisual::Texture(id) => {
+                    let texture = textures.get(&id);
+                    canvas.fill_shape(sprite.rect, sprite.shape, layer.blend, |u, v| {
+                        let texel = texture.map_or(MISSING_TEXTURE, |t| t.sample(u, v));
+                        texel.map(|c| c * opacity)
+                    });
+                }
+            }
+        }
+
+        for template in &layer.templates {
+            let colour = premultiplied(template.colour, opacity);
+            canvas.fill_polygon(&template.outline(), colour, layer.blend);
+        }
+    }
+
+    if !grid_drawn {
+        canvas.grid(&scene.grid, dims);
+    }
+
+    canvas.image
+}
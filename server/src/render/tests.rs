@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use scene::{
+    BlendMode, GridType, Rect, Scene, ScenePoint, SpriteShape, SpriteVisual, TemplateShape,
+};
+
+use crate::test_util::sprite;
+
+use super::{grid_lines, render, Image};
+
+// Golden images are rewritten rather than compared when this is set, after
+// which they should be checked by eye.
+const UPDATE_VAR: &str = "UPDATE_GOLDEN";
+
+fn check(image: &Image, golden: &str) {
+    let path = format!("{}/testdata/render/{golden}", env!("CARGO_MANIFEST_DIR"));
+    if std::env::var(UPDATE_VAR).is_ok() {
+        std::fs::write(&path, image.encode_png().unwrap()).unwrap();
+        return;
+    }
+
+    let expected = Image::decode(&std::fs::read(&path).unwrap()).unwrap();
+    let actual = Image::decode(&image.encode_png().unwrap()).unwrap();
+    assert_eq!(
+        (actual.width, actual.height),
+        (expected.width, expected.height)
+    );
+
+    // Allow for rounding differences between platforms.
+    let differ = actual
+        .pixels
+        .iter()
+        .zip(&expected.pixels)
+        .filter(|(a, e)| {
+            a.iter()
+                .zip(e.iter())
+                .any(|(a, e)| (a - e).abs() > 2.0 / 255.0)
+        })
+        .count();
+    assert_eq!(differ, 0, "{differ} pixels differ from {golden}");
+}
+
+// A 2x2 texture with a transparent corner.
+fn texture() -> Image {
+    #[rustfmt::skip]
+    let data = [
+        255, 0, 0, 255,    0, 255, 0, 255,
+        255, 255, 0, 255,  0, 0, 0, 0,
+    ];
+    Image::from_rgba(2, 2, &data).unwrap()
+}
+
+#[test]
+fn test_render_shapes() {
+    let mut scene = Scene::new();
+    scene.w = 8;
+    scene.h = 4;
+
+    let background = scene.layers[2].id;
+    scene.add_sprite(
+        sprite(
+            10,
+            Rect::new(0.0, 0.0, 8.0, 4.0),
+            SpriteVisual::Colour([0.9, 0.9, 0.8, 1.0]),
+            SpriteShape::Rectangle,
+        ),
+        background,
+    );
+
+    let foreground = scene.layers[0].id;
+    let shapes = [
+        SpriteShape::Rectangle,
+        SpriteShape::Ellipse,
+        SpriteShape::Triangle,
+        SpriteShape::Hexagon,
+    ];
+    for (i, shape) in shapes.into_iter().enumerate() {
+        let x = i as f32 * 2.0 + 0.25;
+        scene.add_sprite(
+            sprite(
+                20 + i as i64,
+                Rect::new(x, 0.25, 1.5, 1.5),
+                SpriteVisual::Colour([0.8, 0.1, 0.1, 1.0]),
+                shape,
+            ),
+            foreground,
+        );
+        scene.add_sprite(
+            sprite(
+                30 + i as i64,
+                Rect::new(x, 2.25, 1.5, 1.5),
+                SpriteVisual::Texture(1),
+                shape,
+            ),
+            foreground,
+        );
+    }
+
+    // Missing textures are drawn blue.
+    scene.add_sprite(
+        sprite(
+            40,
+            Rect::new(7.5, 3.5, 0.5, 0.5),
+            SpriteVisual::Texture(2),
+            SpriteShape::Rectangle,
+        ),
+        foreground,
+    );
+
+    let textures = HashMap::from([(1, texture())]);
+    check(&render(&scene, &textures, 128, 64), "shapes.png");
+}
+
+#[test]
+fn test_render_layers() {
+    let mut scene = Scene::new();
+    scene.w = 6;
+    scene.h = 6;
+    scene.grid.kind = GridType::HexPointy;
+    scene.grid.colour = [0.0, 0.0, 0.0, 1.0];
+
+    let scenery = scene.layers[1].id;
+    scene.add_sprite(
+        sprite(
+            10,
+            Rect::new(0.0, 0.0, 6.0, 6.0),
+            SpriteVisual::Colour([0.2, 0.6, 0.9, 1.0]),
+            SpriteShape::Rectangle,
+        ),
+        scenery,
+    );
+    scene.new_template(
+        ScenePoint::new(3.0, 3.0),
+        TemplateShape::Circle { radius: 1.5 },
+        scenery,
+        None,
+    );
+
+    // Drawn above the grid, at half opacity, multiplied with the layers
+    // beneath.
+    let foreground = scene.layers[0].id;
+    let layer = scene.layer(foreground).unwrap();
+    layer.set_opacity(0.5);
+    layer.blend = BlendMode::Multiply;
+    scene.add_sprite(
+        sprite(
+            20,
+            Rect::new(1.0, 1.0, 4.0, 2.0),
+            SpriteVisual::Colour([1.0, 0.5, 0.0, 1.0]),
+            SpriteShape::Rectangle,
+        ),
+        foreground,
+    );
+
+    // Hidden layers aren't drawn.
+    let background = scene.layers[2].id;
+    scene.layer(background).unwrap().visible = false;
+    scene.add_sprite(
+        sprite(
+            30,
+            Rect::new(0.0, 0.0, 6.0, 6.0),
+            SpriteVisual::Colour([1.0, 0.0, 0.0, 1.0]),
+            SpriteShape::Rectangle,
+        ),
+        background,
+    );
+
+    // The scene is centred in an image of a different aspect ratio.
+    check(&render(&scene, &HashMap::new(), 96, 72), "layers.png");
+}
+
+#[test]
+fn test_grid_limits() {
+    let mut scene = Scene::new();
+    scene.w = 10;
+    scene.h = 10;
+    let dims = Rect::new(0.0, 0.0, 10.0, 10.0);
+    assert_eq!(grid_lines(&scene.grid, dims).len(), 22);
+
+    // Grids too fine to draw, or with no usable size, are skipped.
+    for size in [0.01, 0.0, -1.0, f32::NAN, f32::INFINITY] {
+        scene.grid.size = size;
+        assert!(grid_lines(&scene.grid, dims).is_empty());
+    }
+
+    scene.grid.kind = GridType::HexFlat;
+    scene.grid.size = 0.01;
+    assert!(grid_lines(&scene.grid, dims).is_empty());
+    scene.grid.size = 1.0;
+    assert!(!grid_lines(&scene.grid, dims).is_empty());
+}
+
+#[test]
+fn test_image_codec() {
+    let image = texture();
+    let decoded = Image::decode(&image.encode_png().unwrap()).unwrap();
+    assert_eq!(decoded, image);
+    assert!(Image::decode(b"GIF89a").is_err());
+
+    // A JPEG header claiming a huge image is rejected before decoding.
+    #[rustfmt::skip]
+    let header = [
+        0xff, 0xd8,
+        // Baseline frame, 8 bit, 65535x65535, one component.
+        0xff, 0xc0, 0x00, 0x0b, 0x08, 0xff, 0xff, 0xff, 0xff, 0x01, 0x01, 0x11, 0x00,
+        0xff, 0xd9,
+    ];
+    let err = Image::decode(&header).unwrap_err();
+    assert_eq!(err.to_string(), "JPEG image too large.");
+
+    // Dimensions are read from the header alone.
+    let png = image.encode_png().unwrap();
+    assert_eq!(
+        Image::dimensions(&png).unwrap(),
+        (image.width, image.height)
+    );
+    assert_eq!(Image::dimensions(&header).unwrap(), (65535, 65535));
+    assert!(Image::dimensions(b"GIF89a").is_err());
+}
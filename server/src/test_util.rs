@@ -0,0 +1,35 @@
+use scene::{Rect, Sprite, SpriteShape, SpriteVisual};
+use sqlx::{sqlite::SqlitePoolOptions, Executor, SqlitePool};
+
+pub fn sprite(id: i64, rect: Rect, visual: SpriteVisual, shape: SpriteShape) -> Sprite {
+    let mut sprite = Sprite::new(id, Some(visual), Some(shape));
+    sprite.set_rect(rect);
+    sprite
+}
+
+// An in-memory database with the schema loaded. Each connection to an
+// in-memory database has its own, so the pool has just one which is never
+// closed.
+pub async fn memory_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    pool.execute(include_str!("../schema.sql")).await.unwrap();
+    pool
+}
+
+// Create a user, returning their ID.
+pub async fn create_user(pool: &SqlitePool, username: &str) -> i64 {
+    sqlx::query(
+        "INSERT INTO users (username, salt, hashed_password, recovery_key, created_time) VALUES (?1, '', '', '', 0);",
+    )
+    .bind(username)
+    .execute(pool)
+    .await
+    .unwrap()
+    .last_insert_rowid()
+}
@@ -63,7 +63,10 @@ function new_game() {
     const error = error_fn("launch_game_error");
     post(
         "/game/new",
-        { "scene_key": selected_scene() },
+        {
+            "scene_key": selected_scene(),
+            "save_chat": document.getElementById("launch_game_save_chat").checked,
+        },
         resp => {
             if (resp?.success) {
                 window.location = resp.url;
@@ -17,6 +17,9 @@ function layers_list_entry(layer) {
         btn.innerHTML = get_icon("lock");
     }
 
+    el.querySelector(".layer_opacity").value = layer.opacity;
+    el.querySelector(".layer_blend").value = layer.blend;
+
     return el;
 }
 
@@ -175,7 +175,11 @@ function populate_scene_select(list = null, scene_key = null) {
     add_default_option(scene_select);
     if (list) {
         list.forEach(scene => {
-            scene_select.add(new Option(scene.title, scene.scene_key));
+            const option = new Option(scene.title, scene.scene_key);
+            if (scene.thumbnail) {
+                option.dataset.thumbnail = scene.thumbnail;
+            }
+            scene_select.add(option);
             if (scene.scene_key === scene_key) {
                 set_active_scene(scene_key);
             }
@@ -183,9 +187,22 @@ function populate_scene_select(list = null, scene_key = null) {
     }
 
     scene_select.disabled = scene_select.options.length === 1;
+    show_scene_thumbnail();
     update_url_project_scene();
 }
 
+// Show the thumbnail of the selected scene. Thumbnails are replaced on save,
+// so the URL is varied to avoid showing a stale one.
+function show_scene_thumbnail() {
+    const thumbnail = document.getElementById("scene_thumbnail");
+    const option = document.getElementById("scene_select").selectedOptions[0];
+    const src = option && option.dataset.thumbnail;
+    thumbnail.hidden = !src;
+    if (src) {
+        thumbnail.src = src + "?t=" + Date.now();
+    }
+}
+
 function set_active_project(project_key, scene_key = null) {
     update_url_project_scene();
 
@@ -227,6 +244,7 @@ function set_active_scene(scene_key) {
         resp => {
             document.getElementById("scene_select").value = scene_key;
             document.getElementById("scene_title").value = resp.title;
+            show_scene_thumbnail();
 
             if (resp.success) {
                 load_scene(resp.scene);
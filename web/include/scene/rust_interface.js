@@ -64,6 +64,21 @@ var RustFuncs = {
     Sets locked status for the specified layer.
     */
 
+    layer_opacity: missing_func,
+    /*
+    function layer_opacity(layer_id: number, opacity: number)
+
+    Sets the opacity of the specified layer, from 0 to 1.
+    */
+
+    layer_blend: missing_func,
+    /*
+    function layer_blend(layer_id: number, blend: string)
+
+    Sets how the specified layer is blended with those beneath it. blend is
+    one of "Normal", "Multiply" or "Screen".
+    */
+
     new_layer: missing_func,
     /*
     function new_layer()
@@ -99,12 +114,23 @@ var RustFuncs = {
     Moves the specified sprite to the specified layer.
     */
 
+    restack_sprite: missing_func,
+    /*
+    function restack_sprite(sprite_id: number, order: string)
+
+    Moves the specified sprite within the stack of its layer. order is one of
+    "Raise", "Lower", "Front" or "Back".
+    */
+
     sprite_details: missing_func,
     /*
     function sprite_details(sprite_id: number, json: string)
 
     Given a sprite and a JSON object, update the sprites attributes using the
-    non-null dimensions of the json.
+    non-null dimensions of the json. Token details name, hp, max_hp, temp_hp,
+    conditions (an array) and fields (an object) may also be set, as may
+    owners (an array of user IDs controlling the token), and damage deals
+    damage to the token, or heals it if negative.
     */
 
     clone_sprite: missing_func,
@@ -127,6 +153,75 @@ var RustFuncs = {
 
     Sets the active tool.
     */
+
+    share_ruler: missing_func,
+    /*
+    function share_ruler(share: bool)
+
+    Sets whether rulers measured by this user are shown to other players.
+    */
+
+    roll_dice: missing_func,
+    /*
+    function roll_dice(request_json: string)
+
+    Rolls a dice expression. The request is an object of the form
+    { expression: "4d6kh3", audience: "Public" }, where audience may also be
+    "Gm" or { Whisper: [user_id, ...] }. Results are passed to add_roll.
+    */
+
+    send_chat: missing_func,
+    /*
+    function send_chat(request_json: string)
+
+    Sends a chat message in the current game. The request is an object of the
+    form { text: "Hello", audience: "Public" }, with audience as in roll_dice.
+    Messages, including this user's own, are passed to add_chat_message.
+    */
+
+    add_combatant: missing_func,
+    /*
+    function add_combatant(request_json: string)
+
+    Adds a combatant to the initiative order. The request is an object of the
+    form { name: "Goblin", sprite: sprite_id, initiative: 12 }, where sprite
+    and initiative are optional.
+    */
+
+    remove_combatant: missing_func,
+    /*
+    function remove_combatant(id: number)
+
+    Removes a combatant from the initiative order.
+    */
+
+    move_combatant: missing_func,
+    /*
+    function move_combatant(id: number, position: number)
+
+    Moves a combatant to a position in the initiative order.
+    */
+
+    set_initiative: missing_func,
+    /*
+    function set_initiative(id: number, initiative: string)
+
+    Sets the initiative of a combatant, clearing it if not a number.
+    */
+
+    next_turn: missing_func,
+    /*
+    function next_turn()
+
+    Advances to the next turn, starting combat if it hasn't started.
+    */
+
+    previous_turn: missing_func,
+    /*
+    function previous_turn()
+
+    Goes back a turn, ending combat if on the first turn.
+    */
 };
 
 // Array of callbacks to be performed when a given closure is available.
@@ -164,10 +259,30 @@ function load_texture(media_key) {
 // scene/menu/sprite/sprite_menu.html
 // function set_selected_sprite(sprite_json: string)
 // function clear_selected_sprite()
+// function update_players(players_json: string)
 
 // scene/menu/scene/scene_menu.html
 // function set_scene_details(details_json: string)
 
+// scene/menu/tools/ruler_labels.html
+// function clear_ruler_labels()
+// function add_ruler_label(label: string, x: number, y: number)
+
+// scene/menu/tools/token_labels.html
+// function clear_token_labels()
+// function add_token_label(token_json: string, x: number, y: number)
+
+// scene/menu/dice/dice_menu.html
+// function add_roll(roll_json: string)
+// function roll_error(message: string)
+
+// scene/menu/chat/chat_menu.html
+// function add_chat_message(message_json: string)
+// function chat_error(message: string)
+
+// scene/menu/initiative/initiative_menu.html
+// function update_initiative(initiative_json: string)
+
 // scene/game/role.js
 // function update_interface(role: number)
 